@@ -0,0 +1,104 @@
+//! Load against a running server: `clients` tasks ping as the given users
+//! as fast as they can, `heavy` tasks keep `/admin/verify` (a long hold of
+//! the state lock) busy, and one probe times `/healthz`, which never takes
+//! the lock. A probe that stalls behind lock waits means runtime workers
+//! are blocked, not just busy.
+//!
+//!     cargo run --release --example loadtest -- http://127.0.0.1:3000 alice,bob 64 4 10
+//!
+//! Arguments are the base URL, users, clients, heavy tasks and seconds.
+//! `ADMIN_TOKEN` is sent as `x-admin-token` if set. Start the server with
+//! `RUST_LOG=warn`, or its per-request debug logging is what gets measured.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+fn arg<T: std::str::FromStr>(n: usize, default: T) -> T {
+    std::env::args().nth(n).and_then(|a| a.parse().ok()).unwrap_or(default)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+#[tokio::main]
+async fn main() {
+    let base: String = arg(1, "http://127.0.0.1:3000".to_owned());
+    let users: Vec<String> = arg(2, "alice".to_owned()).split(',').map(str::to_owned).collect();
+    let clients: usize = arg(3, 64);
+    let heavy: usize = arg(4, 4);
+    let secs: u64 = arg(5, 10);
+    let token = std::env::var("ADMIN_TOKEN").unwrap_or_default();
+
+    let http = reqwest::Client::new();
+    let stop = Arc::new(AtomicBool::new(false));
+    let pings = Arc::new(AtomicU64::new(0));
+    let errors = Arc::new(AtomicU64::new(0));
+
+    let mut tasks = Vec::new();
+    for i in 0..clients {
+        let url = format!("{}/users/{}/ping", base, users[i % users.len()]);
+        let (http, stop, pings, errors) = (http.clone(), stop.clone(), pings.clone(), errors.clone());
+        tasks.push(tokio::spawn(async move {
+            while !stop.load(Ordering::Relaxed) {
+                match http.post(&url).send().await {
+                    Ok(_) => pings.fetch_add(1, Ordering::Relaxed),
+                    Err(_) => errors.fetch_add(1, Ordering::Relaxed),
+                };
+            }
+        }));
+    }
+    for _ in 0..heavy {
+        let url = format!("{}/admin/verify", base);
+        let (http, stop, token) = (http.clone(), stop.clone(), token.clone());
+        tasks.push(tokio::spawn(async move {
+            while !stop.load(Ordering::Relaxed) {
+                let _ = http.post(&url).header("x-admin-token", &token).send().await;
+            }
+        }));
+    }
+
+    let probe = {
+        let (url, http, stop) = (format!("{}/healthz", base), http.clone(), stop.clone());
+        tokio::spawn(async move {
+            let mut took = Vec::new();
+            while !stop.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                if http.get(&url).send().await.is_ok() {
+                    took.push(start.elapsed());
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            took
+        })
+    };
+
+    let start = Instant::now();
+    tokio::time::sleep(Duration::from_secs(secs)).await;
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed().as_secs_f64();
+    let mut took = probe.await.unwrap();
+    for t in tasks {
+        let _ = t.await;
+    }
+    took.sort();
+
+    let pings = pings.load(Ordering::Relaxed);
+    println!("clients {}, heavy {}, {:.1}s", clients, heavy, elapsed);
+    println!("pings: {} ({:.0}/s), {} errors", pings, pings as f64 / elapsed, errors.load(Ordering::Relaxed));
+    println!(
+        "healthz: {} probes, p50 {:?}, p99 {:?}, max {:?}",
+        took.len(),
+        percentile(&took, 0.5),
+        percentile(&took, 0.99),
+        took.last().copied().unwrap_or_default()
+    );
+}
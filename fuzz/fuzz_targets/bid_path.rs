@@ -0,0 +1,42 @@
+//! Drives arbitrary ladders and request streams through the bid path.
+//! Run with `cargo fuzz run bid_path` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/matching.rs"]
+#[allow(dead_code)]
+mod matching;
+
+fuzz_target!(|input: (Vec<(i64, i64)>, i64, i64, Vec<(i64, i64)>)| {
+    let (levels, start, fee, bids) = input;
+    let mut asks: matching::Ladder = levels.into_iter().filter(|(_, v)| *v > 0).collect();
+    let units: i128 = asks.values().map(|v| *v as i128).sum();
+    let mut balance = start;
+    let mut filled = 0i128;
+
+    for (price, qty) in bids {
+        let Some(b) = matching::charge_fee(balance, fee) else {
+            assert!(balance < fee || balance.checked_sub(fee).is_none());
+            continue;
+        };
+        balance = b;
+        // Same affordability gate the server applies before matching.
+        let Some(cost) = price.checked_mul(qty) else {
+            continue;
+        };
+        if qty <= 0 || balance.checked_sub(cost).map_or(true, |left| left < 0) {
+            continue;
+        }
+        let before = balance;
+        if let Some(fill) = matching::match_bid(&mut asks, price, qty) {
+            assert!(fill.price <= price && fill.vol > 0 && fill.vol <= qty);
+            balance -= matching::fill_cost(fill);
+            assert_eq!(before - balance, fill.price * fill.vol);
+            filled += fill.vol as i128;
+        }
+        assert!(asks.values().all(|v| *v > 0));
+    }
+    assert_eq!(asks.values().map(|v| *v as i128).sum::<i128>() + filled, units);
+});
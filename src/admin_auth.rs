@@ -0,0 +1,83 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ApiError, handoff::token_matches};
+
+const TOKEN_HEADER: &str = "x-admin-token";
+const TOKEN_ENV: &str = "ADMIN_TOKEN";
+
+/// The token every `/admin/*` request must carry in `x-admin-token`.
+/// `ADMIN_TOKEN` in the environment takes precedence, so the secret can stay
+/// out of the config file. With neither, the admin routes are open.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+    pub client_cert: Option<ClientCertConfig>,
+}
+
+/// Client certificates for `/admin/*`, on top of the token. The server
+/// speaks plain HTTP, so the TLS proxy in front of the admin listener (see
+/// `[listeners] admin`) checks certificates against the CA bundle and
+/// passes its verdict in `verify_header`, as nginx's `$ssl_client_verify`
+/// does. The header is only believed from `trusted_proxies`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClientCertConfig {
+    #[serde(default = "default_verify_header")]
+    pub verify_header: String,
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+fn default_verify_header() -> String {
+    "x-ssl-client-verify".to_owned()
+}
+
+impl AdminConfig {
+    pub fn token(&self) -> Option<String> {
+        std::env::var(TOKEN_ENV).ok().filter(|t| !t.is_empty()).or_else(|| self.token.clone())
+    }
+}
+
+fn guarded(path: &str) -> bool {
+    (path == "/admin" || path.starts_with("/admin/")) && path != "/admin/handoff/receive"
+}
+
+/// Refuses admin requests without the token. The handoff receiver is left
+/// to check its own shared secret, since the sending instance doesn't know
+/// this one's admin token.
+pub async fn require(State(token): State<Arc<str>>, req: Request, next: Next) -> Response {
+    if !guarded(req.uri().path()) {
+        return next.run(req).await;
+    }
+    let given = req.headers().get(TOKEN_HEADER).map(|v| v.as_bytes());
+    if !given.is_some_and(|given| token_matches(&token, given)) {
+        let msg = "missing or wrong admin token";
+        return ApiError::with(StatusCode::UNAUTHORIZED, "BAD_ADMIN_TOKEN", msg).into_response();
+    }
+    next.run(req).await
+}
+
+/// Refuses admin requests unless a trusted proxy vouches for the client's
+/// certificate. Exempts the same routes as `require`.
+pub async fn require_client_cert(State(cfg): State<Arc<ClientCertConfig>>, req: Request, next: Next) -> Response {
+    if !guarded(req.uri().path()) {
+        return next.run(req).await;
+    }
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip());
+    let trusted = peer.is_some_and(|ip| cfg.trusted_proxies.contains(&ip));
+    let verified = req.headers().get(cfg.verify_header.as_str()).is_some_and(|v| v == "SUCCESS");
+    if !trusted || !verified {
+        let msg = "a verified client certificate is required";
+        return ApiError::with(StatusCode::FORBIDDEN, "CLIENT_CERT_REQUIRED", msg).into_response();
+    }
+    next.run(req).await
+}
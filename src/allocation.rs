@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{
+    book, contention::StateLock, market::MarketEvent, matching, now,
+    orders::{OrderStatus, TimeInForce},
+    storage::Store,
+    AppState,
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationMode {
+    /// The first bid to reach a level takes the lot.
+    #[default]
+    FirstWins,
+    /// Bids reaching a level within `window_millis` of the first share its
+    /// volume, see `matching::pro_rata`.
+    ProRata,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AllocationConfig {
+    #[serde(default)]
+    pub mode: AllocationMode,
+    #[serde(default = "default_window_millis")]
+    pub window_millis: u64,
+}
+
+fn default_window_millis() -> u64 {
+    50
+}
+
+/// What became of a parked bid, and the bidder's position after.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub fill: Option<matching::Fill>,
+    /// Lots the order still wants after the fill.
+    pub remaining: i64,
+    /// Left on the book to fill later.
+    pub resting: bool,
+    pub position: i64,
+}
+
+#[derive(Debug)]
+struct PendingBid {
+    order_id: u64,
+    uname: String,
+    filled: oneshot::Sender<Outcome>,
+}
+
+/// Bids waiting out a window, and bids collected during a halt for the
+/// reopen auction, by price level.
+#[derive(Debug, Default)]
+pub struct Batches {
+    windows: HashMap<i64, Vec<PendingBid>>,
+    reopen: HashMap<i64, Vec<PendingBid>>,
+    /// The end of the halt the collected bids wait for.
+    reopen_at: Option<i64>,
+}
+
+/// Parks an accepted order until its level's window closes. The first bid
+/// at a level opens the window; the receiver gets the outcome.
+pub fn enqueue(
+    state: &Arc<Mutex<AppState>>,
+    g: &mut AppState,
+    price: i64,
+    order_id: u64,
+    uname: &str,
+) -> oneshot::Receiver<Outcome> {
+    let (tx, rx) = oneshot::channel();
+    // Withdrawals can empty a batch before its window closes; the window
+    // stays open until `settle` removes the level.
+    let open = g.batches.windows.contains_key(&price);
+    let batch = g.batches.windows.entry(price).or_default();
+    if !open {
+        let state = state.clone();
+        let window = Duration::from_millis(g.allocation.window_millis);
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            settle(&mut state.locked(), price, now());
+        });
+    }
+    batch.push(PendingBid { order_id, uname: uname.to_owned(), filled: tx });
+    rx
+}
+
+fn settle(g: &mut AppState, price: i64, now: i64) {
+    let bids = g.batches.windows.remove(&price).unwrap_or_default();
+    uncross(g, price, bids, now);
+    g.check_breaker(now);
+}
+
+/// Shares the level's volume among `bids` pro-rata.
+fn uncross(g: &mut AppState, price: i64, bids: Vec<PendingBid>, now: i64) {
+    // Entry checks ran when each bid arrived; anything may have changed
+    // since, and a user only gets one bid per window.
+    let mut eligible = Vec::with_capacity(bids.len());
+    for bid in bids {
+        let order = &g.orders.orders[&bid.order_id];
+        let qty = order.remaining;
+        // Cancelled since, by a path that didn't withdraw it.
+        if order.status != OrderStatus::Accepted {
+            let position = g.users.get(&bid.uname).map_or(0, |ua| ua.position);
+            let _ = bid.filled.send(Outcome { fill: None, remaining: 0, resting: false, position });
+            continue;
+        }
+        let reason = match g.users.get(&bid.uname) {
+            None => Some("INELIGIBLE_AT_MATCH"),
+            Some(ua) if ua.done_trade || book::admissible(g, ua, price, qty).is_err() => Some("INELIGIBLE_AT_MATCH"),
+            Some(_) if !g.trading_open(&bid.uname, now) => Some("INELIGIBLE_AT_MATCH"),
+            Some(_) if eligible.iter().any(|b: &PendingBid| b.uname == bid.uname) => Some("DUPLICATE_IN_WINDOW"),
+            Some(_) => None,
+        };
+        match reason {
+            Some(r) => {
+                g.orders.cancel(bid.order_id, r, now);
+                g.notify_order(bid.order_id, now);
+                let position = g.users.get(&bid.uname).map_or(0, |ua| ua.position);
+                let _ = bid.filled.send(Outcome { fill: None, remaining: 0, resting: false, position });
+            }
+            None => eligible.push(bid),
+        }
+    }
+
+    let vol = g.book.asks.get(&price).copied().unwrap_or(0).max(0);
+    let wants: Vec<i64> = eligible.iter().map(|b| g.orders.orders[&b.order_id].remaining).collect();
+    let alloc = matching::pro_rata(vol, &wants, |n| fastrand::usize(..n));
+    for (bid, lots) in eligible.into_iter().zip(alloc) {
+        let fill = g.take_ask(price, lots);
+        if let Some(fill) = fill {
+            g.fill(&bid.uname, fill, now);
+            g.orders.fill(bid.order_id, fill.price, fill.vol, now);
+        }
+        let remaining = g.orders.orders[&bid.order_id].remaining;
+        let resting = remaining > 0 && g.orders.orders[&bid.order_id].tif == TimeInForce::Gtc;
+        if resting {
+            book::rest(g, bid.order_id, now);
+        } else if remaining > 0 {
+            g.orders.cancel(bid.order_id, "PRO_RATA_UNALLOCATED", now);
+        }
+        g.notify_order(bid.order_id, now);
+        let position = g.users.get(&bid.uname).map_or(0, |ua| ua.position);
+        let _ = bid.filled.send(Outcome { fill, remaining, resting, position });
+    }
+}
+
+/// Collects an accepted order for the auction that ends the halt at
+/// `until_nanos`. The first bid of a halt schedules `reopen`. The bidder
+/// was answered at entry; what becomes of the order goes out as order
+/// events.
+pub fn enqueue_reopen(
+    state: &Arc<Mutex<AppState>>,
+    g: &mut AppState,
+    price: i64,
+    order_id: u64,
+    uname: &str,
+    until_nanos: i64,
+    now: i64,
+) {
+    if g.batches.reopen_at != Some(until_nanos) {
+        g.batches.reopen_at = Some(until_nanos);
+        let state = state.clone();
+        let wait = Duration::from_nanos(until_nanos.saturating_sub(now).max(0) as u64);
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            reopen(&mut state.locked(), crate::now());
+        });
+    }
+    let (filled, _) = oneshot::channel();
+    g.batches.reopen.entry(price).or_default().push(PendingBid { order_id, uname: uname.to_owned(), filled });
+    publish_indicative(g, now);
+}
+
+/// The collected level that would trade the most if the halt ended now,
+/// the lower on a tie, and how many lots. A bid only matches asks at its
+/// own price, so each level uncrosses on its own.
+pub fn indicative(g: &AppState) -> (Option<i64>, i64) {
+    let mut levels: Vec<(&i64, &Vec<PendingBid>)> = g.batches.reopen.iter().collect();
+    levels.sort_by_key(|(price, _)| **price);
+    let mut best = (None, 0);
+    for (price, bids) in levels {
+        let demand: i64 = bids
+            .iter()
+            .map(|b| &g.orders.orders[&b.order_id])
+            .filter(|o| o.status == OrderStatus::Accepted)
+            .map(|o| o.remaining)
+            .sum();
+        let vol = demand.min(g.book.asks.get(price).copied().unwrap_or(0).max(0));
+        if vol > best.1 {
+            best = (Some(*price), vol);
+        }
+    }
+    best
+}
+
+fn publish_indicative(g: &AppState, now: i64) {
+    let Some(reopen_nanos) = g.batches.reopen_at else {
+        return;
+    };
+    let (price, vol) = indicative(g);
+    g.market.send(|| MarketEvent::Indicative { price, vol, reopen_nanos, ts_nanos: now });
+}
+
+/// Ends the reopen auction: each collected level is shared pro-rata as if
+/// its window had closed, lowest price first. Bids collected before an
+/// operator pause are cancelled with `PAUSED` instead.
+pub fn reopen(g: &mut AppState, now: i64) {
+    g.batches.reopen_at = None;
+    let mut levels: Vec<(i64, Vec<PendingBid>)> = std::mem::take(&mut g.batches.reopen).into_iter().collect();
+    levels.sort_by_key(|(price, _)| *price);
+    for (price, bids) in levels {
+        if !g.paused {
+            uncross(g, price, bids, now);
+            continue;
+        }
+        for bid in bids {
+            if g.orders.orders[&bid.order_id].status == OrderStatus::Accepted {
+                g.orders.cancel(bid.order_id, "PAUSED", now);
+                g.notify_order(bid.order_id, now);
+            }
+        }
+    }
+    g.check_breaker(now);
+}
+
+/// Takes `order_id` out of its batch, if it is parked, and answers its
+/// bidder with no fill. The caller cancels or replaces the order.
+pub fn withdraw(g: &mut AppState, order_id: u64) {
+    let take = |batch: &mut HashMap<i64, Vec<PendingBid>>| {
+        batch.values_mut().find_map(|bids| {
+            let i = bids.iter().position(|b| b.order_id == order_id)?;
+            Some(bids.remove(i))
+        })
+    };
+    let collected = take(&mut g.batches.reopen);
+    let reopening = collected.is_some();
+    if let Some(bid) = collected.or_else(|| take(&mut g.batches.windows)) {
+        let position = g.users.get(&bid.uname).map_or(0, |ua| ua.position);
+        let _ = bid.filled.send(Outcome { fill: None, remaining: 0, resting: false, position });
+    }
+    if reopening {
+        publish_indicative(g, now());
+    }
+}
+
+/// Cancels every parked and collected bid with `reason`, returning how
+/// many. Their windows, and the reopen auction, then close on nothing.
+pub fn cancel_all(g: &mut AppState, reason: &str, now: i64) -> usize {
+    let mut n = 0;
+    let windows = std::mem::take(&mut g.batches.windows);
+    let reopen = std::mem::take(&mut g.batches.reopen);
+    for (_, bids) in windows.into_iter().chain(reopen) {
+        for bid in bids {
+            g.orders.cancel(bid.order_id, reason, now);
+            g.notify_order(bid.order_id, now);
+            let position = g.users.get(&bid.uname).map_or(0, |ua| ua.position);
+            let _ = bid.filled.send(Outcome { fill: None, remaining: 0, resting: false, position });
+            n += 1;
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn game() -> Arc<Mutex<AppState>> {
+        testing::shared("asks = [{ price = 10, vol = 4 }]\n[allocation]\nmode = \"pro_rata\"")
+    }
+
+    /// Both want 2 of the 4 lots at 10; alice's bid is parked, then gone
+    /// before the window closes by `cancel`.
+    fn cancel_alice_in_window(cancel: impl FnOnce(&mut AppState, u64)) {
+        let state = game();
+        let mut g = state.locked();
+        let a = g.orders.accept("alice", 10, 2, TimeInForce::Ioc, 1);
+        let b = g.orders.accept("bob", 10, 2, TimeInForce::Ioc, 1);
+        let mut alice = enqueue(&state, &mut g, 10, a, "alice");
+        let mut bob = enqueue(&state, &mut g, 10, b, "bob");
+        cancel(&mut g, a);
+        settle(&mut g, 10, 2);
+
+        assert!(alice.try_recv().unwrap().fill.is_none());
+        assert_eq!(g.orders.orders[&a].status, OrderStatus::Cancelled);
+        assert_eq!(g.users["alice"].position, 0);
+        assert_eq!(g.users["alice"].balance, 1000);
+        assert_eq!(bob.try_recv().unwrap().fill.map(|f| f.vol), Some(2));
+        assert_eq!(g.book.asks[&10], 2);
+    }
+
+    #[tokio::test]
+    async fn bid_cancelled_in_window_does_not_fill() {
+        cancel_alice_in_window(|g, id| {
+            withdraw(g, id);
+            g.orders.cancel(id, "USER_CANCEL", 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn bid_cancelled_without_withdrawing_does_not_fill() {
+        cancel_alice_in_window(|g, id| {
+            g.orders.cancel(id, "SETTLED", 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn bids_collected_during_a_halt_uncross_at_reopen() {
+        let state = testing::shared(
+            "asks = [{ price = 10, vol = 4 }, { price = 12, vol = 1 }]\n\
+             [circuit_breaker]\nmax_move_bps = 100\nwindow_secs = 10\nhalt_secs = 30\nreopen_auction = true",
+        );
+        let mut g = state.locked();
+        let halt = crate::breaker::Halt { seq: 1, started_nanos: 0, until_nanos: 100, low: 10, high: 12 };
+        g.breaker.halts.push(halt);
+        let collect = |g: &mut AppState, uname: &str, price: i64, qty: i64| {
+            let id = g.orders.accept(uname, price, qty, TimeInForce::Ioc, 1);
+            enqueue_reopen(&state, g, price, id, uname, 100, 1);
+            id
+        };
+        let a = collect(&mut g, "alice", 10, 3);
+        let b = collect(&mut g, "bob", 10, 3);
+        let c = collect(&mut g, "carol", 12, 1);
+        assert_eq!(indicative(&g), (Some(10), 4));
+        assert_eq!(g.users["alice"].position, 0);
+
+        reopen(&mut g, 100);
+        assert_eq!((g.users["alice"].position, g.users["bob"].position, g.users["carol"].position), (2, 2, 1));
+        assert_eq!(g.orders.orders[&a].status, OrderStatus::Cancelled);
+        assert_eq!(g.orders.orders[&b].remaining, 1);
+        assert_eq!(g.orders.orders[&c].remaining, 0);
+        assert_eq!(indicative(&g), (None, 0));
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use rusqlite::{types::ValueRef, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+
+use crate::{bankruptcy::Bankruptcy, breaker::Halt, contention::StateLock, now, tape::Trade, AppState, ReqClock, RespMeta};
+
+const MAX_ROWS: usize = 10_000;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnalyticsConfig {
+    /// SQLite file trades and account snapshots are copied into.
+    pub db_path: String,
+    #[serde(default = "default_snapshot_secs")]
+    pub snapshot_secs: u64,
+}
+
+fn default_snapshot_secs() -> u64 {
+    10
+}
+
+/// Schema changes for the store, applied in order. `PRAGMA user_version`
+/// records how many have run; append new entries, never edit old ones.
+const MIGRATIONS: &[&str] = &[
+    // Stores created before versioning already have these tables.
+    "CREATE TABLE IF NOT EXISTS trades (
+        seq INTEGER PRIMARY KEY,
+        uname TEXT NOT NULL,
+        price INTEGER NOT NULL,
+        vol INTEGER NOT NULL,
+        ts_nanos INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS snapshots (
+        ts_nanos INTEGER NOT NULL,
+        uname TEXT NOT NULL,
+        balance INTEGER NOT NULL,
+        done_trade INTEGER NOT NULL
+    );",
+    "CREATE TABLE halts (
+        seq INTEGER PRIMARY KEY,
+        started_nanos INTEGER NOT NULL,
+        until_nanos INTEGER NOT NULL,
+        low INTEGER NOT NULL,
+        high INTEGER NOT NULL
+    );",
+    "CREATE TABLE bankruptcies (
+        seq INTEGER PRIMARY KEY,
+        uname TEXT NOT NULL,
+        balance INTEGER NOT NULL,
+        fee INTEGER NOT NULL,
+        ts_nanos INTEGER NOT NULL
+    );",
+];
+
+fn open_store(path: &str) -> rusqlite::Result<Connection> {
+    let mut conn = Connection::open(path)?;
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if applied > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+            Some(format!(
+                "analytics store schema v{} is newer than this server (v{})",
+                applied,
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(conn)
+}
+
+/// Copies new trades, halts and bankruptcies and a snapshot of every account into the analytics
+/// store every `snapshot_secs`. Runs on its own thread so SQLite writes never
+/// hold up the runtime; the state lock is only held while cloning.
+pub fn spawn_writer(cfg: AnalyticsConfig, state: Arc<Mutex<AppState>>) {
+    std::thread::spawn(move || {
+        let mut conn = match open_store(&cfg.db_path) {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("analytics store {} unavailable: {}", cfg.db_path, e);
+                return;
+            }
+        };
+        let mut last_seq: u64 = conn
+            .query_row("SELECT COALESCE(MAX(seq), 0) FROM trades", [], |r| r.get(0))
+            .unwrap_or(0);
+        let mut last_halt: u64 = conn
+            .query_row("SELECT COALESCE(MAX(seq), 0) FROM halts", [], |r| r.get(0))
+            .unwrap_or(0);
+        let mut last_bankruptcy: u64 = conn
+            .query_row("SELECT COALESCE(MAX(seq), 0) FROM bankruptcies", [], |r| r.get(0))
+            .unwrap_or(0);
+
+        loop {
+            std::thread::sleep(Duration::from_secs(cfg.snapshot_secs.max(1)));
+            let (trades, accounts, halts, bankruptcies) = {
+                let g = state.locked();
+                let start = g.tape.trades.partition_point(|t| t.seq <= last_seq);
+                (
+                    g.tape.trades[start..].to_vec(),
+                    g.users.iter().map(|(u, ua)| (u.clone(), ua.balance, ua.done_trade)).collect::<Vec<_>>(),
+                    g.breaker.halts.iter().filter(|h| h.seq > last_halt).cloned().collect::<Vec<_>>(),
+                    g.bankruptcies.0.iter().filter(|b| b.seq > last_bankruptcy).cloned().collect::<Vec<_>>(),
+                )
+            };
+            if let Err(e) = write_batch(&mut conn, &trades, &accounts, &halts, &bankruptcies, now()) {
+                tracing::warn!("analytics write failed: {}", e);
+                continue;
+            }
+            if let Some(t) = trades.last() {
+                last_seq = t.seq;
+            }
+            if let Some(h) = halts.last() {
+                last_halt = h.seq;
+            }
+            if let Some(b) = bankruptcies.last() {
+                last_bankruptcy = b.seq;
+            }
+        }
+    });
+}
+
+fn write_batch(
+    conn: &mut Connection,
+    trades: &[Trade],
+    accounts: &[(String, i64, bool)],
+    halts: &[Halt],
+    bankruptcies: &[Bankruptcy],
+    ts_nanos: i64,
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut ins = tx.prepare_cached(
+            "INSERT OR IGNORE INTO trades (seq, uname, price, vol, ts_nanos) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for t in trades {
+            ins.execute((t.seq as i64, &t.uname, t.price, t.vol, t.ts_nanos))?;
+        }
+        let mut snap = tx.prepare_cached(
+            "INSERT INTO snapshots (ts_nanos, uname, balance, done_trade) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (u, balance, done) in accounts {
+            snap.execute((ts_nanos, u, balance, done))?;
+        }
+        let mut halt = tx.prepare_cached(
+            "INSERT OR IGNORE INTO halts (seq, started_nanos, until_nanos, low, high) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for h in halts {
+            halt.execute((h.seq as i64, h.started_nanos, h.until_nanos, h.low, h.high))?;
+        }
+        let mut bankrupt = tx.prepare_cached(
+            "INSERT OR IGNORE INTO bankruptcies (seq, uname, balance, fee, ts_nanos) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for b in bankruptcies {
+            bankrupt.execute((b.seq as i64, &b.uname, b.balance, b.fee, b.ts_nanos))?;
+        }
+    }
+    tx.commit()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotRow {
+    pub ts_nanos: i64,
+    pub balance: i64,
+    pub done_trade: bool,
+}
+
+/// Everything the store holds about `uname`.
+pub fn user_rows(path: &str, uname: &str) -> rusqlite::Result<(Vec<Trade>, Vec<SnapshotRow>)> {
+    let conn = open_store(path)?;
+    let trades = conn
+        .prepare("SELECT seq, uname, price, vol, ts_nanos FROM trades WHERE uname = ?1 ORDER BY seq")?
+        .query_map([uname], |r| {
+            Ok(Trade {
+                seq: r.get::<_, i64>(0)? as u64,
+                uname: r.get(1)?,
+                price: r.get(2)?,
+                vol: r.get(3)?,
+                ts_nanos: r.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let snapshots = conn
+        .prepare("SELECT ts_nanos, balance, done_trade FROM snapshots WHERE uname = ?1 ORDER BY ts_nanos")?
+        .query_map([uname], |r| {
+            Ok(SnapshotRow { ts_nanos: r.get(0)?, balance: r.get(1)?, done_trade: r.get(2)? })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok((trades, snapshots))
+}
+
+/// Replaces `uname` with `alias` in trades and drops their snapshots.
+pub fn anonymize(path: &str, uname: &str, alias: &str) -> rusqlite::Result<()> {
+    let mut conn = open_store(path)?;
+    let tx = conn.transaction()?;
+    tx.execute("UPDATE trades SET uname = ?2 WHERE uname = ?1", (uname, alias))?;
+    tx.execute("DELETE FROM snapshots WHERE uname = ?1", [uname])?;
+    tx.execute("UPDATE bankruptcies SET uname = ?2 WHERE uname = ?1", (uname, alias))?;
+    tx.commit()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    pub sql: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// Set when the result was cut off at `MAX_ROWS`.
+    pub truncated: bool,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn run_query(path: &str, sql: &str) -> rusqlite::Result<QueryResult> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let started = Instant::now();
+    conn.progress_handler(1000, Some(move || started.elapsed() > QUERY_TIMEOUT));
+
+    let mut stmt = conn.prepare(sql)?;
+    if !stmt.readonly() {
+        return Err(rusqlite::Error::InvalidQuery);
+    }
+    let mut res = QueryResult {
+        columns: stmt.column_names().into_iter().map(str::to_owned).collect(),
+        ..Default::default()
+    };
+    let n = res.columns.len();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if res.rows.len() == MAX_ROWS {
+            res.truncated = true;
+            break;
+        }
+        let mut out = Vec::with_capacity(n);
+        for i in 0..n {
+            out.push(match row.get_ref(i)? {
+                ValueRef::Null => serde_json::Value::Null,
+                ValueRef::Integer(v) => v.into(),
+                ValueRef::Real(v) => v.into(),
+                ValueRef::Text(v) => String::from_utf8_lossy(v).into(),
+                ValueRef::Blob(v) => format!("<{} byte blob>", v.len()).into(),
+            });
+        }
+        res.rows.push(out);
+    }
+    Ok(res)
+}
+
+/// Read-only SQL over the analytics store. Only a single statement that
+/// SQLite reports as read-only is accepted, and it is aborted after
+/// `QUERY_TIMEOUT`.
+pub async fn admin_query(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<QueryRequest>,
+) -> (StatusCode, Json<QueryResult>) {
+    let clock = ReqClock::start();
+    let Some(path) = state.locked().analytics_db.clone() else {
+        return clock.reply(StatusCode::NOT_FOUND, QueryResult::default());
+    };
+
+    let res = tokio::task::spawn_blocking(move || run_query(&path, &req.sql)).await.unwrap();
+    match res {
+        Ok(res) => clock.reply(StatusCode::OK, res),
+        Err(e) => {
+            let err = match e {
+                rusqlite::Error::InvalidQuery => "only read-only statements are allowed".to_owned(),
+                e => e.to_string(),
+            };
+            clock.reply(StatusCode::BAD_REQUEST, QueryResult { error: Some(err), ..Default::default() })
+        }
+    }
+}
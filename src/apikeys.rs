@@ -0,0 +1,36 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, feed, killswitch, AppState};
+
+/// With `required`, every `/users/:uname/*` call must carry the user's key
+/// from `[user_keys]` in `x-api-key` (or `?key=`), so nobody can spend
+/// another user's balance by knowing their name. A user without a key can't
+/// make any call. Without it, keys only guard the private feeds.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiKeysConfig {
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Refuses user calls without the right key before they reach a handler,
+/// so a refused call is never charged. Sits inside `usernames::canonicalize`
+/// so keys are looked up under the roster name.
+pub async fn require(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let Some(uname) = killswitch::user_of(req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let key = Query::<feed::KeyQuery>::try_from_uri(req.uri()).ok().and_then(|q| q.0.key);
+    let ok = feed::key_matches(&state.locked(), uname, req.headers(), key.as_deref());
+    if !ok {
+        return ApiError::with(StatusCode::UNAUTHORIZED, "BAD_KEY", "missing or wrong user key").into_response();
+    }
+    next.run(req).await
+}
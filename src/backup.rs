@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calendar::{Calendar, CalendarConfig},
+    contention::StateLock,
+    errors::ApiError,
+    instruments::InstrumentBook,
+    ledger::Ledger,
+    loans::Loans,
+    invariants::Issuance,
+    matching, now,
+    orders::{OrderStatus, OrderStore},
+    reservations, schema,
+    settlement::{self, SettlementRecord},
+    tape::Tape,
+    AppState, HouseAccount, ReqClock, RespMeta, UserAccount,
+};
+
+/// How long a restore confirmation token stays valid.
+const CONFIRM_TTL_NANOS: i64 = 5 * 60 * 1_000_000_000;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupConfig {
+    pub dir: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// How many of the newest backups to keep; older ones are deleted.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_keep() -> usize {
+    10
+}
+
+/// The parts of `AppState` that make up the game; everything else is
+/// rebuilt from config on start.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StateImage {
+    pub schema_version: u64,
+    pub taken_nanos: i64,
+    pub users: HashMap<String, UserAccount>,
+    pub asks: BTreeMap<i64, i64>,
+    /// When the house volume at a price is withdrawn.
+    pub ask_expiry: BTreeMap<i64, i64>,
+    /// Lots in `asks` that users listed, as (price, seller, vol).
+    pub sells: Vec<(i64, String, i64)>,
+    pub tape: Tape,
+    pub house: HouseAccount,
+    pub issued: Issuance,
+    pub orders: OrderStore,
+    pub settlement: Option<SettlementRecord>,
+    pub instruments: BTreeMap<String, InstrumentBook>,
+    pub ledger: Ledger,
+    pub loans: Loans,
+    /// Keys of users registered at runtime as well as from config.
+    pub user_keys: HashMap<String, String>,
+    /// Every name a user is known by, see `Names::entries`.
+    pub names: BTreeMap<String, String>,
+    /// The schedule, from config or `/admin/arm`.
+    pub trade_start_nanos: Option<i64>,
+    pub calendar: Option<CalendarConfig>,
+    pub trade_end_nanos: Option<i64>,
+}
+
+impl StateImage {
+    pub fn capture(st: &AppState) -> Self {
+        let book = reservations::whole_book(st);
+        StateImage {
+            schema_version: schema::STATE_SCHEMA_VERSION,
+            taken_nanos: now(),
+            users: st.users.clone(),
+            asks: book.asks.clone(),
+            ask_expiry: st.ask_expiry.clone(),
+            sells: book.sell_lots().map(|(p, s, v)| (p, s.to_owned(), v)).collect(),
+            tape: st.tape.clone(),
+            house: st.house.clone(),
+            issued: st.issued.clone(),
+            orders: st.orders.clone(),
+            settlement: st.settlement.clone(),
+            instruments: st.instruments.books.clone(),
+            ledger: st.ledger.clone(),
+            loans: st.loans.clone(),
+            user_keys: st.user_keys.clone(),
+            names: st.names.entries(),
+            trade_start_nanos: st.config.trade_start_nanos,
+            calendar: st.config.calendar.clone(),
+            trade_end_nanos: st.trade_end_nanos,
+        }
+    }
+
+    /// Puts the image in place of the game. A game still in the lobby takes
+    /// the image's schedule too; the returned trade end, if any, is for the
+    /// caller to schedule the close at.
+    pub fn apply(self, st: &mut AppState) -> Option<i64> {
+        st.users = self.users;
+        st.book = matching::OrderBook::default();
+        st.reservations = Default::default();
+        st.book.asks = self.asks;
+        st.ask_expiry = self.ask_expiry;
+        for (price, seller, vol) in self.sells.iter() {
+            st.book.queue_sell(*price, seller, *vol);
+        }
+        // Resting bids are open orders; the book only indexes them.
+        for o in self.orders.orders.values().filter(|o| o.status == OrderStatus::Resting) {
+            st.book.rest_bid(o.price, o.id, o.priority_nanos);
+        }
+        st.book_snapshot = None;
+        st.board_changed();
+        st.tape = self.tape;
+        st.house = self.house;
+        st.issued = self.issued;
+        st.orders = self.orders;
+        st.settlement = self.settlement;
+        st.pending_settlement = None;
+        st.instruments.books = self.instruments;
+        st.ledger = self.ledger;
+        st.loans = self.loans;
+        // Config has the last word on anyone it names.
+        for (u, k) in self.user_keys {
+            st.user_keys.entry(u).or_insert(k);
+        }
+        st.names.restore(self.names);
+
+        if st.calendar.armed() || (self.trade_start_nanos.is_none() && self.calendar.is_none()) {
+            return None;
+        }
+        match Calendar::new(self.trade_start_nanos, self.calendar.as_ref()) {
+            Ok(c) => st.calendar = c,
+            Err(e) => {
+                tracing::error!("state image has a schedule that can't be used: {}", e);
+                return None;
+            }
+        }
+        st.config.trade_start_nanos = self.trade_start_nanos;
+        st.config.calendar = self.calendar;
+        st.board_changed();
+        if st.trade_end_nanos.is_some() {
+            return None;
+        }
+        st.trade_end_nanos = self.trade_end_nanos;
+        st.config.trade_end_nanos = self.trade_end_nanos;
+        self.trade_end_nanos
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupStats {
+    pub succeeded: u64,
+    pub failed: u64,
+    pub last_success_nanos: Option<i64>,
+    pub last_file: Option<String>,
+    pub last_error: Option<String>,
+}
+
+pub fn write_backup(dir: &str, image: &StateImage) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    // Fixed-width nanos keep lexical order equal to age order.
+    let path = Path::new(dir).join(format!("backup-{:020}.json.gz", image.taken_nanos));
+    let tmp = path.with_extension("tmp");
+    let mut enc = GzEncoder::new(std::fs::File::create(&tmp)?, Compression::default());
+    serde_json::to_writer(&mut enc, image)?;
+    enc.finish()?.sync_all()?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// Backup files in `dir`, oldest first.
+pub fn list_backups(dir: &str) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("backup-") && n.ends_with(".json.gz"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+pub fn read_backup(path: &Path) -> std::io::Result<StateImage> {
+    let mut body = Vec::new();
+    GzDecoder::new(std::fs::File::open(path)?).read_to_end(&mut body)?;
+    let raw = serde_json::from_slice(&body).map_err(std::io::Error::other)?;
+    let image = schema::upgrade_state(raw).map_err(std::io::Error::other)?;
+    serde_json::from_value(image).map_err(std::io::Error::other)
+}
+
+/// The backup `--restore` names: the file itself, or the newest one in a
+/// directory.
+pub fn read_for_restore(path: &str) -> std::io::Result<(PathBuf, StateImage)> {
+    let path = if Path::new(path).is_dir() {
+        let files = list_backups(path)?;
+        files
+            .last()
+            .cloned()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no backups in directory"))?
+    } else {
+        PathBuf::from(path)
+    };
+    let image = read_backup(&path)?;
+    Ok((path, image))
+}
+
+/// Writes `image` and deletes every other backup in `dir`, returning how
+/// many went.
+pub fn replace_backups(dir: &str, image: &StateImage) -> std::io::Result<usize> {
+    let path = write_backup(dir, image)?;
+    let old: Vec<PathBuf> = list_backups(dir)?.into_iter().filter(|p| *p != path).collect();
+    for p in old.iter() {
+        std::fs::remove_file(p)?;
+    }
+    Ok(old.len())
+}
+
+fn rotate(dir: &str, keep: usize) -> std::io::Result<()> {
+    let files = list_backups(dir)?;
+    for old in files.iter().take(files.len().saturating_sub(keep.max(1))) {
+        std::fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+pub fn spawn_backups(cfg: BackupConfig, state: Arc<Mutex<AppState>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(cfg.interval_secs.max(1)));
+        let image = StateImage::capture(&state.locked());
+        let res = write_backup(&cfg.dir, &image).and_then(|p| rotate(&cfg.dir, cfg.keep).map(|_| p));
+
+        let mut g = state.locked();
+        let stats = &mut g.backup_stats;
+        match res {
+            Ok(p) => {
+                stats.succeeded += 1;
+                stats.last_success_nanos = Some(image.taken_nanos);
+                stats.last_file = Some(p.display().to_string());
+                stats.last_error = None;
+            }
+            Err(e) => {
+                tracing::error!("backup to {} failed: {}", cfg.dir, e);
+                stats.failed += 1;
+                stats.last_error = Some(e.to_string());
+            }
+        }
+    });
+}
+
+/// A restore that has been previewed and is waiting for its confirmation token.
+#[derive(Debug, Clone)]
+pub struct PendingRestore {
+    pub name: String,
+    pub token: String,
+    pub expires_nanos: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    /// File name of the backup inside the backup directory.
+    pub name: String,
+    /// Token returned by the preview call; omit it to get a preview.
+    pub confirm_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceDelta {
+    pub uname: String,
+    pub live: i64,
+    pub backup: i64,
+    pub delta: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct RestoreResult {
+    pub applied: bool,
+    /// Present on a preview; send it back as `confirm_token` to apply.
+    pub confirm_token: Option<String>,
+    pub backup_taken_nanos: i64,
+    pub users_gained: Vec<String>,
+    pub users_lost: Vec<String>,
+    pub balance_deltas: Vec<BalanceDelta>,
+    pub trades_live: usize,
+    pub trades_backup: usize,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn diff(st: &AppState, image: &StateImage) -> RestoreResult {
+    let mut res = RestoreResult {
+        backup_taken_nanos: image.taken_nanos,
+        trades_live: st.tape.trades.len(),
+        trades_backup: image.tape.trades.len(),
+        ..Default::default()
+    };
+    for (u, ua) in image.users.iter() {
+        match st.users.get(u) {
+            None => res.users_gained.push(u.clone()),
+            Some(live) if live.balance != ua.balance => res.balance_deltas.push(BalanceDelta {
+                uname: u.clone(),
+                live: live.balance,
+                backup: ua.balance,
+                delta: ua.balance - live.balance,
+            }),
+            Some(_) => {}
+        }
+    }
+    res.users_lost = st.users.keys().filter(|u| !image.users.contains_key(*u)).cloned().collect();
+    res.users_gained.sort();
+    res.users_lost.sort();
+    res.balance_deltas.sort_by(|a, b| a.uname.cmp(&b.uname));
+    res
+}
+
+fn refuse(clock: &ReqClock, code: StatusCode, err: &str) -> (StatusCode, Json<RestoreResult>) {
+    let api_err = ApiError { message: err.to_owned(), ..ApiError::of_status(code) };
+    clock.refuse(api_err, RestoreResult { error: Some(err.to_owned()), ..Default::default() })
+}
+
+/// Two-step restore: the first call returns a diff against live state and a
+/// confirmation token, the second call with that token applies the backup.
+/// Only allowed while trading is paused.
+pub async fn admin_restore_backup(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<RestoreRequest>,
+) -> (StatusCode, Json<RestoreResult>) {
+    let clock = ReqClock::start();
+    let Some(dir) = state.locked().backup_dir.clone() else {
+        return refuse(&clock, StatusCode::NOT_FOUND, "backups are not configured");
+    };
+    let path = Path::new(&dir).join(&req.name);
+    let known = list_backups(&dir).map(|files| files.contains(&path)).unwrap_or(false);
+    if !known {
+        return refuse(&clock, StatusCode::NOT_FOUND, "no such backup");
+    }
+    let image = match tokio::task::spawn_blocking(move || read_backup(&path)).await.unwrap() {
+        Ok(i) => i,
+        Err(e) => return refuse(&clock, StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    let mut g = state.locked();
+    if !g.paused {
+        return refuse(&clock, StatusCode::CONFLICT, "pause trading before restoring");
+    }
+    let mut res = diff(&g, &image);
+    let now = now();
+    match req.confirm_token {
+        None => {
+            let token = format!("{:016x}", fastrand::u64(..));
+            g.pending_restore = Some(PendingRestore {
+                name: req.name,
+                token: token.clone(),
+                expires_nanos: now + CONFIRM_TTL_NANOS,
+            });
+            res.confirm_token = Some(token);
+            clock.reply(StatusCode::OK, res)
+        }
+        Some(token) => {
+            let valid = g.pending_restore.as_ref().is_some_and(|p| {
+                p.name == req.name && p.token == token && now < p.expires_nanos
+            });
+            if !valid {
+                return refuse(&clock, StatusCode::FORBIDDEN, "confirmation token is invalid or expired");
+            }
+            g.pending_restore = None;
+            if let Some(end) = image.apply(&mut g) {
+                settlement::spawn_close(state.clone(), end);
+            }
+            tracing::warn!("state restored from backup {}", req.name);
+            g.feeds.timeline.admin_global(format!("state restored from backup {}", req.name));
+            res.applied = true;
+            clock.reply(StatusCode::OK, res)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{registration, testing};
+
+    const CONFIG: &str = "[usernames]\ncase_insensitive = true";
+
+    /// The image as a restart finds it, written at `version`.
+    fn reload(image: &StateImage, version: u64) -> AppState {
+        let mut raw = serde_json::to_value(image).unwrap();
+        raw["schema_version"] = serde_json::Value::from(version);
+        if version < 22 {
+            let m = raw.as_object_mut().unwrap();
+            m.remove("user_keys");
+            m.remove("names");
+        }
+        let image: StateImage = serde_json::from_value(schema::upgrade_state(raw).unwrap()).unwrap();
+        let mut g = testing::game(CONFIG);
+        image.apply(&mut g);
+        g
+    }
+
+    #[test]
+    fn users_registered_at_runtime_keep_their_key_and_name() {
+        let mut g = testing::game(CONFIG);
+        registration::join(&mut g, "Dave", Some("k".to_owned()), 1).unwrap();
+        let image = StateImage::capture(&g);
+
+        let g = reload(&image, schema::STATE_SCHEMA_VERSION);
+        assert_eq!(g.user_keys.get("Dave").map(String::as_str), Some("k"));
+        assert_eq!(g.names.canonical("dave"), Some("Dave"));
+
+        // Older images had no keys to keep, but their users keep their names.
+        let g = reload(&image, 21);
+        assert!(!g.user_keys.contains_key("Dave"));
+        assert_eq!(g.names.canonical("DAVE"), Some("Dave"));
+        assert_eq!(g.names.canonical("alice"), Some("alice"));
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+/// Without this section a fee the balance can't cover is simply refused.
+/// With it, such a fee makes the account bankrupt: every later paid call
+/// is refused with `BANKRUPT`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BankruptcyConfig {
+    /// Lowest balance fees may take an account to; may be negative.
+    #[serde(default)]
+    pub floor: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Bankruptcy {
+    pub seq: u64,
+    pub uname: String,
+    /// Balance left, and the fee it could not cover.
+    pub balance: i64,
+    pub fee: i64,
+    pub ts_nanos: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Bankruptcies(pub Vec<Bankruptcy>);
+
+impl Bankruptcies {
+    pub fn record(&mut self, uname: &str, balance: i64, fee: i64, now: i64) {
+        let seq = self.0.last().map_or(1, |b| b.seq + 1);
+        self.0.push(Bankruptcy { seq, uname: uname.to_owned(), balance, fee, ts_nanos: now });
+    }
+
+    pub fn anonymize(&mut self, uname: &str, alias: &str) {
+        for b in self.0.iter_mut().filter(|b| b.uname == uname) {
+            b.uname = alias.to_owned();
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    book, client_deadline, contention::StateLock, deadline_passed, errors::ApiError, fees::Endpoint, instruments,
+    matching, now, AppState, BidFill, BidStatus, ReqClock, RespMeta,
+};
+
+/// One instrument's part of a basket: `qty` lots at `price` or cheaper.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Leg {
+    pub symbol: String,
+    pub price: i64,
+    #[serde(default = "default_qty")]
+    pub qty: i64,
+}
+
+fn default_qty() -> i64 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BasketRequest {
+    pub legs: Vec<Leg>,
+}
+
+#[derive(Serialize, Default)]
+pub struct LegResult {
+    pub symbol: String,
+    pub price: i64,
+    pub qty: i64,
+    pub fills: Vec<BidFill>,
+    pub filled_qty: i64,
+    pub cost: i64,
+    /// Lots the book couldn't offer at the price; any shortfall leaves the
+    /// whole basket unfilled.
+    pub short: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct BasketResult {
+    /// `filled` if every leg filled, else nothing did.
+    pub status: BidStatus,
+    pub legs: Vec<LegResult>,
+    pub total_cost: i64,
+    pub total_fees: i64,
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Takes up to `qty` lots from `asks` at `price` or below, cheapest first.
+fn sweep(asks: &mut matching::Ladder, price: i64, qty: i64) -> Vec<matching::Fill> {
+    let mut fills = Vec::new();
+    let mut left = qty;
+    let levels: Vec<i64> = asks.range(..=price).map(|(p, _)| *p).collect();
+    for p in levels {
+        if left == 0 {
+            break;
+        }
+        if let Some(fill) = matching::match_bid(asks, p, left) {
+            left -= fill.vol;
+            fills.push(fill);
+        }
+    }
+    fills
+}
+
+/// Clears what the dry run would have filled, keeping `short`.
+fn untraded(legs: &mut [LegResult]) {
+    for l in legs.iter_mut() {
+        l.fills.clear();
+        l.filled_qty = 0;
+        l.cost = 0;
+    }
+}
+
+/// Buys across `[[instruments]]` all or nothing: every leg is matched
+/// against a copy of the books and checked against funds and `[risk]` as
+/// one order, and only if all of it fills is anything traded. Pays each
+/// leg's `place_bid` fee either way. Doesn't count towards `done_trade`.
+pub async fn user_basket(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<BasketResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::PlaceBid;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), BasketResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    let req = match serde_json::from_slice::<BasketRequest>(&body) {
+        Ok(req) if !req.legs.is_empty() && req.legs.iter().all(|l| l.qty >= 1 && g.instruments.contains(&l.symbol)) => req,
+        _ => {
+            g.reject(&uname, ep, "INVALID_BASKET", 0, now);
+            let err = ApiError::with(StatusCode::UNPROCESSABLE_ENTITY, "INVALID_BASKET", "legs must name instruments, with qty of at least 1");
+            return clock.refuse(err, BasketResult::default());
+        }
+    };
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), BasketResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), BasketResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(&uname, ep, "HALTED", 0, now);
+        let res = BasketResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), BasketResult::default());
+    }
+    let fee: i64 = req.legs.iter().map(|l| g.fee_schedule.fee(g.instruments.fee(&l.symbol, g.fee), ep, now)).sum();
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = BasketResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+
+    // Every leg against copies of the books and of the account, so later
+    // legs see what earlier ones took.
+    let mut books: BTreeMap<String, matching::Ladder> = BTreeMap::new();
+    let mut after = instruments::with_all_lots(&g.users[&uname]);
+    let mut held = g.users[&uname].holdings.clone();
+    let mut refused = (!g.trading_open(&uname, now)).then_some("MARKET_CLOSED");
+    let mut legs = Vec::new();
+    for leg in &req.legs {
+        if !g.instruments.started(&leg.symbol, now) {
+            refused = refused.or(Some("MARKET_CLOSED"));
+        }
+        let asks = books.entry(leg.symbol.clone()).or_insert_with(|| g.instruments.books[&leg.symbol].asks.clone());
+        let fills = sweep(asks, leg.price, leg.qty);
+        for f in &fills {
+            let h = held.entry(leg.symbol.clone()).or_default();
+            if let Err(code) = g.instruments.check_position(&leg.symbol, *h, f.vol).and_then(|_| book::admissible(&g, &after, f.price, f.vol)) {
+                refused = refused.or(Some(code));
+            }
+            *h += f.vol;
+            let cost = matching::fill_cost(*f);
+            after.balance -= cost;
+            after.notional_spent += cost;
+            after.position += f.vol;
+        }
+        let filled_qty = fills.iter().map(|f| f.vol).sum();
+        legs.push(LegResult {
+            symbol: leg.symbol.clone(),
+            price: leg.price,
+            qty: leg.qty,
+            fills: fills.iter().map(|f| BidFill { price: f.price, vol: f.vol }).collect(),
+            filled_qty,
+            cost: fills.iter().map(|f| matching::fill_cost(*f)).sum(),
+            short: leg.qty - filled_qty,
+        });
+    }
+    if let Some(code) = refused {
+        g.reject(&uname, ep, code, fee, now);
+        untraded(&mut legs);
+        let res = BasketResult { legs, total_fees: fee, reject_reason: Some(code.to_owned()), ..Default::default() };
+        let code = if code == "MARKET_CLOSED" { g.closed_reason(&uname, now) } else { code };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), res);
+    }
+    if legs.iter().any(|l| l.short > 0) {
+        untraded(&mut legs);
+        return clock.reply(StatusCode::OK, BasketResult { status: BidStatus::Unfilled, legs, total_fees: fee, ..Default::default() });
+    }
+
+    for (symbol, asks) in books {
+        g.instruments.books.get_mut(&symbol).unwrap().asks = asks;
+    }
+    let mut total_cost = 0;
+    for l in &legs {
+        for f in &l.fills {
+            let fill = matching::Fill { price: f.price, vol: f.vol };
+            total_cost += instruments::pay_for_fill(&mut g, &uname, &l.symbol, fill, now);
+        }
+    }
+    clock.reply(StatusCode::OK, BasketResult { status: BidStatus::Filled, legs, total_cost, total_fees: fee, ..Default::default() })
+}
@@ -0,0 +1,336 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allocation::Outcome, client_deadline, contention::StateLock, credit, deadline_passed, errors::ApiError,
+    fees::Endpoint, journal, now, orders::TimeInForce, storage::Store, AppState, PriceVol, ReqClock, RespMeta,
+    UserAccount,
+};
+
+/// Matches an accepted order against the asks at its price. Whatever is
+/// left rests on the book if the order is good-till-cancelled, or is cancelled.
+pub fn enter(g: &mut AppState, uname: &str, id: u64, now: i64) -> Outcome {
+    let (price, qty) = (g.orders.orders[&id].price, g.orders.orders[&id].remaining);
+    let fill = g.take_ask(price, qty);
+    if let Some(fill) = fill {
+        g.fill(uname, fill, now);
+        g.orders.fill(id, fill.price, fill.vol, now);
+    }
+    let mut resting = false;
+    let remaining = g.orders.orders[&id].remaining;
+    if remaining > 0 {
+        if g.orders.orders[&id].tif == TimeInForce::Gtc {
+            rest(g, id, now);
+            resting = true;
+        } else {
+            g.orders.cancel(id, "UNMATCHED", now);
+        }
+    }
+    g.notify_order(id, now);
+    if fill.is_some() {
+        g.check_breaker(now);
+    }
+    Outcome { fill, resting, remaining, position: g.users.get(uname).map_or(0, |ua| ua.position) }
+}
+
+/// Whether `ua` may buy `qty` lots at `price`: the risk limits, then funds.
+pub fn admissible(g: &AppState, ua: &UserAccount, price: i64, qty: i64) -> Result<(), &'static str> {
+    g.risk.check(ua, price, qty)?;
+    let cost = price.checked_mul(qty).ok_or("INSUFFICIENT_FUNDS")?;
+    credit::can_afford(ua, cost).then_some(()).ok_or("INSUFFICIENT_FUNDS")
+}
+
+pub fn rest(g: &mut AppState, id: u64, now: i64) {
+    let o = &g.orders.orders[&id];
+    g.book.rest_bid(o.price, id, o.priority_nanos);
+    g.orders.rest(id, now);
+    g.book_changed(now);
+}
+
+/// Fills resting bids at `price` in priority order while asks last. Bids
+/// whose owner can no longer take the rest of the order are cancelled on
+/// the way; an order that has started filling may finish even though its
+/// owner has now traded.
+pub fn match_resting(g: &mut AppState, price: i64, now: i64) -> usize {
+    let mut fills = 0;
+    while g.book.asks.contains_key(&price) {
+        let Some(id) = g.book.pop_bid(price) else {
+            break;
+        };
+        let o = &g.orders.orders[&id];
+        let (uname, qty, started) = (o.uname.clone(), o.remaining, o.remaining < o.qty);
+        let eligible = g
+            .users
+            .get(&uname)
+            .is_some_and(|ua| (!ua.done_trade || started) && admissible(g, ua, price, qty).is_ok());
+        if !eligible {
+            g.orders.cancel(id, "INELIGIBLE_AT_MATCH", now);
+            g.notify_order(id, now);
+            continue;
+        }
+        let fill = g.take_ask(price, qty).unwrap();
+        g.fill(&uname, fill, now);
+        g.orders.fill(id, fill.price, fill.vol, now);
+        if g.orders.orders[&id].remaining > 0 {
+            rest(g, id, now);
+        }
+        g.notify_order(id, now);
+        fills += 1;
+    }
+    g.book_snapshot = None;
+    if fills > 0 {
+        g.check_breaker(now);
+    }
+    fills
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddAskRequest {
+    pub price: i64,
+    pub vol: i64,
+    /// Withdraws the house volume at the level then, replacing any expiry
+    /// it had.
+    #[serde(default)]
+    pub expires_at_nanos: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct AddAskResult {
+    /// Resting bids the new volume filled.
+    pub fills: usize,
+    /// Volume left at the price afterwards.
+    pub remaining: i64,
+    pub expires_at_nanos: Option<i64>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Adds house volume at `price`, as the config would have. An expiry
+/// applies to all of the house's volume at the level.
+pub fn offer(g: &mut AppState, price: i64, vol: i64, expires_at_nanos: Option<i64>, now: i64) {
+    *g.book.asks.entry(price).or_default() += vol;
+    if let Some(at) = expires_at_nanos {
+        g.ask_expiry.insert(price, at);
+    }
+    g.book_changed(now);
+    g.issued.units += vol;
+    match g.config.asks.iter_mut().find(|pv| pv.price == price) {
+        Some(pv) => {
+            pv.vol += vol;
+            pv.expires_at_nanos = expires_at_nanos.or(pv.expires_at_nanos);
+        }
+        None => g.config.asks.push(PriceVol { price, vol, expires_at_nanos }),
+    }
+    g.journal.record(|| journal::Event::AskAdded { price, vol, expires_at_nanos, ts_nanos: now });
+}
+
+/// Puts `vol` of `uname`'s lots on the book at `price`.
+pub fn list(g: &mut AppState, uname: &str, price: i64, vol: i64, now: i64) {
+    g.book.list_ask(price, uname, vol);
+    g.users.get_mut(uname).unwrap().listed += vol;
+    g.book_changed(now);
+    g.journal.record(|| journal::Event::AskListed { uname: uname.to_owned(), price, vol, ts_nanos: now });
+}
+
+/// Offers more volume, which first goes to bids resting at that price.
+pub async fn admin_add_ask(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<AddAskRequest>,
+) -> (StatusCode, Json<AddAskResult>) {
+    let clock = ReqClock::start();
+    if req.vol <= 0 {
+        return clock.reply(StatusCode::BAD_REQUEST, AddAskResult::default());
+    }
+    let now = now();
+    if req.expires_at_nanos.is_some_and(|at| at <= now) {
+        let err = ApiError::with(StatusCode::BAD_REQUEST, "ALREADY_EXPIRED", "expires_at_nanos has passed");
+        return clock.refuse(err, AddAskResult::default());
+    }
+    let mut g = state.locked();
+    offer(&mut g, req.price, req.vol, req.expires_at_nanos, now);
+    g.feeds.timeline.admin_global(format!("{} lots offered at {}", req.vol, req.price));
+    let fills = match_resting(&mut g, req.price, now);
+    let remaining = g.book.asks.get(&req.price).copied().unwrap_or(0);
+    let expires_at_nanos = g.ask_expiry.get(&req.price).copied();
+    clock.reply(StatusCode::OK, AddAskResult { fills, remaining, expires_at_nanos, ..Default::default() })
+}
+
+#[derive(Serialize, Default)]
+pub struct AskResult {
+    /// Lots put on the book, and how many of them resting bids bought
+    /// straight away.
+    pub listed: i64,
+    pub sold: i64,
+    /// Lots held, listed ones included, and how many of those are listed.
+    pub position: i64,
+    pub listed_total: i64,
+    pub balance: i64,
+    pub total_fees: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Lists `qty` of the user's lots at `price`, paid like a bid. Listed lots
+/// are still held, and paid out at settlement, until someone buys them;
+/// at a level the house's volume sells first.
+pub async fn user_place_ask(
+    Path((uname, price, qty)): Path<(String, i64, i64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<AskResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::PlaceAsk;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), AskResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), AskResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), AskResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(&uname, ep, "HALTED", 0, now);
+        let res = AskResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), AskResult::default());
+    }
+    if qty < 1 || price < 1 {
+        g.reject(&uname, ep, "INVALID_ORDER", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "INVALID_ORDER"), AskResult::default());
+    }
+    let fee = g.fee_schedule.fee(g.fee, ep, now);
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = AskResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+    let open = g.trading_open(&uname, now);
+    let ua = &g.users[&uname];
+    let refused = if !open {
+        Some("MARKET_CLOSED")
+    } else if ua.position - ua.listed < qty {
+        Some("INSUFFICIENT_POSITION")
+    } else {
+        None
+    };
+    if let Some(code) = refused {
+        g.reject(&uname, ep, code, fee, now);
+        let res = AskResult { reject_reason: Some(code.to_owned()), total_fees: fee, ..Default::default() };
+        let code = if code == "MARKET_CLOSED" { g.closed_reason(&uname, now) } else { code };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), res);
+    }
+
+    list(&mut g, &uname, price, qty, now);
+    let before = g.users[&uname].listed;
+    match_resting(&mut g, price, now);
+    let ua = &g.users[&uname];
+    let res = AskResult {
+        listed: qty,
+        sold: before - ua.listed,
+        position: ua.position,
+        listed_total: ua.listed,
+        balance: ua.balance,
+        total_fees: fee,
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+/// Where one of the user's resting bids stands in line at its price.
+#[derive(Serialize)]
+pub struct BidPlace {
+    pub order_id: u64,
+    pub remaining: i64,
+    pub orders_ahead: usize,
+    pub lots_ahead: i64,
+}
+
+/// Where a block of the user's listed lots stands. The house's volume at
+/// the level is always ahead of it.
+#[derive(Serialize)]
+pub struct AskPlace {
+    pub vol: i64,
+    pub lots_ahead: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct QueueResult {
+    pub price: i64,
+    pub bids: Vec<BidPlace>,
+    pub asks: Vec<AskPlace>,
+    pub total_fees: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// The user's places in the queues at `price`, on both sides. Charged as a
+/// `check_asks`, since it shows as much of the book.
+pub async fn user_queue(
+    Path((uname, price)): Path<(String, i64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<QueueResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::CheckAsks;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), QueueResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), QueueResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), QueueResult::default());
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), QueueResult::default());
+    }
+    let fee = g.fee_schedule.fee(g.fee, ep, now);
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = QueueResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+
+    let mut bids = Vec::new();
+    let (mut orders_ahead, mut lots_ahead) = (0, 0);
+    for id in g.book.bid_queue(price) {
+        let o = &g.orders.orders[&id];
+        if o.uname == uname {
+            bids.push(BidPlace { order_id: id, remaining: o.remaining, orders_ahead, lots_ahead });
+        }
+        orders_ahead += 1;
+        lots_ahead += o.remaining;
+    }
+    let mut asks = Vec::new();
+    let mut lots_ahead = g.book.asks.get(&price).copied().unwrap_or(0) - g.book.listed_at(price);
+    for (_, seller, vol) in g.book.sell_lots().filter(|(p, _, _)| *p == price) {
+        if seller == uname {
+            asks.push(AskPlace { vol, lots_ahead });
+        }
+        lots_ahead += vol;
+    }
+    clock.reply(StatusCode::OK, QueueResult { price, bids, asks, total_fees: fee, ..Default::default() })
+}
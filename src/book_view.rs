@@ -0,0 +1,44 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{matching::Ladder, AskLevel, PriceVol};
+
+/// How much of each ask level users see in `check_asks`, quotes and
+/// `/ws/market`. Admin views and the tape are never blinded, so depth can
+/// still be worked out from fills.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Volumes {
+    #[default]
+    Exact,
+    /// Prices only.
+    Hidden,
+    /// Prices with whether anything is left; levels the house offered stay
+    /// listed once exhausted.
+    Availability,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct BookViewConfig {
+    #[serde(default)]
+    pub volumes: Volumes,
+}
+
+/// The ladder as users see it. `offered` is what the house put up, for
+/// telling exhausted levels apart from prices never offered.
+pub fn levels(view: Volumes, asks: &Ladder, expiry: &BTreeMap<i64, i64>, offered: &[PriceVol]) -> Vec<AskLevel> {
+    let level = |price: i64, vol: Option<i64>| AskLevel {
+        price,
+        vol: vol.filter(|_| view == Volumes::Exact),
+        available: (view == Volumes::Availability).then(|| vol.is_some()),
+        expires_at_nanos: expiry.get(&price).copied(),
+    };
+    match view {
+        Volumes::Exact | Volumes::Hidden => asks.iter().map(|(p, v)| level(*p, Some(*v))).collect(),
+        Volumes::Availability => {
+            let prices: BTreeSet<i64> = asks.keys().copied().chain(offered.iter().map(|pv| pv.price)).collect();
+            prices.into_iter().map(|p| level(p, asks.get(&p).copied())).collect()
+        }
+    }
+}
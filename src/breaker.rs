@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tape::Tape;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Halts trading for `halt_secs` when traded prices span more than
+/// `max_move_bps` basis points of the low within `window_secs`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    pub max_move_bps: i64,
+    pub window_secs: u64,
+    pub halt_secs: u64,
+    /// Collect bids during a halt and uncross them when it ends, see
+    /// `allocation::reopen`, rather than refusing them.
+    #[serde(default)]
+    pub reopen_auction: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Halt {
+    pub seq: u64,
+    pub started_nanos: i64,
+    pub until_nanos: i64,
+    /// Price range that tripped the breaker.
+    pub low: i64,
+    pub high: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Breaker {
+    pub halts: Vec<Halt>,
+}
+
+impl Breaker {
+    pub fn halted(&self, now: i64) -> bool {
+        self.halts.last().is_some_and(|h| now < h.until_nanos)
+    }
+
+    /// When the current halt ends, if trading is halted.
+    pub fn reopens_at(&self, now: i64) -> Option<i64> {
+        self.halts.last().map(|h| h.until_nanos).filter(|&until| now < until)
+    }
+
+    /// Looks at fills since the window opened (or the last halt ended, if
+    /// later) and starts a halt if they moved too far.
+    pub fn check(&mut self, cfg: &CircuitBreakerConfig, tape: &Tape, now: i64) -> Option<&Halt> {
+        let mut since = now.saturating_sub(cfg.window_secs as i64 * NANOS_PER_SEC);
+        if let Some(h) = self.halts.last() {
+            since = since.max(h.until_nanos);
+        }
+        let mut prices = tape.trades.iter().rev().take_while(|t| t.ts_nanos >= since).map(|t| t.price);
+        let first = prices.next()?;
+        let (low, high) = prices.fold((first, first), |(lo, hi), p| (lo.min(p), hi.max(p)));
+        let moved = (high - low) as i128 * 10_000 > cfg.max_move_bps as i128 * low.max(1) as i128;
+        if !moved {
+            return None;
+        }
+        self.halts.push(Halt {
+            seq: self.halts.len() as u64 + 1,
+            started_nanos: now,
+            until_nanos: now + cfg.halt_secs as i64 * NANOS_PER_SEC,
+            low,
+            high,
+        });
+        self.halts.last()
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+/// Trading is open from `open_nanos` up to, not including, `close_nanos`.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Session {
+    pub open_nanos: i64,
+    pub close_nanos: i64,
+}
+
+/// Sessions to run, e.g. a morning and an afternoon one. Replaces
+/// `trade_start_nanos`, which alone means one session that never closes.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CalendarConfig {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Transition {
+    pub at_nanos: i64,
+    pub kind: TransitionKind,
+}
+
+/// Sessions in order, never overlapping.
+#[derive(Debug, Clone)]
+pub struct Calendar {
+    sessions: Vec<Session>,
+}
+
+impl Calendar {
+    pub fn new(trade_start_nanos: Option<i64>, cfg: Option<&CalendarConfig>) -> Result<Self, String> {
+        let mut sessions = match (trade_start_nanos, cfg) {
+            (Some(_), Some(_)) => return Err("set either trade_start_nanos or [calendar], not both".to_owned()),
+            (None, None) => return Err("set trade_start_nanos or [calendar]".to_owned()),
+            (Some(t), None) => vec![Session { open_nanos: t, close_nanos: i64::MAX }],
+            (None, Some(c)) => c.sessions.clone(),
+        };
+        if sessions.is_empty() {
+            return Err("[calendar] needs at least one session".to_owned());
+        }
+        sessions.sort_by_key(|s| s.open_nanos);
+        for s in &sessions {
+            if s.close_nanos <= s.open_nanos {
+                return Err(format!("session opening at {} closes before it opens", s.open_nanos));
+            }
+        }
+        if let Some(w) = sessions.windows(2).find(|w| w[1].open_nanos < w[0].close_nanos) {
+            return Err(format!("sessions opening at {} and {} overlap", w[0].open_nanos, w[1].open_nanos));
+        }
+        Ok(Calendar { sessions })
+    }
+
+    /// A game with no schedule yet, see `POST /admin/arm`: it never opens.
+    pub fn lobby() -> Self {
+        Calendar { sessions: vec![Session { open_nanos: i64::MAX, close_nanos: i64::MAX }] }
+    }
+
+    /// False in the lobby, until the game is given a schedule.
+    pub fn armed(&self) -> bool {
+        self.first_open() != i64::MAX
+    }
+
+    pub fn is_open(&self, now: i64) -> bool {
+        self.sessions.iter().any(|s| s.open_nanos <= now && now < s.close_nanos)
+    }
+
+    /// When trading first opens; "before trading starts" means before this.
+    pub fn first_open(&self) -> i64 {
+        self.sessions[0].open_nanos
+    }
+
+    /// Open of the session running now, or else of the next one, or else
+    /// of the last one.
+    pub fn session_open(&self, now: i64) -> i64 {
+        self.sessions
+            .iter()
+            .find(|s| now < s.close_nanos)
+            .unwrap_or(self.sessions.last().unwrap())
+            .open_nanos
+    }
+
+    /// The next time trading opens or closes, if it ever does again.
+    pub fn next_transition(&self, now: i64) -> Option<Transition> {
+        let s = self.sessions.iter().find(|s| now < s.close_nanos)?;
+        if now < s.open_nanos {
+            Some(Transition { at_nanos: s.open_nanos, kind: TransitionKind::Open })
+        } else if s.close_nanos == i64::MAX {
+            None
+        } else {
+            Some(Transition { at_nanos: s.close_nanos, kind: TransitionKind::Close })
+        }
+    }
+
+    /// `next_transition` for a user who may not trade before `start`.
+    pub fn next_transition_from(&self, now: i64, start: i64) -> Option<Transition> {
+        if now >= start {
+            self.next_transition(now)
+        } else if self.is_open(start) {
+            Some(Transition { at_nanos: start, kind: TransitionKind::Open })
+        } else {
+            self.next_transition(start)
+        }
+    }
+}
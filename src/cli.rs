@@ -0,0 +1,84 @@
+//! Command line and environment. Settings come from the config file with
+//! `GTSVR_*` variables layered over it; `__` separates nested keys, so
+//! `GTSVR_FEE=5` sets `fee` and `GTSVR_ADMIN__TOKEN` sets `[admin] token`.
+
+use crate::AppConfig;
+
+pub const USAGE: &str = "usage: guess-trade-svr [--config <path>] [--listen <addr>] [--recover | --restore <backup>]
+
+  --config <path>     config file, default app_config.toml; GTSVR_CONFIG
+  --listen <addr>     host:port or unix:/path.sock, default 127.0.0.1:8080;
+                      GTSVR_LISTEN, or SVR_ADDR as before
+  --recover           replay [journal] before serving; refused if the
+                      [persistence] store already holds a game
+  --restore <backup>  start from a backup file, or the newest in a directory;
+                      replaces any game in the [persistence] store
+
+Any config key can be overridden from the environment: GTSVR_FEE=5,
+GTSVR_ADMIN__TOKEN=secret.";
+
+const DEFAULT_CONFIG: &str = "app_config.toml";
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+const ENV_PREFIX: &str = "GTSVR";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<String>,
+    pub listen: Option<String>,
+    pub recover: bool,
+    pub restore: Option<String>,
+    pub help: bool,
+}
+
+/// Parses the arguments after the program name.
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut a = Args::default();
+    while let Some(arg) = args.next() {
+        // `--flag=value` reads the same as `--flag value`.
+        let (flag, inline) = match arg.split_once('=') {
+            Some((f, v)) if f.starts_with("--") => (f.to_owned(), Some(v.to_owned())),
+            _ => (arg, None),
+        };
+        let mut value = |name: &str| inline.clone().or_else(|| args.next()).ok_or(format!("{} needs a value", name));
+        match flag.as_str() {
+            "--config" => a.config = Some(value("--config")?),
+            "--listen" => a.listen = Some(value("--listen")?),
+            "--restore" => a.restore = Some(value("--restore")?),
+            "--recover" => a.recover = true,
+            "-h" | "--help" => a.help = true,
+            other => return Err(format!("unknown argument {:?}", other)),
+        }
+    }
+    if a.recover && a.restore.is_some() {
+        return Err("--recover and --restore can't be combined".to_owned());
+    }
+    Ok(a)
+}
+
+/// The address to serve users on: `--listen`, then `GTSVR_LISTEN`, then
+/// `SVR_ADDR`.
+pub fn listen_addr(args: &Args) -> String {
+    args.listen
+        .clone()
+        .or_else(|| std::env::var(format!("{}_LISTEN", ENV_PREFIX)).ok())
+        .or_else(|| std::env::var("SVR_ADDR").ok())
+        .unwrap_or_else(|| DEFAULT_LISTEN.to_owned())
+}
+
+/// `--config`, then `GTSVR_CONFIG`.
+pub fn config_path(args: &Args) -> String {
+    args.config
+        .clone()
+        .or_else(|| std::env::var(format!("{}_CONFIG", ENV_PREFIX)).ok())
+        .unwrap_or_else(|| DEFAULT_CONFIG.to_owned())
+}
+
+/// The file at `path` with the environment over it; also what a reload reads.
+pub fn load_config(path: &str) -> Result<AppConfig, String> {
+    let mut settings = config::Config::default();
+    settings.merge(config::File::with_name(path)).map_err(|e| format!("{}: {}", path, e))?;
+    settings
+        .merge(config::Environment::with_prefix(ENV_PREFIX).separator("__"))
+        .map_err(|e| format!("{}_* environment: {}", ENV_PREFIX, e))?;
+    settings.try_into().map_err(|e| format!("{}: {}", path, e))
+}
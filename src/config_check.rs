@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use crate::{instruments, usernames, AppConfig};
+
+/// Everything wrong with `cfg`, one line each, for problems that would
+/// otherwise only show as odd behaviour once the game runs. Empty if it's
+/// fine. Checked at startup and by every reload.
+pub fn problems(cfg: &AppConfig) -> Vec<String> {
+    let mut out = Vec::new();
+
+    let mut seen = BTreeSet::new();
+    for u in &cfg.users {
+        if u.is_empty() {
+            out.push("users: a username is empty".to_owned());
+        } else if !seen.insert(u) {
+            out.push(format!("users: {} is listed more than once", u));
+        }
+    }
+    if let Err(e) = usernames::Names::new(&cfg.usernames.clone().unwrap_or_default(), &cfg.users) {
+        out.push(format!("usernames: {}", e));
+    }
+
+    if let Err(e) = instruments::Instruments::new(&cfg.instruments) {
+        out.push(format!("instruments: {}", e));
+    }
+
+    if cfg.init_balance < 0 {
+        out.push(format!("init_balance: {} is negative", cfg.init_balance));
+    }
+    if cfg.fee < 0 {
+        out.push(format!("fee: {} is negative", cfg.fee));
+    } else if cfg.fee > cfg.init_balance {
+        out.push(format!("fee: {} is more than init_balance {}, so no one can afford a call", cfg.fee, cfg.init_balance));
+    }
+
+    // Without a schedule the game waits in the lobby, where asks can still
+    // be added through `/admin/asks`.
+    let scheduled = cfg.trade_start_nanos.is_some() || cfg.calendar.is_some();
+    if cfg.asks.is_empty() && scheduled {
+        out.push("asks: the ladder is empty, so there is nothing to buy".to_owned());
+    }
+    let mut prices = BTreeSet::new();
+    for pv in &cfg.asks {
+        if pv.price < 1 {
+            out.push(format!("asks: price {} is not positive", pv.price));
+        }
+        if pv.vol < 1 {
+            out.push(format!("asks: volume {} at price {} is not positive", pv.vol, pv.price));
+        }
+        if !prices.insert(pv.price) {
+            out.push(format!("asks: price {} is listed more than once", pv.price));
+        }
+    }
+
+    if let (Some(start), Some(end)) = (cfg.trade_start_nanos, cfg.trade_end_nanos) {
+        if end <= start {
+            out.push(format!("trade_end_nanos: {} is not after trade_start_nanos {}", end, start));
+        }
+    }
+
+    // What the journal doesn't record would be lost by `--recover`.
+    if cfg.journal.is_some() {
+        let unjournaled = [
+            ("[loans]", cfg.loans.is_some()),
+            ("[[penalties]]", !cfg.penalties.is_empty()),
+            ("[credit] interest", cfg.credit.as_ref().is_some_and(|c| c.interest_ppm_per_sec > 0)),
+            ("[quotes] fees", cfg.quotes.as_ref().is_some_and(|q| q.fee_per_update > 0)),
+            ("[[instruments]]", !cfg.instruments.is_empty()),
+        ];
+        for (what, on) in unjournaled {
+            if on {
+                out.push(format!("journal: {} changes balances the journal doesn't record", what));
+            }
+        }
+    }
+
+    if let Some(s) = &cfg.scoreboard {
+        if cfg.public_board.is_none() {
+            out.push("scoreboard: needs [public_board] to say what may be shown".to_owned());
+        }
+        if reqwest::Url::parse(&s.url).map_or(true, |u| u.scheme() != "http" && u.scheme() != "https") {
+            out.push(format!("scoreboard: url {} is not an http(s) URL", s.url));
+        }
+        if s.interval_secs < 1 {
+            out.push("scoreboard: interval_secs must be at least 1".to_owned());
+        }
+    }
+    out
+}
+
+/// What's odd but allowed at startup: a schedule already under way is
+/// normal for a restart, and opens trading at once for a new game. These
+/// are not `problems`, because a restart of a running game or one restored
+/// with `--restore` would otherwise be refused, and the config can't tell
+/// the two apart.
+pub fn warnings(cfg: &AppConfig, now: i64) -> Vec<String> {
+    let mut out = Vec::new();
+    if let Some(start) = cfg.trade_start_nanos.filter(|s| *s < now) {
+        out.push(format!("trade_start_nanos {} is in the past: trading is open from the start", start));
+    }
+    if let Some(end) = cfg.trade_end_nanos.filter(|e| *e <= now) {
+        out.push(format!("trade_end_nanos {} is in the past: the game settles as soon as it starts", end));
+    }
+    out
+}
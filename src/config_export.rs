@@ -0,0 +1,65 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{contention::StateLock, errors::ApiError, AppConfig, AppState};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// The config a rerun of this game should start from: the file it was
+/// started with, plus users and keys added since, asks offered through
+/// `/admin/asks` and the schedule given to `/admin/arm`.
+fn effective(g: &AppState) -> AppConfig {
+    let mut cfg = g.config.clone();
+    let mut users: Vec<String> = g.users.keys().cloned().collect();
+    users.sort();
+    cfg.users = users;
+    cfg.user_keys = g.user_keys.clone();
+    cfg.fee = g.fee;
+    cfg
+}
+
+/// `GET /admin/config/export?format=toml|json`
+pub async fn admin_export_config(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(q): Query<ExportQuery>,
+) -> Response {
+    let cfg = effective(&state.locked());
+    let body = match q.format {
+        Format::Json => serde_json::to_string_pretty(&cfg).map_err(|e| e.to_string()),
+        // Through `Value`, which puts plain keys ahead of tables as TOML needs.
+        Format::Toml => toml::Value::try_from(&cfg)
+            .and_then(|v| toml::to_string_pretty(&v))
+            .map_err(|e| e.to_string()),
+    };
+    match body {
+        Ok(body) => {
+            let ty = match q.format {
+                Format::Json => "application/json",
+                Format::Toml => "application/toml",
+            };
+            (StatusCode::OK, [(header::CONTENT_TYPE, ty)], body).into_response()
+        }
+        Err(e) => {
+            tracing::error!("exporting the config failed: {}", e);
+            ApiError::with(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL", e).into_response()
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper_util::{
+    rt::{TokioIo, TokioTimer},
+    service::TowerToHyperService,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tower_http::{add_extension::AddExtension, timeout::RequestBodyTimeout};
+
+use crate::{
+    runtime::Listener,
+    shutdown::{self, Stopping},
+};
+
+/// Socket-level limits, enforced before a request reaches any handler.
+/// Connections over a cap are closed as soon as they are accepted.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConnLimitsConfig {
+    /// Open connections across all clients.
+    pub max_connections: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    /// Time a client gets to send a complete request head.
+    #[serde(default = "default_timeout_secs")]
+    pub header_timeout_secs: u64,
+    /// Longest pause allowed while a request body is being read.
+    #[serde(default = "default_timeout_secs")]
+    pub body_timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for ConnLimitsConfig {
+    fn default() -> Self {
+        ConnLimitsConfig {
+            max_connections: None,
+            max_per_ip: None,
+            header_timeout_secs: default_timeout_secs(),
+            body_timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Held for the life of a connection; gives its slot back on drop.
+struct Slot {
+    open: Arc<Mutex<Open>>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut o = self.open.lock().unwrap();
+        o.total -= 1;
+        if let Some(n) = o.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                o.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn admit(open: &Arc<Mutex<Open>>, cfg: &ConnLimitsConfig, ip: IpAddr) -> Option<Slot> {
+    let mut o = open.lock().unwrap();
+    if cfg.max_connections.is_some_and(|max| o.total >= max) {
+        return None;
+    }
+    let n = o.per_ip.entry(ip).or_default();
+    if cfg.max_per_ip.is_some_and(|max| *n >= max) {
+        return None;
+    }
+    *n += 1;
+    o.total += 1;
+    Some(Slot { open: open.clone(), ip })
+}
+
+/// Accept loop in place of `axum::serve`, which has no connection limits or
+/// header timeout. Once `stop` flips it stops accepting and returns when
+/// every connection has closed, or after `drain` at the latest.
+pub async fn serve(listener: Listener, app: Router, cfg: ConnLimitsConfig, mut stop: Stopping, drain: Duration) {
+    let open = Arc::new(Mutex::new(Open::default()));
+    let app = RequestBodyTimeout::new(app, Duration::from_secs(cfg.body_timeout_secs));
+    let mut conns = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            a = listener.accept() => a,
+            _ = shutdown::stopped(&mut stop) => break,
+        };
+        while conns.try_join_next().is_some() {}
+        let (stream, peer) = match accepted {
+            Ok(c) => c,
+            Err(e) => {
+                // Typically out of file descriptors; give connections time to close.
+                tracing::warn!("accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        let Some(slot) = admit(&open, &cfg, peer.ip()) else {
+            tracing::debug!("refusing connection from {}: over limit", peer);
+            continue;
+        };
+        // Handlers and middleware can ask for `ConnectInfo<SocketAddr>`.
+        let svc = TowerToHyperService::new(AddExtension::new(app.clone(), ConnectInfo(peer)));
+        let header_timeout = Duration::from_secs(cfg.header_timeout_secs);
+        let mut stop = stop.clone();
+        conns.spawn(async move {
+            let _slot = slot;
+            let conn = hyper::server::conn::http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(header_timeout)
+                .serve_connection(TokioIo::new(stream), svc)
+                .with_upgrades();
+            tokio::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = shutdown::stopped(&mut stop) => {
+                    // Finishes the request being served, then closes.
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = res {
+                tracing::debug!("connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+    drop(listener);
+    let drained = tokio::time::timeout(drain, async { while conns.join_next().await.is_some() {} }).await;
+    if drained.is_err() {
+        tracing::warn!("{} connections still open after {:?}; dropping them", conns.len(), drain);
+    }
+}
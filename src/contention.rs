@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex, MutexGuard, TryLockError,
+    },
+    time::Instant,
+};
+
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+
+use crate::{AppState, ReqClock, RespMeta};
+
+/// Upper bounds of the wait histogram, in seconds.
+const WAIT_BUCKETS: [f64; 7] = [0.00001, 0.0001, 0.001, 0.005, 0.01, 0.1, 1.0];
+
+/// Threads blocked on the state lock right now.
+static WAITING: AtomicI64 = AtomicI64::new(0);
+/// Per call site. Written only while the state lock is held, so writers
+/// never wait on each other here; `/metrics` and `/admin/contention` read
+/// it without the state lock and may briefly hold up one writer.
+static SITES: Mutex<Option<HashMap<&'static Location<'static>, SiteStats>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Default)]
+struct SiteStats {
+    acquisitions: u64,
+    wait_nanos: u128,
+    max_wait_nanos: u128,
+    hold_nanos: u128,
+    max_hold_nanos: u128,
+    /// Acquisitions that waited at most each of `WAIT_BUCKETS`.
+    wait_buckets: [u64; WAIT_BUCKETS.len()],
+}
+
+fn with_site(site: &'static Location<'static>, f: impl FnOnce(&mut SiteStats)) {
+    f(SITES.lock().unwrap().get_or_insert_with(HashMap::new).entry(site).or_default());
+}
+
+/// Takes the state lock, timing the wait and, until the guard drops, the
+/// hold, against the caller's file and line.
+///
+/// The game is one lock on purpose. Even a ping moves money between an
+/// account, the house and the ledger, and journals it, and `/admin/verify`
+/// checks the sums across all of them; sharding accounts by user would leave
+/// every fee and fill still taking the shared parts. Find the call sites
+/// that hold it too long with `/admin/contention` and shorten those instead.
+///
+/// No call site holds it across an `.await`, so an async mutex would buy
+/// nothing once the lock is free. When it isn't, the wait happens in
+/// `block_in_place`, so the tasks queued behind this one on the same
+/// runtime worker move to another thread instead of stalling with it.
+pub(crate) trait StateLock {
+    fn locked(&self) -> StateGuard<'_>;
+}
+
+impl StateLock for Mutex<AppState> {
+    #[track_caller]
+    fn locked(&self) -> StateGuard<'_> {
+        let site = Location::caller();
+        let start = Instant::now();
+        WAITING.fetch_add(1, Ordering::Relaxed);
+        let guard = match self.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => tokio::task::block_in_place(|| self.lock().unwrap()),
+            Err(TryLockError::Poisoned(e)) => panic!("state lock poisoned: {}", e),
+        };
+        WAITING.fetch_sub(1, Ordering::Relaxed);
+        let acquired = Instant::now();
+        let wait = (acquired - start).as_nanos();
+        with_site(site, |s| {
+            s.acquisitions += 1;
+            s.wait_nanos += wait;
+            s.max_wait_nanos = s.max_wait_nanos.max(wait);
+            let secs = wait as f64 / 1e9;
+            for (n, le) in s.wait_buckets.iter_mut().zip(WAIT_BUCKETS) {
+                if secs <= le {
+                    *n += 1;
+                }
+            }
+        });
+        StateGuard { guard, site, acquired }
+    }
+}
+
+pub(crate) struct StateGuard<'a> {
+    guard: MutexGuard<'a, AppState>,
+    site: &'static Location<'static>,
+    acquired: Instant,
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = AppState;
+
+    fn deref(&self) -> &AppState {
+        &self.guard
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut AppState {
+        &mut self.guard
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    /// Runs before the inner guard unlocks.
+    fn drop(&mut self) {
+        let hold = self.acquired.elapsed().as_nanos();
+        with_site(self.site, |s| {
+            s.hold_nanos += hold;
+            s.max_hold_nanos = s.max_hold_nanos.max(hold);
+        });
+    }
+}
+
+/// Lock telemetry in Prometheus text format, for `/metrics`.
+pub fn write_metrics(out: &mut String) {
+    let sites = SITES.lock().unwrap().clone().unwrap_or_default();
+    let _ = writeln!(out, "# HELP guess_trade_lock_waiters Threads blocked on the state lock.");
+    let _ = writeln!(out, "# TYPE guess_trade_lock_waiters gauge");
+    let _ = writeln!(out, "guess_trade_lock_waiters {}", WAITING.load(Ordering::Relaxed));
+
+    let mut buckets = [0u64; WAIT_BUCKETS.len()];
+    let (mut count, mut wait, mut hold) = (0u64, 0u128, 0u128);
+    for s in sites.values() {
+        for (b, n) in buckets.iter_mut().zip(s.wait_buckets) {
+            *b += n;
+        }
+        count += s.acquisitions;
+        wait += s.wait_nanos;
+        hold += s.hold_nanos;
+    }
+    let _ = writeln!(out, "# HELP guess_trade_lock_wait_seconds Time spent waiting for the state lock.");
+    let _ = writeln!(out, "# TYPE guess_trade_lock_wait_seconds histogram");
+    for (le, n) in WAIT_BUCKETS.iter().zip(buckets) {
+        let _ = writeln!(out, "guess_trade_lock_wait_seconds_bucket{{le=\"{}\"}} {}", le, n);
+    }
+    let _ = writeln!(out, "guess_trade_lock_wait_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "guess_trade_lock_wait_seconds_sum {}", wait as f64 / 1e9);
+    let _ = writeln!(out, "guess_trade_lock_wait_seconds_count {}", count);
+    let _ = writeln!(out, "# HELP guess_trade_lock_hold_seconds_total Time the state lock was held.");
+    let _ = writeln!(out, "# TYPE guess_trade_lock_hold_seconds_total counter");
+    let _ = writeln!(out, "guess_trade_lock_hold_seconds_total {}", hold as f64 / 1e9);
+}
+
+#[derive(Debug, Serialize)]
+pub struct SiteReport {
+    /// `file:line` that took the lock.
+    pub site: String,
+    pub acquisitions: u64,
+    pub wait_micros_total: u64,
+    pub wait_micros_max: u64,
+    pub hold_micros_total: u64,
+    pub hold_micros_max: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct ContentionResult {
+    pub waiting: i64,
+    /// Most time spent waiting first.
+    pub sites: Vec<SiteReport>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Where the state lock is taken, and how long each site waited for and
+/// held it since start.
+pub async fn admin_contention() -> (StatusCode, Json<ContentionResult>) {
+    let clock = ReqClock::start();
+    let sites = SITES.lock().unwrap().clone().unwrap_or_default();
+    let mut sites: Vec<SiteReport> = sites
+        .into_iter()
+        .map(|(loc, s)| SiteReport {
+            site: format!("{}:{}", loc.file(), loc.line()),
+            acquisitions: s.acquisitions,
+            wait_micros_total: (s.wait_nanos / 1000) as u64,
+            wait_micros_max: (s.max_wait_nanos / 1000) as u64,
+            hold_micros_total: (s.hold_nanos / 1000) as u64,
+            hold_micros_max: (s.max_hold_nanos / 1000) as u64,
+        })
+        .collect();
+    sites.sort_by(|a, b| b.wait_micros_total.cmp(&a.wait_micros_total).then_with(|| a.site.cmp(&b.site)));
+    let res = ContentionResult { waiting: WAITING.load(Ordering::Relaxed), sites, ..Default::default() };
+    clock.reply(StatusCode::OK, res)
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+use crate::UserAccount;
+
+const PPM_X_NANOS: i128 = 1_000_000 * 1_000_000_000;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CreditConfig {
+    /// How far below zero a bid may take a balance.
+    pub limit: i64,
+    /// Interest on the negative part of a balance, in millionths per second.
+    pub interest_ppm_per_sec: i64,
+}
+
+/// Credit state carried on each account.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CreditLine {
+    pub limit: i64,
+    pub interest_paid: i64,
+    pub accrued_at_nanos: i64,
+    /// Sub-unit interest not yet charged, in units of 1 / `PPM_X_NANOS`.
+    pub interest_frac: i64,
+}
+
+impl CreditLine {
+    pub fn new(cfg: &CreditConfig, now: i64) -> Self {
+        CreditLine { limit: cfg.limit, accrued_at_nanos: now, ..Default::default() }
+    }
+}
+
+/// Charges interest on any debt since the last accrual. Called before an
+/// account is used or shown, so displayed balances include interest to date.
+/// Returns the interest charged.
+pub fn accrue(ua: &mut UserAccount, cfg: &CreditConfig, now: i64) -> i64 {
+    let elapsed = now.saturating_sub(ua.credit.accrued_at_nanos);
+    ua.credit.accrued_at_nanos = now;
+    if ua.balance >= 0 || elapsed <= 0 || cfg.interest_ppm_per_sec <= 0 {
+        return 0;
+    }
+    let debt = -(ua.balance as i128);
+    let total = debt * cfg.interest_ppm_per_sec as i128 * elapsed as i128 + ua.credit.interest_frac as i128;
+    let charge = i64::try_from(total / PPM_X_NANOS).unwrap_or(i64::MAX);
+    ua.credit.interest_frac = (total % PPM_X_NANOS) as i64;
+    ua.balance = ua.balance.saturating_sub(charge);
+    ua.credit.interest_paid = ua.credit.interest_paid.saturating_add(charge);
+    charge
+}
+
+/// Whether `ua` can pay `cost` out of balance plus credit line.
+pub fn can_afford(ua: &UserAccount, cost: i64) -> bool {
+    ua.balance
+        .checked_add(ua.credit.limit)
+        .and_then(|avail| avail.checked_sub(cost))
+        .is_some_and(|left| left >= 0)
+}
@@ -0,0 +1,86 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+/// A refusal clients can branch on: `code` is stable and upper snake case,
+/// `message` is for people and may change. Middleware returns it as the
+/// whole body; handlers flatten it into their result through `RespMeta`, so
+/// the fields they always carried stay where they were.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    /// With the message known for `code`, if any.
+    pub fn new(status: StatusCode, code: &'static str) -> Self {
+        let message = describe(code).map_or_else(|| of_status(status).1.to_owned(), str::to_owned);
+        ApiError { status, code, message }
+    }
+
+    pub fn with(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into() }
+    }
+
+    /// What a refusal says when nothing more specific was given.
+    pub fn of_status(status: StatusCode) -> Self {
+        let (code, message) = of_status(status);
+        ApiError { status, code, message: message.to_owned() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+pub async fn not_found() -> ApiError {
+    ApiError::of_status(StatusCode::NOT_FOUND)
+}
+
+/// Mostly rejection reasons, so a refused call reads the same in its
+/// response and in `/rejections`; `TRADE_NOT_STARTED` is filed there as
+/// `MARKET_CLOSED`.
+fn describe(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "BAD_DEADLINE" => "the deadline header is not a number of nanoseconds",
+        "DEADLINE_PASSED" => "the client deadline passed before the request was handled",
+        "PAUSED" => "trading is paused",
+        "HALTED" => "trading is halted by the circuit breaker",
+        "TRADE_NOT_STARTED" => "trading has not started yet",
+        "MARKET_CLOSED" => "the market is closed",
+        "ALREADY_TRADED" => "the user has already traded",
+        "BOOK_HIDDEN" => "the book is hidden in this game mode",
+        "INSUFFICIENT_FUNDS" => "the balance can't cover this",
+        "BANKRUPT" => "the user is bankrupt",
+        "INVALID_ORDER" => "the order is not valid",
+        "INSUFFICIENT_POSITION" => "the user doesn't hold enough lots",
+        "RISK_LIMIT_POSITION" => "the order would take the position past its risk limit",
+        "RISK_LIMIT_NOTIONAL" => "the order would take the notional past its risk limit",
+        "UNKNOWN_USER" => "no such user",
+        _ => return None,
+    })
+}
+
+fn of_status(status: StatusCode) -> (&'static str, &'static str) {
+    match status {
+        StatusCode::BAD_REQUEST => ("BAD_REQUEST", "the request is not valid"),
+        StatusCode::UNAUTHORIZED => ("UNAUTHORIZED", "missing or wrong credentials"),
+        StatusCode::FORBIDDEN => ("FORBIDDEN", "the request is not allowed now"),
+        StatusCode::NOT_FOUND => ("NOT_FOUND", "not found"),
+        StatusCode::REQUEST_TIMEOUT => ("DEADLINE_PASSED", "the client deadline passed before the request was handled"),
+        StatusCode::CONFLICT => ("CONFLICT", "the request conflicts with the current state"),
+        StatusCode::PAYLOAD_TOO_LARGE => ("BODY_TOO_LARGE", "the request body is too large"),
+        StatusCode::TOO_MANY_REQUESTS => ("TOO_MANY_REQUESTS", "too many requests"),
+        StatusCode::SERVICE_UNAVAILABLE => ("UNAVAILABLE", "the service is unavailable"),
+        s if s.is_server_error() => ("INTERNAL", "the server failed to handle the request"),
+        _ => ("ERROR", "the request failed"),
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, settlement::SettlementEntry, AppState, ReqClock, RespMeta};
+
+/// How a user's buys compare with the cheapest lot that traded while they
+/// could trade, i.e. from their start to the close. Worked out from the
+/// tape as kept in memory, so trades already pruned by `[retention]` or
+/// `[memory]` don't count.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExecutionReport {
+    pub bought: i64,
+    pub spent: i64,
+    /// Lowest price on the tape in the user's window, if anything traded.
+    pub best_price: Option<i64>,
+    /// What `bought` would have cost at `best_price`.
+    pub best_cost: Option<i64>,
+    /// `spent` less `best_cost`: what was paid over the best execution.
+    pub gap: Option<i64>,
+}
+
+/// A report for every user, taken as the game settles.
+pub fn report(g: &AppState) -> BTreeMap<String, ExecutionReport> {
+    g.users
+        .keys()
+        .map(|u| {
+            let start = g.starts.of(u).unwrap_or(i64::MIN).max(g.calendar.first_open());
+            let best_price = g.tape.trades.iter().filter(|t| t.ts_nanos >= start).map(|t| t.price).min();
+            let (bought, spent) = g
+                .tape
+                .trades
+                .iter()
+                .filter(|t| t.uname == *u)
+                .fold((0i64, 0i64), |(b, s), t| (b + t.vol, s.saturating_add(t.price.saturating_mul(t.vol))));
+            let best_cost = best_price.map(|p| p.saturating_mul(bought));
+            let gap = best_cost.map(|c| spent - c);
+            (u.clone(), ExecutionReport { bought, spent, best_price, best_cost, gap })
+        })
+        .collect()
+}
+
+#[derive(Serialize, Default)]
+pub struct UserResultsResult {
+    pub entry: Option<SettlementEntry>,
+    pub execution: Option<ExecutionReport>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// The user's line on the final board with their execution report. 409
+/// NOT_SETTLED until the game settles.
+pub async fn user_results(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<UserResultsResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), UserResultsResult::default());
+    }
+    let Some(s) = &g.settlement else {
+        let err = ApiError::with(StatusCode::CONFLICT, "NOT_SETTLED", "the game hasn't settled yet");
+        return clock.refuse(err, UserResultsResult::default());
+    };
+    let res = UserResultsResult {
+        entry: s.entries.iter().find(|e| e.uname == uname).cloned(),
+        execution: s.execution.get(&uname).cloned(),
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
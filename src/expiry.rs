@@ -0,0 +1,60 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{contention::StateLock, feed, journal, market, now, AppState};
+
+/// Longest the sweeper sleeps, so expiries added through the admin API are
+/// picked up without it being woken.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+/// Takes `vol` of the house's volume at `price` off the book, as if it had
+/// never been issued.
+pub fn withdraw(g: &mut AppState, price: i64, vol: i64, now: i64) {
+    if let Some(left) = g.book.asks.get_mut(&price) {
+        *left -= vol;
+        if *left <= 0 {
+            g.book.asks.remove(&price);
+        }
+    }
+    g.ask_expiry.remove(&price);
+    g.issued.units -= vol;
+    g.book_changed(now);
+    g.journal.record(|| journal::Event::AskExpired { price, vol, ts_nanos: now });
+    g.market.send(|| market::MarketEvent::AskExpired { price, vol, ts_nanos: now });
+    g.feeds.send_all(feed::UserEvent::AskExpired { price, vol, ts_nanos: now });
+}
+
+/// Withdraws the house volume at every level past its expiry. Lots users
+/// listed at the level stay. Nothing expires once the game is settled.
+pub fn sweep(g: &mut AppState, now: i64) {
+    if g.settlement.is_some() {
+        return;
+    }
+    let due: Vec<i64> = g.ask_expiry.iter().filter(|(_, at)| **at <= now).map(|(p, _)| *p).collect();
+    for price in due {
+        let on_book = g.book.asks.get(&price).copied().unwrap_or(0);
+        let house = on_book - g.book.listed_at(price);
+        if house > 0 {
+            tracing::info!("ask level {} expired: {} lots withdrawn", price, house);
+            withdraw(g, price, house, now);
+        } else {
+            g.ask_expiry.remove(&price);
+        }
+    }
+}
+
+pub fn spawn_sweeper(state: Arc<Mutex<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            let next = {
+                let mut g = state.locked();
+                let now = now();
+                sweep(&mut g, now);
+                g.ask_expiry.values().min().map(|at| Duration::from_nanos((at - now).max(0) as u64))
+            };
+            tokio::time::sleep(next.unwrap_or(MAX_SLEEP).min(MAX_SLEEP)).await;
+        }
+    });
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    contention::StateLock,
+    errors::ApiError,
+    handoff::token_matches,
+    ledger,
+    orders::OrderStatus,
+    quotes,
+    timeline::{Item, Timeline},
+    AppState, AskLevel,
+};
+
+/// Events a subscriber falls behind by before it is told it lagged.
+const FEED_BUFFER: usize = 256;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Something that happened to one account. Every event that moves money
+/// carries the balance it left behind.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UserEvent {
+    Fee { amount: i64, balance: i64, ts_nanos: i64 },
+    /// Any change to the balance, with why; sent beside the event that
+    /// caused it, and for fines, payouts and quote fees, which have none.
+    Balance { reason: ledger::Reason, delta: i64, balance: i64, ts_nanos: i64 },
+    Interest { amount: i64, balance: i64, ts_nanos: i64 },
+    Fill { price: i64, vol: i64, balance: i64, ts_nanos: i64 },
+    /// A fill on one of the further instruments.
+    SymbolFill { symbol: String, price: i64, vol: i64, balance: i64, ts_nanos: i64 },
+    /// Lots listed with `place_ask` were bought.
+    Sold { price: i64, vol: i64, balance: i64, ts_nanos: i64 },
+    Order { id: u64, status: OrderStatus, remaining: i64, ts_nanos: i64 },
+    /// The fee could not be paid; paid calls are refused from now on.
+    Bankrupt { balance: i64, fee: i64, ts_nanos: i64 },
+    /// Trading is halted for everyone until `until_nanos`.
+    Halt { until_nanos: i64, low: i64, high: i64 },
+    /// House volume withdrawn because its level expired.
+    AskExpired { price: i64, vol: i64, ts_nanos: i64 },
+    /// The book after it changed, for quote subscribers only.
+    Book { asks: Vec<AskLevel>, ts_nanos: i64 },
+    /// The quote subscription ended, e.g. because updates can't be paid for.
+    QuotesStopped { reason: String },
+    /// Sent in place of events dropped because the client read too slowly.
+    Lagged { missed: u64 },
+}
+
+/// Per-user senders, created when the first feed for a user opens. Every
+/// event also goes into the timeline, subscribed or not.
+#[derive(Debug, Default)]
+pub struct Feeds {
+    senders: HashMap<String, broadcast::Sender<UserEvent>>,
+    pub timeline: Timeline,
+    /// Open quote subscriptions per user.
+    pub quote_subs: HashMap<String, usize>,
+}
+
+impl Feeds {
+    pub fn send(&mut self, uname: &str, ev: UserEvent) {
+        if let Some(tx) = self.senders.get(uname).filter(|tx| tx.receiver_count() > 0) {
+            let _ = tx.send(ev.clone());
+        }
+        self.timeline.record(uname, Item::Event { event: ev });
+    }
+
+    /// For market-wide events every subscriber should see.
+    pub fn send_all(&mut self, ev: UserEvent) {
+        for tx in self.senders.values().filter(|tx| tx.receiver_count() > 0) {
+            let _ = tx.send(ev.clone());
+        }
+        self.timeline.record_global(Item::Event { event: ev });
+    }
+
+    /// Book updates go out to subscribers only, and stay off the timeline.
+    pub fn send_quotes(&self, ev: impl FnOnce() -> UserEvent) {
+        if self.quote_subs.is_empty() {
+            return;
+        }
+        let ev = ev();
+        for u in self.quote_subs.keys() {
+            if let Some(tx) = self.senders.get(u) {
+                let _ = tx.send(ev.clone());
+            }
+        }
+    }
+
+    fn subscribe(&mut self, uname: &str) -> broadcast::Receiver<UserEvent> {
+        self.senders.entry(uname.to_owned()).or_insert_with(|| broadcast::channel(FEED_BUFFER).0).subscribe()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyQuery {
+    /// For clients that can't set headers on a WebSocket handshake.
+    pub key: Option<String>,
+    /// Also push book updates, billed per update, see `[quotes]`.
+    #[serde(default)]
+    pub quotes: bool,
+}
+
+/// Whether the user's key from `[user_keys]` came in `x-api-key` or `?key=`.
+pub fn key_matches(g: &AppState, uname: &str, headers: &HeaderMap, key: Option<&str>) -> bool {
+    let given = headers.get(API_KEY_HEADER).map(|v| v.as_bytes()).or(key.map(str::as_bytes));
+    match (g.user_keys.get(uname), given) {
+        (Some(expected), Some(given)) => token_matches(expected, given),
+        _ => false,
+    }
+}
+
+/// Private, free push feed of the user's own events. Requires the key from
+/// `[user_keys]` in `x-api-key` or `?key=`.
+pub async fn user_ws(
+    Path(uname): Path<String>,
+    Query(q): Query<KeyQuery>,
+    headers: HeaderMap,
+    State(state): State<Arc<Mutex<AppState>>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let (rx, sub) = {
+        let mut g = state.locked();
+        if !g.users.contains_key(&uname) {
+            return ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER").into_response();
+        }
+        if !key_matches(&g, &uname, &headers, q.key.as_deref()) {
+            return ApiError::with(StatusCode::UNAUTHORIZED, "BAD_KEY", "missing or wrong user key").into_response();
+        }
+        if q.quotes && g.quotes.is_none() {
+            return ApiError::with(StatusCode::NOT_FOUND, "NOT_OFFERED", "quotes are not offered").into_response();
+        }
+        let rx = g.feeds.subscribe(&uname);
+        let sub = q.quotes.then(|| quotes::Subscription::open(state.clone(), &mut g, &uname));
+        (rx, sub)
+    };
+    ws.on_upgrade(move |socket| pump(socket, rx, sub))
+}
+
+async fn pump(mut socket: WebSocket, mut rx: broadcast::Receiver<UserEvent>, mut sub: Option<quotes::Subscription>) {
+    loop {
+        let ev = tokio::select! {
+            ev = rx.recv() => match ev {
+                Ok(ev) => ev,
+                Err(broadcast::error::RecvError::Lagged(missed)) => UserEvent::Lagged { missed },
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let is_quote = matches!(ev, UserEvent::Book { .. });
+        if is_quote && sub.is_none() {
+            continue;
+        }
+        let text = serde_json::to_string(&ev).unwrap();
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+        let Some(s) = sub.as_ref().filter(|_| is_quote) else {
+            continue;
+        };
+        let paid = quotes::bill(&mut s.state.locked(), &s.uname);
+        if !paid {
+            sub = None;
+            let ev = UserEvent::QuotesStopped { reason: "INSUFFICIENT_FUNDS".to_owned() };
+            if socket.send(Message::Text(serde_json::to_string(&ev).unwrap())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
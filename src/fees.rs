@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Ping,
+    CheckAsks,
+    PlaceBid,
+    PlaceAsk,
+    /// Opening `/ws/market`.
+    MarketData,
+    /// An update delivered to a `[quotes]` subscription, priced there
+    /// rather than by fee windows.
+    Quotes,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 6] =
+        [Endpoint::Ping, Endpoint::CheckAsks, Endpoint::PlaceBid, Endpoint::PlaceAsk, Endpoint::MarketData, Endpoint::Quotes];
+
+    /// As serialized.
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::Ping => "ping",
+            Endpoint::CheckAsks => "check_asks",
+            Endpoint::PlaceBid => "place_bid",
+            Endpoint::PlaceAsk => "place_ask",
+            Endpoint::MarketData => "market_data",
+            Endpoint::Quotes => "quotes",
+        }
+    }
+}
+
+/// Scales the fee while `start_nanos <= now < end_nanos`: 0 makes calls
+/// free, 200 doubles them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FeeWindow {
+    pub start_nanos: i64,
+    pub end_nanos: i64,
+    /// Calls affected; all paid calls when empty.
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    pub percent: u32,
+}
+
+/// Fee holidays and surges. Where windows overlap the first one listed
+/// applies.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FeeScheduleConfig {
+    pub windows: Vec<FeeWindow>,
+}
+
+/// What each paid call costs right now.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CurrentFees {
+    pub ping: i64,
+    pub check_asks: i64,
+    pub place_bid: i64,
+    pub place_ask: i64,
+}
+
+impl FeeScheduleConfig {
+    pub fn fee(&self, base: i64, ep: Endpoint, now: i64) -> i64 {
+        self.windows
+            .iter()
+            .find(|w| w.start_nanos <= now && now < w.end_nanos && (w.endpoints.is_empty() || w.endpoints.contains(&ep)))
+            .map_or(base, |w| (base as i128 * w.percent as i128 / 100).min(i64::MAX as i128) as i64)
+    }
+
+    pub fn current(&self, base: i64, now: i64) -> CurrentFees {
+        CurrentFees {
+            ping: self.fee(base, Endpoint::Ping, now),
+            check_asks: self.fee(base, Endpoint::CheckAsks, now),
+            place_bid: self.fee(base, Endpoint::PlaceBid, now),
+            place_ask: self.fee(base, Endpoint::PlaceAsk, now),
+        }
+    }
+
+    /// When some window next starts or ends.
+    pub fn next_change(&self, now: i64) -> Option<i64> {
+        self.windows.iter().flat_map(|w| [w.start_nanos, w.end_nanos]).filter(|t| *t > now).min()
+    }
+}
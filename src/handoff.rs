@@ -0,0 +1,207 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{backup::StateImage, contention::StateLock, errors::ApiError, schema, settlement, AppState, ReqClock, RespMeta};
+
+const TOKEN_HEADER: &str = "x-handoff-token";
+const CHECKSUM_HEADER: &str = "x-handoff-sha256";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HandoffConfig {
+    /// Shared secret both instances must be configured with.
+    pub token: String,
+}
+
+fn sha256_hex(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// HMAC-SHA256 (RFC 2104), for settlement records and signed requests.
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut k = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        k[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        k[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new().chain_update(k.map(|b| b ^ 0x36)).chain_update(msg).finalize();
+    Sha256::new().chain_update(k.map(|b| b ^ 0x5c)).chain_update(inner).finalize().into()
+}
+
+pub fn token_matches(expected: &str, got: &[u8]) -> bool {
+    let expected = expected.as_bytes();
+    expected.len() == got.len() && expected.iter().zip(got).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HandoffRequest {
+    /// Base URL of the standby, e.g. `http://10.0.0.2:3000`.
+    pub target: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct HandoffResult {
+    pub handed_off: bool,
+    pub sha256: String,
+    pub users: usize,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn failed(clock: &ReqClock, code: StatusCode, err: String) -> (StatusCode, Json<HandoffResult>) {
+    let api_err = ApiError { message: err.clone(), ..ApiError::of_status(code) };
+    clock.refuse(api_err, HandoffResult { error: Some(err), ..Default::default() })
+}
+
+/// Pauses trading, ships the full state to `target` and, once the standby
+/// confirms the checksum, starts redirecting user traffic there. If anything
+/// fails trading stays paused here so the operator can retry or resume.
+pub async fn admin_handoff(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<HandoffRequest>,
+) -> (StatusCode, Json<HandoffResult>) {
+    let clock = ReqClock::start();
+    let (token, body, users) = {
+        let mut g = state.locked();
+        let Some(token) = g.handoff_token.clone() else {
+            return failed(&clock, StatusCode::NOT_FOUND, "handoff is not configured".to_owned());
+        };
+        if g.handed_off_to.is_some() {
+            return failed(&clock, StatusCode::CONFLICT, "state was already handed off".to_owned());
+        }
+        g.paused = true;
+        g.feeds.timeline.admin_global("trading paused for handoff");
+        let image = StateImage::capture(&g);
+        (token, serde_json::to_vec(&image).unwrap(), image.users.len())
+    };
+    let sum = sha256_hex(&body);
+    let target = req.target.trim_end_matches('/').to_owned();
+    tracing::warn!("handing off {} bytes of state to {}", body.len(), target);
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/admin/handoff/receive", target))
+        .header(TOKEN_HEADER, token)
+        .header(CHECKSUM_HEADER, &sum)
+        .body(body)
+        .send()
+        .await;
+    #[derive(Deserialize)]
+    struct Ack {
+        sha256: String,
+    }
+    let ack: Ack = match resp {
+        Ok(r) if r.status().is_success() => match r.json().await {
+            Ok(a) => a,
+            Err(e) => return failed(&clock, StatusCode::BAD_GATEWAY, format!("bad ack from standby: {}", e)),
+        },
+        Ok(r) => return failed(&clock, StatusCode::BAD_GATEWAY, format!("standby refused: {}", r.status())),
+        Err(e) => return failed(&clock, StatusCode::BAD_GATEWAY, format!("standby unreachable: {}", e)),
+    };
+    if ack.sha256 != sum {
+        return failed(&clock, StatusCode::BAD_GATEWAY, "standby checksum mismatch".to_owned());
+    }
+
+    state.locked().handed_off_to = Some(target);
+    clock.reply(StatusCode::OK, HandoffResult { handed_off: true, sha256: sum, users, ..Default::default() })
+}
+
+/// Standby side: verifies token and checksum, then replaces local state.
+pub async fn admin_handoff_receive(
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<HandoffResult>) {
+    let clock = ReqClock::start();
+    let Some(token) = state.locked().handoff_token.clone() else {
+        return failed(&clock, StatusCode::NOT_FOUND, "handoff is not configured".to_owned());
+    };
+    let got = headers.get(TOKEN_HEADER).map(|v| v.as_bytes()).unwrap_or_default();
+    if !token_matches(&token, got) {
+        return failed(&clock, StatusCode::UNAUTHORIZED, "bad handoff token".to_owned());
+    }
+    let sum = sha256_hex(&body);
+    if headers.get(CHECKSUM_HEADER).map(|v| v.as_bytes()) != Some(sum.as_bytes()) {
+        return failed(&clock, StatusCode::BAD_REQUEST, "checksum mismatch".to_owned());
+    }
+    let image: StateImage = match serde_json::from_slice(&body)
+        .map_err(|e| e.to_string())
+        .and_then(schema::upgrade_state)
+        .and_then(|v| serde_json::from_value(v).map_err(|e| e.to_string()))
+    {
+        Ok(i) => i,
+        Err(e) => return failed(&clock, StatusCode::BAD_REQUEST, e),
+    };
+
+    let users = image.users.len();
+    let mut g = state.locked();
+    if let Some(end) = image.apply(&mut g) {
+        settlement::spawn_close(state.clone(), end);
+    }
+    g.paused = false;
+    tracing::warn!("accepted handoff of {} users", users);
+    g.feeds.timeline.admin_global("state received by handoff");
+    clock.reply(StatusCode::OK, HandoffResult { handed_off: true, sha256: sum, users, ..Default::default() })
+}
+
+/// After a handoff, sends user traffic to the new instance. Admin routes stay
+/// local so the old instance can still be inspected.
+pub async fn redirect_if_handed_off(
+    State(state): State<Arc<Mutex<AppState>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !req.uri().path().starts_with("/admin/") {
+        let target = state.locked().handed_off_to.clone();
+        if let Some(target) = target {
+            let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            return (
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, format!("{}{}", target, path))],
+            )
+                .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 4231 test cases 1, 2 and 6.
+    #[test]
+    fn hmac_matches_the_rfc_vectors() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (&[0x0b; 20], b"Hi There", "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe", b"what do ya want for nothing?", "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, msg, mac) in cases {
+            assert_eq!(hex::encode(hmac_sha256(key, msg)), mac);
+        }
+    }
+
+    #[test]
+    fn tokens_match_only_exactly() {
+        assert!(token_matches("s3cret", b"s3cret"));
+        assert!(!token_matches("s3cret", b"s3creT"));
+        assert!(!token_matches("s3cret", b"s3cre"));
+        assert!(!token_matches("s3cret", b""));
+    }
+}
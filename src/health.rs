@@ -0,0 +1,50 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+
+use crate::shutdown::Stopping;
+
+/// Whether the server should be sent traffic. The config is loaded before
+/// this exists; it turns ready once the `--listen` address is bound, and
+/// unready again as soon as shutdown begins, see `[shutdown] unready_secs`.
+#[derive(Debug)]
+pub struct Readiness {
+    bound: AtomicBool,
+    stop: Stopping,
+}
+
+impl Readiness {
+    pub fn new(stop: Stopping) -> Arc<Self> {
+        Arc::new(Readiness { bound: AtomicBool::new(false), stop })
+    }
+
+    pub fn bound(&self) {
+        self.bound.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProbeResult {
+    pub status: &'static str,
+}
+
+/// 200 for as long as the process can answer at all.
+pub async fn healthz() -> Json<ProbeResult> {
+    Json(ProbeResult { status: "ok" })
+}
+
+pub async fn readyz(State(r): State<Arc<Readiness>>) -> (StatusCode, Json<ProbeResult>) {
+    let status = if *r.stop.borrow() {
+        "stopping"
+    } else if !r.bound.load(Ordering::Relaxed) {
+        "starting"
+    } else {
+        "ready"
+    };
+    let code = if status == "ready" { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(ProbeResult { status }))
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ApiError;
+
+/// How many `/users/:uname/*` requests one user may have inside the server
+/// at once. Past that they are refused before taking the state lock, so a
+/// burst of parallel bids can't queue ahead of everyone else's.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserConcurrencyConfig {
+    pub max_in_flight: usize,
+}
+
+/// Requests in flight per username, as given in the path.
+#[derive(Debug)]
+pub struct InFlight {
+    max: usize,
+    by_user: Mutex<HashMap<String, usize>>,
+}
+
+impl InFlight {
+    pub fn new(cfg: &UserConcurrencyConfig) -> Arc<Self> {
+        Arc::new(InFlight { max: cfg.max_in_flight.max(1), by_user: Mutex::new(HashMap::new()) })
+    }
+}
+
+/// Frees the user's slot when the request is done, however it ends.
+struct Slot {
+    inflight: Arc<InFlight>,
+    uname: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut by_user = self.inflight.by_user.lock().unwrap();
+        if let Some(n) = by_user.get_mut(&self.uname) {
+            *n -= 1;
+            if *n == 0 {
+                by_user.remove(&self.uname);
+            }
+        }
+    }
+}
+
+pub async fn cap(State(inflight): State<Arc<InFlight>>, req: Request, next: Next) -> Response {
+    let Some(uname) = req.uri().path().strip_prefix("/users/").and_then(|rest| rest.split('/').next()) else {
+        return next.run(req).await;
+    };
+    let _slot = {
+        let mut by_user = inflight.by_user.lock().unwrap();
+        let n = by_user.entry(uname.to_owned()).or_default();
+        if *n >= inflight.max {
+            let msg = "wait for earlier requests to finish";
+            return ApiError::with(StatusCode::TOO_MANY_REQUESTS, "TOO_MANY_IN_FLIGHT", msg).into_response();
+        }
+        *n += 1;
+        Slot { inflight: inflight.clone(), uname: uname.to_owned() }
+    };
+    next.run(req).await
+}
@@ -0,0 +1,70 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{book, contention::StateLock, market::MarketEvent, now, AppState};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// House volume added on a schedule, `after_secs` past the first open. Set
+/// under `[[injections]]`, one table per injection.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Injection {
+    pub after_secs: u64,
+    pub price: i64,
+    pub vol: i64,
+    /// Tell `/ws/market` about it; otherwise it only shows in the book.
+    #[serde(default)]
+    pub announce: bool,
+    /// Withdraw what is left this long after, see `expiry`.
+    #[serde(default)]
+    pub lasts_secs: Option<u64>,
+}
+
+/// Makes each injection when it falls due. One whose time passed before
+/// the server started is skipped rather than made late, so a restart
+/// never adds volume twice. In the lobby the schedule waits for arming.
+pub fn spawn_scheduler(state: Arc<Mutex<AppState>>, mut injections: Vec<Injection>) {
+    injections.sort_by_key(|i| i.after_secs);
+    let started = now();
+    tokio::spawn(async move {
+        let first_open = loop {
+            let (armed, first_open) = {
+                let g = state.locked();
+                (g.calendar.armed(), g.calendar.first_open())
+            };
+            if armed {
+                break first_open;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        for inj in injections {
+            let at = first_open.saturating_add(inj.after_secs as i64 * NANOS_PER_SEC);
+            if at < started {
+                tracing::warn!("skipping injection of {} at {}: it was due before the server started", inj.vol, inj.price);
+                continue;
+            }
+            tokio::time::sleep(Duration::from_nanos(at.saturating_sub(now()).max(0) as u64)).await;
+            let mut g = state.locked();
+            if g.settlement.is_some() {
+                return;
+            }
+            inject(&mut g, &inj, now());
+        }
+    });
+}
+
+fn inject(g: &mut AppState, inj: &Injection, now: i64) {
+    let expires_at_nanos = inj.lasts_secs.map(|s| now + s as i64 * NANOS_PER_SEC);
+    book::offer(g, inj.price, inj.vol, expires_at_nanos, now);
+    tracing::info!("injected {} lots at {}", inj.vol, inj.price);
+    if inj.announce {
+        let (price, vol) = (inj.price, inj.vol);
+        g.market.send(|| MarketEvent::Injection { price, vol, expires_at_nanos, ts_nanos: now });
+        g.feeds.timeline.admin_global(format!("{} lots injected at {}", vol, price));
+    }
+    book::match_resting(g, inj.price, now);
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    book, book_view, client_deadline, contention::StateLock, deadline_passed, errors::ApiError, feed, fees::Endpoint, ledger, matching,
+    now, AppState, AskLevel, BidFill, BidResult, BidStatus, PriceVol, ReqClock, RespMeta, UserAccount,
+};
+
+/// A further instrument traded beside the main book, under
+/// `/users/:uname/check_asks/:symbol` and `/users/:uname/place_bid/:symbol/:price`.
+/// Bids are single lots that fill or are cancelled, and don't count
+/// towards `done_trade`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InstrumentConfig {
+    /// Starts with a letter, so it can't be mistaken for a price.
+    pub symbol: String,
+    pub asks: Vec<PriceVol>,
+    /// Base fee for this instrument's calls; the game's `fee` if unset.
+    pub fee: Option<i64>,
+    /// Bids are refused before this, on top of the game's own schedule.
+    pub trade_start_nanos: Option<i64>,
+    /// Settlement price for held lots; the last trade's if unset.
+    pub mark_price: Option<i64>,
+    /// Settles at what an option on another price pays instead.
+    #[serde(default)]
+    pub payoff: Option<Payoff>,
+    /// Most lots of this instrument a user may hold, on top of `[risk]`.
+    pub max_position: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionKind {
+    /// Pays `max(S - strike, 0)`.
+    Call,
+    /// Pays `max(strike - S, 0)`.
+    Put,
+}
+
+/// An option settled in cash per lot, where S is the underlying's settlement
+/// price: the main book's if `underlying` is unset, else that instrument's mark.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Payoff {
+    pub kind: OptionKind,
+    pub strike: i64,
+    pub underlying: Option<String>,
+}
+
+impl Payoff {
+    pub fn value(&self, s: i64) -> i64 {
+        match self.kind {
+            OptionKind::Call => s.saturating_sub(self.strike).max(0),
+            OptionKind::Put => self.strike.saturating_sub(s).max(0),
+        }
+    }
+}
+
+/// The state of one instrument.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct InstrumentBook {
+    pub asks: matching::Ladder,
+    pub last_price: Option<i64>,
+    /// Lots put into the game, which the book and holdings must add up to.
+    pub issued: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Instruments {
+    pub cfg: BTreeMap<String, InstrumentConfig>,
+    pub books: BTreeMap<String, InstrumentBook>,
+}
+
+impl Instruments {
+    pub fn new(cfgs: &[InstrumentConfig]) -> Result<Self, String> {
+        let mut ins = Instruments::default();
+        for c in cfgs {
+            let valid = c.symbol.starts_with(|ch: char| ch.is_ascii_alphabetic())
+                && c.symbol.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+            if !valid {
+                return Err(format!("instrument symbol {:?} must be a letter followed by letters, digits, - or _", c.symbol));
+            }
+            if ins.cfg.insert(c.symbol.clone(), c.clone()).is_some() {
+                return Err(format!("instrument {} is listed twice", c.symbol));
+            }
+            if let Some(p) = &c.payoff {
+                if c.mark_price.is_some() {
+                    return Err(format!("instrument {} has both a payoff and a mark_price", c.symbol));
+                }
+                if p.strike < 0 {
+                    return Err(format!("instrument {} has a negative strike", c.symbol));
+                }
+            }
+            if c.max_position.is_some_and(|m| m < 0) {
+                return Err(format!("instrument {} has a negative max_position", c.symbol));
+            }
+            let asks: matching::Ladder = c.asks.iter().filter(|pv| pv.vol > 0).map(|pv| (pv.price, pv.vol)).collect();
+            let issued = asks.values().sum();
+            ins.books.insert(c.symbol.clone(), InstrumentBook { asks, last_price: None, issued });
+        }
+        // Options on options would need an order to settle in.
+        for c in cfgs {
+            let Some(u) = c.payoff.as_ref().and_then(|p| p.underlying.as_ref()) else {
+                continue;
+            };
+            match ins.cfg.get(u) {
+                None => return Err(format!("instrument {} is an option on {}, which is not an instrument", c.symbol, u)),
+                Some(uc) if uc.payoff.is_some() => return Err(format!("instrument {} is an option on {}, itself an option", c.symbol, u)),
+                Some(_) => {}
+            }
+        }
+        Ok(ins)
+    }
+
+    pub fn fee(&self, symbol: &str, base: i64) -> i64 {
+        self.cfg[symbol].fee.unwrap_or(base)
+    }
+
+    pub fn started(&self, symbol: &str, now: i64) -> bool {
+        self.cfg[symbol].trade_start_nanos.map_or(true, |s| now >= s)
+    }
+
+    /// What a held lot is worth at settlement, with the main book settling
+    /// at `price`.
+    pub fn mark(&self, symbol: &str, price: i64) -> i64 {
+        let cfg = self.cfg.get(symbol);
+        if let Some(p) = cfg.and_then(|c| c.payoff.as_ref()) {
+            let s = p.underlying.as_deref().map_or(price, |u| self.mark(u, price));
+            return p.value(s);
+        }
+        let last = self.books.get(symbol).and_then(|b| b.last_price);
+        cfg.and_then(|c| c.mark_price).or(last).unwrap_or(0)
+    }
+
+    /// Refuses `qty` more lots of `symbol` for a user holding `held`.
+    pub fn check_position(&self, symbol: &str, held: i64, qty: i64) -> Result<(), &'static str> {
+        match self.cfg[symbol].max_position {
+            Some(max) if held.checked_add(qty).map_or(true, |p| p > max) => Err("RISK_LIMIT_POSITION"),
+            _ => Ok(()),
+        }
+    }
+
+    /// `holdings` against each instrument's `max_position`, where one is set.
+    pub fn position_use(&self, holdings: &BTreeMap<String, i64>) -> Vec<PositionUse> {
+        self.cfg
+            .values()
+            .filter_map(|c| {
+                let held = holdings.get(&c.symbol).copied().unwrap_or(0);
+                c.max_position.map(|max_position| PositionUse { symbol: c.symbol.clone(), held, max_position })
+            })
+            .collect()
+    }
+
+    /// Configured, and with a book; a restored image may lack one.
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.cfg.contains_key(symbol) && self.books.contains_key(symbol)
+    }
+}
+
+/// `ua` as `[risk]` sees it here: limits cover lots across all instruments.
+pub fn with_all_lots(ua: &UserAccount) -> UserAccount {
+    UserAccount { position: ua.position + ua.holdings.values().sum::<i64>(), ..ua.clone() }
+}
+
+#[derive(Serialize)]
+pub struct PositionUse {
+    pub symbol: String,
+    pub held: i64,
+    pub max_position: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct SymbolBookResult {
+    pub symbol: String,
+    pub asks: Vec<AskLevel>,
+    /// What a lot settles at, for options.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payoff: Option<Payoff>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// `check_asks` for one instrument, charged at its fee.
+pub async fn user_check_symbol(
+    Path((uname, symbol)): Path<(String, String)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<SymbolBookResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::CheckAsks;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), SymbolBookResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), SymbolBookResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), SymbolBookResult::default());
+    }
+    if !g.users.contains_key(&uname) || !g.instruments.contains(&symbol) {
+        return clock.reply(StatusCode::NOT_FOUND, SymbolBookResult::default());
+    }
+    let fee = g.fee_schedule.fee(g.instruments.fee(&symbol, g.fee), ep, now);
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = SymbolBookResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+    let open = g.trading_open(&uname, now) && g.instruments.started(&symbol, now);
+    if !open && g.settlement.is_none() {
+        g.reject(&uname, ep, "MARKET_CLOSED", fee, now);
+        let err = ApiError::new(StatusCode::FORBIDDEN, g.closed_reason(&uname, now));
+        return clock.refuse(err, SymbolBookResult::default());
+    }
+    let cfg = &g.instruments.cfg[&symbol];
+    let asks = book_view::levels(g.book_view, &g.instruments.books[&symbol].asks, &BTreeMap::new(), &cfg.asks);
+    let payoff = cfg.payoff.clone();
+    clock.reply(StatusCode::OK, SymbolBookResult { symbol, asks, payoff, ..Default::default() })
+}
+
+/// Pays for `fill`, already taken off `symbol`'s book, returning the cost.
+pub fn pay_for_fill(g: &mut AppState, uname: &str, symbol: &str, fill: matching::Fill, now: i64) -> i64 {
+    g.instruments.books.get_mut(symbol).unwrap().last_price = Some(fill.price);
+    let cost = matching::fill_cost(fill);
+    let ua = g.users.get_mut(uname).unwrap();
+    ua.balance -= cost;
+    ua.notional_spent += cost;
+    *ua.holdings.entry(symbol.to_owned()).or_default() += fill.vol;
+    let balance = ua.balance;
+    g.house.proceeds += cost;
+    g.board_changed();
+    let symbol = symbol.to_owned();
+    g.feeds.send(uname, feed::UserEvent::SymbolFill { symbol, price: fill.price, vol: fill.vol, balance, ts_nanos: now });
+    g.balance_moved(uname, ledger::Reason::Trade, -cost, None, now);
+    cost
+}
+
+/// `place_bid` on one instrument, charged at its fee. Reached through the
+/// main route, which tells symbols from prices.
+pub fn bid(
+    state: &Arc<Mutex<AppState>>,
+    uname: &str,
+    symbol: &str,
+    price: i64,
+    deadline: Option<i64>,
+    clock: ReqClock,
+) -> (StatusCode, Json<BidResult>) {
+    let ep = Endpoint::PlaceBid;
+    let mut g = state.locked();
+    let now = now();
+    if deadline_passed(deadline) {
+        g.reject(uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), BidResult::default());
+    }
+    if g.paused {
+        g.reject(uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), BidResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(uname, ep, "HALTED", 0, now);
+        let res = BidResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    if !g.users.contains_key(uname) || !g.instruments.contains(symbol) {
+        return clock.reply(StatusCode::NOT_FOUND, BidResult::default());
+    }
+    let fee = g.fee_schedule.fee(g.instruments.fee(symbol, g.fee), ep, now);
+    if let Err(reason) = g.charge_request(uname, fee, ep, Some(clock.id()), now) {
+        g.reject(uname, ep, reason, 0, now);
+        let res = BidResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+    let refused = if !g.trading_open(uname, now) || !g.instruments.started(symbol, now) {
+        Err("MARKET_CLOSED")
+    } else {
+        let held = g.users[uname].holdings.get(symbol).copied().unwrap_or(0);
+        g.instruments.check_position(symbol, held, 1).and_then(|_| book::admissible(&g, &with_all_lots(&g.users[uname]), price, 1))
+    };
+    if let Err(code) = refused {
+        g.reject(uname, ep, code, fee, now);
+        let reject_reason = (code != "MARKET_CLOSED").then(|| code.to_owned());
+        let res = BidResult { reject_reason, total_fees: fee, ..Default::default() };
+        let code = if code == "MARKET_CLOSED" { g.closed_reason(uname, now) } else { code };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), res);
+    }
+
+    let mut res = BidResult { qty: 1, total_fees: fee, status: BidStatus::Unfilled, ..Default::default() };
+    let ins = g.instruments.books.get_mut(symbol).unwrap();
+    if let Some(fill) = matching::match_bid(&mut ins.asks, price, 1) {
+        let cost = pay_for_fill(&mut g, uname, symbol, fill, now);
+        res.fills = vec![BidFill { price: fill.price, vol: fill.vol }];
+        res.filled_qty = fill.vol;
+        res.total_cost = cost;
+        res.trade_succ = true;
+        res.status = BidStatus::Filled;
+    }
+    res.position = g.users[uname].holdings.get(symbol).copied().unwrap_or(0);
+    clock.reply(StatusCode::OK, res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_position_caps_each_instrument_alone() {
+        let cfgs: Vec<InstrumentConfig> = toml::from_str::<BTreeMap<String, Vec<InstrumentConfig>>>(
+            r#"
+            [[i]]
+            symbol = "GOLD"
+            asks = [ { price = 5, vol = 10 } ]
+            max_position = 2
+
+            [[i]]
+            symbol = "SILVER"
+            asks = [ { price = 1, vol = 10 } ]
+            "#,
+        )
+        .unwrap()
+        .remove("i")
+        .unwrap();
+        let ins = Instruments::new(&cfgs).unwrap();
+        assert_eq!(ins.check_position("GOLD", 1, 1), Ok(()));
+        assert_eq!(ins.check_position("GOLD", 2, 1), Err("RISK_LIMIT_POSITION"));
+        assert_eq!(ins.check_position("GOLD", i64::MAX, 1), Err("RISK_LIMIT_POSITION"));
+        assert_eq!(ins.check_position("SILVER", 100, 1), Ok(()));
+
+        let used = ins.position_use(&BTreeMap::from([("GOLD".to_owned(), 2)]));
+        assert_eq!(used.len(), 1);
+        assert_eq!((used[0].symbol.as_str(), used[0].held, used[0].max_position), ("GOLD", 2, 2));
+    }
+}
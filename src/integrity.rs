@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    contention::StateLock,
+    loans::Loan,
+    tape::{Sale, Trade},
+    AppState, ReqClock, RespMeta,
+};
+
+/// Longest ring of loans looked for; longer ones are left to the tape.
+const MAX_CYCLE_LEN: usize = 5;
+
+/// Thresholds for flagging a seller and buyer pair.
+#[derive(Debug, Deserialize)]
+pub struct IntegrityQuery {
+    /// Sales between the two; 3 if unset.
+    pub min_sales: Option<usize>,
+    /// Of the seller's sold lots, the share that went to this buyer; 50 if unset.
+    pub min_share_percent: Option<i64>,
+    /// How far under the tape's average price the pair traded; 10 if unset.
+    pub min_discount_percent: Option<i64>,
+}
+
+/// A seller whose listed lots kept going cheap to one buyer.
+#[derive(Debug, Serialize)]
+pub struct PairFlag {
+    pub seller: String,
+    pub buyer: String,
+    pub sales: usize,
+    pub lots: i64,
+    /// Of everything the seller sold.
+    pub share_percent: i64,
+    pub avg_price: i64,
+    /// Over every trade on the tape, house volume included.
+    pub market_avg_price: i64,
+}
+
+/// Money lent round a ring of users back to where it started.
+#[derive(Debug, Serialize)]
+pub struct LoanCycle {
+    /// Each lent to the next, and the last to the first.
+    pub users: Vec<String>,
+    pub loans: Vec<u64>,
+    /// The least lent along any step, so what went all the way round.
+    pub amount: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct IntegrityResult {
+    pub cheap_sales: Vec<PairFlag>,
+    pub loan_cycles: Vec<LoanCycle>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn avg(cost: i128, lots: i64) -> i64 {
+    if lots == 0 {
+        0
+    } else {
+        (cost / lots as i128) as i64
+    }
+}
+
+/// Pairs that meet every threshold, most lots first.
+pub fn cheap_sales(trades: &[Trade], sales: &[Sale], q: &IntegrityQuery) -> Vec<PairFlag> {
+    let (min_sales, min_share, min_discount) =
+        (q.min_sales.unwrap_or(3), q.min_share_percent.unwrap_or(50), q.min_discount_percent.unwrap_or(10));
+    let market_lots: i64 = trades.iter().map(|t| t.vol).sum();
+    let market_cost: i128 = trades.iter().map(|t| t.price as i128 * t.vol as i128).sum();
+    let market_avg_price = avg(market_cost, market_lots);
+
+    let mut sold: BTreeMap<&str, i64> = BTreeMap::new();
+    let mut pairs: BTreeMap<(&str, &str), (usize, i64, i128)> = BTreeMap::new();
+    for s in sales {
+        *sold.entry(&s.seller).or_default() += s.vol;
+        let p = pairs.entry((&s.seller, &s.buyer)).or_default();
+        p.0 += 1;
+        p.1 += s.vol;
+        p.2 += s.price as i128 * s.vol as i128;
+    }
+    let mut out: Vec<PairFlag> = pairs
+        .into_iter()
+        .filter_map(|((seller, buyer), (n, lots, cost))| {
+            let share_percent = lots * 100 / sold[seller];
+            let avg_price = avg(cost, lots);
+            let cheap = (avg_price as i128) * 100 <= market_avg_price as i128 * (100 - min_discount) as i128;
+            (n >= min_sales && share_percent >= min_share && cheap).then(|| PairFlag {
+                seller: seller.to_owned(),
+                buyer: buyer.to_owned(),
+                sales: n,
+                lots,
+                share_percent,
+                avg_price,
+                market_avg_price,
+            })
+        })
+        .collect();
+    out.sort_by_key(|f| std::cmp::Reverse(f.lots));
+    out
+}
+
+/// Rings of lenders, each found once, starting from its first name.
+pub fn loan_cycles<'a>(loans: impl Iterator<Item = &'a Loan>) -> Vec<LoanCycle> {
+    // lender -> borrower -> (loan ids, amount lent)
+    let mut edges: BTreeMap<&str, BTreeMap<&str, (Vec<u64>, i64)>> = BTreeMap::new();
+    for l in loans {
+        let Some(borrower) = l.borrower.as_deref() else {
+            continue;
+        };
+        let e = edges.entry(&l.lender).or_default().entry(borrower).or_default();
+        e.0.push(l.id);
+        e.1 = e.1.saturating_add(l.amount);
+    }
+    let mut out = Vec::new();
+    for &start in edges.keys() {
+        let mut path = vec![start];
+        walk(&edges, start, &mut path, &mut out);
+    }
+    out
+}
+
+fn walk<'a>(
+    edges: &BTreeMap<&'a str, BTreeMap<&'a str, (Vec<u64>, i64)>>,
+    start: &'a str,
+    path: &mut Vec<&'a str>,
+    out: &mut Vec<LoanCycle>,
+) {
+    let Some(next) = edges.get(path.last().unwrap()) else {
+        return;
+    };
+    for &to in next.keys() {
+        if to == start {
+            let steps = path.iter().zip(path.iter().skip(1).chain([&start])).map(|(a, b)| &edges[a][b]);
+            out.push(LoanCycle {
+                users: path.iter().map(|u| u.to_string()).collect(),
+                loans: steps.clone().flat_map(|(ids, _)| ids.iter().copied()).collect(),
+                amount: steps.map(|(_, amount)| *amount).min().unwrap_or(0),
+            });
+        } else if to > start && !path.contains(&to) && path.len() < MAX_CYCLE_LEN {
+            path.push(to);
+            walk(edges, start, path, out);
+            path.pop();
+        }
+    }
+}
+
+/// Pairs worth a human look: listed lots going cheap to the same buyer,
+/// and loans lent round in a ring. Nothing is done to anyone flagged. Only
+/// trades still on the tape count.
+pub async fn admin_integrity(
+    Query(q): Query<IntegrityQuery>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<IntegrityResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    let cheap_sales = cheap_sales(&g.tape.trades, &g.tape.sales, &q);
+    let loan_cycles = loan_cycles(g.loans.taken());
+    clock.reply(StatusCode::OK, IntegrityResult { cheap_sales, loan_cycles, ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loans::LoanStatus;
+
+    fn trade(seq: u64, price: i64, vol: i64) -> Trade {
+        Trade { seq, uname: "b".to_owned(), price, vol, ts_nanos: 0 }
+    }
+
+    fn sale(seq: u64, seller: &str, buyer: &str, price: i64) -> Sale {
+        Sale { seq, seller: seller.to_owned(), buyer: buyer.to_owned(), price, vol: 1, ts_nanos: 0 }
+    }
+
+    fn loan(id: u64, lender: &str, borrower: &str, amount: i64) -> Loan {
+        Loan {
+            id,
+            lender: lender.to_owned(),
+            borrower: Some(borrower.to_owned()),
+            amount,
+            rate_percent: 0,
+            term_secs: 60,
+            status: LoanStatus::Open,
+            offered_at_nanos: 0,
+            due_at_nanos: None,
+            repaid: 0,
+        }
+    }
+
+    #[test]
+    fn flags_a_seller_feeding_one_buyer_cheap_lots() {
+        let trades: Vec<Trade> = (1..=10).map(|s| trade(s, if s <= 3 { 50 } else { 100 }, 1)).collect();
+        let sales = vec![sale(1, "s", "b", 50), sale(2, "s", "b", 50), sale(3, "s", "b", 50), sale(4, "s", "c", 100)];
+        let q = IntegrityQuery { min_sales: None, min_share_percent: None, min_discount_percent: None };
+        let flags = cheap_sales(&trades, &sales, &q);
+        assert_eq!(flags.len(), 1);
+        let f = &flags[0];
+        assert_eq!((f.seller.as_str(), f.buyer.as_str(), f.sales, f.lots), ("s", "b", 3, 3));
+        assert_eq!((f.share_percent, f.avg_price, f.market_avg_price), (75, 50, 85));
+
+        let strict = IntegrityQuery { min_sales: Some(4), ..q };
+        assert!(cheap_sales(&trades, &sales, &strict).is_empty());
+    }
+
+    #[test]
+    fn finds_each_ring_of_loans_once() {
+        let loans = [loan(1, "a", "b", 100), loan(2, "b", "c", 80), loan(3, "c", "a", 90), loan(4, "c", "d", 5), loan(5, "b", "a", 10)];
+        let cycles = loan_cycles(loans.iter());
+        let found: Vec<(Vec<String>, Vec<u64>, i64)> = cycles.into_iter().map(|c| (c.users, c.loans, c.amount)).collect();
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(found, vec![(names(&["a", "b"]), vec![1, 5], 10), (names(&["a", "b", "c"]), vec![1, 2, 3], 80)]);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, orders::OrderStatus, reservations, AppState, ReqClock, RespMeta};
+
+/// What was put into the game: starting balances and the ask ladder.
+/// Adjusted only when accounts or lots leave the game entirely.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Issuance {
+    pub cash: i64,
+    pub units: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct VerifyResult {
+    pub ok: bool,
+    pub violations: Vec<String>,
+    pub issued: Issuance,
+    pub user_cash: i64,
+    pub house_cash: i64,
+    pub book_units: i64,
+    pub held_units: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Money and lots are only ever moved, never created: user balances plus the
+/// house equal the cash issued, and lots on the book plus lots held equal the
+/// units issued. Open positions are paid for in cash to the house, so they
+/// don't appear on the cash side.
+pub fn verify(st: &AppState) -> VerifyResult {
+    // Reserved lots are still the house's or their sellers'.
+    let book = reservations::whole_book(st);
+    let mut res = VerifyResult {
+        issued: st.issued.clone(),
+        user_cash: st.users.values().map(|ua| ua.balance).sum(),
+        house_cash: st.house.balance(),
+        book_units: book.asks.values().sum(),
+        // Listed lots are counted on the book.
+        held_units: st.users.values().map(|ua| ua.position - ua.listed).sum(),
+        ..Default::default()
+    };
+    if res.user_cash + res.house_cash != res.issued.cash {
+        res.violations.push(format!(
+            "cash drift: users {} + house {} != issued {}",
+            res.user_cash, res.house_cash, res.issued.cash
+        ));
+    }
+    if res.book_units + res.held_units != res.issued.units {
+        res.violations.push(format!(
+            "unit drift: book {} + held {} != issued {}",
+            res.book_units, res.held_units, res.issued.units
+        ));
+    }
+    for (price, vol) in book.asks.iter() {
+        if *vol <= 0 {
+            res.violations.push(format!("empty ask level {} left with volume {}", price, vol));
+        }
+    }
+    let mut listed: HashMap<&str, i64> = HashMap::new();
+    let mut listed_at: BTreeMap<i64, i64> = BTreeMap::new();
+    for (price, seller, vol) in book.sell_lots() {
+        *listed.entry(seller).or_default() += vol;
+        *listed_at.entry(price).or_default() += vol;
+    }
+    for (price, vol) in listed_at {
+        let on_book = book.asks.get(&price).copied().unwrap_or(0);
+        if vol > on_book {
+            res.violations.push(format!("{} lots listed at {} but the level holds {}", vol, price, on_book));
+        }
+    }
+    for (u, ua) in st.users.iter() {
+        let on_book = listed.remove(u.as_str()).unwrap_or(0);
+        if ua.listed != on_book || ua.listed > ua.position {
+            res.violations.push(format!("{} lists {} of {} lots but the book holds {}", u, ua.listed, ua.position, on_book));
+        }
+    }
+    for (u, vol) in listed {
+        res.violations.push(format!("{} lots listed by unknown user {}", vol, u));
+    }
+    for (s, b) in st.instruments.books.iter() {
+        let held: i64 = st.users.values().filter_map(|ua| ua.holdings.get(s)).sum();
+        let on_book: i64 = b.asks.values().sum();
+        if on_book + held != b.issued {
+            res.violations.push(format!("{} unit drift: book {} + held {} != issued {}", s, on_book, held, b.issued));
+        }
+    }
+    let resting = st.orders.orders.values().filter(|o| o.status == OrderStatus::Resting).count();
+    let on_book: usize = st.book.bid_levels().map(|(_, n)| n).sum();
+    if resting != on_book {
+        res.violations.push(format!("{} orders are resting but the book holds {} bids", resting, on_book));
+    }
+    // A negative `[bankruptcy] floor` lets fees go deeper than credit does.
+    let fee_floor = st.bankruptcy.as_ref().map_or(0, |b| b.floor);
+    for (u, ua) in st.users.iter() {
+        let lowest = (-ua.credit.limit).min(fee_floor);
+        if ua.balance < lowest {
+            res.violations.push(format!("{} is {} below what credit and fees allow", u, lowest - ua.balance));
+        }
+        if ua.position < 0 {
+            res.violations.push(format!("{} holds a negative position {}", u, ua.position));
+        }
+    }
+    res.ok = res.violations.is_empty();
+    res
+}
+
+pub async fn admin_verify(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<VerifyResult>) {
+    let clock = ReqClock::start();
+    let res = verify(&state.locked());
+    for v in res.violations.iter() {
+        tracing::error!("invariant violated: {}", v);
+    }
+    let code = if res.ok { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+    clock.reply(code, res)
+}
+
+/// Debug builds only: re-checks the invariants after every request and
+/// panics on drift, naming the request that caused it. The lock is released
+/// first, so only the offending connection dies.
+pub async fn check_after_request(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let what = format!("{} {}", req.method(), req.uri());
+    let resp = next.run(req).await;
+    let res = verify(&state.locked());
+    assert!(res.ok, "invariants violated after {}: {:?}", what, res.violations);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{book, orders::TimeInForce, testing};
+
+    fn game() -> AppState {
+        let mut g = testing::game("users = [\"alice\", \"bob\"]\nasks = [{ price = 10, vol = 4 }]");
+        let a = g.orders.accept("alice", 10, 3, TimeInForce::Ioc, 1);
+        book::enter(&mut g, "alice", a, 1);
+        book::list(&mut g, "alice", 12, 2, 2);
+        let b = g.orders.accept("bob", 12, 1, TimeInForce::Ioc, 3);
+        book::enter(&mut g, "bob", b, 3);
+        g
+    }
+
+    #[test]
+    fn trading_keeps_cash_and_lots_whole() {
+        let g = game();
+        let res = verify(&g);
+        assert!(res.ok, "{:?}", res.violations);
+        assert_eq!((res.user_cash, res.house_cash, res.issued.cash), (2000 - 30, 30, 2000));
+        // alice holds 2 after selling 1, with 1 still listed on the book.
+        assert_eq!((res.book_units, res.held_units, res.issued.units), (2, 2, 4));
+    }
+
+    #[test]
+    fn drift_is_named() {
+        let mut g = game();
+        g.users.get_mut("bob").unwrap().balance += 5;
+        g.users.get_mut("bob").unwrap().position += 1;
+        let v = verify(&g).violations;
+        assert_eq!(v.len(), 2);
+        assert!(v[0].starts_with("cash drift"));
+        assert!(v[1].starts_with("unit drift"));
+    }
+
+    #[test]
+    fn book_and_accounts_must_agree() {
+        let mut g = game();
+        g.users.get_mut("alice").unwrap().listed = 2;
+        let o = g.orders.accept("bob", 3, 1, TimeInForce::Gtc, 4);
+        g.orders.rest(o, 4);
+        let v = verify(&g).violations;
+        assert!(v.iter().any(|m| m == "alice lists 2 of 2 lots but the book holds 1"), "{:?}", v);
+        assert!(v.iter().any(|m| m == "1 orders are resting but the book holds 0 bids"), "{:?}", v);
+    }
+}
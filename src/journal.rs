@@ -0,0 +1,159 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{book, expiry, fees, matching::Fill, modes, privacy, registration, settlement, storage::Store, AppState};
+
+/// Appends every change to accounts, the ask ladder and the tape to `path`,
+/// one JSON event per line. Starting with `--recover` replays it over the
+/// game built from config; without it, a non-empty journal stops the start
+/// so a new game never lands on top of an old one. Settlement and forgotten
+/// users are journaled too. Loans, interest, quote fees, penalty fines and
+/// other instruments are not, so config that turns them on is refused with
+/// `[journal]`. Orders aren't either: their fills are, and resting bids are
+/// gone after a recovery, as are reservations, whose lots are back on the
+/// ladder.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JournalConfig {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    UserAdded { uname: String, balance: i64, ts_nanos: i64 },
+    /// Volume offered through `/admin/asks`.
+    AskAdded {
+        price: i64,
+        vol: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at_nanos: Option<i64>,
+        ts_nanos: i64,
+    },
+    /// Lots a user listed with `place_ask`.
+    AskListed { uname: String, price: i64, vol: i64, ts_nanos: i64 },
+    /// Lots a bid took off the ladder; its `fill` follows.
+    AskTaken { price: i64, vol: i64 },
+    Fee {
+        uname: String,
+        amount: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<fees::Endpoint>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+        ts_nanos: i64,
+    },
+    Fill { uname: String, price: i64, vol: i64, ts_nanos: i64 },
+    /// House volume withdrawn when its level expired.
+    AskExpired { price: i64, vol: i64, ts_nanos: i64 },
+    /// House volume a Dutch round moved down the ladder.
+    AskMoved { from: i64, to: i64, vol: i64, ts_nanos: i64 },
+    Settled { price: i64, ts_nanos: i64 },
+    /// Through `/admin/users/:uname/forget`.
+    UserForgotten { uname: String, ts_nanos: i64 },
+}
+
+/// Where events go; a default one, as during replay, drops them.
+#[derive(Debug, Default)]
+pub struct Journal {
+    file: Option<File>,
+    pub appended: u64,
+}
+
+impl Journal {
+    /// Opens `path` for appending, returning what it already holds. A
+    /// torn last line from a crash mid-write is dropped.
+    pub fn open(cfg: &JournalConfig) -> std::io::Result<(Self, Vec<Event>)> {
+        let mut events = Vec::new();
+        if let Ok(f) = File::open(&cfg.path) {
+            let lines: Vec<String> = BufReader::new(f).lines().collect::<Result<_, _>>()?;
+            for (i, line) in lines.iter().enumerate() {
+                match serde_json::from_str(line) {
+                    Ok(ev) => events.push(ev),
+                    Err(e) if i + 1 == lines.len() => tracing::warn!("journal {}: dropping torn last line: {}", cfg.path, e),
+                    Err(e) => return Err(std::io::Error::other(format!("line {}: {}", i + 1, e))),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&cfg.path)?;
+        Ok((Journal { file: Some(file), appended: 0 }, events))
+    }
+
+    /// Writes the event before the change it records is answered; each line
+    /// goes out in one write, so a crash leaves at most the last one torn.
+    pub fn record(&mut self, ev: impl FnOnce() -> Event) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let mut line = serde_json::to_vec(&ev()).unwrap();
+        line.push(b'\n');
+        match file.write_all(&line) {
+            Ok(()) => self.appended += 1,
+            Err(e) => tracing::error!("journal write failed: {}", e),
+        }
+    }
+}
+
+/// Applies journaled events through the same paths that recorded them.
+pub fn replay(g: &mut AppState, events: Vec<Event>) {
+    for ev in events {
+        match ev {
+            Event::UserAdded { uname, balance, ts_nanos } => {
+                let _ = g.names.add(&uname);
+                registration::register(g, &uname, balance, ts_nanos);
+            }
+            Event::AskAdded { price, vol, expires_at_nanos, ts_nanos } => {
+                book::offer(g, price, vol, expires_at_nanos, ts_nanos)
+            }
+            Event::AskListed { uname, price, vol, ts_nanos } => book::list(g, &uname, price, vol, ts_nanos),
+            Event::AskTaken { price, vol } => {
+                g.take_ask(price, vol);
+            }
+            Event::Fee { uname, amount, endpoint, request_id, ts_nanos } => {
+                g.pay_fee(&uname, amount, endpoint, request_id, ts_nanos)
+            }
+            Event::Fill { uname, price, vol, ts_nanos } => g.fill(&uname, Fill { price, vol }, ts_nanos),
+            Event::AskExpired { price, vol, ts_nanos } => expiry::withdraw(g, price, vol, ts_nanos),
+            Event::AskMoved { from, to, vol, ts_nanos } => modes::move_house(g, from, to, vol, ts_nanos),
+            Event::Settled { price, ts_nanos } => {
+                settlement::settle(g, price, ts_nanos);
+            }
+            Event::UserForgotten { uname, ts_nanos } => {
+                privacy::forget(g, &uname, ts_nanos);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const CONFIG: &str = "asks = [{ price = 10, vol = 4 }]";
+
+    #[test]
+    fn settling_and_forgetting_replay() {
+        let path = std::env::temp_dir().join(format!("journal-{}.jsonl", fastrand::u64(..)));
+        let cfg = JournalConfig { path: path.display().to_string() };
+        let mut g = testing::game(CONFIG);
+        g.journal = Journal::open(&cfg).unwrap().0;
+        let fill = g.take_ask(10, 2).unwrap();
+        g.fill("alice", fill, 1);
+        g.pay_fee("bob", 5, None, None, 2);
+        settlement::settle(&mut g, 20, 3);
+        privacy::forget(&mut g, "bob", 4);
+
+        let (_, events) = Journal::open(&cfg).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut r = testing::game(CONFIG);
+        replay(&mut r, events);
+        assert_eq!(serde_json::to_value(&r.users).unwrap(), serde_json::to_value(&g.users).unwrap());
+        assert_eq!((r.users["alice"].balance, r.users.contains_key("bob")), (1020, false));
+        assert_eq!(r.settlement.map(|s| s.price), Some(20));
+        assert_eq!((r.issued.cash, r.issued.units), (g.issued.cash, g.issued.units));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, now, AppState, ReqClock, RespMeta};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Locks out a user whose requests are rejected more than
+/// `max_rejects_per_sec` times a second for `sustain_secs` seconds in a row.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KillSwitchConfig {
+    pub max_rejects_per_sec: u32,
+    #[serde(default = "default_sustain_secs")]
+    pub sustain_secs: u32,
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u32,
+}
+
+fn default_sustain_secs() -> u32 {
+    3
+}
+
+fn default_lockout_secs() -> u32 {
+    60
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RejectTracker {
+    #[serde(skip)]
+    window_sec: i64,
+    #[serde(skip)]
+    window_rejects: u32,
+    #[serde(skip)]
+    last_hot_sec: i64,
+    #[serde(skip)]
+    hot_streak: u32,
+    pub locked_until_nanos: Option<i64>,
+    /// How many times the switch tripped for this user.
+    pub trips: u32,
+}
+
+impl RejectTracker {
+    fn locked(&self, now: i64) -> bool {
+        self.locked_until_nanos.is_some_and(|t| now < t)
+    }
+
+    /// Rejections this second can take before it counts towards a lockout.
+    pub fn rejects_left(&self, cfg: &KillSwitchConfig, now: i64) -> u32 {
+        if self.window_sec == now / NANOS_PER_SEC {
+            cfg.max_rejects_per_sec.saturating_sub(self.window_rejects)
+        } else {
+            cfg.max_rejects_per_sec
+        }
+    }
+
+    /// Counts one rejection, returning true if it tripped the switch.
+    fn reject(&mut self, cfg: &KillSwitchConfig, now: i64) -> bool {
+        let sec = now / NANOS_PER_SEC;
+        if sec != self.window_sec {
+            self.window_sec = sec;
+            self.window_rejects = 0;
+        }
+        self.window_rejects += 1;
+        if self.window_rejects != cfg.max_rejects_per_sec + 1 {
+            return false;
+        }
+        // This second just went over the threshold.
+        self.hot_streak = if self.last_hot_sec + 1 == sec { self.hot_streak + 1 } else { 1 };
+        self.last_hot_sec = sec;
+        if self.hot_streak < cfg.sustain_secs.max(1) {
+            return false;
+        }
+        self.hot_streak = 0;
+        self.trips += 1;
+        self.locked_until_nanos = Some(now + cfg.lockout_secs as i64 * NANOS_PER_SEC);
+        true
+    }
+}
+
+pub fn user_of(path: &str) -> Option<&str> {
+    path.strip_prefix("/users/")?.split('/').next()
+}
+
+/// Refuses requests from locked-out users before they reach state, and feeds
+/// every rejected user request into their tracker. Only layered with
+/// `[kill_switch]` set.
+pub async fn guard(
+    State((cfg, state)): State<(KillSwitchConfig, Arc<Mutex<AppState>>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(uname) = user_of(req.uri().path()).map(str::to_owned) else {
+        return next.run(req).await;
+    };
+    if state.locked().reject_trackers.get(&uname).is_some_and(|t| t.locked(now())) {
+        let msg = "locked out after repeated rejected requests";
+        return ApiError::with(StatusCode::TOO_MANY_REQUESTS, "LOCKED_OUT", msg).into_response();
+    }
+
+    let resp = next.run(req).await;
+    if resp.status().is_client_error() {
+        let mut g = state.locked();
+        // Unknown names are not tracked, or anyone could grow the map.
+        if g.users.contains_key(&uname) {
+            let tracker = g.reject_trackers.entry(uname.clone()).or_default();
+            if tracker.reject(&cfg, now()) {
+                let trips = tracker.trips;
+                tracing::warn!("kill switch tripped for {} (trip #{})", uname, trips);
+                g.feeds.timeline.admin(&uname, format!("kill switch tripped (trip #{})", trips));
+            }
+        }
+    }
+    resp
+}
+
+#[derive(Serialize, Default)]
+pub struct LockoutsResult {
+    pub lockouts: HashMap<String, RejectTracker>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Users that are locked out now or have tripped the switch before.
+pub async fn admin_lockouts(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<LockoutsResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    let res = LockoutsResult {
+        lockouts: g.reject_trackers.iter().filter(|(_, t)| t.trips > 0).map(|(u, t)| (u.clone(), t.clone())).collect(),
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+pub async fn admin_lift_lockout(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<LockoutsResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let Some(t) = g.reject_trackers.get_mut(&uname) else {
+        return clock.reply(StatusCode::NOT_FOUND, LockoutsResult::default());
+    };
+    t.locked_until_nanos = None;
+    t.hot_streak = 0;
+    tracing::warn!("lockout lifted for {}", uname);
+    let mut res = LockoutsResult::default();
+    res.lockouts.insert(uname.clone(), t.clone());
+    g.feeds.timeline.admin(&uname, "lockout lifted");
+    clock.reply(StatusCode::OK, res)
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, killswitch::user_of, now, AppState, ReqClock, RespMeta};
+
+/// Samples kept per user; older ones are dropped.
+const MAX_SAMPLES: usize = 100;
+
+/// Evens out network latency: before trading starts users measure their
+/// round trip via `/users/:uname/calibrate`, and once it starts each bid is
+/// held back by how much faster its sender is than the slowest calibrated
+/// user.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LatencyFloorConfig {
+    /// Largest delay ever added to one order.
+    pub max_delay_micros: i64,
+    /// Users with fewer samples get no delay and don't set the floor.
+    #[serde(default = "default_min_samples")]
+    pub min_samples: usize,
+}
+
+fn default_min_samples() -> usize {
+    5
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    samples_micros: Vec<i64>,
+    /// Challenge handed out and when, awaiting its echo.
+    pending: Option<(u64, i64)>,
+}
+
+impl Profile {
+    fn median(&self) -> Option<i64> {
+        let mut s = self.samples_micros.clone();
+        s.sort_unstable();
+        s.get(s.len() / 2).copied()
+    }
+}
+
+type Offsets = HashMap<String, (Option<i64>, i64)>;
+
+#[derive(Debug, Default)]
+pub struct Latency {
+    profiles: HashMap<String, Profile>,
+    /// Worked out once trading starts, when samples stop changing.
+    frozen: Option<Arc<Offsets>>,
+}
+
+impl Latency {
+    /// Per-user medians when calibrated, and the delay each user's orders get.
+    fn offsets(&mut self, cfg: &LatencyFloorConfig, trading: bool) -> Arc<Offsets> {
+        if let Some(o) = &self.frozen {
+            return o.clone();
+        }
+        let medians: HashMap<&String, i64> = self
+            .profiles
+            .iter()
+            .filter(|(_, p)| p.samples_micros.len() >= cfg.min_samples)
+            .filter_map(|(u, p)| Some((u, p.median()?)))
+            .collect();
+        let floor = medians.values().max().copied().unwrap_or(0);
+        let offsets: Offsets = self
+            .profiles
+            .keys()
+            .map(|u| {
+                let m = medians.get(u).copied();
+                let offset = m.map_or(0, |m| (floor - m).clamp(0, cfg.max_delay_micros.max(0)));
+                (u.clone(), (m, offset))
+            })
+            .collect();
+        let offsets = Arc::new(offsets);
+        if trading {
+            self.frozen = Some(offsets.clone());
+        }
+        offsets
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CalibrateQuery {
+    /// Nonce from the previous challenge, sent back as soon as it arrived.
+    pub echo: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct CalibrateResult {
+    pub nonce: u64,
+    /// Round trip measured from this request's echo.
+    pub rtt_micros: Option<i64>,
+    pub samples: usize,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Free, and only open before trading starts. Each call answers with a
+/// nonce; echoing it straight back times one round trip.
+pub async fn user_calibrate(
+    Path(uname): Path<String>,
+    Query(q): Query<CalibrateQuery>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<CalibrateResult>) {
+    let clock = ReqClock::start();
+    let now = now();
+    let mut g = state.locked();
+    if g.latency_floor.is_none() {
+        return clock.reply(StatusCode::NOT_FOUND, CalibrateResult::default());
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.reply(StatusCode::NOT_FOUND, CalibrateResult::default());
+    }
+    if now >= g.calendar.first_open() {
+        return clock.reply(StatusCode::FORBIDDEN, CalibrateResult::default());
+    }
+    let p = g.latency.profiles.entry(uname).or_default();
+    let mut res = CalibrateResult::default();
+    if let (Some(echo), Some((nonce, issued))) = (q.echo, p.pending) {
+        if echo == nonce {
+            let rtt = (now - issued) / 1000;
+            p.samples_micros.push(rtt);
+            if p.samples_micros.len() > MAX_SAMPLES {
+                p.samples_micros.remove(0);
+            }
+            res.rtt_micros = Some(rtt);
+        }
+    }
+    res.nonce = fastrand::u64(..);
+    res.samples = p.samples_micros.len();
+    p.pending = Some((res.nonce, now));
+    clock.reply(StatusCode::OK, res)
+}
+
+#[derive(Serialize)]
+pub struct LatencyEntry {
+    pub uname: String,
+    pub samples_micros: Vec<i64>,
+    pub median_micros: Option<i64>,
+    pub applied_delay_micros: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct LatencyResult {
+    pub entries: Vec<LatencyEntry>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Calibration data and the delays it produced, published once trading
+/// has started and the numbers can no longer change.
+pub async fn public_latency(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<LatencyResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let Some(cfg) = g.latency_floor.clone() else {
+        return clock.reply(StatusCode::NOT_FOUND, LatencyResult::default());
+    };
+    if now() < g.calendar.first_open() {
+        return clock.reply(StatusCode::FORBIDDEN, LatencyResult::default());
+    }
+    let offsets = g.latency.offsets(&cfg, true);
+    let mut entries: Vec<_> = g
+        .latency
+        .profiles
+        .iter()
+        .map(|(u, p)| LatencyEntry {
+            uname: u.clone(),
+            samples_micros: p.samples_micros.clone(),
+            median_micros: offsets[u].0,
+            applied_delay_micros: offsets[u].1,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.uname.cmp(&b.uname));
+    clock.reply(StatusCode::OK, LatencyResult { entries, ..Default::default() })
+}
+
+/// Route layer for order entry, holding back users faster than the floor.
+pub async fn delay(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let wait = {
+        let mut g = state.locked();
+        match (g.latency_floor.clone(), user_of(req.uri().path())) {
+            (Some(cfg), Some(uname)) => {
+                let trading = now() >= g.calendar.first_open();
+                g.latency.offsets(&cfg, trading).get(uname).map_or(0, |o| o.1)
+            }
+            _ => 0,
+        }
+    };
+    if wait > 0 {
+        tokio::time::sleep(Duration::from_micros(wait as u64)).await;
+    }
+    next.run(req).await
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, fees::Endpoint, AppState, ReqClock, RespMeta};
+
+/// Why a balance moved, written as `fee:ping`, `interest`, `trade` and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Reason {
+    /// A paid call; just `fee` when replayed from a journal written before
+    /// the endpoint was kept.
+    Fee(Option<Endpoint>),
+    /// On debt, see `[credit]`.
+    Interest,
+    /// Buying lots, or being paid for lots listed with `place_ask`.
+    Trade,
+    /// A `[[penalties]]` fine.
+    Penalty,
+    /// For lots held at settlement.
+    Payout,
+    /// Lent to or borrowed from another user, see `[loans]`.
+    Loan,
+    /// Paying a loan back, in full or what the borrower had when it fell due.
+    Repayment,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Fee(Some(ep)) => write!(f, "fee:{}", ep.name()),
+            Reason::Fee(None) => f.write_str("fee"),
+            Reason::Interest => f.write_str("interest"),
+            Reason::Trade => f.write_str("trade"),
+            Reason::Penalty => f.write_str("penalty"),
+            Reason::Payout => f.write_str("payout"),
+            Reason::Loan => f.write_str("loan"),
+            Reason::Repayment => f.write_str("repayment"),
+        }
+    }
+}
+
+impl From<Reason> for String {
+    fn from(r: Reason) -> String {
+        r.to_string()
+    }
+}
+
+impl TryFrom<String> for Reason {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        Ok(match s.as_str() {
+            "fee" => Reason::Fee(None),
+            "interest" => Reason::Interest,
+            "trade" => Reason::Trade,
+            "penalty" => Reason::Penalty,
+            "payout" => Reason::Payout,
+            "loan" => Reason::Loan,
+            "repayment" => Reason::Repayment,
+            other => {
+                let ep = other.strip_prefix("fee:").and_then(|n| Endpoint::ALL.into_iter().find(|ep| ep.name() == n));
+                Reason::Fee(Some(ep.ok_or_else(|| format!("unknown balance change reason {:?}", s))?))
+            }
+        })
+    }
+}
+
+/// One change to a balance.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub seq: u64,
+    pub reason: Reason,
+    /// Negative for money taken.
+    pub delta: i64,
+    /// What it left behind; unset for fees from before this was kept.
+    pub balance: Option<i64>,
+    pub ts_nanos: i64,
+    /// The `request_id` of the response to the call that paid it; only fees
+    /// carry one.
+    pub request_id: Option<u64>,
+}
+
+/// Every change to every balance, per user, kept with the game so it
+/// survives restarts and travels in backups. Zero changes leave no line.
+/// `[memory] ledger_max_bytes` bounds it, oldest lines first.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Ledger {
+    users: BTreeMap<String, VecDeque<Entry>>,
+    seq: u64,
+}
+
+impl Ledger {
+    /// Whether a line was written.
+    pub fn record(&mut self, uname: &str, reason: Reason, delta: i64, balance: i64, request_id: Option<u64>, now: i64) -> bool {
+        if delta == 0 {
+            return false;
+        }
+        self.seq += 1;
+        let e = Entry { seq: self.seq, reason, delta, balance: Some(balance), ts_nanos: now, request_id };
+        self.users.entry(uname.to_owned()).or_default().push_back(e);
+        true
+    }
+
+    pub fn of_user(&self, uname: &str) -> Vec<Entry> {
+        self.users.get(uname).map(|q| q.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn fees_of_user(&self, uname: &str) -> Vec<FeeItem> {
+        let entries = self.users.get(uname).into_iter().flatten();
+        entries
+            .filter_map(|e| match e.reason {
+                Reason::Fee(endpoint) => Some(FeeItem {
+                    seq: e.seq,
+                    endpoint,
+                    amount: -e.delta,
+                    ts_nanos: e.ts_nanos,
+                    request_id: e.request_id,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn forget(&mut self, uname: &str) {
+        self.users.remove(uname);
+    }
+
+    pub fn approx_bytes(&self) -> usize {
+        self.users.values().map(|q| q.len() * std::mem::size_of::<Entry>()).sum()
+    }
+
+    /// Drops the oldest lines, whoever they belong to, until the rest fit in
+    /// `max_bytes`. Returns how many went.
+    pub fn evict_to(&mut self, max_bytes: usize) -> usize {
+        let mut over = self.approx_bytes().saturating_sub(max_bytes);
+        let mut n = 0;
+        while over > 0 {
+            let oldest = self.users.values_mut().filter(|q| !q.is_empty()).min_by_key(|q| q[0].seq);
+            if oldest.and_then(|q| q.pop_front()).is_none() {
+                break;
+            }
+            over = over.saturating_sub(std::mem::size_of::<Entry>());
+            n += 1;
+        }
+        self.users.retain(|_, q| !q.is_empty());
+        n
+    }
+}
+
+/// A fee as `/fees` lists it.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeItem {
+    pub seq: u64,
+    pub endpoint: Option<Endpoint>,
+    pub amount: i64,
+    pub ts_nanos: i64,
+    pub request_id: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct FeesResult {
+    pub fees: Vec<FeeItem>,
+    /// Sum of `amount` over the list; `fees_paid` on the account unless
+    /// older lines were evicted under `[memory]`.
+    pub total: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Free, like `/rejections`.
+pub async fn user_fees(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<FeesResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), FeesResult::default());
+    }
+    let fees = g.ledger.fees_of_user(&uname);
+    let total = fees.iter().map(|f| f.amount).sum();
+    clock.reply(StatusCode::OK, FeesResult { fees, total, ..Default::default() })
+}
+
+#[derive(Serialize, Default)]
+pub struct LedgerResult {
+    pub entries: Vec<Entry>,
+    pub balance: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Every balance change with its reason. Free.
+pub async fn user_ledger(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<LedgerResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    let Some(ua) = g.users.get(&uname) else {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), LedgerResult::default());
+    };
+    let res = LedgerResult { entries: g.ledger.of_user(&uname), balance: ua.balance, ..Default::default() };
+    clock.reply(StatusCode::OK, res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_round_trips_through_its_name() {
+        for r in [Reason::Fee(Some(Endpoint::Ping)), Reason::Fee(None), Reason::Interest, Reason::Loan, Reason::Repayment] {
+            assert_eq!(Reason::try_from(r.to_string()), Ok(r));
+        }
+        assert!(Reason::try_from("fee:nope".to_owned()).is_err());
+    }
+
+    #[test]
+    fn eviction_drops_the_oldest_lines_across_users() {
+        let mut l = Ledger::default();
+        for i in 0..4 {
+            l.record("alice", Reason::Trade, -1, 10 - i, None, i);
+            l.record("bob", Reason::Trade, -1, 10 - i, None, i);
+        }
+        assert!(!l.record("bob", Reason::Trade, 0, 6, None, 9));
+        let line = std::mem::size_of::<Entry>();
+        assert_eq!(l.approx_bytes(), 8 * line);
+
+        assert_eq!(l.evict_to(3 * line), 5);
+        assert_eq!(l.approx_bytes(), 3 * line);
+        let seqs: Vec<u64> = l.of_user("alice").iter().chain(&l.of_user("bob")).map(|e| e.seq).collect();
+        assert_eq!(seqs, [7, 6, 8]);
+        assert_eq!(l.evict_to(0), 3);
+        assert!(l.of_user("alice").is_empty() && l.users.is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ApiError;
+
+/// Caps on what a request may carry, checked before routing so oversized
+/// requests cost nothing past the parse. Unset means no cap beyond axum's
+/// 2 MB default for bodies.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct RequestLimitsConfig {
+    pub max_body_bytes: Option<usize>,
+    pub max_headers: Option<usize>,
+    /// For any one header, name and value together.
+    pub max_header_bytes: Option<usize>,
+}
+
+/// Refuses requests over `[request_limits]`. Bodies that announce their
+/// length are judged on it; chunked ones are cut off by
+/// `RequestBodyLimitLayer` while being read.
+pub async fn enforce(State(cfg): State<RequestLimitsConfig>, req: Request, next: Next) -> Response {
+    let headers = req.headers();
+    if cfg.max_headers.is_some_and(|max| headers.len() > max) {
+        let msg = "the request has too many headers";
+        return ApiError::with(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "TOO_MANY_HEADERS", msg).into_response();
+    }
+    if let Some(max) = cfg.max_header_bytes {
+        if headers.iter().any(|(k, v)| k.as_str().len() + v.len() > max) {
+            let msg = "a header is too long";
+            return ApiError::with(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "HEADER_TOO_LARGE", msg).into_response();
+        }
+    }
+    if let Some(max) = cfg.max_body_bytes {
+        let len = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        if len.is_some_and(|len| len > max) {
+            return ApiError::of_status(StatusCode::PAYLOAD_TOO_LARGE).into_response();
+        }
+    }
+    next.run(req).await
+}
@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connlimit::{self, ConnLimitsConfig},
+    errors::ApiError,
+    health::Readiness,
+    runtime::{self, RuntimeConfig},
+    shutdown::Stopping,
+};
+
+/// Separate addresses for the admin and observability routes, so a firewall
+/// can keep them off the public interface. A surface without an address of
+/// its own stays on the `--listen` address; one with an address is only served there.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ListenersConfig {
+    /// `/admin/*`.
+    pub admin: Option<String>,
+    /// `/metrics`.
+    pub metrics: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Surface {
+    User,
+    Admin,
+    Metrics,
+    /// `/healthz` and `/readyz`, answered on every listener.
+    Probe,
+}
+
+impl Surface {
+    fn of(path: &str) -> Self {
+        if path == "/admin" || path.starts_with("/admin/") {
+            Surface::Admin
+        } else if path == "/metrics" {
+            Surface::Metrics
+        } else if path == "/healthz" || path == "/readyz" {
+            Surface::Probe
+        } else {
+            Surface::User
+        }
+    }
+}
+
+/// What one listener answers; everything else is a 404 there.
+#[derive(Debug, Clone, Copy)]
+struct Serves {
+    user: bool,
+    admin: bool,
+    metrics: bool,
+}
+
+async fn only(State(serves): State<Serves>, req: Request, next: Next) -> Response {
+    let ok = match Surface::of(req.uri().path()) {
+        Surface::Probe => true,
+        Surface::User => serves.user,
+        Surface::Admin => serves.admin,
+        Surface::Metrics => serves.metrics,
+    };
+    if !ok {
+        return ApiError::of_status(StatusCode::NOT_FOUND).into_response();
+    }
+    next.run(req).await
+}
+
+/// How each listener is run and stopped.
+#[derive(Clone)]
+pub struct Serving {
+    pub rt_cfg: RuntimeConfig,
+    pub conns: ConnLimitsConfig,
+    pub stop: Stopping,
+    pub drain: Duration,
+    pub ready: Arc<Readiness>,
+}
+
+async fn listen(addr: String, app: Router, serves: Serves, s: Serving) {
+    let listener = runtime::bind(&addr, &s.rt_cfg).await.unwrap();
+    tracing::info!("listening on {} for {:?}", addr, serves);
+    if serves.user {
+        s.ready.bound();
+    }
+    let app = app.layer(axum::middleware::from_fn_with_state(serves, only));
+    connlimit::serve(listener, app, s.conns, s.stop, s.drain).await;
+}
+
+/// Serves `app` on `main` and on each configured surface's own address,
+/// until every listener has drained after shutdown. Connection limits apply
+/// to each listener separately.
+pub async fn serve(main: String, app: Router, cfg: ListenersConfig, s: Serving) {
+    let serves = Serves { user: true, admin: cfg.admin.is_none(), metrics: cfg.metrics.is_none() };
+    let mut others = Vec::new();
+    if let Some(addr) = cfg.admin {
+        let only_admin = Serves { user: false, admin: true, metrics: false };
+        others.push(tokio::spawn(listen(addr, app.clone(), only_admin, s.clone())));
+    }
+    if let Some(addr) = cfg.metrics {
+        let only_metrics = Serves { user: false, admin: false, metrics: true };
+        others.push(tokio::spawn(listen(addr, app.clone(), only_metrics, s.clone())));
+    }
+    listen(main, app, serves, s).await;
+    for l in others {
+        let _ = l.await;
+    }
+}
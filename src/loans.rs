@@ -0,0 +1,467 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, ledger, now, AppState, ReqClock, RespMeta};
+
+/// Loans between users, beside the book: a lender offers an amount at a
+/// rate for a term, a borrower takes it, and it is paid back from the
+/// borrower's balance when due or at settlement, whichever comes first.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoansConfig {
+    /// Most one offer may lend.
+    pub max_amount: Option<i64>,
+    pub max_term_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanStatus {
+    /// Waiting for a borrower.
+    Offered,
+    Cancelled,
+    /// Lent, and not yet due.
+    Open,
+    Repaid,
+    /// Due while the borrower couldn't pay all of it; `repaid` is what the
+    /// lender got.
+    Defaulted,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Loan {
+    pub id: u64,
+    pub lender: String,
+    pub borrower: Option<String>,
+    pub amount: i64,
+    /// Simple interest over the whole term, on `amount`.
+    pub rate_percent: i64,
+    pub term_secs: u64,
+    pub status: LoanStatus,
+    pub offered_at_nanos: i64,
+    pub due_at_nanos: Option<i64>,
+    pub repaid: i64,
+}
+
+impl Loan {
+    /// What the borrower pays back. Saturates rather than wrapping, as
+    /// `rate_percent` has no upper bound.
+    pub fn owed(&self) -> i64 {
+        let interest = self.amount as i128 * self.rate_percent as i128 / 100;
+        (self.amount as i128 + interest).min(i64::MAX as i128) as i64
+    }
+}
+
+/// Every loan and offer, kept with the game.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Loans {
+    loans: BTreeMap<u64, Loan>,
+    next_id: u64,
+}
+
+impl Loans {
+    pub fn of_user(&self, uname: &str) -> Vec<Loan> {
+        let mine = self.loans.values().filter(|l| l.lender == uname || l.borrower.as_deref() == Some(uname));
+        mine.cloned().collect()
+    }
+
+    /// Loans some borrower took up, whatever became of them since.
+    pub fn taken(&self) -> impl Iterator<Item = &Loan> + '_ {
+        self.loans.values().filter(|l| l.borrower.is_some())
+    }
+
+    /// Puts `alias` in place of `uname` on either side of every loan.
+    pub fn anonymize(&mut self, uname: &str, alias: &str) -> usize {
+        let mut n = 0;
+        for l in self.loans.values_mut() {
+            for name in [Some(&mut l.lender), l.borrower.as_mut()].into_iter().flatten() {
+                if name == uname {
+                    *name = alias.to_owned();
+                    n += 1;
+                }
+            }
+        }
+        n
+    }
+}
+
+/// Makes loan `id` due now: the borrower pays what they owe, or what
+/// their balance covers, to the lender.
+fn collect(g: &mut AppState, id: u64, now: i64) {
+    let loan = g.loans.loans[&id].clone();
+    let borrower = loan.borrower.as_deref().unwrap();
+    let have = g.users.get(borrower).map_or(0, |ua| ua.balance.max(0));
+    let paid = loan.owed().min(have);
+    if paid > 0 {
+        g.users.get_mut(borrower).unwrap().balance -= paid;
+        g.users.get_mut(&loan.lender).unwrap().balance += paid;
+        g.balance_moved(borrower, ledger::Reason::Repayment, -paid, None, now);
+        g.balance_moved(&loan.lender, ledger::Reason::Repayment, paid, None, now);
+        g.board_changed();
+    }
+    let l = g.loans.loans.get_mut(&id).unwrap();
+    l.repaid = paid;
+    l.status = if paid == loan.owed() { LoanStatus::Repaid } else { LoanStatus::Defaulted };
+    if l.status == LoanStatus::Defaulted {
+        tracing::info!("loan {} from {} to {} defaulted: {} of {} repaid", id, loan.lender, borrower, paid, loan.owed());
+    }
+}
+
+/// Collects every loan past its due time.
+pub fn collect_due(g: &mut AppState, now: i64) {
+    let due: Vec<u64> = g
+        .loans
+        .loans
+        .values()
+        .filter(|l| l.status == LoanStatus::Open && l.due_at_nanos.is_some_and(|d| d <= now))
+        .map(|l| l.id)
+        .collect();
+    for id in due {
+        collect(g, id, now);
+    }
+}
+
+/// At settlement, before anything is paid out: open loans are collected
+/// and offers withdrawn. Lots the borrower holds are not sold to pay.
+pub fn close_all(g: &mut AppState, now: i64) {
+    close(g, |_| true, now);
+}
+
+/// Closes `uname`'s loans and offers, on either side, before they leave.
+pub fn close_user(g: &mut AppState, uname: &str, now: i64) {
+    close(g, |l| l.lender == uname || l.borrower.as_deref() == Some(uname), now);
+}
+
+fn close(g: &mut AppState, which: impl Fn(&Loan) -> bool, now: i64) {
+    let ids: Vec<u64> = g.loans.loans.values().filter(|l| which(l)).map(|l| l.id).collect();
+    for id in ids {
+        match g.loans.loans[&id].status {
+            LoanStatus::Open => collect(g, id, now),
+            LoanStatus::Offered => g.loans.loans.get_mut(&id).unwrap().status = LoanStatus::Cancelled,
+            _ => {}
+        }
+    }
+}
+
+pub fn spawn_collector(state: Arc<Mutex<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let mut g = state.locked();
+            if g.settlement.is_none() {
+                collect_due(&mut g, now());
+            }
+        }
+    });
+}
+
+#[derive(Serialize, Default)]
+pub struct LoansResult {
+    /// Everyone's offers still waiting for a borrower.
+    pub offers: Vec<Loan>,
+    /// The user's loans and offers, as lender or borrower.
+    pub mine: Vec<Loan>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+#[derive(Serialize, Default)]
+pub struct LoanResult {
+    pub loan: Option<Loan>,
+    pub balance: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn not_offered() -> ApiError {
+    ApiError::with(StatusCode::NOT_FOUND, "NOT_OFFERED", "loans are not offered")
+}
+
+/// Free.
+pub async fn user_loans(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<LoansResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if g.loans_cfg.is_none() {
+        return clock.refuse(not_offered(), LoansResult::default());
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), LoansResult::default());
+    }
+    let offers = g.loans.loans.values().filter(|l| l.status == LoanStatus::Offered).cloned().collect();
+    clock.reply(StatusCode::OK, LoansResult { offers, mine: g.loans.of_user(&uname), ..Default::default() })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OfferRequest {
+    pub amount: i64,
+    pub rate_percent: i64,
+    pub term_secs: u64,
+}
+
+/// Offers a loan. Nothing moves until someone accepts, and the lender must
+/// still have the amount then.
+pub async fn user_offer(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    body: Bytes,
+) -> (StatusCode, Json<LoanResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let now = now();
+    let Some(cfg) = g.loans_cfg.clone() else {
+        return clock.refuse(not_offered(), LoanResult::default());
+    };
+    let Some(ua) = g.users.get(&uname) else {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), LoanResult::default());
+    };
+    let valid = serde_json::from_slice::<OfferRequest>(&body).ok().filter(|o| {
+        o.amount >= 1
+            && o.rate_percent >= 0
+            && o.term_secs >= 1
+            && cfg.max_amount.map_or(true, |max| o.amount <= max)
+            && cfg.max_term_secs.map_or(true, |max| o.term_secs <= max)
+    });
+    let Some(offer) = valid else {
+        let err = ApiError::with(StatusCode::UNPROCESSABLE_ENTITY, "INVALID_LOAN", "amount, rate_percent or term_secs out of range");
+        return clock.refuse(err, LoanResult::default());
+    };
+    let balance = ua.balance;
+    if g.paused {
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), LoanResult { balance, ..Default::default() });
+    }
+    if balance < offer.amount {
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "INSUFFICIENT_FUNDS"), LoanResult { balance, ..Default::default() });
+    }
+    g.loans.next_id += 1;
+    let loan = Loan {
+        id: g.loans.next_id,
+        lender: uname,
+        borrower: None,
+        amount: offer.amount,
+        rate_percent: offer.rate_percent,
+        term_secs: offer.term_secs,
+        status: LoanStatus::Offered,
+        offered_at_nanos: now,
+        due_at_nanos: None,
+        repaid: 0,
+    };
+    g.loans.loans.insert(loan.id, loan.clone());
+    clock.reply(StatusCode::OK, LoanResult { loan: Some(loan), balance, ..Default::default() })
+}
+
+/// Borrows on offer `id`. An offer the lender can no longer fund is
+/// withdrawn.
+pub async fn user_accept(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<LoanResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let now = now();
+    if g.loans_cfg.is_none() {
+        return clock.refuse(not_offered(), LoanResult::default());
+    }
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), LoanResult::default());
+    }
+    if g.paused {
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), LoanResult::default());
+    }
+    let Some(loan) = g.loans.loans.get(&id).filter(|l| l.status == LoanStatus::Offered).cloned() else {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_OFFER"), LoanResult::default());
+    };
+    if loan.lender == uname {
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "OWN_OFFER"), LoanResult::default());
+    }
+    if g.users[&loan.lender].balance < loan.amount {
+        g.loans.loans.get_mut(&id).unwrap().status = LoanStatus::Cancelled;
+        let err = ApiError::with(StatusCode::CONFLICT, "LENDER_SHORT", "the lender can no longer fund this offer");
+        return clock.refuse(err, LoanResult::default());
+    }
+
+    g.users.get_mut(&loan.lender).unwrap().balance -= loan.amount;
+    g.users.get_mut(&uname).unwrap().balance += loan.amount;
+    g.balance_moved(&loan.lender, ledger::Reason::Loan, -loan.amount, None, now);
+    g.balance_moved(&uname, ledger::Reason::Loan, loan.amount, None, now);
+    g.board_changed();
+    let l = g.loans.loans.get_mut(&id).unwrap();
+    l.borrower = Some(uname.clone());
+    l.status = LoanStatus::Open;
+    l.due_at_nanos = Some(now.saturating_add(Duration::from_secs(l.term_secs).as_nanos() as i64));
+    let loan = l.clone();
+    let balance = g.users[&uname].balance;
+    clock.reply(StatusCode::OK, LoanResult { loan: Some(loan), balance, ..Default::default() })
+}
+
+/// Withdraws one of the user's own offers.
+pub async fn user_cancel(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<LoanResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if g.loans_cfg.is_none() {
+        return clock.refuse(not_offered(), LoanResult::default());
+    }
+    let Some(l) = g.loans.loans.get_mut(&id).filter(|l| l.lender == uname && l.status == LoanStatus::Offered) else {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_OFFER"), LoanResult::default());
+    };
+    l.status = LoanStatus::Cancelled;
+    let loan = l.clone();
+    let balance = g.users.get(&uname).map_or(0, |ua| ua.balance);
+    clock.reply(StatusCode::OK, LoanResult { loan: Some(loan), balance, ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{invariants, testing};
+
+    const SEC: i64 = 1_000_000_000;
+
+    fn game(loans: &str) -> Arc<Mutex<AppState>> {
+        testing::shared(&format!("users = [\"alice\", \"bob\"]
+{}", loans))
+    }
+
+    async fn offer(state: &Arc<Mutex<AppState>>, uname: &str, body: &str) -> (StatusCode, LoanResult) {
+        let (code, Json(res)) = user_offer(Path(uname.to_owned()), State(state.clone()), Bytes::from(body.to_owned())).await;
+        (code, res)
+    }
+
+    async fn accept(state: &Arc<Mutex<AppState>>, uname: &str, id: u64) -> (StatusCode, LoanResult) {
+        let (code, Json(res)) = user_accept(Path((uname.to_owned(), id)), State(state.clone())).await;
+        (code, res)
+    }
+
+    fn error(res: &LoanResult) -> Option<&'static str> {
+        res.meta.error.as_ref().map(|e| e.code)
+    }
+
+    /// alice lends bob 100 at 10% for 60s.
+    async fn lent(state: &Arc<Mutex<AppState>>) -> Loan {
+        let (_, res) = offer(state, "alice", r#"{"amount": 100, "rate_percent": 10, "term_secs": 60}"#).await;
+        let id = res.loan.unwrap().id;
+        let (code, res) = accept(state, "bob", id).await;
+        assert_eq!(code, StatusCode::OK);
+        res.loan.unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_loan_is_repaid_with_interest_when_due() {
+        let state = game("[loans]");
+        let loan = lent(&state).await;
+        assert_eq!((loan.status, loan.borrower.as_deref(), loan.owed()), (LoanStatus::Open, Some("bob"), 110));
+        let due = loan.due_at_nanos.unwrap();
+        // The term runs from when bob took it up.
+        assert!(due - loan.offered_at_nanos >= 60 * SEC);
+
+        let mut g = state.locked();
+        assert_eq!((g.users["alice"].balance, g.users["bob"].balance), (900, 1100));
+        collect_due(&mut g, due - 1);
+        assert_eq!(g.loans.loans[&loan.id].status, LoanStatus::Open);
+        collect_due(&mut g, due);
+        let l = &g.loans.loans[&loan.id];
+        assert_eq!((l.status, l.repaid), (LoanStatus::Repaid, 110));
+        assert_eq!((g.users["alice"].balance, g.users["bob"].balance), (1010, 990));
+        let reasons: Vec<ledger::Reason> = g.ledger.of_user("bob").iter().map(|e| e.reason).collect();
+        assert_eq!(reasons, vec![ledger::Reason::Loan, ledger::Reason::Repayment]);
+        assert!(invariants::verify(&g).ok);
+    }
+
+    #[tokio::test]
+    async fn a_borrower_short_at_the_due_time_defaults() {
+        let state = game("[loans]");
+        let loan = lent(&state).await;
+        let mut g = state.locked();
+        // bob's money went elsewhere in the meantime.
+        g.users.get_mut("bob").unwrap().balance = 40;
+        collect_due(&mut g, loan.due_at_nanos.unwrap());
+        let l = &g.loans.loans[&loan.id];
+        assert_eq!((l.status, l.repaid), (LoanStatus::Defaulted, 40));
+        assert_eq!((g.users["alice"].balance, g.users["bob"].balance), (940, 0));
+    }
+
+    #[tokio::test]
+    async fn closing_collects_open_loans_and_withdraws_offers() {
+        let state = game("[loans]");
+        let loan = lent(&state).await;
+        let (_, res) = offer(&state, "bob", r#"{"amount": 5, "rate_percent": 0, "term_secs": 1}"#).await;
+        let waiting = res.loan.unwrap().id;
+        let mut g = state.locked();
+        close_all(&mut g, loan.offered_at_nanos);
+        assert_eq!(g.loans.loans[&loan.id].status, LoanStatus::Repaid);
+        assert_eq!(g.loans.loans[&waiting].status, LoanStatus::Cancelled);
+        assert_eq!(g.loans.taken().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_user_keeps_their_loans_under_the_alias() {
+        let state = game("[loans]");
+        let loan = lent(&state).await;
+        let mut g = state.locked();
+        assert_eq!(g.loans.anonymize("bob", "anon-1"), 1);
+        assert!(g.loans.of_user("bob").is_empty());
+        let l = &g.loans.loans[&loan.id];
+        assert_eq!((l.lender.as_str(), l.borrower.as_deref()), ("alice", Some("anon-1")));
+    }
+
+    #[tokio::test]
+    async fn offers_are_checked() {
+        let state = game("[loans]\nmax_amount = 500\nmax_term_secs = 60");
+        for body in [
+            r#"{"amount": 501, "rate_percent": 0, "term_secs": 1}"#,
+            r#"{"amount": 0, "rate_percent": 0, "term_secs": 1}"#,
+            r#"{"amount": 1, "rate_percent": -1, "term_secs": 1}"#,
+            r#"{"amount": 1, "rate_percent": 0, "term_secs": 61}"#,
+            r#"{"amount": 1}"#,
+        ] {
+            let (code, res) = offer(&state, "alice", body).await;
+            assert_eq!((code, error(&res)), (StatusCode::UNPROCESSABLE_ENTITY, Some("INVALID_LOAN")), "{}", body);
+        }
+        let (_, res) = offer(&state, "alice", r#"{"amount": 500, "rate_percent": 0, "term_secs": 60}"#).await;
+        let id = res.loan.unwrap().id;
+        let (_, res) = accept(&state, "alice", id).await;
+        assert_eq!(error(&res), Some("OWN_OFFER"));
+        state.locked().users.get_mut("alice").unwrap().balance = 499;
+        let (code, res) = accept(&state, "bob", id).await;
+        assert_eq!((code, error(&res)), (StatusCode::CONFLICT, Some("LENDER_SHORT")));
+        assert_eq!(state.locked().loans.loans[&id].status, LoanStatus::Cancelled);
+        let (_, res) = accept(&state, "bob", id).await;
+        assert_eq!(error(&res), Some("UNKNOWN_OFFER"));
+
+        let off = game("");
+        let (code, res) = offer(&off, "alice", r#"{"amount": 1, "rate_percent": 0, "term_secs": 1}"#).await;
+        assert_eq!((code, error(&res)), (StatusCode::NOT_FOUND, Some("NOT_OFFERED")));
+    }
+
+    #[test]
+    fn owed_saturates() {
+        let l = Loan {
+            id: 1,
+            lender: "a".to_owned(),
+            borrower: None,
+            amount: i64::MAX / 2,
+            rate_percent: 300,
+            term_secs: 1,
+            status: LoanStatus::Offered,
+            offered_at_nanos: 0,
+            due_at_nanos: None,
+            repaid: 0,
+        };
+        assert_eq!(l.owed(), i64::MAX);
+    }
+}
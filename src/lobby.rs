@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calendar::{Calendar, CalendarConfig},
+    contention::StateLock,
+    errors::ApiError,
+    settlement, AppState, ReqClock, RespMeta,
+};
+
+/// The schedule a lobby game starts on, given as in the config file.
+#[derive(Debug, Deserialize)]
+pub struct ArmRequest {
+    pub trade_start_nanos: Option<i64>,
+    pub calendar: Option<CalendarConfig>,
+    pub trade_end_nanos: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ArmResult {
+    pub armed: bool,
+    pub trade_start_nanos: Option<i64>,
+    pub trade_end_nanos: Option<i64>,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+fn refuse(clock: &ReqClock, code: StatusCode, err: impl Into<String>) -> (StatusCode, Json<ArmResult>) {
+    let err = err.into();
+    let api_err = ApiError { message: err.clone(), ..ApiError::of_status(code) };
+    clock.refuse(api_err, ArmResult { error: Some(err), ..Default::default() })
+}
+
+/// Ends lobby mode: a server started without `trade_start_nanos` or
+/// `[calendar]` takes users and asks through the admin API, then opens on
+/// the schedule given here. Allowed once, and only with something to trade.
+pub async fn admin_arm(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<ArmRequest>,
+) -> (StatusCode, Json<ArmResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if g.calendar.armed() {
+        return refuse(&clock, StatusCode::CONFLICT, "the game already has a schedule");
+    }
+    if g.users.is_empty() || g.book.asks.is_empty() {
+        return refuse(&clock, StatusCode::CONFLICT, "add users and asks before arming");
+    }
+    let calendar = match Calendar::new(req.trade_start_nanos, req.calendar.as_ref()) {
+        Ok(c) => c,
+        Err(e) => return refuse(&clock, StatusCode::BAD_REQUEST, e),
+    };
+    if let Some(end) = req.trade_end_nanos {
+        if g.trade_end_nanos.is_some() {
+            return refuse(&clock, StatusCode::CONFLICT, "trade_end_nanos is already set in the config");
+        }
+        if end <= calendar.first_open() {
+            return refuse(&clock, StatusCode::BAD_REQUEST, "trade_end_nanos is before trading opens");
+        }
+        g.trade_end_nanos = Some(end);
+        settlement::spawn_close(state.clone(), end);
+    }
+    let start = calendar.first_open();
+    g.calendar = calendar;
+    g.config.trade_start_nanos = req.trade_start_nanos;
+    g.config.calendar = req.calendar;
+    g.config.trade_end_nanos = g.trade_end_nanos;
+    g.board_changed();
+    tracing::warn!("game armed: trading opens at {}", start);
+    g.feeds.timeline.admin_global(format!("game armed, trading opens at {}", start));
+    let res = ArmResult {
+        armed: true,
+        trade_start_nanos: Some(start),
+        trade_end_nanos: g.trade_end_nanos,
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup::StateImage, schema, testing};
+
+    fn lobby() -> AppState {
+        let mut g = testing::game("asks = [{ price = 10, vol = 4 }]");
+        g.calendar = Calendar::lobby();
+        g.config.trade_start_nanos = None;
+        g
+    }
+
+    /// `image` as it comes back after a restart.
+    fn reread(image: &StateImage) -> StateImage {
+        let raw = serde_json::to_value(image).unwrap();
+        serde_json::from_value(schema::upgrade_state(raw).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_armed_schedule_survives_a_restart() {
+        let state = Arc::new(Mutex::new(lobby()));
+        let end = i64::MAX / 2;
+        let req = ArmRequest { trade_start_nanos: Some(5_000), calendar: None, trade_end_nanos: Some(end) };
+        let (code, _) = admin_arm(State(state.clone()), Json(req)).await;
+        assert_eq!(code, StatusCode::OK);
+        let image = StateImage::capture(&state.locked());
+
+        let mut g = lobby();
+        assert_eq!(reread(&image).apply(&mut g), Some(end));
+        assert_eq!((g.calendar.first_open(), g.trade_end_nanos), (5_000, Some(end)));
+        assert_eq!((g.config.trade_start_nanos, g.config.trade_end_nanos), (Some(5_000), Some(end)));
+
+        // A schedule from config stays as it is.
+        let mut g = testing::game("");
+        assert_eq!(reread(&image).apply(&mut g), None);
+        assert_eq!((g.calendar.first_open(), g.trade_end_nanos), (0, None));
+    }
+}
@@ -1,22 +1,101 @@
-use std::{sync::{Mutex, Arc}, collections::{HashMap, BTreeMap}};
+use std::{sync::{Mutex, Arc}, collections::{BTreeMap, HashMap}};
+
+mod admin_auth;
+mod allocation;
+mod analytics;
+mod apikeys;
+mod backup;
+mod bankruptcy;
+mod baskets;
+mod book;
+mod book_view;
+mod breaker;
+mod calendar;
+mod cli;
+mod config_check;
+mod config_export;
+mod connlimit;
+mod contention;
+mod credit;
+mod errors;
+mod execution;
+mod expiry;
+mod feed;
+mod fees;
+mod handoff;
+mod health;
+mod inflight;
+mod injections;
+mod instruments;
+mod integrity;
+mod invariants;
+mod journal;
+mod killswitch;
+mod latency;
+mod ledger;
+mod limits;
+mod listeners;
+mod lobby;
+mod loans;
+mod market;
+mod matching;
+mod memory;
+mod metrics;
+mod modes;
+mod orders;
+mod penalty;
+mod persist;
+mod plugins;
+mod privacy;
+mod public_board;
+mod quotes;
+mod ratelimit;
+mod registration;
+mod rejections;
+mod reload;
+mod reservations;
+mod rules;
+mod retention;
+mod risk;
+mod runtime;
+mod schema;
+mod scoreboard;
+mod settlement;
+mod shutdown;
+mod signing;
+mod speedbump;
+mod starts;
+mod storage;
+mod tape;
+#[cfg(test)]
+mod testing;
+mod timeline;
+mod tls;
+mod usernames;
 
 use axum::{
-    routing::{get, post},
-    http::StatusCode,
+    routing::{delete, get, post, MethodRouter},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    body::Bytes,
     Json, Router, extract::Path,
 };
 use axum::extract::State;
+use contention::StateLock;
+use errors::ApiError;
+use storage::Store;
 
 use serde::{Deserialize, Serialize};
-use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
-use tracing::{info_span, Span};
+use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 
 
 
-#[tokio::main]
-async fn main() {
+fn main() {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -32,64 +111,502 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let mut settings = config::Config::default();
-    settings.merge(config::File::with_name("app_config.toml")).unwrap();
-    let config: AppConfig = settings.try_into().unwrap();
-
-    let mut init_st = AppState {
-        users: HashMap::new(),
-        trade_start_nanos: config.trade_start_nanos,
-        fee: config.fee,
-        asks: BTreeMap::new()
-    };
-    for u in config.users.iter() {
-        init_st.users.insert(u.to_owned(), UserAccount { 
-            balance: config.init_balance, done_trade: false
-        });
+    let args = cli::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let path = cli::config_path(&args);
+    let config = cli::load_config(&path).unwrap_or_else(|e| {
+        eprintln!("config: {}", e);
+        std::process::exit(2);
+    });
+    let problems = config_check::problems(&config);
+    if !problems.is_empty() {
+        eprintln!("config: {} can't be used:", path);
+        for p in problems {
+            eprintln!("  {}", p);
+        }
+        std::process::exit(2);
+    }
+    for w in config_check::warnings(&config, now()) {
+        tracing::warn!("config: {}", w);
     }
 
-    for pv in config.asks.iter() {
-        init_st.asks.insert(pv.price, pv.vol);
+    let rt_cfg = config.runtime.clone().unwrap_or_default();
+    runtime::build(&rt_cfg).unwrap().block_on(serve(config, rt_cfg, args));
+}
+
+async fn serve(config: AppConfig, rt_cfg: runtime::RuntimeConfig, args: cli::Args) {
+    let (recover, restore) = (args.recover, args.restore.clone());
+    if let Some(t) = &config.tls {
+        t.check().unwrap();
+    }
+    plugins::check(&config.plugins).unwrap();
+    let mut init_st = AppState::new(&config, cli::config_path(&args));
+    if let Some(j) = &config.journal {
+        let (journal, events) = journal::Journal::open(j).unwrap();
+        if !events.is_empty() {
+            if !recover {
+                panic!("journal {} holds {} events: start with --recover to replay them, or move it away", j.path, events.len());
+            }
+            tracing::warn!("replaying {} events from {}", events.len(), j.path);
+            journal::replay(&mut init_st, events);
+        }
+        init_st.journal = journal;
     }
 
     let shared_state = Arc::new(Mutex::new(init_st));
     // let shared_state = Arc::new(AppState::from(&config));
+    let persister = config.persistence.as_ref().map(|p| {
+        let persister = persist::Persister::open(p, shared_state.clone(), recover).unwrap();
+        persist::spawn_flusher(p, persister.clone());
+        persister
+    });
+    // After the store has loaded, so the backup is what serves and is
+    // written straight back over it.
+    if let Some(path) = restore {
+        let (file, image) = backup::read_for_restore(&path).unwrap();
+        tracing::warn!("restoring {} users and {} trades from {}", image.users.len(), image.tape.trades.len(), file.display());
+        let mut g = shared_state.locked();
+        image.apply(&mut g);
+        g.feeds.timeline.admin_global(format!("state restored from {} on start", file.display()));
+        drop(g);
+        if let Some(p) = &persister {
+            p.flush();
+        }
+    }
+
+    let api_keys = config.api_keys.clone().unwrap_or_default();
+    if api_keys.required {
+        let g = shared_state.locked();
+        let mut keyless: Vec<&String> = g.users.keys().filter(|u| !g.user_keys.contains_key(*u)).collect();
+        keyless.sort();
+        if !keyless.is_empty() {
+            tracing::warn!("[api_keys] required, but these users have no key and can't trade: {:?}", keyless);
+        }
+    }
+    if let Some(a) = config.analytics.clone() {
+        analytics::spawn_writer(a, shared_state.clone());
+    }
+    if let Some(r) = config.retention.clone() {
+        retention::spawn_pruner(r, shared_state.clone());
+    }
+    memory::spawn_guard(config.memory.clone().unwrap_or_default(), shared_state.clone());
+    expiry::spawn_sweeper(shared_state.clone());
+    modes::spawn_ticker(shared_state.clone());
+    reload::listen_for_hup(shared_state.clone());
+    if config.reservations.is_some() {
+        reservations::spawn_sweeper(shared_state.clone());
+    }
+    if config.loans.is_some() {
+        loans::spawn_collector(shared_state.clone());
+    }
+    if !config.injections.is_empty() {
+        injections::spawn_scheduler(shared_state.clone(), config.injections.clone());
+    }
+    if let Some(b) = config.backup.clone() {
+        backup::spawn_backups(b, shared_state.clone());
+    }
+    if let Some(s) = config.scoreboard.clone() {
+        scoreboard::spawn_pusher(s, shared_state.clone());
+    }
+    // From config, or from a restored game that was armed in the lobby.
+    let trade_end_nanos = shared_state.locked().trade_end_nanos;
+    if let Some(end) = trade_end_nanos {
+        settlement::spawn_close(shared_state.clone(), end);
+    }
+
+    // Order entry's delays, only where configured, so the rest never lock
+    // the state for them.
+    let (bump, floor) = (config.speed_bump.is_some(), config.latency_floor.is_some());
+    let delayed = |mut r: MethodRouter<Arc<Mutex<AppState>>>| {
+        if bump {
+            r = r.route_layer(axum::middleware::from_fn_with_state(shared_state.clone(), speedbump::delay));
+        }
+        if floor {
+            r = r.route_layer(axum::middleware::from_fn_with_state(shared_state.clone(), latency::delay));
+        }
+        r
+    };
 
     // build our application with a route
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/admin/board", post(admin_board))
+        .route("/admin/board/stream", get(admin_board_stream))
+        .route("/admin/analytics", get(admin_analytics))
+        .route("/admin/arm", post(lobby::admin_arm))
+        .route("/admin/pause", post(admin_pause))
+        .route("/admin/resume", post(admin_resume))
+        .route("/admin/restore_backup", post(backup::admin_restore_backup))
+        .route("/admin/reload", post(reload::admin_reload))
+        .route("/admin/handoff", post(handoff::admin_handoff))
+        .route("/admin/handoff/receive", post(handoff::admin_handoff_receive))
+        .route("/admin/lockouts", get(killswitch::admin_lockouts))
+        .route("/admin/lockouts/:uname/lift", post(killswitch::admin_lift_lockout))
+        .route("/admin/penalties", get(penalty::admin_penalties))
+        .route("/admin/penalties/:uname/lift", post(penalty::admin_lift_penalty))
+        .route("/admin/asks", post(book::admin_add_ask))
+        .route("/admin/verify", post(invariants::admin_verify))
+        .route("/admin/integrity", get(integrity::admin_integrity))
+        .route("/admin/contention", get(contention::admin_contention))
+        .route("/admin/config/export", get(config_export::admin_export_config))
+        .route("/admin/tape", get(tape::admin_tape))
+        .route("/admin/query", post(analytics::admin_query))
+        .route("/admin/settlement_preview", get(settlement::admin_settlement_preview))
+        .route("/admin/settle", post(settlement::admin_settle))
+        .route("/admin/settlement", get(settlement::admin_settlement))
+        .route("/admin/users", post(registration::admin_add_user))
+        .route("/admin/users/:uname/export", get(privacy::admin_export_user))
+        .route("/admin/users/:uname/forget", post(privacy::admin_forget_user))
+        .route("/admin/users/:uname/timeline", get(timeline::admin_user_timeline))
+        .route("/board", get(public_board::public_board))
+        .route("/metrics", get(metrics::metrics))
         .route("/users/:uname/ping", post(user_ping))
         .route("/users/:uname/check_asks", post(user_check))
-        .route("/users/:uname/place_bid/:price", post(user_bid))
-        .with_state(shared_state)
+        .route("/users/:uname/place_bid/:price", delayed(post(user_bid)))
+        .route("/users/:uname/check_asks/:symbol", post(instruments::user_check_symbol))
+        .route("/users/:uname/place_bid/:price/:qty", delayed(post(user_bid_qty)))
+        .route("/users/:uname/place_ask/:price/:qty", delayed(post(book::user_place_ask)))
+        .route("/users/:uname/queue/:price", post(book::user_queue))
+        .route("/users/:uname/reservations", delayed(post(reservations::user_reserve)))
+        .route("/users/:uname/reservations/:id", delete(reservations::user_release))
+        .route("/users/:uname/reservations/:id/confirm", post(reservations::user_confirm))
+        .route("/users/:uname/baskets", delayed(post(baskets::user_basket)))
+        .route("/users/:uname/loans", get(loans::user_loans).post(loans::user_offer))
+        .route("/users/:uname/loans/:id", delete(loans::user_cancel))
+        .route("/users/:uname/loans/:id/accept", post(loans::user_accept))
+        .route("/users/:uname/calibrate", post(latency::user_calibrate))
+        .route("/latency", get(latency::public_latency))
+        .route("/users/:uname/ws", get(feed::user_ws))
+        .route("/ws/market", get(market::market_ws))
+        .route(
+            "/users/:uname/orders",
+            get(orders::user_orders).post(delayed(post(orders::user_new_order))),
+        )
+        .route("/users/:uname/orders/:id", get(orders::user_order).delete(orders::user_cancel_order))
+        .route("/users/:uname/orders/:id/replace", post(orders::user_replace_order))
+        .route("/users/:uname/cancel_all", post(orders::user_cancel_all))
+        .route("/users/:uname/rejections", get(rejections::user_rejections))
+        .route("/users/:uname/fees", get(ledger::user_fees))
+        .route("/users/:uname/ledger", get(ledger::user_ledger))
+        .route("/users/:uname/results", get(execution::user_results))
+        .fallback(errors::not_found);
+    if cfg!(debug_assertions) {
+        app = app.layer(axum::middleware::from_fn_with_state(shared_state.clone(), invariants::check_after_request));
+    }
+    if let Some(p) = persister.clone() {
+        app = app.layer(axum::middleware::from_fn_with_state(p, persist::flush_after_request));
+    }
+    app = app
+        .layer(axum::middleware::from_fn_with_state(shared_state.clone(), settlement::read_only))
+        .layer(axum::middleware::from_fn_with_state(shared_state.clone(), timeline::record_requests));
+    if let Some(k) = config.kill_switch.clone() {
+        app = app.layer(axum::middleware::from_fn_with_state((k, shared_state.clone()), killswitch::guard));
+    }
+    // Only a configured handoff can ever hand the game off.
+    if config.handoff.is_some() {
+        app = app.layer(axum::middleware::from_fn_with_state(shared_state.clone(), handoff::redirect_if_handed_off));
+    }
+    let app = app.with_state(shared_state.clone());
+    let shutdown = config.shutdown.clone().unwrap_or_default();
+    let stop = shutdown::listen_for_signals();
+    let ready = health::Readiness::new(stop.clone());
+    // Outside the router, so routes already see the roster name. Probes
+    // skip the game's own middleware.
+    let mut app = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz).with_state(ready.clone()))
+        .fallback_service(app);
+    if let Some(c) = &config.user_concurrency {
+        app = app.layer(axum::middleware::from_fn_with_state(inflight::InFlight::new(c), inflight::cap));
+    }
+    let rate_limiter = shared_state.locked().rate_limiter.clone();
+    if let Some(rl) = rate_limiter {
+        app = app.layer(axum::middleware::from_fn_with_state(rl, ratelimit::limit));
+    }
+    if api_keys.required {
+        app = app.layer(axum::middleware::from_fn_with_state(shared_state.clone(), apikeys::require));
+    }
+    if let Some(s) = config.signed_requests.clone() {
+        app = app.layer(axum::middleware::from_fn_with_state((s, shared_state.clone()), signing::verify));
+    }
+    if !config.penalties.is_empty() {
+        app = app.layer(axum::middleware::from_fn_with_state(shared_state.clone(), penalty::watch));
+    }
+    // Without aliases or case folding every name is already its roster name.
+    let usernames = config.usernames.clone().unwrap_or_default();
+    if usernames.case_insensitive || !usernames.aliases.is_empty() {
+        app = app.layer(axum::middleware::from_fn_with_state(shared_state.clone(), usernames::canonicalize));
+    }
+    let admin = config.admin.clone().unwrap_or_default();
+    match admin.token() {
+        Some(token) => app = app.layer(axum::middleware::from_fn_with_state(Arc::from(token), admin_auth::require)),
+        None => tracing::warn!("no [admin] token or ADMIN_TOKEN: /admin/* is open to anyone"),
+    }
+    if let Some(c) = admin.client_cert {
+        app = app.layer(axum::middleware::from_fn_with_state(Arc::new(c), admin_auth::require_client_cert));
+    }
+    let request_limits = config.request_limits.unwrap_or_default();
+    if let Some(max) = request_limits.max_body_bytes {
+        app = app.layer(tower_http::limit::RequestBodyLimitLayer::new(max));
+    }
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(stop.clone(), shutdown::refuse_when_stopping))
+        .layer(axum::middleware::from_fn_with_state(request_limits, limits::enforce))
+        .layer(axum::middleware::from_fn(metrics::track))
         .layer(TraceLayer::new_for_http());
 
-    let svr_addr = std::env::var("SVR_ADDR").unwrap();
-    let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(svr_addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listeners = config.listeners.clone().unwrap_or_default();
+    let serving = listeners::Serving {
+        rt_cfg,
+        conns: config.connections.clone().unwrap_or_default(),
+        stop: shutdown::after(stop, std::time::Duration::from_secs(shutdown.unready_secs)),
+        drain: std::time::Duration::from_secs(shutdown.drain_secs),
+        ready,
+    };
+    listeners::serve(cli::listen_addr(&args), app, listeners, serving).await;
+    let backup_dir = config.backup.as_ref().map(|b| b.dir.as_str());
+    tokio::task::block_in_place(|| shutdown::finish(&shutdown, backup_dir, &shared_state, persister.as_deref()));
 }
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct PriceVol {
     pub price: i64,
-    pub vol: i64
+    pub vol: i64,
+    /// For ask levels: when the house volume is withdrawn, see `expiry`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_nanos: Option<i64>,
+}
+
+/// One ask price as users see it; `[book_view]` decides which of `vol` and
+/// `available` it carries.
+#[derive(Debug, Clone, Serialize)]
+struct AskLevel {
+    pub price: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vol: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at_nanos: Option<i64>,
+}
+
+/// Resting bids at one price, best first in `check_asks`.
+#[derive(Debug, Clone, Serialize)]
+struct BidLevel {
+    pub price: i64,
+    pub orders: usize,
 }
  
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct AppConfig {
+    /// May be empty, with `asks`, when users are added through the admin API.
+    #[serde(default)]
     pub users: Vec<String>,
-    pub trade_start_nanos: i64,
+    #[serde(default)]
+    pub trade_start_nanos: Option<i64>,
+    /// Trading stops here and the game settles, see `[settlement]`.
+    #[serde(default)]
+    pub trade_end_nanos: Option<i64>,
     pub init_balance: i64,
     pub fee: i64,
-    pub asks: Vec<PriceVol>
-}
+    #[serde(default)]
+    pub asks: Vec<PriceVol>,
+    #[serde(default)]
+    pub analytics: Option<analytics::AnalyticsConfig>,
+    #[serde(default)]
+    pub retention: Option<retention::RetentionConfig>,
+    #[serde(default)]
+    pub memory: Option<memory::MemoryConfig>,
+    #[serde(default)]
+    pub persistence: Option<persist::PersistenceConfig>,
+    #[serde(default)]
+    pub journal: Option<journal::JournalConfig>,
+    #[serde(default)]
+    pub storage: Option<storage::StorageConfig>,
+    #[serde(default)]
+    pub backup: Option<backup::BackupConfig>,
+    #[serde(default)]
+    pub handoff: Option<handoff::HandoffConfig>,
+    #[serde(default)]
+    pub admin: Option<admin_auth::AdminConfig>,
+    #[serde(default)]
+    pub risk: Option<risk::RiskConfig>,
+    #[serde(default)]
+    pub credit: Option<credit::CreditConfig>,
+    #[serde(default)]
+    pub kill_switch: Option<killswitch::KillSwitchConfig>,
+    #[serde(default)]
+    pub public_board: Option<public_board::PublicBoardConfig>,
+    #[serde(default)]
+    pub runtime: Option<runtime::RuntimeConfig>,
+    #[serde(default)]
+    pub connections: Option<connlimit::ConnLimitsConfig>,
+    #[serde(default)]
+    pub listeners: Option<listeners::ListenersConfig>,
+    #[serde(default)]
+    pub request_limits: Option<limits::RequestLimitsConfig>,
+    #[serde(default)]
+    pub user_concurrency: Option<inflight::UserConcurrencyConfig>,
+    #[serde(default)]
+    pub rate_limit: Option<ratelimit::RateLimitConfig>,
+    #[serde(default)]
+    pub penalties: Vec<penalty::PenaltyRule>,
+    #[serde(default)]
+    pub injections: Vec<injections::Injection>,
+    #[serde(default)]
+    pub rules: Vec<rules::RuleConfig>,
+    #[serde(default)]
+    pub tls: Option<tls::TlsConfig>,
+    #[serde(default)]
+    pub plugins: Vec<plugins::PluginConfig>,
+    /// Secret per user, for the private feed and, with `[api_keys]`, every call.
+    #[serde(default)]
+    pub user_keys: HashMap<String, String>,
+    #[serde(default)]
+    pub api_keys: Option<apikeys::ApiKeysConfig>,
+    #[serde(default)]
+    pub signed_requests: Option<signing::SignedRequestsConfig>,
+    #[serde(default)]
+    pub orders: Option<orders::OrdersConfig>,
+    #[serde(default)]
+    pub book_view: Option<book_view::BookViewConfig>,
+    #[serde(default)]
+    pub circuit_breaker: Option<breaker::CircuitBreakerConfig>,
+    #[serde(default)]
+    pub allocation: Option<allocation::AllocationConfig>,
+    #[serde(default)]
+    pub speed_bump: Option<speedbump::SpeedBumpConfig>,
+    #[serde(default)]
+    pub latency_floor: Option<latency::LatencyFloorConfig>,
+    #[serde(default)]
+    pub usernames: Option<usernames::UsernamesConfig>,
+    #[serde(default)]
+    pub calendar: Option<calendar::CalendarConfig>,
+    #[serde(default)]
+    pub staggered_start: Option<starts::StaggeredStartConfig>,
+    #[serde(default)]
+    pub late_registration: Option<registration::LateRegistrationConfig>,
+    #[serde(default)]
+    pub bankruptcy: Option<bankruptcy::BankruptcyConfig>,
+    #[serde(default)]
+    pub fee_schedule: Option<fees::FeeScheduleConfig>,
+    #[serde(default)]
+    pub quotes: Option<quotes::QuotesConfig>,
+    #[serde(default)]
+    pub reservations: Option<reservations::ReservationsConfig>,
+    #[serde(default)]
+    pub loans: Option<loans::LoansConfig>,
+    #[serde(default)]
+    pub scoreboard: Option<scoreboard::ScoreboardConfig>,
+    #[serde(default)]
+    pub settlement: Option<settlement::SettlementConfig>,
+    #[serde(default)]
+    pub shutdown: Option<shutdown::ShutdownConfig>,
+    #[serde(default)]
+    pub game_mode: Option<modes::GameModeConfig>,
 
+    #[serde(default)]
+    pub instruments: Vec<instruments::InstrumentConfig>,
+    #[serde(default)]
+    pub market_data: Option<market::MarketDataConfig>,
+}
 
+/// The whole game, behind the one lock every handler and background task
+/// shares; see `contention::StateLock` for why one.
+///
+/// Handlers take the lock themselves rather than send commands to a task
+/// that owns the game. Each route's checks, fees and fills already run in
+/// one critical section, so a channel would add a hop and a message type
+/// per route without changing what is serialized. Logic worth testing
+/// without HTTP goes in plain functions over the books, as in `matching`.
 #[derive(Debug)]
 struct AppState {
+    /// What the game was started with; `/admin/config/export` lays the
+    /// runtime changes over it.
+    pub config: AppConfig,
+    /// Where `config` was read from, and what a reload re-reads.
+    pub config_path: String,
     pub users: HashMap<String, UserAccount>,
-    pub trade_start_nanos: i64,
+    pub journal: journal::Journal,
+    pub calendar: calendar::Calendar,
+    pub starts: starts::Starts,
+    pub init_balance: i64,
+    pub late_registration: Option<registration::LateRegistrationConfig>,
+    pub bankruptcy: Option<bankruptcy::BankruptcyConfig>,
+    pub bankruptcies: bankruptcy::Bankruptcies,
+    pub rejections: rejections::Rejections,
+    pub ledger: ledger::Ledger,
+    pub fee_schedule: fees::FeeScheduleConfig,
+    pub quotes: Option<quotes::QuotesConfig>,
+    pub reservations_cfg: Option<reservations::ReservationsConfig>,
+    /// Lots held off the book for a later confirm.
+    pub reservations: reservations::Reservations,
+    pub loans_cfg: Option<loans::LoansConfig>,
+    pub loans: loans::Loans,
+    pub market_data: Option<market::MarketDataConfig>,
+    pub market: market::Market,
+    pub settlement_cfg: settlement::SettlementConfig,
+    pub pending_settlement: Option<settlement::PendingSettlement>,
+    /// Set once the game is settled; it is read-only from then on.
+    pub settlement: Option<settlement::SettlementRecord>,
+    pub trade_end_nanos: Option<i64>,
+    pub instruments: instruments::Instruments,
     pub fee: i64,
-    pub asks: BTreeMap<i64, i64>
+    pub book: matching::OrderBook,
+    pub book_view: book_view::Volumes,
+    pub mode: Arc<dyn modes::GameMode>,
+    pub rules: rules::Rules,
+    /// Expiry of the house volume per ask price.
+    pub ask_expiry: BTreeMap<i64, i64>,
+    pub tape: tape::Tape,
+    pub analytics_db: Option<String>,
+    pub prune_dir: Option<String>,
+    pub memory: memory::MemoryStats,
+    pub forgotten_users: u64,
+    pub backup_stats: backup::BackupStats,
+    pub backup_dir: Option<String>,
+    pub pending_restore: Option<backup::PendingRestore>,
+    /// Set by `/admin/pause`; checks and bids are refused, free of charge.
+    pub paused: bool,
+    pub handoff_token: Option<String>,
+    /// Base URL of the instance this one handed its state to.
+    pub handed_off_to: Option<String>,
+    pub risk: risk::RiskConfig,
+    pub credit: credit::CreditConfig,
+    pub kill_switch: Option<killswitch::KillSwitchConfig>,
+    /// Shared with the `[rate_limit]` middleware, so `ping` can report it.
+    pub rate_limiter: Option<Arc<ratelimit::RateLimiter>>,
+    pub reject_trackers: HashMap<String, killswitch::RejectTracker>,
+    /// Used by signed requests, see `[signed_requests]`.
+    pub nonces: signing::NonceCache,
+    pub penalties: penalty::PenaltyBox,
+    pub public_board: Option<public_board::PublicBoardConfig>,
+    pub house: HouseAccount,
+    pub issued: invariants::Issuance,
+    /// `check_asks` body, shared by every check until the book next
+    /// changes. Cleared by whatever mutates `asks`.
+    pub book_snapshot: Option<Prebuilt>,
+    /// Public `/board` body. Cleared by whatever mutates `users`, through
+    /// `board_changed`.
+    pub board_snapshot: Option<Prebuilt>,
+    /// Bumped on every board change; `/admin/board/stream` watches it.
+    pub board_version: tokio::sync::watch::Sender<u64>,
+    pub user_keys: HashMap<String, String>,
+    pub feeds: feed::Feeds,
+    pub orders: orders::OrderStore,
+    pub orders_cfg: orders::OrdersConfig,
+    pub circuit_breaker: Option<breaker::CircuitBreakerConfig>,
+    pub breaker: breaker::Breaker,
+    pub allocation: allocation::AllocationConfig,
+    pub batches: allocation::Batches,
+    pub speed_bump: Option<speedbump::SpeedBump>,
+    pub latency_floor: Option<latency::LatencyFloorConfig>,
+    pub latency: latency::Latency,
+    pub names: usernames::Names,
 }
 
 
@@ -100,169 +617,1003 @@ fn now() -> i64 {
         .as_nanos() as i64
 
 }
+
+/// Started when a handler is entered; stamps the response with the server
+/// clock, how long the request spent inside the server (lock wait included),
+/// and an id that ledger lines for fees refer back to.
+struct ReqClock(std::time::Instant, u64);
+
+static NEXT_REQUEST_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+impl ReqClock {
+    fn start() -> Self {
+        ReqClock(std::time::Instant::now(), NEXT_REQUEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+    }
+
+    fn id(&self) -> u64 {
+        self.1
+    }
+
+    /// Error statuses get the generic code for the status; `refuse` says more.
+    fn reply<T: Stamped>(&self, code: StatusCode, mut body: T) -> (StatusCode, Json<T>) {
+        let error = (code.is_client_error() || code.is_server_error()).then(|| ApiError::of_status(code));
+        *body.meta_mut() = RespMeta {
+            server_time_nanos: now(),
+            processing_micros: self.0.elapsed().as_micros() as i64,
+            request_id: self.1,
+            error,
+        };
+        (code, Json(body))
+    }
+
+    fn refuse<T: Stamped>(&self, err: ApiError, body: T) -> (StatusCode, Json<T>) {
+        let (code, mut body) = self.reply(err.status, body);
+        body.meta_mut().error = Some(err);
+        (code, body)
+    }
+
+    /// Replies with a cached body, splicing this request's `RespMeta` in.
+    fn reply_prebuilt(&self, code: StatusCode, body: &Prebuilt) -> Response {
+        let meta = serde_json::to_vec(&RespMeta {
+            server_time_nanos: now(),
+            processing_micros: self.0.elapsed().as_micros() as i64,
+            request_id: self.1,
+            error: None,
+        })
+        .unwrap();
+        let fields = &body.0[..body.0.len() - 1];
+        let mut buf = Vec::with_capacity(fields.len() + meta.len() + 1);
+        buf.extend_from_slice(fields);
+        if fields.len() > 1 {
+            buf.push(b',');
+        }
+        buf.extend_from_slice(&meta[1..]);
+        (code, [(header::CONTENT_TYPE, "application/json")], buf).into_response()
+    }
+}
+
+/// A JSON object serialized once and served until the data behind it
+/// changes, for endpoints whose bodies can run to thousands of entries.
+#[derive(Debug, Clone)]
+struct Prebuilt(Bytes);
+
+impl Prebuilt {
+    /// `fields` must serialize to an object, without `RespMeta`.
+    fn new<T: Serialize>(fields: &T) -> Self {
+        let v = serde_json::to_vec(fields).unwrap();
+        debug_assert!(v.first() == Some(&b'{') && v.last() == Some(&b'}'));
+        Prebuilt(v.into())
+    }
+}
+
+/// Header bots can set to bound how long a request may sit behind the state
+/// lock; past this instant the request is refused before any fee is charged.
+const DEADLINE_HEADER: &str = "x-deadline-nanos";
+
+fn client_deadline(headers: &HeaderMap) -> Result<Option<i64>, ()> {
+    match headers.get(DEADLINE_HEADER) {
+        None => Ok(None),
+        Some(v) => v.to_str().ok().and_then(|v| v.trim().parse().ok()).map(Some).ok_or(()),
+    }
+}
+
+fn deadline_passed(deadline: Option<i64>) -> bool {
+    deadline.is_some_and(|d| now() > d)
+}
+
+trait Stamped {
+    fn meta_mut(&mut self) -> &mut RespMeta;
+}
+
+macro_rules! impl_stamped {
+    ($($t:ty),*) => {
+        $(impl Stamped for $t {
+            fn meta_mut(&mut self) -> &mut RespMeta { &mut self.meta }
+        })*
+    };
+}
+
+impl_stamped!(BoardResult, AnalyticsResult, PauseResult, CheckResult, PingResult, BidResult, tape::TapeResult, analytics::QueryResult,
+    privacy::UserExport, privacy::ForgetResult, backup::RestoreResult,
+    handoff::HandoffResult, killswitch::LockoutsResult, penalty::PenaltiesResult,
+    public_board::PublicBoardResult, orders::OrdersResult, orders::OrderResult,
+    orders::ReplaceResult, latency::CalibrateResult, latency::LatencyResult,
+    invariants::VerifyResult, timeline::TimelineResult, rejections::RejectionsResult, ledger::FeesResult, ledger::LedgerResult, reload::ReloadResult,
+    reservations::ReserveResult, reservations::ReleaseResult, baskets::BasketResult,
+    loans::LoansResult, loans::LoanResult,
+    settlement::SettlementPreviewResult, instruments::SymbolBookResult, settlement::SettleResult, settlement::SettlementResult,
+    registration::AddUserResult, lobby::ArmResult, book::AddAskResult, book::AskResult, book::QueueResult, contention::ContentionResult,
+    execution::UserResultsResult, integrity::IntegrityResult);
 async fn admin_board(
     State(state): State<Arc<Mutex<AppState>>>,
 ) -> (StatusCode, Json<BoardResult>) {
-    let g = state.lock().unwrap();
+    let clock = ReqClock::start();
+    let res = board(&mut state.locked());
+    clock.reply(StatusCode::OK, res)
+}
+
+/// `admin_board` as Server-Sent Events: the board now, then again after
+/// every change to a balance or who is done. Changes while a board is
+/// being sent are folded into the next one.
+async fn admin_board_stream(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Sse<impl futures_util::Stream<Item = Result<Event, axum::Error>>> {
+    let rx = state.locked().board_version.subscribe();
+    let stream = futures_util::stream::unfold((state, rx, true), |(state, mut rx, first)| async move {
+        if !first && rx.changed().await.is_err() {
+            return None;
+        }
+        let clock = ReqClock::start();
+        let res = {
+            let mut g = state.locked();
+            let res = board(&mut g);
+            // Interest charged while building this board is already in it.
+            rx.borrow_and_update();
+            res
+        };
+        let (_, Json(res)) = clock.reply(StatusCode::OK, res);
+        Some((Event::default().event("board").json_data(res), (state, rx, false)))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+fn board(g: &mut AppState) -> BoardResult {
+    g.accrue_all(now());
     let mut res = BoardResult {
-        done_users: Vec::new(),
-        running_users: Vec::new()
+        round: if g.settlement.is_some() { settlement::Round::Settled } else { settlement::Round::Live },
+        trade_end_nanos: g.trade_end_nanos,
+        scores: g.settlement.as_ref().map(|s| s.entries.clone()).unwrap_or_default(),
+        ..Default::default()
     };
 
-    for (u, ua) in g.users.iter() {
-        if ua.done_trade {
-            res.done_users.push((u.to_owned(), ua.clone()));
-        } else {
-            res.running_users.push((u.to_owned(), ua.clone()));
-        }
+    let (done, running) = g.board().into_iter().partition(|(_, ua)| ua.done_trade);
+    res.done_users = done;
+    res.running_users = running;
+    res
+}
+
+async fn admin_analytics(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<AnalyticsResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    let res = AnalyticsResult {
+        users: g.users.len(),
+        done_users: g.users.values().filter(|ua| ua.done_trade).count(),
+        trades: g.tape.trades.len(),
+        remaining_ask_vol: g.book.asks.values().sum(),
+        backups: g.backup_stats.clone(),
+        house_balance: g.house.balance(),
+        house: g.house.clone(),
+        halts: g.breaker.halts.clone(),
+        bankruptcies: g.bankruptcies.0.clone(),
+        memory: g.memory.clone(),
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+async fn set_paused(state: Arc<Mutex<AppState>>, paused: bool) -> (StatusCode, Json<PauseResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if g.paused != paused {
+        tracing::warn!("trading {}", if paused { "paused" } else { "resumed" });
+        g.feeds.timeline.admin_global(if paused { "trading paused" } else { "trading resumed" });
+    }
+    g.paused = paused;
+    if !paused {
+        g.pending_restore = None;
     }
+    clock.reply(StatusCode::OK, PauseResult { paused, ..Default::default() })
+}
 
-    res.done_users.sort_by_key(|(u, ua)| - ua.balance);
-    res.running_users.sort_by_key(|(u, ua)| - ua.balance);
+async fn admin_pause(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<PauseResult>) {
+    set_paused(state, true).await
+}
 
-    (StatusCode::OK, Json(res))
+async fn admin_resume(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<PauseResult>) {
+    set_paused(state, false).await
 }
 
 
 async fn user_bid(
     Path((uname, price)): Path<(String, i64)>,
     State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
 ) -> (StatusCode, Json<BidResult>) {
-    let mut g = state.lock().unwrap();
-    let fee = g.fee;
-    let start_ts= g.trade_start_nanos;
-    let now = now();
-    let balance = {
-        if g.users.get(&uname).is_none() {
-            return (StatusCode::NOT_FOUND, Json(BidResult::default()));
-        }
+    let clock = ReqClock::start();
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, fees::Endpoint::PlaceBid, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), BidResult::default());
+    };
+    submit_bid(&state, uname, price, BidOpts::default(), deadline, clock).await
+}
+
+/// `place_bid` for several lots. Whatever the level can't cover is left
+/// unfilled, or rests with `[orders] resting_bids`. The same shape serves
+/// `place_bid/:symbol/:price` for other instruments; symbols start with a
+/// letter, so a number here is always a price.
+async fn user_bid_qty(
+    Path((uname, first, second)): Path<(String, String, String)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<BidResult>) {
+    let clock = ReqClock::start();
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, fees::Endpoint::PlaceBid, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), BidResult::default());
+    };
+    let (price, qty) = match (first.parse::<i64>(), second.parse::<i64>()) {
+        (Ok(price), Ok(qty)) => (price, qty),
+        (Err(_), Ok(price)) => return instruments::bid(&state, &uname, &first, price, deadline, clock),
+        // Not numbers where they must be: refused as a bad quantity below.
+        _ => (0, 0),
+    };
+    if qty < 1 {
+        state.locked().reject(&uname, fees::Endpoint::PlaceBid, "INVALID_ORDER", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "INVALID_ORDER"), BidResult::default());
+    }
+    let opts = BidOpts { qty: Some(qty), ..Default::default() };
+    submit_bid(&state, uname, price, opts, deadline, clock).await
+}
+
+/// What an order may say beyond its price; `place_bid` takes the defaults.
+#[derive(Debug, Default)]
+struct BidOpts {
+    qty: Option<i64>,
+    tif: Option<orders::TimeInForce>,
+    client_id: Option<String>,
+}
 
-        let mut res = BidResult::default();
-        let ua = g.users.get_mut(&uname).unwrap();
-        if ua.balance < fee {
-            return (StatusCode::FORBIDDEN, Json(res));
+/// The one order entry path, shared by `place_bid` and `POST /users/:uname/orders`.
+async fn submit_bid(
+    state: &Arc<Mutex<AppState>>,
+    uname: String,
+    price: i64,
+    opts: BidOpts,
+    deadline: Option<i64>,
+    clock: ReqClock,
+) -> (StatusCode, Json<BidResult>) {
+    let qty = opts.qty.unwrap_or(1);
+    // Pro-rata bids park here; the lock must be released before waiting.
+    let (mut res, filled) = {
+        let mut g = state.locked();
+        let now = now();
+        let ep = fees::Endpoint::PlaceBid;
+        if deadline_passed(deadline) {
+            g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+            return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), BidResult::default());
         }
-        ua.balance -= fee;
-        if now < start_ts {
-            return (StatusCode::FORBIDDEN, Json(res));
+        if g.paused {
+            g.reject(&uname, ep, "PAUSED", 0, now);
+            return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), BidResult::default());
         }
-        if ua.done_trade {
-            return (StatusCode::FORBIDDEN, Json(res));
+        // With a reopen auction, bids during a halt are collected instead.
+        let auction = g.circuit_breaker.as_ref().is_some_and(|c| c.reopen_auction);
+        let reopens_at = g.breaker.reopens_at(now);
+        if reopens_at.is_some() && !auction {
+            g.reject(&uname, ep, "HALTED", 0, now);
+            let res = BidResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+            return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
         }
+        let fee = g.fee_schedule.fee(g.fee, ep, now);
+        let (price, qty, fee, entry) = {
+            if g.get_user(&uname).is_none() {
+                return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), BidResult::default());
+            }
+            let ua = &g.users[&uname];
+            let elapsed_secs = now.saturating_sub(g.calendar.first_open()) / 1_000_000_000;
+            let bid = rules::Bid { price, qty, fee, balance: ua.balance, position: ua.position, elapsed_secs };
+            let (bid, vetoed) = match g.rules.apply(bid) {
+                Ok(b) => (b, None),
+                // Vetoed bids pay the fee before any rule changed it.
+                Err(code) => (bid, Some(code)),
+            };
+            let (price, qty, fee) = (bid.price, bid.qty, bid.fee.max(0));
 
-        ua.balance
-    };
-
-    let mut res = BidResult::default();
-    match g.asks.entry(price) {
-        std::collections::btree_map::Entry::Vacant(e) => {
-            return (StatusCode::OK, Json(res));
-        }
-        std::collections::btree_map::Entry::Occupied(mut e) => {
-            let v = e.get_mut();
-            if *v <= 0 {
-                return (StatusCode::OK, Json(res));
+            if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+                g.reject(&uname, ep, reason, 0, now);
+                let res = BidResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+                return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
             }
-            *v -= 1;
-            if *v <= 0 {
-                e.remove();
+            let mode = g.mode.clone();
+            let entry = match vetoed {
+                Some(code) => Err(code),
+                None if qty < 1 => Err("INVALID_ORDER"),
+                None => mode.on_bid(&g, &uname, price, qty, now),
+            };
+            match entry {
+                Ok(entry) => (price, qty, fee, entry),
+                Err(code) => {
+                    g.reject(&uname, ep, code, fee, now);
+                    // Closed and already-traded refusals never carried a reason.
+                    let reject_reason = (code != "MARKET_CLOSED" && code != "ALREADY_TRADED").then(|| code.to_owned());
+                    let res = BidResult { reject_reason, total_fees: fee, ..Default::default() };
+                    let code = if code == "MARKET_CLOSED" { g.closed_reason(&uname, now) } else { code };
+                    let err = match vetoed {
+                        Some(_) => ApiError::with(StatusCode::FORBIDDEN, code, "refused by an operator rule"),
+                        None => ApiError::new(StatusCode::FORBIDDEN, code),
+                    };
+                    return clock.refuse(err, res);
+                }
             }
+        };
 
-            res.trade_succ = true;
+        let tif = opts.tif.unwrap_or(g.orders_cfg.default_tif());
+        let id = g.orders.accept(&uname, price, qty, tif, now);
+        g.orders.orders.get_mut(&id).unwrap().client_id = opts.client_id;
+        g.notify_order(id, now);
+        let mut res = BidResult { order_id: Some(id), qty, total_fees: fee, ..Default::default() };
+        if entry == modes::Entry::Seal {
+            res.status = BidStatus::Sealed;
+            res.position = g.users[&uname].position;
+            return clock.reply(StatusCode::OK, res);
         }
-    }
-
-    {
-        let ua = g.users.get_mut(&uname).unwrap();
-        ua.balance -= price;
-        ua.done_trade = true;
-    }
-
-
-    (StatusCode::OK, Json(res))
-
+        if let Some(until) = reopens_at {
+            allocation::enqueue_reopen(state, &mut g, price, id, &uname, until, now);
+            res.status = BidStatus::Auction;
+            res.position = g.users[&uname].position;
+            return clock.reply(StatusCode::OK, res);
+        }
+        if g.allocation.mode != allocation::AllocationMode::ProRata || !g.book.asks.contains_key(&price) {
+            res.report(book::enter(&mut g, &uname, id, now));
+            return clock.reply(StatusCode::OK, res);
+        }
+        (res, allocation::enqueue(state, &mut g, price, id, &uname))
+    };
+    res.report(filled.await.unwrap_or_default());
+    clock.reply(StatusCode::OK, res)
 }
 
 
 async fn user_check(
     Path(uname): Path<String>,
     State(state): State<Arc<Mutex<AppState>>>,
-) -> (StatusCode, Json<CheckResult>) {
-    let mut g = state.lock().unwrap();
-    let fee = g.fee;
-    let start_ts= g.trade_start_nanos;
+    headers: HeaderMap,
+) -> Response {
+    let clock = ReqClock::start();
+    let ep = fees::Endpoint::CheckAsks;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        let err = ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE");
+        return clock.refuse(err, CheckResult::default()).into_response();
+    };
+    let mut g = state.locked();
     let now = now();
-    if g.users.get(&uname).is_none() {
-        return (StatusCode::NOT_FOUND, Json(CheckResult::default()));
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        let err = ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED");
+        return clock.refuse(err, CheckResult::default()).into_response();
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), CheckResult::default()).into_response();
+    }
+    let fee = g.fee_schedule.fee(g.fee, ep, now);
+    let open = g.trading_open(&uname, now);
+    if !g.users.contains_key(&uname) {
+        let err = ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER");
+        return clock.refuse(err, CheckResult::default()).into_response();
     }
 
-    let ua = g.users.get_mut(&uname).unwrap();
-    if ua.balance < fee {
-        return (StatusCode::FORBIDDEN, Json(CheckResult::default()));
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = CheckResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res).into_response();
     }
-    ua.balance -= fee;
 
-    if now < start_ts {
-        return (StatusCode::FORBIDDEN, Json(CheckResult::default()));
+    // After settlement the final book stays in view.
+    if !open && g.settlement.is_none() {
+        g.reject(&uname, ep, "MARKET_CLOSED", fee, now);
+        let err = ApiError::new(StatusCode::FORBIDDEN, g.closed_reason(&uname, now));
+        return clock.refuse(err, CheckResult::default()).into_response();
+    }
+    if let Err(code) = g.mode.on_check(&g, &uname, now) {
+        g.reject(&uname, ep, code, fee, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), CheckResult::default()).into_response();
     }
 
-    let res = CheckResult {
-        asks: g.asks.iter().map(|(k,v)| PriceVol {price: *k, vol: *v }).collect()
-    };
-    (StatusCode::OK, Json(res))
+    let body = g.book_snapshot();
+    drop(g);
+    clock.reply_prebuilt(StatusCode::OK, &body)
 }
 
 
 async fn user_ping(
     Path(uname): Path<String>,
     State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
 ) -> (StatusCode, Json<PingResult>) {
-    let mut g = state.lock().unwrap();
-    let fee = g.fee;
-    let start_ts= g.trade_start_nanos;
-    if g.users.get(&uname).is_none() {
-        return (StatusCode::NOT_FOUND, Json(PingResult::default()));
+    let clock = ReqClock::start();
+    let ep = fees::Endpoint::Ping;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), PingResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), PingResult::default());
+    }
+    let fee = g.fee_schedule.fee(g.fee, ep, now);
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), PingResult::default());
     }
 
-    let ua = g.users.get_mut(&uname).unwrap();
-    if ua.balance < fee {
-        return (StatusCode::FORBIDDEN, Json(PingResult::default()));
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = PingResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
     }
-    ua.balance -= fee;
 
-    let ping_res = PingResult{ now_nanos: now(), trade_start_nanos: start_ts, balance: ua.balance };
-    (StatusCode::OK, Json(ping_res))
+    let game_over = g.settlement.is_some();
+    // Seen from this user's own start, if they have one.
+    let start = g.starts.of(&uname).unwrap_or(i64::MIN);
+    let ping_res = PingResult{
+        now_nanos: now,
+        trade_start_nanos: g.calendar.session_open(now.max(start)).max(start),
+        session_open: g.calendar.is_open(now) && now >= start,
+        next_transition: g.calendar.next_transition_from(now, start),
+        balance: g.users[&uname].balance,
+        fees: if game_over { fees::CurrentFees::default() } else { g.fee_schedule.current(g.fee, now) },
+        next_fee_change_nanos: g.fee_schedule.next_change(now).filter(|_| !game_over),
+        game_over,
+        allowance: g.allowance(&uname, now),
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, ping_res)
+}
+
+#[derive(Serialize, Default)]
+struct RespMeta {
+    pub server_time_nanos: i64,
+    pub processing_micros: i64,
+    /// Unique while the process runs; see `/users/:uname/fees`.
+    pub request_id: u64,
+    /// `code` and `message`, on refusals only.
+    #[serde(flatten)]
+    pub error: Option<ApiError>,
 }
 
 #[derive(Serialize, Default)]
 struct BoardResult {
+    pub round: settlement::Round,
+    pub trade_end_nanos: Option<i64>,
     pub done_users:  Vec<(String, UserAccount)>,
-    pub running_users:  Vec<(String, UserAccount)>
+    pub running_users:  Vec<(String, UserAccount)>,
+    /// Final standings, once settled.
+    pub scores: Vec<settlement::SettlementEntry>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
 }
 
 
 
+#[derive(Serialize, Default)]
+struct AnalyticsResult {
+    pub users: usize,
+    pub done_users: usize,
+    /// Trades still held in memory, see `[retention]`.
+    pub trades: usize,
+    pub remaining_ask_vol: i64,
+    pub backups: backup::BackupStats,
+    pub house: HouseAccount,
+    pub house_balance: i64,
+    pub halts: Vec<breaker::Halt>,
+    pub bankruptcies: Vec<bankruptcy::Bankruptcy>,
+    /// See `[memory]`.
+    pub memory: memory::MemoryStats,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+#[derive(Serialize, Default)]
+struct PauseResult {
+    pub paused: bool,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
 #[derive(Serialize, Default)]
 struct CheckResult {
-    pub asks: Vec<PriceVol>
+    pub asks: Vec<AskLevel>,
+    pub bids: Vec<BidLevel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
 }
 
 
 #[derive(Serialize, Default)]
 struct PingResult {
     pub now_nanos: i64,
+    /// Open of the current session, or the next one if closed; never
+    /// before the user's own start.
     pub trade_start_nanos: i64,
+    pub session_open: bool,
+    pub next_transition: Option<calendar::Transition>,
 
     pub balance: i64,
+    /// Fees in force now, see `[fee_schedule]`.
+    pub fees: fees::CurrentFees,
+    pub next_fee_change_nanos: Option<i64>,
+    /// Settled: queries are free and nothing else is accepted.
+    pub game_over: bool,
+    pub allowance: Allowance,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// What the user may do right now, as of just after this ping. Each part
+/// is unset when the limit behind it isn't configured.
+#[derive(Serialize, Default)]
+struct Allowance {
+    /// The `[rate_limit] per_user` bucket.
+    pub rate_limit: Option<ratelimit::Budget>,
+    /// Rejections left this second before `[kill_switch]` counts it.
+    pub rejects_left: Option<u32>,
+    /// Violations towards each `[[penalties]]` rule.
+    pub strikes: Vec<penalty::Strikes>,
+    /// `check_asks` calls the balance pays for at today's fee; unset while
+    /// they are free.
+    pub checks_left: Option<i64>,
+    /// Lots held against each instrument's `max_position`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub positions: Vec<instruments::PositionUse>,
 }
 
 #[derive(Serialize, Default)]
 struct BidResult {
+    pub status: BidStatus,
+    /// Whether anything filled; `fills` has the detail.
     pub trade_succ: bool,
+    pub fills: Vec<BidFill>,
+    /// Lots filled, out of the lots asked for.
+    pub filled_qty: i64,
+    pub qty: i64,
+    /// Paid for `fills`, and in fees for this request.
+    pub total_cost: i64,
+    pub total_fees: i64,
+    /// Lots held once the order is done.
+    pub position: i64,
+    /// Machine-readable cause when the order was refused at entry.
+    pub reject_reason: Option<String>,
+    /// Set once the order passed entry checks, see `GET /users/:uname/orders/:id`.
+    pub order_id: Option<u64>,
+    /// Problems with a JSON order body, one per field.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<orders::FieldError>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum BidStatus {
+    Filled,
+    /// Filled in part; the rest was cancelled.
+    PartiallyFilled,
+    /// On the book, see `[orders] resting_bids`, possibly after a partial fill.
+    Resting,
+    /// Accepted, but nothing matched and nothing rests.
+    Unfilled,
+    /// Held unseen until the close, see `[game_mode] kind = "sealed_bid"`.
+    Sealed,
+    /// Collected for the auction that ends a halt, see `[circuit_breaker]
+    /// reopen_auction`; order events tell what it got.
+    Auction,
+    /// Refused at entry, see `reject_reason`.
+    #[default]
+    Rejected,
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+struct BidFill {
+    pub price: i64,
+    pub vol: i64,
+}
+
+impl BidResult {
+    fn report(&mut self, outcome: allocation::Outcome) {
+        self.fills = outcome.fill.map(|f| BidFill { price: f.price, vol: f.vol }).into_iter().collect();
+        self.total_cost = outcome.fill.map_or(0, matching::fill_cost);
+        self.filled_qty = outcome.fill.map_or(0, |f| f.vol);
+        self.trade_succ = !self.fills.is_empty();
+        self.position = outcome.position;
+        self.status = match (self.trade_succ, outcome.resting) {
+            (_, true) => BidStatus::Resting,
+            (true, false) if outcome.remaining > 0 => BidStatus::PartiallyFilled,
+            (true, false) => BidStatus::Filled,
+            (false, false) => BidStatus::Unfilled,
+        };
+    }
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct UserAccount {
     pub balance: i64,
-    pub done_trade: bool
+    pub done_trade: bool,
+    /// Lots currently held.
+    pub position: i64,
+    /// Total paid for fills, fees excluded.
+    pub notional_spent: i64,
+    /// Price and time of the latest fill.
+    pub exec_price: Option<i64>,
+    pub exec_ts_nanos: Option<i64>,
+    pub fees_paid: i64,
+    pub credit: credit::CreditLine,
+    /// Set once a fee could not be paid, see `[bankruptcy]`.
+    pub bankrupt_at_nanos: Option<i64>,
+    pub quotes: quotes::QuoteUsage,
+    /// Of `position`, lots offered on the book with `place_ask`.
+    pub listed: i64,
+    /// Lots held of each further instrument, see `[[instruments]]`.
+    pub holdings: BTreeMap<String, i64>,
+}
+
+/// Where every unit debited from a user ends up, so money is conserved.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct HouseAccount {
+    pub fees: i64,
+    /// What buyers paid for the house's lots.
+    pub proceeds: i64,
+    pub interest: i64,
+    /// Paid out for held lots at settlement.
+    pub payouts: i64,
+    /// Taken by `[[penalties]]`.
+    pub fines: i64,
+}
+
+impl HouseAccount {
+    fn balance(&self) -> i64 {
+        self.fees + self.proceeds + self.interest + self.fines - self.payouts
+    }
+}
+
+impl AppState {
+    /// A new game from `cfg`: its users at `init_balance` and its asks on
+    /// the book, before any journal replay or restore.
+    fn new(config: &AppConfig, config_path: String) -> AppState {
+        let names = usernames::Names::new(&config.usernames.clone().unwrap_or_default(), &config.users).unwrap();
+        // With no schedule the server starts in the lobby, see `POST /admin/arm`.
+        let calendar = match (config.trade_start_nanos, &config.calendar) {
+            (None, None) => {
+                tracing::warn!("no trade_start_nanos or [calendar]: waiting in the lobby for POST /admin/arm");
+                calendar::Calendar::lobby()
+            }
+            _ => calendar::Calendar::new(config.trade_start_nanos, config.calendar.as_ref()).unwrap(),
+        };
+        let starts = match &config.staggered_start {
+            Some(_) if !calendar.armed() => panic!("[staggered_start] needs trade_start_nanos or [calendar]"),
+            Some(s) => starts::Starts::new(s, &config.users, calendar.first_open()).unwrap(),
+            None => starts::Starts::default(),
+        };
+        let mut st = AppState {
+            config: config.clone(),
+            config_path,
+            users: HashMap::new(),
+            journal: journal::Journal::default(),
+            calendar,
+            starts,
+            init_balance: config.init_balance,
+            late_registration: config.late_registration.clone(),
+            bankruptcy: config.bankruptcy.clone(),
+            bankruptcies: bankruptcy::Bankruptcies::default(),
+            rejections: rejections::Rejections::default(),
+            ledger: ledger::Ledger::default(),
+            fee_schedule: config.fee_schedule.clone().unwrap_or_default(),
+            quotes: config.quotes.clone(),
+            reservations_cfg: config.reservations.clone(),
+            reservations: reservations::Reservations::default(),
+            loans_cfg: config.loans.clone(),
+            loans: loans::Loans::default(),
+            market_data: config.market_data.clone(),
+            market: market::Market::default(),
+            settlement_cfg: config.settlement.clone().unwrap_or_default(),
+            pending_settlement: None,
+            settlement: None,
+            trade_end_nanos: config.trade_end_nanos,
+            instruments: instruments::Instruments::new(&config.instruments).unwrap(),
+            fee: config.fee,
+            book: matching::OrderBook::default(),
+            book_view: config.book_view.unwrap_or_default().volumes,
+            mode: modes::build(&config.game_mode.clone().unwrap_or_default()).unwrap(),
+            rules: rules::Rules::new(&config.rules).unwrap(),
+            ask_expiry: BTreeMap::new(),
+            tape: tape::Tape::default(),
+            analytics_db: config.analytics.as_ref().map(|a| a.db_path.clone()),
+            prune_dir: config.retention.as_ref().and_then(|r| r.prune_dir.clone()),
+            forgotten_users: 0,
+            memory: memory::MemoryStats::default(),
+            backup_stats: backup::BackupStats::default(),
+            backup_dir: config.backup.as_ref().map(|b| b.dir.clone()),
+            pending_restore: None,
+            paused: false,
+            handoff_token: config.handoff.as_ref().map(|h| h.token.clone()),
+            handed_off_to: None,
+            risk: config.risk.clone().unwrap_or_default(),
+            credit: config.credit.clone().unwrap_or_default(),
+            kill_switch: config.kill_switch.clone(),
+            rate_limiter: config.rate_limit.as_ref().map(ratelimit::RateLimiter::new),
+            reject_trackers: HashMap::new(),
+            nonces: signing::NonceCache::default(),
+            penalties: penalty::PenaltyBox::new(config.penalties.clone()),
+            public_board: config.public_board.clone(),
+            house: HouseAccount::default(),
+            issued: invariants::Issuance::default(),
+            book_snapshot: None,
+            board_snapshot: None,
+            board_version: tokio::sync::watch::Sender::new(0),
+            // Keys may be listed under any name the user goes by.
+            user_keys: config
+                .user_keys
+                .iter()
+                .map(|(u, k)| (names.canonical(u).unwrap_or(u).to_owned(), k.clone()))
+                .collect(),
+            feeds: feed::Feeds::default(),
+            orders: orders::OrderStore::default(),
+            orders_cfg: config.orders.clone().unwrap_or_default(),
+            circuit_breaker: config.circuit_breaker.clone(),
+            breaker: breaker::Breaker::default(),
+            allocation: config.allocation.clone().unwrap_or_default(),
+            batches: allocation::Batches::default(),
+            speed_bump: config.speed_bump.clone().map(speedbump::SpeedBump::new),
+            latency_floor: config.latency_floor.clone(),
+            latency: latency::Latency::default(),
+            names,
+        };
+        for u in config.users.iter() {
+            st.users.insert(u.to_owned(), UserAccount { 
+                balance: config.init_balance, done_trade: false, position: 0, notional_spent: 0,
+                exec_price: None, exec_ts_nanos: None, fees_paid: 0,
+                credit: credit::CreditLine::new(&st.credit, now()),
+                bankrupt_at_nanos: None,
+                quotes: quotes::QuoteUsage::default(),
+                listed: 0,
+                holdings: BTreeMap::new(),
+            });
+        }
+
+        for pv in config.asks.iter() {
+            st.book.asks.insert(pv.price, pv.vol);
+            if let Some(at) = pv.expires_at_nanos {
+                st.ask_expiry.insert(pv.price, at);
+            }
+        }
+        st.issued = invariants::Issuance {
+            cash: st.users.values().map(|ua| ua.balance).sum(),
+            units: st.book.asks.values().sum(),
+        };
+        st
+    }
+
+    fn accrue(&mut self, uname: &str, now: i64) {
+        if let Some(ua) = self.users.get_mut(uname) {
+            let charge = credit::accrue(ua, &self.credit, now);
+            if charge != 0 {
+                let balance = ua.balance;
+                self.house.interest += charge;
+                self.board_changed();
+                self.feeds.send(uname, feed::UserEvent::Interest { amount: charge, balance, ts_nanos: now });
+                self.balance_moved(uname, ledger::Reason::Interest, -charge, None, now);
+            }
+        }
+    }
+
+    fn accrue_all(&mut self, now: i64) {
+        let mut charged = Vec::new();
+        for (u, ua) in self.users.iter_mut() {
+            let charge = credit::accrue(ua, &self.credit, now);
+            if charge != 0 {
+                self.house.interest += charge;
+                let balance = ua.balance;
+                self.feeds.send(u, feed::UserEvent::Interest { amount: charge, balance, ts_nanos: now });
+                charged.push((u.clone(), charge));
+            }
+        }
+        if !charged.is_empty() {
+            self.board_changed();
+        }
+        for (u, charge) in charged {
+            self.balance_moved(&u, ledger::Reason::Interest, -charge, None, now);
+        }
+    }
+
+    /// After any change to a balance: the ledger records why, and the
+    /// user's feed is told.
+    fn balance_moved(&mut self, uname: &str, reason: ledger::Reason, delta: i64, request_id: Option<u64>, now: i64) {
+        let balance = self.users[uname].balance;
+        if self.ledger.record(uname, reason, delta, balance, request_id, now) {
+            self.feeds.send(uname, feed::UserEvent::Balance { reason, delta, balance, ts_nanos: now });
+        }
+    }
+
+    /// Whether `uname` may trade now: in session, past their start, and
+    /// before the end of the game.
+    fn trading_open(&self, uname: &str, now: i64) -> bool {
+        self.calendar.is_open(now)
+            && self.starts.started(uname, now)
+            && self.trade_end_nanos.map_or(true, |end| now < end)
+            && self.settlement.is_none()
+    }
+
+    /// The code for a refusal because `trading_open` is false: the rejection
+    /// ledger says `MARKET_CLOSED` either way.
+    fn closed_reason(&self, uname: &str, now: i64) -> &'static str {
+        let start = self.starts.of(uname).unwrap_or(i64::MIN).max(self.calendar.first_open());
+        if now < start {
+            "TRADE_NOT_STARTED"
+        } else {
+            "MARKET_CLOSED"
+        }
+    }
+
+    /// Notes a refused paid call in the user's rejection ledger; calls from
+    /// unknown users aren't recorded.
+    fn reject(&mut self, uname: &str, ep: fees::Endpoint, reason: &str, fee_charged: i64, now: i64) {
+        if self.users.contains_key(uname) {
+            self.rejections.record(uname, ep, reason, fee_charged, now);
+        }
+    }
+
+    fn allowance(&self, uname: &str, now: i64) -> Allowance {
+        let check_fee = self.fee_schedule.fee(self.fee, fees::Endpoint::CheckAsks, now);
+        let floor = self.bankruptcy.as_ref().map_or(0, |b| b.floor);
+        let free = check_fee <= 0 || self.settlement.is_some();
+        Allowance {
+            rate_limit: self.rate_limiter.as_ref().and_then(|rl| rl.user_budget(uname)),
+            rejects_left: self.kill_switch.as_ref().map(|cfg| {
+                self.reject_trackers.get(uname).map_or(cfg.max_rejects_per_sec, |t| t.rejects_left(cfg, now))
+            }),
+            strikes: self.penalties.strikes(uname, now),
+            checks_left: (!free).then(|| (self.users[uname].balance - floor).max(0) / check_fee),
+            positions: self.instruments.position_use(&self.users[uname].holdings),
+        }
+    }
+
+    /// Brings interest up to date and takes the request fee. If the balance
+    /// can't cover it nothing is charged, and with `[bankruptcy]` set the
+    /// account is bankrupt from then on. Once settled, looking is free.
+    fn charge_request(&mut self, uname: &str, fee: i64, ep: fees::Endpoint, request_id: Option<u64>, now: i64) -> Result<(), &'static str> {
+        if self.settlement.is_some() {
+            return Ok(());
+        }
+        self.accrue(uname, now);
+        let ua = self.users.get_mut(uname).unwrap();
+        if ua.bankrupt_at_nanos.is_some() {
+            return Err("BANKRUPT");
+        }
+        let covered = match &self.bankruptcy {
+            None => matching::charge_fee(ua.balance, fee),
+            Some(b) => matching::charge_fee_to_floor(ua.balance, fee, b.floor),
+        }
+        .is_some();
+        if !covered {
+            if self.bankruptcy.is_none() {
+                return Err("INSUFFICIENT_FUNDS");
+            }
+            ua.bankrupt_at_nanos = Some(now);
+            let balance = ua.balance;
+            tracing::warn!("{} is bankrupt: balance {} can't cover fee {}", uname, balance, fee);
+            self.bankruptcies.record(uname, balance, fee, now);
+            self.board_changed();
+            self.feeds.send(uname, feed::UserEvent::Bankrupt { balance, fee, ts_nanos: now });
+            return Err("BANKRUPT");
+        }
+        self.pay_fee(uname, fee, Some(ep), request_id, now);
+        Ok(())
+    }
+
+    /// Takes a fee the balance is known to cover.
+    fn pay_fee(&mut self, uname: &str, fee: i64, ep: Option<fees::Endpoint>, request_id: Option<u64>, now: i64) {
+        self.users.get_mut(uname).unwrap().fees_paid += fee;
+        let balance = self.debit(uname, fee);
+        self.house.fees += fee;
+        self.board_changed();
+        self.feeds.send(uname, feed::UserEvent::Fee { amount: fee, balance, ts_nanos: now });
+        self.balance_moved(uname, ledger::Reason::Fee(ep), -fee, request_id, now);
+        self.journal.record(|| journal::Event::Fee { uname: uname.to_owned(), amount: fee, endpoint: ep, request_id, ts_nanos: now });
+    }
+
+    /// The `check_asks` body, serialized at most once per book change so a
+    /// burst of checks neither copies nor re-encodes the whole book.
+    fn book_snapshot(&mut self) -> Prebuilt {
+        if self.book_snapshot.is_none() {
+            #[derive(Serialize)]
+            struct Body {
+                asks: Vec<AskLevel>,
+                bids: Vec<BidLevel>,
+            }
+            let body = Body {
+                asks: self.ask_levels(),
+                bids: self.book.bid_levels().map(|(price, orders)| BidLevel { price, orders }).collect(),
+            };
+            self.book_snapshot = Some(Prebuilt::new(&body));
+        }
+        self.book_snapshot.clone().unwrap()
+    }
+
+    fn ask_levels(&self) -> Vec<AskLevel> {
+        book_view::levels(self.book_view, &self.book.asks, &self.ask_expiry, &self.config.asks)
+    }
+
+    fn check_breaker(&mut self, now: i64) {
+        let Some(cfg) = &self.circuit_breaker else {
+            return;
+        };
+        if let Some(h) = self.breaker.check(cfg, &self.tape, now) {
+            tracing::warn!("circuit breaker: prices {}..{} moved too far, halted until {}", h.low, h.high, h.until_nanos);
+            self.feeds.send_all(feed::UserEvent::Halt { until_nanos: h.until_nanos, low: h.low, high: h.high });
+        }
+    }
+
+    fn notify_order(&mut self, id: u64, now: i64) {
+        let o = &self.orders.orders[&id];
+        self.feeds.send(&o.uname, feed::UserEvent::Order {
+            id,
+            status: o.status,
+            remaining: o.remaining,
+            ts_nanos: now,
+        });
+    }
+
+    /// Books a fill for `uname` that already left the ask ladder.
+    fn fill(&mut self, uname: &str, fill: matching::Fill, now: i64) {
+        self.journal.record(|| journal::Event::Fill { uname: uname.to_owned(), price: fill.price, vol: fill.vol, ts_nanos: now });
+        self.book_snapshot = None;
+        self.board_changed();
+        let cost = matching::fill_cost(fill);
+        let balance = self.debit(uname, cost);
+        let ua = self.users.get_mut(uname).unwrap();
+        ua.done_trade = true;
+        ua.exec_price = Some(fill.price);
+        ua.exec_ts_nanos = Some(now);
+        ua.position += fill.vol;
+        ua.notional_spent += cost;
+        self.feeds.send(uname, feed::UserEvent::Fill { price: fill.price, vol: fill.vol, balance, ts_nanos: now });
+        self.balance_moved(uname, ledger::Reason::Trade, -cost, None, now);
+        let seq = self.record_trade(uname, fill.price, fill.vol, now);
+        self.market.send(|| market::MarketEvent::Trade { seq, price: fill.price, vol: fill.vol, ts_nanos: now });
+        // Lots users listed are paid for to them; the rest to the house.
+        let mut house_vol = fill.vol;
+        for (seller, vol) in self.book.take_sold(fill.price) {
+            house_vol -= vol;
+            let balance = self.debit(&seller, -fill.price * vol);
+            let ua = self.users.get_mut(&seller).unwrap();
+            ua.position -= vol;
+            ua.listed -= vol;
+            self.feeds.send(&seller, feed::UserEvent::Sold { price: fill.price, vol, balance, ts_nanos: now });
+            self.balance_moved(&seller, ledger::Reason::Trade, fill.price * vol, None, now);
+            self.tape.record_sale(seq, &seller, uname, fill.price, vol, now);
+        }
+        self.house.proceeds += fill.price * house_vol;
+        self.feeds.send_quotes(|| feed::UserEvent::Book { asks: self.ask_levels(), ts_nanos: now });
+        self.market.send(|| self.market_book(now));
+    }
+
+    /// After any change to a balance or who is done: `/board` is rebuilt,
+    /// and `/admin/board/stream` sends the new board.
+    fn board_changed(&mut self) {
+        self.board_snapshot = None;
+        self.board_version.send_modify(|v| *v += 1);
+    }
+
+    /// After any change to the book: `check_asks` re-encodes it, and
+    /// `/ws/market` connections get it.
+    fn book_changed(&mut self, now: i64) {
+        self.book_snapshot = None;
+        self.market.send(|| self.market_book(now));
+    }
+
+    fn market_book(&self, now: i64) -> market::MarketEvent {
+        market::MarketEvent::Book {
+            asks: self.ask_levels(),
+            bids: self.book.bid_levels().map(|(price, orders)| BidLevel { price, orders }).collect(),
+            ts_nanos: now,
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{contention::StateLock, errors::ApiError, feed, fees::Endpoint, now, AppState, AskLevel, BidLevel};
+
+/// Events a connection falls behind by before it is told it lagged.
+const MARKET_BUFFER: usize = 1024;
+
+/// Offers `GET /ws/market`; without this section the route answers 404.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MarketDataConfig {
+    /// Charged once per connection, like any other paid call.
+    pub connection_fee: i64,
+}
+
+/// Public market data, the same for every connection. Trades don't name
+/// the buyer.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    /// The whole book after it changed, as `check_asks` would show it.
+    Book { asks: Vec<AskLevel>, bids: Vec<BidLevel>, ts_nanos: i64 },
+    Trade { seq: u64, price: i64, vol: i64, ts_nanos: i64 },
+    /// Scheduled house volume, for `[[injections]]` that are announced; a
+    /// `book` follows.
+    Injection { price: i64, vol: i64, expires_at_nanos: Option<i64>, ts_nanos: i64 },
+    /// House volume withdrawn because its level expired; a `book` follows.
+    AskExpired { price: i64, vol: i64, ts_nanos: i64 },
+    /// Where the reopen auction would uncross if the halt ended now, sent
+    /// as bids are collected or withdrawn; `price` is null while nothing
+    /// would trade. See `[circuit_breaker] reopen_auction`.
+    Indicative { price: Option<i64>, vol: i64, reopen_nanos: i64, ts_nanos: i64 },
+    /// Sent in place of events dropped because the client read too slowly.
+    Lagged { missed: u64 },
+}
+
+pub struct Market {
+    tx: broadcast::Sender<MarketEvent>,
+}
+
+impl Default for Market {
+    fn default() -> Self {
+        Market { tx: broadcast::channel(MARKET_BUFFER).0 }
+    }
+}
+
+impl std::fmt::Debug for Market {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Market").field("connections", &self.tx.receiver_count()).finish()
+    }
+}
+
+impl Market {
+    /// Builds the event only if someone is listening.
+    pub fn send(&self, ev: impl FnOnce() -> MarketEvent) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(ev());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarketQuery {
+    /// Who pays for the connection.
+    pub uname: String,
+    /// For clients that can't set headers on a WebSocket handshake.
+    pub key: Option<String>,
+}
+
+/// Pushes book changes and trades as they happen, for
+/// `[market_data] connection_fee` per connection. Requires the user's key
+/// like `/users/:uname/ws`.
+pub async fn market_ws(
+    Query(q): Query<MarketQuery>,
+    headers: HeaderMap,
+    State(state): State<Arc<Mutex<AppState>>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let (first, rx) = {
+        let mut g = state.locked();
+        let Some(cfg) = g.market_data.clone() else {
+            return ApiError::with(StatusCode::NOT_FOUND, "NOT_OFFERED", "market data is not offered").into_response();
+        };
+        if !g.users.contains_key(&q.uname) {
+            return ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER").into_response();
+        }
+        if !feed::key_matches(&g, &q.uname, &headers, q.key.as_deref()) {
+            return ApiError::with(StatusCode::UNAUTHORIZED, "BAD_KEY", "missing or wrong user key").into_response();
+        }
+        let now = now();
+        let fee = g.fee_schedule.fee(cfg.connection_fee, Endpoint::MarketData, now);
+        if let Err(reason) = g.charge_request(&q.uname, fee, Endpoint::MarketData, None, now) {
+            g.reject(&q.uname, Endpoint::MarketData, reason, 0, now);
+            return ApiError::new(StatusCode::FORBIDDEN, reason).into_response();
+        }
+        let rx = g.market.tx.subscribe();
+        // Start from the current book rather than the next change.
+        let first = g.market_book(now);
+        (first, rx)
+    };
+    ws.on_upgrade(move |socket| pump(socket, first, rx))
+}
+
+async fn pump(mut socket: WebSocket, first: MarketEvent, mut rx: broadcast::Receiver<MarketEvent>) {
+    let mut next = Some(first);
+    loop {
+        let ev = match next.take() {
+            Some(ev) => ev,
+            None => tokio::select! {
+                ev = rx.recv() => match ev {
+                    Ok(ev) => ev,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => MarketEvent::Lagged { missed },
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                },
+            },
+        };
+        if socket.send(Message::Text(serde_json::to_string(&ev).unwrap())).await.is_err() {
+            return;
+        }
+    }
+}
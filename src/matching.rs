@@ -0,0 +1,394 @@
+//! The accounting core of order entry, kept free of server state so it can
+//! be property-tested and fuzzed on its own (see `fuzz/`). Nothing here may
+//! depend on the rest of the crate.
+
+use std::collections::{BTreeMap, VecDeque};
+
+/// Price -> lots offered at that price.
+pub type Ladder = BTreeMap<i64, i64>;
+
+/// Both sides of the book. Asks are volume per price, some of it listed
+/// by users; bids are resting orders, by id, queued per price in priority
+/// order.
+#[derive(Debug, Clone, Default)]
+pub struct OrderBook {
+    pub asks: Ladder,
+    bids: BTreeMap<i64, VecDeque<(i64, u64)>>,
+    /// Who listed which of the lots in `asks`, oldest first. The rest of a
+    /// level belongs to the house.
+    sells: BTreeMap<i64, VecDeque<(String, i64)>>,
+}
+
+impl OrderBook {
+    /// Offers `vol` of `seller`'s lots at `price`.
+    pub fn list_ask(&mut self, price: i64, seller: &str, vol: i64) {
+        *self.asks.entry(price).or_default() += vol;
+        self.queue_sell(price, seller, vol);
+    }
+
+    /// Records `vol` lots already in `asks` at `price` as `seller`'s.
+    pub fn queue_sell(&mut self, price: i64, seller: &str, vol: i64) {
+        self.sells.entry(price).or_default().push_back((seller.to_owned(), vol));
+    }
+
+    /// After a fill at `price`, who was sold to. House volume at a level
+    /// goes before listed lots, which go oldest first.
+    pub fn take_sold(&mut self, price: i64) -> Vec<(String, i64)> {
+        let Some(q) = self.sells.get_mut(&price) else {
+            return Vec::new();
+        };
+        let left = self.asks.get(&price).copied().unwrap_or(0);
+        let mut over = q.iter().map(|(_, v)| *v).sum::<i64>() - left;
+        let mut sold = Vec::new();
+        while over > 0 {
+            let (seller, vol) = q.front_mut().unwrap();
+            let take = over.min(*vol);
+            sold.push((seller.clone(), take));
+            *vol -= take;
+            over -= take;
+            if *vol == 0 {
+                q.pop_front();
+            }
+        }
+        if q.is_empty() {
+            self.sells.remove(&price);
+        }
+        sold
+    }
+
+    /// Queues lots `take_sold` reported at the head of the level again, as
+    /// if they had never been sold.
+    pub fn unsell(&mut self, price: i64, sold: &[(String, i64)]) {
+        let q = self.sells.entry(price).or_default();
+        for (seller, vol) in sold.iter().rev() {
+            q.push_front((seller.clone(), *vol));
+        }
+        if q.is_empty() {
+            self.sells.remove(&price);
+        }
+    }
+
+    /// Takes all of `seller`'s listed lots off the book, returning how many.
+    pub fn withdraw_asks(&mut self, seller: &str) -> i64 {
+        let mut total = 0;
+        for (price, q) in self.sells.iter_mut() {
+            let vol: i64 = q.iter().filter(|(s, _)| s == seller).map(|(_, v)| *v).sum();
+            q.retain(|(s, _)| s != seller);
+            if let Some(v) = self.asks.get_mut(price) {
+                *v -= vol;
+                if *v <= 0 {
+                    self.asks.remove(price);
+                }
+            }
+            total += vol;
+        }
+        self.sells.retain(|_, q| !q.is_empty());
+        total
+    }
+
+    /// Lots users have listed at `price`; the rest of the level is the house's.
+    pub fn listed_at(&self, price: i64) -> i64 {
+        self.sells.get(&price).map_or(0, |q| q.iter().map(|(_, v)| *v).sum())
+    }
+
+    /// Listed lots as (price, seller, vol), in queue order per price.
+    pub fn sell_lots(&self) -> impl Iterator<Item = (i64, &str, i64)> + '_ {
+        self.sells.iter().flat_map(|(p, q)| q.iter().map(move |(s, v)| (*p, s.as_str(), *v)))
+    }
+
+    /// Queues order `id` at `price` behind everything with the same or
+    /// earlier `priority`.
+    pub fn rest_bid(&mut self, price: i64, id: u64, priority: i64) {
+        let q = self.bids.entry(price).or_default();
+        let at = q.partition_point(|(p, _)| *p <= priority);
+        q.insert(at, (priority, id));
+    }
+
+    /// Takes order `id` off the book, returning whether it was resting.
+    pub fn remove_bid(&mut self, price: i64, id: u64) -> bool {
+        let Some(q) = self.bids.get_mut(&price) else {
+            return false;
+        };
+        let before = q.len();
+        q.retain(|(_, i)| *i != id);
+        let removed = q.len() != before;
+        if q.is_empty() {
+            self.bids.remove(&price);
+        }
+        removed
+    }
+
+    /// The first bid in line at `price`, taken off the book.
+    pub fn pop_bid(&mut self, price: i64) -> Option<u64> {
+        let q = self.bids.get_mut(&price)?;
+        let (_, id) = q.pop_front()?;
+        if q.is_empty() {
+            self.bids.remove(&price);
+        }
+        Some(id)
+    }
+
+    /// The bids resting at `price`, first in line first.
+    pub fn bid_queue(&self, price: i64) -> impl Iterator<Item = u64> + '_ {
+        self.bids.get(&price).into_iter().flat_map(|q| q.iter().map(|(_, id)| *id))
+    }
+
+    /// Resting bids per price, best first.
+    pub fn bid_levels(&self) -> impl Iterator<Item = (i64, usize)> + '_ {
+        self.bids.iter().rev().map(|(p, q)| (*p, q.len()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub price: i64,
+    pub vol: i64,
+}
+
+/// Balance after paying `fee`, or `None` if it can't be covered. Fees never
+/// draw on credit.
+pub fn charge_fee(balance: i64, fee: i64) -> Option<i64> {
+    charge_fee_to_floor(balance, fee, 0)
+}
+
+/// As `charge_fee`, but the balance may go as low as `floor`.
+pub fn charge_fee_to_floor(balance: i64, fee: i64, floor: i64) -> Option<i64> {
+    balance.checked_sub(fee).filter(|b| *b >= floor)
+}
+
+/// Takes up to `qty` lots off the ladder if something is offered at exactly
+/// `price`; a short level fills partially. Levels are removed once empty,
+/// so the book never shows zero volume.
+pub fn match_bid(asks: &mut Ladder, price: i64, qty: i64) -> Option<Fill> {
+    let v = asks.get_mut(&price)?;
+    if *v <= 0 || qty <= 0 {
+        return None;
+    }
+    let vol = qty.min(*v);
+    *v -= vol;
+    if *v <= 0 {
+        asks.remove(&price);
+    }
+    Some(Fill { price, vol })
+}
+
+/// What a fill costs the buyer. Saturates rather than wrapping, though
+/// `book::admissible` already refuses a cost that doesn't fit.
+pub fn fill_cost(fill: Fill) -> i64 {
+    fill.price.saturating_mul(fill.vol)
+}
+
+/// Splits `vol` lots among bids wanting `wants` lots, in proportion to
+/// size. Everyone is filled in full if there is enough. Otherwise each bid
+/// gets the floor of its exact share, and the lots left over go one each
+/// to the largest remainders. Ties are broken by `pick(n)`, which must
+/// return an index below `n`; pass a random source so arrival order within
+/// the window earns nothing.
+pub fn pro_rata(vol: i64, wants: &[i64], mut pick: impl FnMut(usize) -> usize) -> Vec<i64> {
+    let total: i128 = wants.iter().map(|w| *w as i128).sum();
+    if total <= vol as i128 {
+        return wants.to_vec();
+    }
+    let mut alloc: Vec<i64> = wants.iter().map(|w| (vol as i128 * *w as i128 / total) as i64).collect();
+    let mut left = vol - alloc.iter().sum::<i64>();
+
+    let mut order: Vec<usize> = (0..wants.len()).collect();
+    for i in (1..order.len()).rev() {
+        order.swap(i, pick(i + 1));
+    }
+    order.sort_by_key(|&i| std::cmp::Reverse(vol as i128 * wants[i] as i128 % total));
+    for i in order {
+        if left == 0 {
+            break;
+        }
+        alloc[i] += 1;
+        left -= 1;
+    }
+    alloc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn ladder() -> impl Strategy<Value = Ladder> {
+        prop::collection::btree_map(1i64..50, 1i64..5, 0..10)
+    }
+
+    /// (fee, bid price, quantity); prices spill outside the ladder and
+    /// quantities past a level's volume on purpose.
+    fn requests() -> impl Strategy<Value = Vec<(i64, i64, i64)>> {
+        prop::collection::vec((0i64..20, -5i64..60, 0i64..8), 0..200)
+    }
+
+    fn wants() -> impl Strategy<Value = Vec<i64>> {
+        prop::collection::vec(1i64..10, 0..30)
+    }
+
+    #[test]
+    fn pro_rata_rounds_unit_bids_to_a_lottery() {
+        let mut next = 0;
+        let alloc = pro_rata(2, &[1, 1, 1, 1], |n| {
+            next += 1;
+            next % n
+        });
+        assert_eq!(alloc.iter().sum::<i64>(), 2);
+        assert!(alloc.iter().all(|a| *a == 0 || *a == 1));
+    }
+
+    #[test]
+    fn fill_cost_saturates() {
+        assert_eq!(fill_cost(Fill { price: 7, vol: 3 }), 21);
+        assert_eq!(fill_cost(Fill { price: i64::MAX / 2, vol: 3 }), i64::MAX);
+    }
+
+    proptest! {
+        #[test]
+        fn book_never_shows_empty_or_negative_levels(mut asks in ladder(), reqs in requests()) {
+            for (_, price, qty) in reqs {
+                match_bid(&mut asks, price, qty);
+                prop_assert!(asks.values().all(|v| *v > 0));
+            }
+        }
+
+        #[test]
+        fn fills_never_exceed_the_bid(mut asks in ladder(), reqs in requests()) {
+            for (_, price, qty) in reqs {
+                if let Some(fill) = match_bid(&mut asks, price, qty) {
+                    prop_assert!(fill.price <= price);
+                    prop_assert!(fill.vol > 0 && fill.vol <= qty);
+                }
+            }
+        }
+
+        #[test]
+        fn volume_is_conserved(mut asks in ladder(), reqs in requests()) {
+            let before: i64 = asks.values().sum();
+            let mut filled = 0;
+            for (_, price, qty) in reqs {
+                filled += match_bid(&mut asks, price, qty).map_or(0, |f| f.vol);
+            }
+            prop_assert_eq!(asks.values().sum::<i64>() + filled, before);
+        }
+
+        #[test]
+        fn balance_moves_by_fees_and_fills_only(
+            mut asks in ladder(),
+            reqs in requests(),
+            start in 0i64..1000,
+        ) {
+            let mut balance = start;
+            let (mut fees, mut spent) = (0, 0);
+            for (fee, price, qty) in reqs {
+                let Some(b) = charge_fee(balance, fee) else {
+                    prop_assert!(balance < fee);
+                    continue;
+                };
+                prop_assert!(b >= 0);
+                balance = b;
+                fees += fee;
+                if let Some(fill) = match_bid(&mut asks, price, qty) {
+                    balance -= fill_cost(fill);
+                    spent += fill_cost(fill);
+                }
+            }
+            prop_assert_eq!(start - balance, fees + spent);
+        }
+
+        #[test]
+        fn short_levels_fill_partially(mut asks in ladder(), price in 1i64..50, qty in 1i64..8) {
+            let offered = asks.get(&price).copied().unwrap_or(0);
+            let fill = match_bid(&mut asks, price, qty);
+            prop_assert_eq!(fill.map_or(0, |f| f.vol), qty.min(offered));
+            prop_assert_eq!(asks.get(&price).copied().unwrap_or(0), offered - qty.min(offered));
+        }
+
+        #[test]
+        fn listed_lots_sell_after_the_house(
+            house in 0i64..5,
+            listed in prop::collection::vec((0usize..3, 1i64..4), 0..6),
+            qty in 1i64..20,
+        ) {
+            let mut book = OrderBook::default();
+            if house > 0 {
+                book.asks.insert(10, house);
+            }
+            for (seller, vol) in listed.iter() {
+                book.list_ask(10, &seller.to_string(), *vol);
+            }
+            let total: i64 = listed.iter().map(|(_, v)| v).sum();
+            let fill = match_bid(&mut book.asks, 10, qty);
+            let sold: i64 = book.take_sold(10).iter().map(|(_, v)| v).sum();
+            let filled = fill.map_or(0, |f| f.vol);
+            prop_assert_eq!(sold, (filled - house).max(0));
+            let still_listed: i64 = book.sell_lots().map(|(_, _, v)| v).sum();
+            prop_assert_eq!(still_listed, total - sold);
+            prop_assert!(still_listed <= book.asks.get(&10).copied().unwrap_or(0));
+        }
+
+        #[test]
+        fn resting_bids_leave_in_priority_order(bids in prop::collection::vec((0i64..5, 0i64..100), 0..50)) {
+            let mut book = OrderBook::default();
+            for (id, (price, priority)) in bids.iter().enumerate() {
+                book.rest_bid(*price, id as u64, *priority);
+            }
+            for price in 0..5 {
+                let mut last = (i64::MIN, 0);
+                while let Some(id) = book.pop_bid(price) {
+                    let (p, prio) = bids[id as usize];
+                    prop_assert_eq!(p, price);
+                    // Earlier priority first; equal priority in arrival order.
+                    prop_assert!((prio, id) > last);
+                    last = (prio, id);
+                }
+            }
+            prop_assert_eq!(book.bid_levels().count(), 0);
+        }
+
+        #[test]
+        fn bid_queue_is_the_order_bids_leave_in(bids in prop::collection::vec(0i64..100, 0..20)) {
+            let mut book = OrderBook::default();
+            for (id, priority) in bids.iter().enumerate() {
+                book.rest_bid(7, id as u64, *priority);
+            }
+            let queued: Vec<u64> = book.bid_queue(7).collect();
+            let popped: Vec<u64> = std::iter::from_fn(|| book.pop_bid(7)).collect();
+            prop_assert_eq!(queued, popped);
+        }
+
+        #[test]
+        fn fees_never_cross_the_floor(balance in -1000i64..1000, fee in 0i64..100, floor in -500i64..500) {
+            match charge_fee_to_floor(balance, fee, floor) {
+                Some(b) => prop_assert!(b >= floor && b == balance - fee),
+                None => prop_assert!(balance - fee < floor),
+            }
+        }
+
+        #[test]
+        fn pro_rata_allocates_everything_it_can(vol in 0i64..100, wants in wants(), seed: u64) {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let alloc = pro_rata(vol, &wants, |n| rng.usize(..n));
+            let want: i64 = wants.iter().sum();
+            prop_assert_eq!(alloc.iter().sum::<i64>(), vol.min(want));
+        }
+
+        #[test]
+        fn pro_rata_never_overfills_and_stays_within_a_lot_of_the_share(
+            vol in 0i64..100,
+            wants in wants(),
+            seed: u64,
+        ) {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let alloc = pro_rata(vol, &wants, |n| rng.usize(..n));
+            let total: i64 = wants.iter().sum();
+            for (a, w) in alloc.iter().zip(&wants) {
+                prop_assert!(*a >= 0 && a <= w);
+                if total > vol {
+                    let floor = vol * w / total;
+                    prop_assert!(*a == floor || *a == floor + 1);
+                }
+            }
+        }
+    }
+}
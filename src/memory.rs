@@ -0,0 +1,126 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, now, retention, AppState, Prebuilt};
+
+/// Caps on what the in-memory histories may hold, in approximate bytes.
+/// Past a cap the oldest entries go first; evicted trades are archived to
+/// `[retention] prune_dir` like pruned ones. Unset caps only measure.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemoryConfig {
+    pub tape_max_bytes: Option<usize>,
+    pub timeline_max_bytes: Option<usize>,
+    /// Only filled and cancelled orders are evicted.
+    pub orders_max_bytes: Option<usize>,
+    pub rejections_max_bytes: Option<usize>,
+    pub ledger_max_bytes: Option<usize>,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            tape_max_bytes: None,
+            timeline_max_bytes: None,
+            orders_max_bytes: None,
+            rejections_max_bytes: None,
+            ledger_max_bytes: None,
+            interval_secs: default_interval_secs(),
+        }
+    }
+}
+
+/// Approximate bytes held by each store at the last measurement.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub tape: usize,
+    pub timeline: usize,
+    pub orders: usize,
+    pub rejections: usize,
+    pub ledger: usize,
+    /// Cached `check_asks` and `/board` bodies; rebuilt on demand, never evicted.
+    pub snapshots: usize,
+}
+
+/// Entries evicted from each store since start.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Evictions {
+    pub tape: u64,
+    pub timeline: u64,
+    pub orders: u64,
+    pub rejections: u64,
+    pub ledger: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryStats {
+    pub usage: Usage,
+    pub evicted: Evictions,
+    pub measured_at_nanos: i64,
+}
+
+fn measure(g: &AppState) -> Usage {
+    let snapshot = |p: &Option<Prebuilt>| p.as_ref().map_or(0, |p| p.0.len());
+    Usage {
+        tape: g.tape.approx_bytes(),
+        timeline: g.feeds.timeline.approx_bytes(),
+        orders: g.orders.approx_bytes(),
+        rejections: g.rejections.approx_bytes(),
+        ledger: g.ledger.approx_bytes(),
+        snapshots: snapshot(&g.book_snapshot) + snapshot(&g.board_snapshot),
+    }
+}
+
+/// Measures the histories every `interval_secs` and evicts whatever is
+/// over its cap, so a loose `[retention]` can't exhaust the host.
+pub fn spawn_guard(cfg: MemoryConfig, state: Arc<Mutex<AppState>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(cfg.interval_secs.max(1)));
+        let (evicted_trades, dir) = {
+            let mut g = state.locked();
+            let trades = cfg.tape_max_bytes.map(|max| g.tape.evict_to(max)).unwrap_or_default();
+            let timeline = cfg.timeline_max_bytes.map_or(0, |max| g.feeds.timeline.evict_to(max));
+            let orders = cfg.orders_max_bytes.map_or(0, |max| g.orders.evict_to(max));
+            let rejections = cfg.rejections_max_bytes.map_or(0, |max| g.rejections.evict_to(max));
+            let ledger = cfg.ledger_max_bytes.map_or(0, |max| g.ledger.evict_to(max));
+            if timeline + orders + rejections + ledger > 0 {
+                tracing::warn!(
+                    "memory caps: evicted {} timeline entries, {} orders, {} rejections, {} ledger lines",
+                    timeline,
+                    orders,
+                    rejections,
+                    ledger
+                );
+            }
+            let usage = measure(&g);
+            let ev = &mut g.memory.evicted;
+            ev.tape += trades.len() as u64;
+            ev.timeline += timeline as u64;
+            ev.orders += orders as u64;
+            ev.rejections += rejections as u64;
+            ev.ledger += ledger as u64;
+            g.memory.usage = usage;
+            g.memory.measured_at_nanos = now();
+            (trades, g.prune_dir.clone())
+        };
+        if evicted_trades.is_empty() {
+            continue;
+        }
+        match dir {
+            Some(dir) => match retention::archive(&dir, &evicted_trades) {
+                Ok(()) => tracing::warn!("memory caps: archived {} trades to {}", evicted_trades.len(), dir),
+                Err(e) => tracing::error!("archiving {} evicted trades failed: {}", evicted_trades.len(), e),
+            },
+            None => tracing::warn!("memory caps: dropped {} trades", evicted_trades.len()),
+        }
+    });
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    contention::{self, StateLock},
+    now, AppState,
+};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+/// `request_error_ratio` covers this many trailing seconds.
+const ERROR_WINDOW_SECS: i64 = 60;
+
+/// Request counts kept outside the state lock, so taking them never waits
+/// on the thing they measure.
+struct RequestStats {
+    total: AtomicU64,
+    errors: AtomicU64,
+    in_flight: AtomicI64,
+    /// `(second, requests, errors)`, oldest first.
+    recent: Mutex<VecDeque<(i64, u64, u64)>>,
+}
+
+static STATS: RequestStats = RequestStats {
+    total: AtomicU64::new(0),
+    errors: AtomicU64::new(0),
+    in_flight: AtomicI64::new(0),
+    recent: Mutex::new(VecDeque::new()),
+};
+
+impl RequestStats {
+    fn record(&self, error: bool, now: i64) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let sec = now / NANOS_PER_SEC;
+        let mut recent = self.recent.lock().unwrap();
+        match recent.back_mut() {
+            Some(b) if b.0 == sec => {
+                b.1 += 1;
+                b.2 += error as u64;
+            }
+            _ => recent.push_back((sec, 1, error as u64)),
+        }
+        while recent.front().is_some_and(|b| b.0 <= sec - ERROR_WINDOW_SECS) {
+            recent.pop_front();
+        }
+    }
+
+    /// Share of requests over the window that got a 4xx or 5xx.
+    fn error_ratio(&self, now: i64) -> f64 {
+        let since = now / NANOS_PER_SEC - ERROR_WINDOW_SECS;
+        let recent = self.recent.lock().unwrap();
+        let (n, err) = recent.iter().filter(|b| b.0 > since).fold((0, 0), |(n, e), b| (n + b.1, e + b.2));
+        if n == 0 {
+            0.0
+        } else {
+            err as f64 / n as f64
+        }
+    }
+}
+
+/// Counts every request and how many are inside the server at once; with
+/// one state lock, those are the ones queued on it or holding it.
+pub async fn track(req: Request, next: Next) -> Response {
+    STATS.in_flight.fetch_add(1, Ordering::Relaxed);
+    let resp = next.run(req).await;
+    STATS.in_flight.fetch_sub(1, Ordering::Relaxed);
+    let status = resp.status();
+    STATS.record(status.is_client_error() || status.is_server_error(), now());
+    resp
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP guess_trade_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE guess_trade_{} gauge", name);
+    for (labels, v) in samples {
+        let _ = writeln!(out, "guess_trade_{}{} {}", name, labels, v);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP guess_trade_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE guess_trade_{} counter", name);
+    for (labels, v) in samples {
+        let _ = writeln!(out, "guess_trade_{}{} {}", name, labels, v);
+    }
+}
+
+/// Prometheus text exposition of the gauges worth paging on during a game.
+pub async fn metrics(State(state): State<Arc<Mutex<AppState>>>) -> Response {
+    let now = now();
+    let mut out = String::new();
+    {
+        let g = state.locked();
+        let until_start = (g.calendar.first_open().saturating_sub(now)).max(0) as f64 / NANOS_PER_SEC as f64;
+        gauge(&mut out, "seconds_until_start", "Seconds until trading first opens; 0 once it has.", &[("", until_start)]);
+
+        let mut remaining = vec![("{book=\"main\"}".to_owned(), g.book.asks.values().sum::<i64>() as f64)];
+        for (s, b) in g.instruments.books.iter() {
+            remaining.push((format!("{{book=\"{}\"}}", s), b.asks.values().sum::<i64>() as f64));
+        }
+        let remaining: Vec<(&str, f64)> = remaining.iter().map(|(l, v)| (l.as_str(), *v)).collect();
+        gauge(&mut out, "remaining_ask_volume", "Lots still offered on each book.", &remaining);
+
+        let done = g.users.values().filter(|ua| ua.done_trade).count();
+        let done_pct = if g.users.is_empty() { 0.0 } else { 100.0 * done as f64 / g.users.len() as f64 };
+        gauge(&mut out, "users_done_percent", "Percentage of users done trading.", &[("", done_pct)]);
+
+        let (u, ev) = (&g.memory.usage, &g.memory.evicted);
+        let bytes = [
+            ("{store=\"tape\"}", u.tape as f64),
+            ("{store=\"timeline\"}", u.timeline as f64),
+            ("{store=\"orders\"}", u.orders as f64),
+            ("{store=\"rejections\"}", u.rejections as f64),
+            ("{store=\"ledger\"}", u.ledger as f64),
+            ("{store=\"snapshots\"}", u.snapshots as f64),
+        ];
+        gauge(&mut out, "memory_bytes", "Approximate bytes held by each in-memory store, see [memory].", &bytes);
+        let evicted = [
+            ("{store=\"tape\"}", ev.tape),
+            ("{store=\"timeline\"}", ev.timeline),
+            ("{store=\"orders\"}", ev.orders),
+            ("{store=\"rejections\"}", ev.rejections),
+            ("{store=\"ledger\"}", ev.ledger),
+        ];
+        counter(&mut out, "memory_evicted_total", "Entries evicted from each store to stay under its cap.", &evicted);
+    }
+    gauge(
+        &mut out,
+        "request_error_ratio",
+        "Share of requests answered 4xx or 5xx over the last 60 seconds.",
+        &[("", STATS.error_ratio(now))],
+    );
+    gauge(
+        &mut out,
+        "requests_in_flight",
+        "Requests being served, waiting on or holding the state lock.",
+        &[("", STATS.in_flight.load(Ordering::Relaxed) as f64)],
+    );
+    counter(&mut out, "requests_total", "Requests served.", &[("", STATS.total.load(Ordering::Relaxed))]);
+    counter(&mut out, "request_errors_total", "Requests answered 4xx or 5xx.", &[("", STATS.errors.load(Ordering::Relaxed))]);
+    contention::write_metrics(&mut out);
+    (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
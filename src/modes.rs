@@ -0,0 +1,230 @@
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{book, contention::StateLock, journal, now, orders::OrderStatus, AppState};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// The rules of the round, under `[game_mode]`. Each kind is a `GameMode`
+/// of its own; `standard` is the game as it always was.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GameModeConfig {
+    #[default]
+    Standard,
+    /// Bids are held unseen and filled at the close, highest price first.
+    SealedBid,
+    /// The house's asks drop by `step` every `interval_secs` the market is
+    /// open, down to `floor`.
+    Dutch {
+        step: i64,
+        interval_secs: u64,
+        #[serde(default)]
+        floor: i64,
+    },
+    /// Prices must be guessed: `check_asks` is refused until settlement.
+    Guessing,
+}
+
+/// What becomes of a bid that passed entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// Matched now, see `book::enter`.
+    Match,
+    /// Held, open but off the book, until the mode fills it.
+    Seal,
+}
+
+/// Hooks where the rule variants differ. Every method defaults to the
+/// standard game, so a mode only overrides what it changes.
+pub trait GameMode: Send + Sync + std::fmt::Debug {
+    /// Whether `uname` may bid for `qty` lots at `price`, and how the bid
+    /// is entered if so.
+    fn on_bid(&self, g: &AppState, uname: &str, price: i64, qty: i64, now: i64) -> Result<Entry, &'static str> {
+        standard_bid(g, uname, price, qty, now).map(|_| Entry::Match)
+    }
+
+    /// Enters the order that replaced an open one; it already passed the
+    /// funding checks.
+    fn on_replace(&self, g: &mut AppState, uname: &str, id: u64, now: i64) {
+        book::enter(g, uname, id, now);
+    }
+
+    /// Whether `uname` may see the book through `check_asks`.
+    fn on_check(&self, _g: &AppState, _uname: &str, _now: i64) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Called about once a second while the server runs.
+    fn on_tick(&self, _g: &mut AppState, _now: i64) {}
+
+    /// Called as settlement starts, before open orders are cancelled.
+    fn on_settle(&self, _g: &mut AppState, _now: i64) {}
+}
+
+/// The entry checks of the standard game: the market is open for the
+/// user, they haven't traded, and they can take the order.
+pub fn standard_bid(g: &AppState, uname: &str, price: i64, qty: i64, now: i64) -> Result<(), &'static str> {
+    let ua = &g.users[uname];
+    if !g.trading_open(uname, now) {
+        Err("MARKET_CLOSED")
+    } else if ua.done_trade {
+        Err("ALREADY_TRADED")
+    } else {
+        book::admissible(g, ua, price, qty)
+    }
+}
+
+#[derive(Debug)]
+pub struct Standard;
+
+impl GameMode for Standard {}
+
+#[derive(Debug)]
+pub struct SealedBid;
+
+impl GameMode for SealedBid {
+    /// One sealed bid per user; it can be cancelled or replaced until the close.
+    fn on_bid(&self, g: &AppState, uname: &str, price: i64, qty: i64, now: i64) -> Result<Entry, &'static str> {
+        standard_bid(g, uname, price, qty, now)?;
+        if g.orders.orders.values().any(|o| o.uname == uname && o.status.is_open()) {
+            return Err("ALREADY_TRADED");
+        }
+        Ok(Entry::Seal)
+    }
+
+    /// The replacement stays sealed.
+    fn on_replace(&self, _g: &mut AppState, _uname: &str, _id: u64, _now: i64) {}
+
+    /// Fills the held bids, highest price first and earliest first at a
+    /// price. A bid its owner can no longer take is cancelled.
+    fn on_settle(&self, g: &mut AppState, now: i64) {
+        let mut sealed: Vec<(i64, i64, u64)> = g
+            .orders
+            .orders
+            .values()
+            .filter(|o| o.status == OrderStatus::Accepted)
+            .map(|o| (o.price, o.priority_nanos, o.id))
+            .collect();
+        sealed.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        for (price, _, id) in sealed {
+            let o = &g.orders.orders[&id];
+            let (uname, qty) = (o.uname.clone(), o.remaining);
+            let eligible = g.users.get(&uname).is_some_and(|ua| book::admissible(g, ua, price, qty).is_ok());
+            if !eligible {
+                g.orders.cancel(id, "INELIGIBLE_AT_MATCH", now);
+                g.notify_order(id, now);
+                continue;
+            }
+            book::enter(g, &uname, id, now);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Dutch {
+    step: i64,
+    interval_nanos: i64,
+    floor: i64,
+    /// When the next step is due, counted from when the market was last
+    /// seen open; kept out of the game's state, so a restart never drops
+    /// prices twice.
+    next_at: AtomicI64,
+}
+
+impl GameMode for Dutch {
+    fn on_tick(&self, g: &mut AppState, now: i64) {
+        if g.settlement.is_some() || g.paused || !g.calendar.is_open(now) {
+            self.next_at.store(0, Ordering::Relaxed);
+            return;
+        }
+        let next_at = self.next_at.load(Ordering::Relaxed);
+        if next_at == 0 {
+            self.next_at.store(now + self.interval_nanos, Ordering::Relaxed);
+            return;
+        }
+        if now < next_at {
+            return;
+        }
+        self.next_at.store(now + self.interval_nanos, Ordering::Relaxed);
+        // Lowest first, so each level lands below the ones still to move.
+        let house: Vec<(i64, i64)> = g
+            .book
+            .asks
+            .iter()
+            .map(|(p, v)| (*p, v - g.book.listed_at(*p)))
+            .filter(|(p, vol)| *vol > 0 && p - self.step >= self.floor)
+            .collect();
+        for (price, vol) in house {
+            move_house(g, price, price - self.step, vol, now);
+            book::match_resting(g, price - self.step, now);
+        }
+    }
+}
+
+/// Moves `vol` of the house's volume at `from` to `to`, taking the level's
+/// expiry along.
+pub fn move_house(g: &mut AppState, from: i64, to: i64, vol: i64, now: i64) {
+    if let Some(left) = g.book.asks.get_mut(&from) {
+        *left -= vol;
+        if *left <= 0 {
+            g.book.asks.remove(&from);
+        }
+    }
+    *g.book.asks.entry(to).or_default() += vol;
+    if let Some(at) = g.ask_expiry.remove(&from) {
+        g.ask_expiry.insert(to, at);
+    }
+    g.book_changed(now);
+    g.journal.record(|| journal::Event::AskMoved { from, to, vol, ts_nanos: now });
+}
+
+#[derive(Debug)]
+pub struct Guessing;
+
+impl GameMode for Guessing {
+    fn on_check(&self, g: &AppState, _uname: &str, _now: i64) -> Result<(), &'static str> {
+        if g.settlement.is_some() {
+            Ok(())
+        } else {
+            Err("BOOK_HIDDEN")
+        }
+    }
+}
+
+pub fn build(cfg: &GameModeConfig) -> Result<Arc<dyn GameMode>, String> {
+    Ok(match cfg {
+        GameModeConfig::Standard => Arc::new(Standard),
+        GameModeConfig::SealedBid => Arc::new(SealedBid),
+        GameModeConfig::Dutch { step, interval_secs, floor } => {
+            if *step < 1 || *interval_secs < 1 {
+                return Err("[game_mode] dutch needs step and interval_secs of at least 1".to_owned());
+            }
+            Arc::new(Dutch {
+                step: *step,
+                interval_nanos: *interval_secs as i64 * NANOS_PER_SEC,
+                floor: *floor,
+                next_at: AtomicI64::new(0),
+            })
+        }
+        GameModeConfig::Guessing => Arc::new(Guessing),
+    })
+}
+
+pub fn spawn_ticker(state: Arc<Mutex<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let mut g = state.locked();
+            let mode = g.mode.clone();
+            mode.on_tick(&mut g, now());
+        }
+    });
+}
@@ -0,0 +1,636 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allocation, book, client_deadline, contention::StateLock, deadline_passed, errors::ApiError, fees::Endpoint, now, submit_bid, AppState, BidOpts,
+    BidResult, ReqClock, RespMeta,
+};
+
+/// How a cancel/replace treats the original order's place in the queue.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplacePriority {
+    /// The replacement queues as if newly entered.
+    #[default]
+    Reset,
+    /// Keeps priority when only the quantity goes down, as most venues do.
+    KeepOnReduce,
+    Keep,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct OrdersConfig {
+    #[serde(default)]
+    pub replace_priority: ReplacePriority,
+    /// Bids that miss rest on the book instead of being cancelled, and fill
+    /// if a matching ask arrives later.
+    #[serde(default)]
+    pub resting_bids: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Whatever doesn't fill on arrival is cancelled.
+    Ioc,
+    /// Whatever doesn't fill rests on the book.
+    Gtc,
+}
+
+impl OrdersConfig {
+    /// For orders that don't say.
+    pub fn default_tif(&self) -> TimeInForce {
+        if self.resting_bids {
+            TimeInForce::Gtc
+        } else {
+            TimeInForce::Ioc
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    Accepted,
+    /// On the book, waiting for an ask at its price.
+    Resting,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn is_open(self) -> bool {
+        matches!(self, OrderStatus::Accepted | OrderStatus::Resting | OrderStatus::PartiallyFilled)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OrderEvent {
+    pub ts_nanos: i64,
+    pub status: OrderStatus,
+    /// Lots filled by this event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filled: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Order {
+    pub id: u64,
+    pub uname: String,
+    pub price: i64,
+    pub qty: i64,
+    pub remaining: i64,
+    pub status: OrderStatus,
+    pub tif: TimeInForce,
+    /// The user's own reference, echoed back on every view of the order.
+    pub client_id: Option<String>,
+    /// Time the order queues by; earlier goes first at the same price.
+    pub priority_nanos: i64,
+    /// The order this one replaced, and the one that replaced it.
+    pub replaces: Option<u64>,
+    pub replaced_by: Option<u64>,
+    pub history: Vec<OrderEvent>,
+}
+
+/// Every order accepted at entry, under server-assigned ids. Bids that
+/// don't fill on arrival are cancelled with reason `UNMATCHED`, or rest on
+/// the book with `[orders] resting_bids`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct OrderStore {
+    pub orders: BTreeMap<u64, Order>,
+    next_id: u64,
+}
+
+fn order_bytes(o: &Order) -> usize {
+    let history: usize = o.history.iter().map(|e| std::mem::size_of::<OrderEvent>() + e.reason.as_ref().map_or(0, String::len)).sum();
+    std::mem::size_of::<Order>() + o.uname.len() + o.client_id.as_ref().map_or(0, String::len) + history
+}
+
+impl OrderStore {
+    pub fn accept(&mut self, uname: &str, price: i64, qty: i64, tif: TimeInForce, now: i64) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        let ev = OrderEvent { ts_nanos: now, status: OrderStatus::Accepted, filled: None, price: None, reason: None };
+        self.orders.insert(
+            id,
+            Order {
+                id,
+                uname: uname.to_owned(),
+                price,
+                qty,
+                remaining: qty,
+                status: OrderStatus::Accepted,
+                tif,
+                client_id: None,
+                priority_nanos: now,
+                replaces: None,
+                replaced_by: None,
+                history: vec![ev],
+            },
+        );
+        id
+    }
+
+    pub fn fill(&mut self, id: u64, price: i64, vol: i64, now: i64) -> &Order {
+        let o = self.orders.get_mut(&id).unwrap();
+        o.remaining -= vol;
+        o.status = if o.remaining > 0 { OrderStatus::PartiallyFilled } else { OrderStatus::Filled };
+        o.history.push(OrderEvent { ts_nanos: now, status: o.status, filled: Some(vol), price: Some(price), reason: None });
+        o
+    }
+
+    pub fn rest(&mut self, id: u64, now: i64) {
+        let o = self.orders.get_mut(&id).unwrap();
+        o.status = OrderStatus::Resting;
+        o.history.push(OrderEvent { ts_nanos: now, status: o.status, filled: None, price: None, reason: None });
+    }
+
+    pub fn cancel(&mut self, id: u64, reason: &str, now: i64) -> &Order {
+        let o = self.orders.get_mut(&id).unwrap();
+        o.status = OrderStatus::Cancelled;
+        o.history.push(OrderEvent {
+            ts_nanos: now,
+            status: o.status,
+            filled: None,
+            price: None,
+            reason: Some(reason.to_owned()),
+        });
+        o
+    }
+
+    /// Cancels `id` and enters its replacement in one step, returning the
+    /// new order's id.
+    pub fn replace(&mut self, id: u64, price: i64, qty: i64, keep_priority: bool, now: i64) -> u64 {
+        let old = self.cancel(id, "REPLACED", now);
+        let (uname, priority, tif, client_id) = (old.uname.clone(), old.priority_nanos, old.tif, old.client_id.clone());
+        let new_id = self.accept(&uname, price, qty, tif, now);
+        self.orders.get_mut(&id).unwrap().replaced_by = Some(new_id);
+        let o = self.orders.get_mut(&new_id).unwrap();
+        o.replaces = Some(id);
+        o.client_id = client_id;
+        if keep_priority {
+            o.priority_nanos = priority;
+        }
+        new_id
+    }
+
+    pub fn of_user<'a>(&'a self, uname: &'a str) -> impl Iterator<Item = &'a Order> + 'a {
+        self.orders.values().filter(move |o| o.uname == uname)
+    }
+
+    /// Rough size in memory, see `[memory]`.
+    pub fn approx_bytes(&self) -> usize {
+        self.orders.values().map(order_bytes).sum()
+    }
+
+    /// Drops the oldest filled and cancelled orders until the rest fit in
+    /// `max_bytes`; open orders always stay. Returns how many went.
+    pub fn evict_to(&mut self, max_bytes: usize) -> usize {
+        let mut over = self.approx_bytes().saturating_sub(max_bytes);
+        let mut gone = Vec::new();
+        for o in self.orders.values().filter(|o| !o.status.is_open()) {
+            if over == 0 {
+                break;
+            }
+            over = over.saturating_sub(order_bytes(o));
+            gone.push(o.id);
+        }
+        for id in gone.iter() {
+            self.orders.remove(id);
+        }
+        gone.len()
+    }
+
+    /// Rewrites `uname` to `alias` on every order, returning how many changed.
+    pub fn anonymize(&mut self, uname: &str, alias: &str) -> usize {
+        let mut n = 0;
+        for o in self.orders.values_mut().filter(|o| o.uname == uname) {
+            o.uname = alias.to_owned();
+            n += 1;
+        }
+        n
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct OrdersResult {
+    pub orders: Vec<Order>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+#[derive(Serialize, Default)]
+pub struct OrderResult {
+    pub order: Option<Order>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// The user's open orders. Free, like the private feed.
+pub async fn user_orders(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<OrdersResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.reply(StatusCode::NOT_FOUND, OrdersResult::default());
+    }
+    let orders = g.orders.of_user(&uname).filter(|o| o.status.is_open()).cloned().collect();
+    clock.reply(StatusCode::OK, OrdersResult { orders, ..Default::default() })
+}
+
+/// One order with its full history, open or not.
+pub async fn user_order(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<OrderResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    match g.orders.orders.get(&id).filter(|o| o.uname == uname) {
+        Some(o) => clock.reply(StatusCode::OK, OrderResult { order: Some(o.clone()), ..Default::default() }),
+        None => clock.reply(StatusCode::NOT_FOUND, OrderResult::default()),
+    }
+}
+
+/// Cancels one open order. Closed orders are left alone and answer 409.
+pub async fn user_cancel_order(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<OrderResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let Some(o) = g.orders.orders.get(&id).filter(|o| o.uname == uname) else {
+        return clock.reply(StatusCode::NOT_FOUND, OrderResult::default());
+    };
+    if !o.status.is_open() {
+        let res = OrderResult { order: Some(o.clone()), ..Default::default() };
+        return clock.reply(StatusCode::CONFLICT, res);
+    }
+    let (now, price) = (now(), o.price);
+    allocation::withdraw(&mut g, id);
+    g.book.remove_bid(price, id);
+    g.book_changed(now);
+    let order = g.orders.cancel(id, "USER_CANCEL", now).clone();
+    g.notify_order(id, now);
+    clock.reply(StatusCode::OK, OrderResult { order: Some(order), ..Default::default() })
+}
+
+/// Cancels every open order of the user in one step, so no fill can land
+/// between two of the cancels. Returns the orders cancelled.
+pub async fn user_cancel_all(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<OrdersResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.reply(StatusCode::NOT_FOUND, OrdersResult::default());
+    }
+    let now = now();
+    let ids: Vec<u64> = g.orders.of_user(&uname).filter(|o| o.status.is_open()).map(|o| o.id).collect();
+    let mut orders = Vec::with_capacity(ids.len());
+    for id in ids {
+        let price = g.orders.orders[&id].price;
+        allocation::withdraw(&mut g, id);
+        g.book.remove_bid(price, id);
+        g.book_changed(now);
+        orders.push(g.orders.cancel(id, "USER_CANCEL_ALL", now).clone());
+        g.notify_order(id, now);
+    }
+    clock.reply(StatusCode::OK, OrdersResult { orders, ..Default::default() })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplaceRequest {
+    /// Unchanged if left out.
+    pub price: Option<i64>,
+    /// New remaining quantity; unchanged if left out.
+    pub qty: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ReplaceResult {
+    /// The cancelled original, with `replaced_by` set.
+    pub original: Option<Order>,
+    pub order: Option<Order>,
+    pub priority_kept: bool,
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Atomically swaps an open order for one with a new price and/or
+/// quantity. The replacement passes the same risk and funding checks as a
+/// new bid; if it fails them the original stays in place.
+pub async fn user_replace_order(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+    req: Result<Json<ReplaceRequest>, JsonRejection>,
+) -> (StatusCode, Json<ReplaceResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::PlaceBid;
+    let Ok(Json(req)) = req else {
+        return clock.reply(StatusCode::BAD_REQUEST, ReplaceResult::default());
+    };
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), ReplaceResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    if !g.users.contains_key(&uname) {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), ReplaceResult::default());
+    }
+    // The replacement can fill at once, so it passes the gates a new bid does.
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), ReplaceResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), ReplaceResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(&uname, ep, "HALTED", 0, now);
+        let res = ReplaceResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    if !g.trading_open(&uname, now) {
+        g.reject(&uname, ep, "MARKET_CLOSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, g.closed_reason(&uname, now)), ReplaceResult::default());
+    }
+    let Some(o) = g.orders.orders.get(&id).filter(|o| o.uname == uname) else {
+        return clock.reply(StatusCode::NOT_FOUND, ReplaceResult::default());
+    };
+    if !o.status.is_open() {
+        let res = ReplaceResult { original: Some(o.clone()), ..Default::default() };
+        return clock.reply(StatusCode::CONFLICT, res);
+    }
+    let price = req.price.unwrap_or(o.price);
+    let qty = req.qty.unwrap_or(o.remaining);
+    if qty <= 0 {
+        return clock.reply(StatusCode::BAD_REQUEST, ReplaceResult::default());
+    }
+    let keep = match g.orders_cfg.replace_priority {
+        ReplacePriority::Reset => false,
+        ReplacePriority::KeepOnReduce => price == o.price && qty <= o.remaining,
+        ReplacePriority::Keep => true,
+    };
+
+    if let Err(code) = book::admissible(&g, &g.users[&uname], price, qty) {
+        let res = ReplaceResult { reject_reason: Some(code.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), res);
+    }
+
+    let old_price = o.price;
+    allocation::withdraw(&mut g, id);
+    g.book.remove_bid(old_price, id);
+    g.book_changed(now);
+    let new_id = g.orders.replace(id, price, qty, keep, now);
+    g.notify_order(id, now);
+    g.notify_order(new_id, now);
+    let mode = g.mode.clone();
+    mode.on_replace(&mut g, &uname, new_id, now);
+    let res = ReplaceResult {
+        original: g.orders.orders.get(&id).cloned(),
+        order: g.orders.orders.get(&new_id).cloned(),
+        priority_kept: keep,
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Buy,
+}
+
+/// Body of `POST /users/:uname/orders`.
+#[derive(Debug)]
+pub struct NewOrder {
+    pub side: Side,
+    pub price: i64,
+    pub quantity: i64,
+    pub tif: Option<TimeInForce>,
+    pub client_id: Option<String>,
+}
+
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+fn field_error(errors: &mut Vec<FieldError>, field: &str, message: &str) {
+    errors.push(FieldError { field: field.to_owned(), message: message.to_owned() });
+}
+
+impl NewOrder {
+    /// Checks every field and reports all problems at once.
+    pub fn parse(body: &[u8]) -> Result<NewOrder, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let v: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(v) => v,
+            Err(e) => {
+                field_error(&mut errors, "", &format!("not valid JSON: {}", e));
+                return Err(errors);
+            }
+        };
+        let Some(obj) = v.as_object() else {
+            field_error(&mut errors, "", "expected a JSON object");
+            return Err(errors);
+        };
+        for k in obj.keys() {
+            if !["side", "price", "quantity", "tif", "client_id"].contains(&k.as_str()) {
+                field_error(&mut errors, k, "unknown field");
+            }
+        }
+
+        let side = match obj.get("side").map(|s| s.as_str()) {
+            None => Err("required"),
+            Some(Some("buy")) => Ok(Side::Buy),
+            Some(Some("sell")) => Err("only buy orders are supported"),
+            Some(_) => Err("expected \"buy\""),
+        };
+        let price = match obj.get("price") {
+            None => Err("required"),
+            Some(p) => p.as_i64().ok_or("expected an integer"),
+        };
+        let quantity = match obj.get("quantity") {
+            None => Ok(1),
+            Some(q) => match q.as_i64() {
+                None => Err("expected an integer"),
+                Some(q) if q < 1 => Err("must be at least 1"),
+                Some(q) => Ok(q),
+            },
+        };
+        let tif = match obj.get("tif") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(t) => serde_json::from_value(t.clone()).map(Some).map_err(|_| "expected \"ioc\" or \"gtc\""),
+        };
+        let client_id = match obj.get("client_id") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(c) => match c.as_str() {
+                None => Err("expected a string"),
+                Some("") => Err("must not be empty"),
+                Some(c) if c.len() > MAX_CLIENT_ID_LEN => Err("longer than 64 bytes"),
+                Some(c) => Ok(Some(c.to_owned())),
+            },
+        };
+
+        for (field, r) in [
+            ("side", side.err()),
+            ("price", price.err()),
+            ("quantity", quantity.err()),
+            ("tif", tif.err()),
+            ("client_id", client_id.as_ref().err().copied()),
+        ] {
+            if let Some(msg) = r {
+                field_error(&mut errors, field, msg);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(NewOrder {
+            side: side.unwrap(),
+            price: price.unwrap(),
+            quantity: quantity.unwrap(),
+            tif: tif.unwrap(),
+            client_id: client_id.unwrap(),
+        })
+    }
+}
+
+/// Order entry with a JSON body; charged and checked exactly like
+/// `place_bid`. A body that doesn't validate is refused before any fee,
+/// with one entry per bad field.
+pub async fn user_new_order(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<BidResult>) {
+    let clock = ReqClock::start();
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, Endpoint::PlaceBid, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), BidResult::default());
+    };
+    let order = match NewOrder::parse(&body) {
+        Ok(o) => o,
+        Err(errors) => {
+            state.locked().reject(&uname, Endpoint::PlaceBid, "INVALID_ORDER", 0, now());
+            let err = ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "INVALID_ORDER");
+            return clock.refuse(err, BidResult { errors, ..Default::default() });
+        }
+    };
+    // Only bids exist so far; `parse` refuses anything else.
+    let Side::Buy = order.side;
+    let opts = BidOpts { qty: Some(order.quantity), tif: order.tif, client_id: order.client_id };
+    submit_bid(&state, uname, order.price, opts, deadline, clock).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(body: &str) -> Vec<(String, String)> {
+        NewOrder::parse(body.as_bytes()).unwrap_err().into_iter().map(|e| (e.field, e.message)).collect()
+    }
+
+    fn pair(field: &str, message: &str) -> (String, String) {
+        (field.to_owned(), message.to_owned())
+    }
+
+    #[test]
+    fn parse_fills_in_defaults() {
+        let o = NewOrder::parse(br#"{"side": "buy", "price": 42}"#).unwrap();
+        assert_eq!((o.side, o.price, o.quantity, o.tif, o.client_id), (Side::Buy, 42, 1, None, None));
+        let o = NewOrder::parse(br#"{"side": "buy", "price": 7, "quantity": 3, "tif": "gtc", "client_id": "a-1"}"#).unwrap();
+        assert_eq!((o.quantity, o.tif, o.client_id.as_deref()), (3, Some(TimeInForce::Gtc), Some("a-1")));
+        let o = NewOrder::parse(br#"{"side": "buy", "price": 7, "tif": null, "client_id": null}"#).unwrap();
+        assert_eq!((o.tif, o.client_id), (None, None));
+    }
+
+    #[test]
+    fn parse_reports_every_bad_field_at_once() {
+        let long = "x".repeat(MAX_CLIENT_ID_LEN + 1);
+        let body = format!(r#"{{"side": "sell", "price": 1.5, "quantity": 0, "tif": "day", "client_id": "{}", "extra": 1}}"#, long);
+        assert_eq!(
+            fields(&body),
+            vec![
+                pair("extra", "unknown field"),
+                pair("side", "only buy orders are supported"),
+                pair("price", "expected an integer"),
+                pair("quantity", "must be at least 1"),
+                pair("tif", "expected \"ioc\" or \"gtc\""),
+                pair("client_id", "longer than 64 bytes"),
+            ]
+        );
+        assert_eq!(fields("{}"), vec![pair("side", "required"), pair("price", "required")]);
+        assert_eq!(
+            fields(r#"{"side": 1, "price": "5", "quantity": "2", "client_id": ""}"#),
+            vec![
+                pair("side", "expected \"buy\""),
+                pair("price", "expected an integer"),
+                pair("quantity", "expected an integer"),
+                pair("client_id", "must not be empty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_refuses_what_is_not_an_object() {
+        assert_eq!(fields("[1]"), vec![pair("", "expected a JSON object")]);
+        let e = fields("{");
+        assert_eq!(e.len(), 1);
+        assert!(e[0].1.starts_with("not valid JSON"));
+    }
+
+    #[test]
+    fn replace_links_both_orders_and_keeps_priority_if_asked() {
+        let mut s = OrderStore::default();
+        let a = s.accept("u", 10, 2, TimeInForce::Gtc, 100);
+        s.orders.get_mut(&a).unwrap().client_id = Some("c".to_owned());
+        let b = s.replace(a, 11, 3, true, 200);
+        let c = s.replace(b, 12, 3, false, 300);
+        assert_eq!((s.orders[&a].status, s.orders[&a].replaced_by), (OrderStatus::Cancelled, Some(b)));
+        assert_eq!(s.orders[&a].history.last().unwrap().reason.as_deref(), Some("REPLACED"));
+        let nb = &s.orders[&b];
+        assert_eq!((nb.replaces, nb.priority_nanos, nb.client_id.as_deref(), nb.tif), (Some(a), 100, Some("c"), TimeInForce::Gtc));
+        assert_eq!(s.orders[&c].priority_nanos, 300);
+        assert_eq!(s.of_user("u").filter(|o| o.status.is_open()).count(), 1);
+    }
+
+
+    #[test]
+    fn eviction_keeps_open_orders() {
+        let mut s = OrderStore::default();
+        let open = s.accept("u", 10, 2, TimeInForce::Gtc, 1);
+        let done = s.accept("u", 10, 2, TimeInForce::Ioc, 2);
+        s.fill(open, 10, 1, 3);
+        s.fill(done, 10, 2, 3);
+        assert_eq!(s.orders[&open].status, OrderStatus::PartiallyFilled);
+        assert_eq!(s.evict_to(0), 1);
+        assert!(s.orders.contains_key(&open) && !s.orders.contains_key(&done));
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, killswitch, ledger, now, storage::Store, AppState, ReqClock, RespMeta};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// A protocol violation, judged from the status a user request got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    /// 429: over the in-flight cap or another rate limit.
+    RateLimit,
+    /// 400, 413 or 431: a request that couldn't be understood.
+    Malformed,
+    /// 401: a wrong key, signature or nonce.
+    Unauthorized,
+}
+
+impl Violation {
+    fn of(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Some(Violation::RateLimit),
+            StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE => {
+                Some(Violation::Malformed)
+            }
+            StatusCode::UNAUTHORIZED => Some(Violation::Unauthorized),
+            _ => None,
+        }
+    }
+}
+
+/// `threshold` violations of one kind within `window_secs` lock the user
+/// out for `lockout_secs` and fine them `fine`, as far as their balance
+/// goes. Set under `[[penalties]]`, one table per rule.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PenaltyRule {
+    pub violation: Violation,
+    pub threshold: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    #[serde(default)]
+    pub lockout_secs: u64,
+    #[serde(default)]
+    pub fine: i64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Penalty {
+    pub seq: u64,
+    pub violation: Violation,
+    /// Violations in the window that earned it.
+    pub count: u32,
+    pub locked_until_nanos: Option<i64>,
+    /// What was taken, which may be less than the rule's fine.
+    pub fine: i64,
+    pub ts_nanos: i64,
+    /// Set when an admin lifted the lockout.
+    pub lifted_at_nanos: Option<i64>,
+}
+
+/// How near a user is to one rule's penalty.
+#[derive(Debug, Clone, Serialize)]
+pub struct Strikes {
+    pub violation: Violation,
+    /// Violations in the current window.
+    pub count: u32,
+    pub threshold: u32,
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct PenaltyBox {
+    rules: Vec<PenaltyRule>,
+    recent: HashMap<(String, Violation), VecDeque<i64>>,
+    log: HashMap<String, Vec<Penalty>>,
+    seq: u64,
+}
+
+impl PenaltyBox {
+    pub fn new(rules: Vec<PenaltyRule>) -> Self {
+        PenaltyBox { rules, ..Default::default() }
+    }
+
+    pub fn of_user(&self, uname: &str) -> Vec<Penalty> {
+        self.log.get(uname).cloned().unwrap_or_default()
+    }
+
+    pub fn forget(&mut self, uname: &str) {
+        self.log.remove(uname);
+        self.recent.retain(|(u, _), _| u != uname);
+    }
+
+    /// One line per rule.
+    pub fn strikes(&self, uname: &str, now: i64) -> Vec<Strikes> {
+        self.rules
+            .iter()
+            .map(|r| {
+                let since = now - r.window_secs as i64 * NANOS_PER_SEC;
+                let recent = self.recent.get(&(uname.to_owned(), r.violation));
+                let count = recent.map_or(0, |q| q.iter().filter(|t| **t > since).count() as u32);
+                Strikes { violation: r.violation, count, threshold: r.threshold.max(1), window_secs: r.window_secs }
+            })
+            .collect()
+    }
+
+    fn locked_until(&self, uname: &str, now: i64) -> Option<i64> {
+        self.log
+            .get(uname)?
+            .iter()
+            .filter(|p| p.lifted_at_nanos.is_none())
+            .filter_map(|p| p.locked_until_nanos)
+            .filter(|t| now < *t)
+            .max()
+    }
+
+    /// Counts one violation, returning the rule it tripped, if any.
+    fn violate(&mut self, uname: &str, v: Violation, now: i64) -> Option<(PenaltyRule, u32)> {
+        let rule = self.rules.iter().find(|r| r.violation == v)?.clone();
+        let q = self.recent.entry((uname.to_owned(), v)).or_default();
+        q.push_back(now);
+        let since = now - rule.window_secs as i64 * NANOS_PER_SEC;
+        while q.front().is_some_and(|t| *t <= since) {
+            q.pop_front();
+        }
+        if (q.len() as u32) < rule.threshold.max(1) {
+            return None;
+        }
+        let count = q.len() as u32;
+        q.clear();
+        Some((rule, count))
+    }
+}
+
+fn punish(g: &mut AppState, uname: &str, v: Violation, rule: &PenaltyRule, count: u32, now: i64) {
+    // Fines stop at zero and, like fees, once the game is settled.
+    let fine = if g.settlement.is_some() { 0 } else { rule.fine.min(g.users[uname].balance).max(0) };
+    if fine > 0 {
+        g.debit(uname, fine);
+        g.house.fines += fine;
+        g.board_changed();
+        g.balance_moved(uname, ledger::Reason::Penalty, -fine, None, now);
+    }
+    let locked_until_nanos = (rule.lockout_secs > 0).then(|| now + rule.lockout_secs as i64 * NANOS_PER_SEC);
+    let pb = &mut g.penalties;
+    pb.seq += 1;
+    let p = Penalty { seq: pb.seq, violation: v, count, locked_until_nanos, fine, ts_nanos: now, lifted_at_nanos: None };
+    pb.log.entry(uname.to_owned()).or_default().push(p);
+    tracing::warn!("penalty for {}: {} {:?} violations, fined {}, locked until {:?}", uname, count, v, fine, locked_until_nanos);
+    g.feeds.timeline.admin(uname, format!("penalized for {} {:?} violations: fined {}", count, v, fine));
+}
+
+/// Turns away users serving a lockout and feeds every violating response
+/// into the rules. Sits outside the in-flight cap and the rate limiter so
+/// their 429s count. Only layered with `[[penalties]]` set.
+pub async fn watch(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let Some(uname) = killswitch::user_of(req.uri().path()).map(str::to_owned) else {
+        return next.run(req).await;
+    };
+    let locked = state.locked().penalties.locked_until(&uname, now());
+    // Not a violation itself, so waiting it out is enough. Reads stay open
+    // so a locked-out user can see why in `/rejections`.
+    if let Some(until) = locked.filter(|_| req.method() != Method::GET) {
+        let msg = format!("locked out for protocol violations until {}", until);
+        return ApiError::with(StatusCode::FORBIDDEN, "PENALTY_BOX", msg).into_response();
+    }
+
+    let resp = next.run(req).await;
+    if let Some(v) = Violation::of(resp.status()) {
+        let mut g = state.locked();
+        let now = now();
+        // Unknown names are not tracked, or anyone could grow the map.
+        if g.users.contains_key(&uname) {
+            if let Some((rule, count)) = g.penalties.violate(&uname, v, now) {
+                punish(&mut g, &uname, v, &rule, count, now);
+            }
+        }
+    }
+    resp
+}
+
+#[derive(Serialize, Default)]
+pub struct PenaltiesResult {
+    pub penalties: HashMap<String, Vec<Penalty>>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+pub async fn admin_penalties(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<PenaltiesResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    clock.reply(StatusCode::OK, PenaltiesResult { penalties: g.penalties.log.clone(), ..Default::default() })
+}
+
+/// The appeal: ends any lockout the user is serving. Fines stand.
+pub async fn admin_lift_penalty(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<PenaltiesResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let now = now();
+    let Some(log) = g.penalties.log.get_mut(&uname) else {
+        return clock.reply(StatusCode::NOT_FOUND, PenaltiesResult::default());
+    };
+    for p in log.iter_mut().filter(|p| p.lifted_at_nanos.is_none() && p.locked_until_nanos.is_some_and(|t| now < t)) {
+        p.lifted_at_nanos = Some(now);
+    }
+    let mut res = PenaltiesResult::default();
+    res.penalties.insert(uname.clone(), log.clone());
+    tracing::warn!("penalty lockout lifted for {}", uname);
+    g.feeds.timeline.admin(&uname, "penalty lockout lifted");
+    clock.reply(StatusCode::OK, res)
+}
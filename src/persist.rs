@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{backup::StateImage, contention::StateLock, now, reservations, schema, tape::Trade, AppState};
+
+/// Keeps the game in SQLite so a restart picks up where it left off.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PersistenceConfig {
+    pub db_path: String,
+    /// Catches changes no request made, like interest or the automatic
+    /// settlement; every mutating request is written straight away.
+    #[serde(default = "default_flush_secs")]
+    pub flush_secs: u64,
+}
+
+fn default_flush_secs() -> u64 {
+    5
+}
+
+/// Schema changes for the store, applied in order. `PRAGMA user_version`
+/// records how many have run; append new entries, never edit old ones.
+const MIGRATIONS: &[&str] = &["CREATE TABLE users (
+        uname TEXT PRIMARY KEY,
+        balance INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        done_trade INTEGER NOT NULL,
+        account TEXT NOT NULL
+    );
+    CREATE TABLE asks (
+        price INTEGER PRIMARY KEY,
+        vol INTEGER NOT NULL
+    );
+    CREATE TABLE trades (
+        seq INTEGER PRIMARY KEY,
+        uname TEXT NOT NULL,
+        price INTEGER NOT NULL,
+        vol INTEGER NOT NULL,
+        ts_nanos INTEGER NOT NULL
+    );
+    CREATE TABLE game (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        image TEXT NOT NULL
+    );"];
+
+/// What the database holds, so each flush writes only what changed.
+struct Store {
+    conn: Connection,
+    users: HashMap<String, String>,
+    asks: BTreeMap<i64, i64>,
+    last_seq: u64,
+    first_seq: u64,
+    /// `AppState::forgotten_users` as of the last flush.
+    forgotten: u64,
+    /// The rest of the `StateImage`, without its users, asks and trades.
+    game: String,
+}
+
+/// Changes since the last flush, taken under the state lock and written
+/// after it is released.
+struct Delta {
+    users: Vec<(String, i64, i64, bool, String)>,
+    removed: Vec<String>,
+    asks: Option<BTreeMap<i64, i64>>,
+    trades: Vec<Trade>,
+    /// Someone was forgotten, so `trades` is the whole tape and replaces
+    /// what is stored.
+    rewrite_trades: bool,
+    first_seq: u64,
+    forgotten: u64,
+    game: Option<String>,
+}
+
+fn game_json(g: &AppState) -> String {
+    let mut rest = serde_json::to_value(StateImage {
+        schema_version: schema::STATE_SCHEMA_VERSION,
+        taken_nanos: 0,
+        users: HashMap::new(),
+        asks: BTreeMap::new(),
+        ask_expiry: g.ask_expiry.clone(),
+        sells: reservations::whole_book(g).sell_lots().map(|(p, s, v)| (p, s.to_owned(), v)).collect(),
+        tape: g.tape.without_trades(),
+        house: g.house.clone(),
+        issued: g.issued.clone(),
+        orders: g.orders.clone(),
+        settlement: g.settlement.clone(),
+        instruments: g.instruments.books.clone(),
+        ledger: g.ledger.clone(),
+        loans: g.loans.clone(),
+        user_keys: g.user_keys.clone(),
+        names: g.names.entries(),
+        trade_start_nanos: g.config.trade_start_nanos,
+        calendar: g.config.calendar.clone(),
+        trade_end_nanos: g.trade_end_nanos,
+    })
+    .unwrap();
+    for k in ["users", "asks", "taken_nanos"] {
+        rest.as_object_mut().unwrap().remove(k);
+    }
+    rest.to_string()
+}
+
+impl Store {
+    fn open(path: &str) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        let applied: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        if applied > MIGRATIONS.len() {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+                Some(format!("game store schema v{} is newer than this server (v{})", applied, MIGRATIONS.len())),
+            ));
+        }
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(Store { conn, users: HashMap::new(), asks: BTreeMap::new(), last_seq: 0, first_seq: 0, forgotten: 0, game: String::new() })
+    }
+
+    /// The stored game as a `StateImage`, if one was ever written.
+    fn load(&mut self) -> Result<Option<StateImage>, String> {
+        let e = |e: rusqlite::Error| e.to_string();
+        let Some(game) = self.conn.query_row("SELECT image FROM game", [], |r| r.get::<_, String>(0)).optional().map_err(e)?
+        else {
+            return Ok(None);
+        };
+        let mut image: Value = serde_json::from_str(&game).map_err(|e| e.to_string())?;
+        image["taken_nanos"] = Value::from(now());
+
+        let mut users = serde_json::Map::new();
+        let mut q = self.conn.prepare("SELECT uname, account FROM users").map_err(e)?;
+        for row in q.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))).map_err(e)? {
+            let (u, account) = row.map_err(e)?;
+            users.insert(u.clone(), serde_json::from_str(&account).map_err(|e| e.to_string())?);
+            self.users.insert(u, account);
+        }
+        image["users"] = Value::Object(users);
+
+        let mut q = self.conn.prepare("SELECT price, vol FROM asks").map_err(e)?;
+        for row in q.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?))).map_err(e)? {
+            let (price, vol) = row.map_err(e)?;
+            self.asks.insert(price, vol);
+        }
+        image["asks"] = serde_json::to_value(&self.asks).unwrap();
+
+        let mut q = self.conn.prepare("SELECT seq, uname, price, vol, ts_nanos FROM trades ORDER BY seq").map_err(e)?;
+        let trades = q
+            .query_map([], |r| {
+                Ok(Trade { seq: r.get::<_, i64>(0)? as u64, uname: r.get(1)?, price: r.get(2)?, vol: r.get(3)?, ts_nanos: r.get(4)? })
+            })
+            .map_err(e)?
+            .collect::<rusqlite::Result<Vec<Trade>>>()
+            .map_err(e)?;
+        self.first_seq = trades.first().map_or(0, |t| t.seq);
+        self.last_seq = trades.last().map_or(0, |t| t.seq);
+        image["tape"]["trades"] = serde_json::to_value(trades).unwrap();
+        self.game = game;
+
+        let image = schema::upgrade_state(image)?;
+        serde_json::from_value(image).map(Some).map_err(|e| e.to_string())
+    }
+
+    fn delta(&self, g: &AppState) -> Delta {
+        let mut users = Vec::new();
+        for (u, ua) in g.users.iter() {
+            let account = serde_json::to_string(ua).unwrap();
+            if self.users.get(u) != Some(&account) {
+                users.push((u.clone(), ua.balance, ua.position, ua.done_trade, account));
+            }
+        }
+        let rewrite_trades = g.forgotten_users != self.forgotten;
+        let start = if rewrite_trades { 0 } else { g.tape.trades.partition_point(|t| t.seq <= self.last_seq) };
+        let game = game_json(g);
+        let book = reservations::whole_book(g);
+        Delta {
+            users,
+            removed: self.users.keys().filter(|u| !g.users.contains_key(*u)).cloned().collect(),
+            asks: (book.asks != self.asks).then(|| book.asks.clone()),
+            trades: g.tape.trades[start..].to_vec(),
+            rewrite_trades,
+            first_seq: g.tape.trades.first().map_or(self.last_seq + 1, |t| t.seq),
+            forgotten: g.forgotten_users,
+            game: (game != self.game).then_some(game),
+        }
+    }
+
+    fn write(&mut self, d: Delta) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut up = tx.prepare_cached(
+                "INSERT INTO users (uname, balance, position, done_trade, account) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (uname) DO UPDATE SET balance = ?2, position = ?3, done_trade = ?4, account = ?5",
+            )?;
+            for (u, balance, position, done, account) in d.users.iter() {
+                up.execute((u, balance, position, done, account))?;
+            }
+            for u in d.removed.iter() {
+                tx.execute("DELETE FROM users WHERE uname = ?1", [u])?;
+            }
+            if let Some(asks) = &d.asks {
+                tx.execute("DELETE FROM asks", [])?;
+                let mut ins = tx.prepare_cached("INSERT INTO asks (price, vol) VALUES (?1, ?2)")?;
+                for (price, vol) in asks {
+                    ins.execute((price, vol))?;
+                }
+            }
+            if d.rewrite_trades {
+                tx.execute("DELETE FROM trades", [])?;
+            }
+            let mut ins = tx.prepare_cached(
+                "INSERT OR REPLACE INTO trades (seq, uname, price, vol, ts_nanos) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for t in d.trades.iter() {
+                ins.execute((t.seq as i64, &t.uname, t.price, t.vol, t.ts_nanos))?;
+            }
+            // Trades pruned or evicted from memory leave the store too.
+            if d.first_seq > self.first_seq {
+                tx.execute("DELETE FROM trades WHERE seq < ?1", [d.first_seq as i64])?;
+            }
+            if let Some(game) = &d.game {
+                tx.execute(
+                    "INSERT INTO game (id, image) VALUES (1, ?1) ON CONFLICT (id) DO UPDATE SET image = ?1",
+                    [game],
+                )?;
+            }
+        }
+        tx.commit()?;
+
+        for (u, _, _, _, account) in d.users {
+            self.users.insert(u, account);
+        }
+        for u in d.removed.iter() {
+            self.users.remove(u);
+        }
+        if let Some(asks) = d.asks {
+            self.asks = asks;
+        }
+        if let Some(t) = d.trades.last() {
+            self.last_seq = t.seq;
+        }
+        self.first_seq = d.first_seq;
+        self.forgotten = d.forgotten;
+        if let Some(game) = d.game {
+            self.game = game;
+        }
+        Ok(())
+    }
+}
+
+pub struct Persister {
+    state: Arc<Mutex<AppState>>,
+    /// Taken before the state lock, so flushes land in the order their
+    /// changes were made.
+    store: Mutex<Store>,
+}
+
+impl Persister {
+    /// Opens the store and, if it holds a game, puts it in place of the one
+    /// built from config. `recovered` says the journal was replayed over
+    /// that one, which a stored game would undo, so the two are refused.
+    pub fn open(cfg: &PersistenceConfig, state: Arc<Mutex<AppState>>, recovered: bool) -> Result<Arc<Self>, String> {
+        let mut store = Store::open(&cfg.db_path).map_err(|e| format!("game store {}: {}", cfg.db_path, e))?;
+        match store.load().map_err(|e| format!("game store {}: {}", cfg.db_path, e))? {
+            Some(_) if recovered => {
+                return Err(format!("game store {} holds a game, so --recover can't replay the journal: move one of them away", cfg.db_path));
+            }
+            Some(image) => {
+                let mut g = state.locked();
+                tracing::warn!("restoring {} users and {} trades from {}", image.users.len(), image.tape.trades.len(), cfg.db_path);
+                image.apply(&mut g);
+                g.feeds.timeline.admin_global(format!("state restored from {}", cfg.db_path));
+            }
+            None => tracing::info!("game store {} is empty; starting from config", cfg.db_path),
+        }
+        let p = Arc::new(Persister { state, store: Mutex::new(store) });
+        p.flush();
+        Ok(p)
+    }
+
+    pub fn flush(&self) {
+        let mut store = self.store.lock().unwrap();
+        let delta = store.delta(&self.state.locked());
+        if let Err(e) = store.write(delta) {
+            tracing::error!("writing the game store failed: {}", e);
+        }
+    }
+}
+
+pub fn spawn_flusher(cfg: &PersistenceConfig, p: Arc<Persister>) {
+    let every = Duration::from_secs(cfg.flush_secs.max(1));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        p.flush();
+    });
+}
+
+/// Writes whatever a mutating request changed before its response goes out.
+pub async fn flush_after_request(State(p): State<Arc<Persister>>, req: Request, next: Next) -> Response {
+    let mutates = req.method() != Method::GET;
+    let resp = next.run(req).await;
+    if mutates {
+        tokio::task::block_in_place(|| p.flush());
+    }
+    resp
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginKind {
+    /// Trades as a user of its own.
+    Bot,
+    /// Hooks into entry and settlement like a `GameMode`.
+    Rules,
+}
+
+/// A WebAssembly module under `[[plugins]]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    pub path: String,
+    pub kind: PluginKind,
+}
+
+/// This build has no WebAssembly runtime, so any `[[plugins]]` stop the
+/// start rather than the game running without them. `[[rules]]` and
+/// `[game_mode]` cover rule changes without one.
+pub fn check(plugins: &[PluginConfig]) -> Result<(), String> {
+    match plugins.first() {
+        None => Ok(()),
+        Some(p) => Err(format!(
+            "[[plugins]] ({}, {:?}) needs a WebAssembly runtime, which this build doesn't include",
+            p.path, p.kind
+        )),
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    allocation, analytics, backup, journal, contention::StateLock, ledger, loans, now, orders::Order, rejections::Rejection, reservations, retention, tape::Trade, timeline::Entry, AppState, ReqClock, RespMeta, UserAccount,
+};
+
+/// Everything the server holds about one user, across live state, the tape
+/// archive and the analytics store.
+#[derive(Serialize, Default)]
+pub struct UserExport {
+    pub uname: String,
+    pub account: Option<UserAccount>,
+    pub trades: Vec<Trade>,
+    pub orders: Vec<Order>,
+    pub timeline: Vec<Entry>,
+    pub rejections: Vec<Rejection>,
+    pub ledger: Vec<ledger::Entry>,
+    pub loans: Vec<loans::Loan>,
+    pub archived_trades: Vec<Trade>,
+    pub analytics_trades: Vec<Trade>,
+    pub analytics_snapshots: Vec<analytics::SnapshotRow>,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+pub async fn admin_export_user(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<UserExport>) {
+    let clock = ReqClock::start();
+    let (mut res, prune_dir, analytics_db) = {
+        let g = state.locked();
+        let res = UserExport {
+            uname: uname.clone(),
+            account: g.users.get(&uname).cloned(),
+            trades: g.tape.trades.iter().filter(|t| t.uname == uname).cloned().collect(),
+            orders: g.orders.of_user(&uname).cloned().collect(),
+            timeline: g.feeds.timeline.of_user(&uname),
+            rejections: g.rejections.of_user(&uname),
+            ledger: g.ledger.of_user(&uname),
+            loans: g.loans.of_user(&uname),
+            ..Default::default()
+        };
+        (res, g.prune_dir.clone(), g.analytics_db.clone())
+    };
+
+    let res = tokio::task::spawn_blocking(move || {
+        if let Some(dir) = prune_dir {
+            match retention::read_archive(&dir) {
+                Ok(all) => res.archived_trades = all.into_iter().filter(|t| t.uname == uname).collect(),
+                Err(e) => res.error = Some(format!("tape archive: {}", e)),
+            }
+        }
+        if let Some(db) = analytics_db {
+            match analytics::user_rows(&db, &uname) {
+                Ok((trades, snaps)) => {
+                    res.analytics_trades = trades;
+                    res.analytics_snapshots = snaps;
+                }
+                Err(e) => res.error = Some(format!("analytics store: {}", e)),
+            }
+        }
+        res
+    })
+    .await
+    .unwrap();
+
+    let found = res.account.is_some()
+        || !res.trades.is_empty()
+        || !res.orders.is_empty()
+        || !res.archived_trades.is_empty()
+        || !res.analytics_trades.is_empty();
+    let code = match (&res.error, found) {
+        (Some(_), _) => StatusCode::INTERNAL_SERVER_ERROR,
+        (None, false) => StatusCode::NOT_FOUND,
+        (None, true) => StatusCode::OK,
+    };
+    clock.reply(code, res)
+}
+
+#[derive(Serialize, Default)]
+pub struct ForgetResult {
+    /// Pseudonym the user's trades now carry.
+    pub alias: String,
+    pub account_removed: bool,
+    pub trades_anonymized: usize,
+    pub orders_anonymized: usize,
+    pub archived_trades_anonymized: usize,
+    /// Backups taken before the user was forgotten, deleted once a fresh
+    /// one is written.
+    pub backups_replaced: usize,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Takes the user out of live state: the account goes, and their trades,
+/// orders and loans carry a pseudonym so the tape stays consistent for
+/// everyone else. Journaled, so `--recover` forgets them again.
+pub fn forget(g: &mut AppState, uname: &str, now: i64) -> ForgetResult {
+    g.forgotten_users += 1;
+    let alias = format!("anon-{}", g.forgotten_users);
+    // The user's holds, and holds on their listings, go back first so
+    // the listing is whole when it is withdrawn.
+    reservations::release_user(g, uname, now);
+    loans::close_user(g, uname, now);
+    // Open orders go too, so nothing trades or answers under the alias.
+    let open: Vec<u64> = g.orders.of_user(uname).filter(|o| o.status.is_open()).map(|o| o.id).collect();
+    for id in open {
+        let price = g.orders.orders[&id].price;
+        allocation::withdraw(g, id);
+        if g.book.remove_bid(price, id) {
+            g.book_changed(now);
+        }
+        g.orders.cancel(id, "USER_FORGOTTEN", now);
+        g.notify_order(id, now);
+    }
+    // Whatever the user held leaves the game with them.
+    let removed = g.users.remove(uname);
+    if removed.is_some() {
+        g.book.withdraw_asks(uname);
+        g.book_snapshot = None;
+    }
+    if let Some(ua) = &removed {
+        g.issued.cash -= ua.balance;
+        g.issued.units -= ua.position;
+        for (s, n) in ua.holdings.iter() {
+            if let Some(b) = g.instruments.books.get_mut(s) {
+                b.issued -= n;
+            }
+        }
+        g.board_changed();
+    }
+    g.feeds.timeline.forget(uname);
+    g.rejections.forget(uname);
+    g.ledger.forget(uname);
+    g.nonces.forget(uname);
+    g.penalties.forget(uname);
+    g.reject_trackers.remove(uname);
+    g.names.forget(uname);
+    g.user_keys.remove(uname);
+    g.bankruptcies.anonymize(uname, &alias);
+    g.loans.anonymize(uname, &alias);
+    g.journal.record(|| journal::Event::UserForgotten { uname: uname.to_owned(), ts_nanos: now });
+    ForgetResult {
+        account_removed: removed.is_some(),
+        trades_anonymized: g.tape.anonymize(uname, &alias),
+        orders_anonymized: g.orders.anonymize(uname, &alias),
+        alias,
+        ..Default::default()
+    }
+}
+
+/// `forget`, then the tape archive and analytics store get the pseudonym
+/// too, and older backups are replaced by one taken now. The journal is
+/// append-only: its events from before keep the name until it is moved
+/// away.
+pub async fn admin_forget_user(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<ForgetResult>) {
+    let clock = ReqClock::start();
+    let (mut res, prune_dir, analytics_db, backup) = {
+        let mut g = state.locked();
+        let res = forget(&mut g, &uname, now());
+        let backup = g.backup_dir.clone().map(|dir| (dir, backup::StateImage::capture(&g)));
+        (res, g.prune_dir.clone(), g.analytics_db.clone(), backup)
+    };
+
+    let res = tokio::task::spawn_blocking(move || {
+        if let Some(dir) = prune_dir {
+            match retention::anonymize_archive(&dir, &uname, &res.alias) {
+                Ok(n) => res.archived_trades_anonymized = n,
+                Err(e) => res.error = Some(format!("tape archive: {}", e)),
+            }
+        }
+        if let Some(db) = analytics_db {
+            if let Err(e) = analytics::anonymize(&db, &uname, &res.alias) {
+                res.error = Some(format!("analytics store: {}", e));
+            }
+        }
+        if let Some((dir, image)) = backup {
+            match backup::replace_backups(&dir, &image) {
+                Ok(n) => res.backups_replaced = n,
+                Err(e) => res.error = Some(format!("backups: {}", e)),
+            }
+        }
+        res
+    })
+    .await
+    .unwrap();
+
+    let code = if res.error.is_some() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::OK
+    };
+    clock.reply(code, res)
+}
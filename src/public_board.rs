@@ -0,0 +1,113 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, AppState, Prebuilt, ReqClock, RespMeta};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceVisibility {
+    /// Rank and name only.
+    Rank,
+    /// Balance rounded down to a multiple of `band_width`.
+    Band,
+    Exact,
+}
+
+/// What participants may see of the board; the admin board is unaffected.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PublicBoardConfig {
+    pub balances: BalanceVisibility,
+    #[serde(default = "default_band_width")]
+    pub band_width: i64,
+    /// Leave users out until they have finished trading.
+    #[serde(default)]
+    pub hide_running: bool,
+}
+
+fn default_band_width() -> i64 {
+    100
+}
+
+#[derive(Serialize, Default)]
+pub struct PublicEntry {
+    pub rank: usize,
+    pub uname: String,
+    pub done_trade: bool,
+    pub bankrupt: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<i64>,
+    /// Inclusive lower and exclusive upper bound of the balance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_band: Option<(i64, i64)>,
+}
+
+#[derive(Serialize, Default)]
+pub struct PublicBoardResult {
+    pub entries: Vec<PublicEntry>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Free, participant-facing leaderboard. Disabled unless `[public_board]`
+/// is configured.
+pub async fn public_board(State(state): State<Arc<Mutex<AppState>>>) -> Response {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if g.public_board.is_none() {
+        return clock.reply(StatusCode::NOT_FOUND, PublicBoardResult::default()).into_response();
+    }
+    let body = snapshot(&mut g);
+    drop(g);
+    clock.reply_prebuilt(StatusCode::OK, &body)
+}
+
+/// The masked board, built at most once per change. `[public_board]` must
+/// be configured.
+pub(crate) fn snapshot(g: &mut AppState) -> Prebuilt {
+    match &g.board_snapshot {
+        Some(b) => b.clone(),
+        None => {
+            let b = build(g);
+            g.board_snapshot = Some(b.clone());
+            b
+        }
+    }
+}
+
+fn build(g: &AppState) -> Prebuilt {
+    let cfg = g.public_board.as_ref().unwrap();
+    let mut users: Vec<_> = g
+        .users
+        .iter()
+        .filter(|(_, ua)| ua.done_trade || !cfg.hide_running)
+        .collect();
+    users.sort_by(|a, b| b.1.balance.cmp(&a.1.balance).then_with(|| a.0.cmp(b.0)));
+
+    let width = cfg.band_width.max(1);
+    let entries: Vec<_> = users
+        .into_iter()
+        .enumerate()
+        .map(|(i, (u, ua))| {
+            let lo = ua.balance.div_euclid(width) * width;
+            PublicEntry {
+                rank: i + 1,
+                uname: u.clone(),
+                done_trade: ua.done_trade,
+                bankrupt: ua.bankrupt_at_nanos.is_some(),
+                balance: (cfg.balances == BalanceVisibility::Exact).then_some(ua.balance),
+                balance_band: (cfg.balances == BalanceVisibility::Band).then_some((lo, lo + width)),
+            }
+        })
+        .collect();
+    #[derive(Serialize)]
+    struct Body {
+        entries: Vec<PublicEntry>,
+    }
+    Prebuilt::new(&Body { entries })
+}
@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, fees::Endpoint, ledger, matching, now, AppState};
+
+/// Book updates pushed over `/users/:uname/ws?quotes=true`, billed per
+/// update delivered instead of per `check_asks`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuotesConfig {
+    pub fee_per_update: i64,
+    /// Most a user is ever billed for updates; later ones are free.
+    pub max_total_fee: Option<i64>,
+}
+
+/// Per-account record of the subscription.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QuoteUsage {
+    pub updates: u64,
+    pub fees_paid: i64,
+}
+
+/// Bills `uname` for one delivered update. False if they can't pay, which
+/// ends the subscription; fees never draw on credit. Free once settled.
+pub fn bill(g: &mut AppState, uname: &str) -> bool {
+    let Some(cfg) = g.quotes.clone() else {
+        return false;
+    };
+    let Some(ua) = g.users.get_mut(uname) else {
+        return false;
+    };
+    let room = cfg.max_total_fee.map_or(i64::MAX, |max| (max - ua.quotes.fees_paid).max(0));
+    let fee = if g.settlement.is_some() { 0 } else { cfg.fee_per_update.min(room) };
+    let Some(balance) = matching::charge_fee(ua.balance, fee) else {
+        return false;
+    };
+    ua.balance = balance;
+    ua.fees_paid += fee;
+    ua.quotes.fees_paid += fee;
+    ua.quotes.updates += 1;
+    g.house.fees += fee;
+    g.balance_moved(uname, ledger::Reason::Fee(Some(Endpoint::Quotes)), -fee, None, now());
+    if fee > 0 {
+        g.board_changed();
+    }
+    true
+}
+
+/// Held by a socket that asked for quotes; while any is open the user's
+/// feed carries book updates.
+pub struct Subscription {
+    pub state: Arc<Mutex<AppState>>,
+    pub uname: String,
+}
+
+impl Subscription {
+    pub fn open(state: Arc<Mutex<AppState>>, g: &mut AppState, uname: &str) -> Self {
+        *g.feeds.quote_subs.entry(uname.to_owned()).or_default() += 1;
+        Subscription { state, uname: uname.to_owned() }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut g = self.state.locked();
+        if let Some(n) = g.feeds.quote_subs.get_mut(&self.uname) {
+            *n -= 1;
+            if *n == 0 {
+                g.feeds.quote_subs.remove(&self.uname);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ApiError, killswitch};
+
+/// Buckets kept before idle, full ones are dropped.
+const PRUNE_AT: usize = 10_000;
+
+/// Requests refill at `per_sec`, and up to `burst` can be spent at once.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Bucket {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+/// Token buckets checked before the state lock is taken, so a client in a
+/// hot loop is turned away without queueing on it. `per_user` covers
+/// `/users/:uname/*`; `per_ip` covers every request from an address.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    pub per_user: Option<Bucket>,
+    pub per_ip: Option<Bucket>,
+}
+
+#[derive(Debug)]
+struct Tokens {
+    left: f64,
+    at: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    cfg: Bucket,
+    by_key: Mutex<HashMap<K, Tokens>>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(cfg: Bucket) -> Self {
+        Buckets { cfg, by_key: Mutex::new(HashMap::new()) }
+    }
+
+    fn refill(&self, t: &mut Tokens, now: Instant) {
+        let burst = self.cfg.burst.max(1) as f64;
+        t.left = (t.left + now.duration_since(t.at).as_secs_f64() * self.cfg.per_sec).min(burst);
+        t.at = now;
+    }
+
+    /// Spends a token, or says how long until one is back.
+    fn take(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut by_key = self.by_key.lock().unwrap();
+        if by_key.len() >= PRUNE_AT {
+            let burst = self.cfg.burst.max(1) as f64;
+            by_key.retain(|_, t| {
+                self.refill(t, now);
+                t.left < burst
+            });
+        }
+        let t = by_key.entry(key).or_insert(Tokens { left: self.cfg.burst.max(1) as f64, at: now });
+        self.refill(t, now);
+        if t.left >= 1.0 {
+            t.left -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - t.left) / self.cfg.per_sec.max(f64::MIN_POSITIVE)))
+    }
+}
+
+/// What a bucket holds right now.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Budget {
+    /// Requests that would go through back to back.
+    pub requests: u32,
+    pub burst: u32,
+    pub per_sec: f64,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    users: Option<Buckets<String>>,
+    ips: Option<Buckets<IpAddr>>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: &RateLimitConfig) -> Arc<Self> {
+        Arc::new(RateLimiter { users: cfg.per_user.map(Buckets::new), ips: cfg.per_ip.map(Buckets::new) })
+    }
+
+    /// `uname`'s per-user bucket, without spending from it.
+    pub fn user_budget(&self, uname: &str) -> Option<Budget> {
+        let users = self.users.as_ref()?;
+        let now = Instant::now();
+        let burst = users.cfg.burst.max(1);
+        let mut t = match users.by_key.lock().unwrap().get(uname) {
+            Some(t) => Tokens { left: t.left, at: t.at },
+            None => Tokens { left: burst as f64, at: now },
+        };
+        users.refill(&mut t, now);
+        Some(Budget { requests: t.left as u32, burst, per_sec: users.cfg.per_sec })
+    }
+}
+
+/// Refuses with 429 and `Retry-After` once a bucket is empty. The address
+/// is the peer's, as proxies aren't trusted to say who they forward for.
+pub async fn limit(State(rl): State<Arc<RateLimiter>>, req: Request, next: Next) -> Response {
+    let now = Instant::now();
+    let mut wait = None;
+    if let (Some(ips), Some(ConnectInfo(peer))) = (&rl.ips, req.extensions().get::<ConnectInfo<SocketAddr>>()) {
+        wait = ips.take(peer.ip(), now).err();
+    }
+    if let (None, Some(users), Some(uname)) = (wait, &rl.users, killswitch::user_of(req.uri().path())) {
+        wait = users.take(uname.to_owned(), now).err();
+    }
+    if let Some(wait) = wait {
+        let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let mut resp = ApiError::with(StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED", "too many requests; see Retry-After")
+            .into_response();
+        resp.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        return resp;
+    }
+    next.run(req).await
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, credit, journal, now, quotes, AppState, ReqClock, RespMeta, UserAccount};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Starting balance for users added once trading has opened. Joining within
+/// `grace_secs` of the first open gets the full balance; after that it
+/// shrinks linearly over `decay_secs`, never below `min_percent`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LateRegistrationConfig {
+    #[serde(default)]
+    pub grace_secs: u64,
+    pub decay_secs: u64,
+    #[serde(default)]
+    pub min_percent: u8,
+}
+
+impl LateRegistrationConfig {
+    pub fn prorate(&self, init_balance: i64, elapsed_nanos: i64) -> i64 {
+        let late = elapsed_nanos.saturating_sub(self.grace_secs as i64 * NANOS_PER_SEC).max(0) as i128;
+        let decay = (self.decay_secs as i128 * NANOS_PER_SEC as i128).max(1);
+        let keep = (decay - late).max(0);
+        let min = init_balance as i128 * self.min_percent.min(100) as i128 / 100;
+        (init_balance as i128 * keep / decay).max(min) as i64
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddUserRequest {
+    pub uname: String,
+    /// Secret for the private feed, as in `[user_keys]`.
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct AddUserResult {
+    pub uname: String,
+    pub balance: i64,
+    /// Whether the balance was cut for joining late.
+    pub prorated: bool,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Opens an account for a name already on the roster.
+pub fn register(g: &mut AppState, uname: &str, balance: i64, now: i64) {
+    let account = UserAccount {
+        balance,
+        done_trade: false,
+        position: 0,
+        notional_spent: 0,
+        exec_price: None,
+        exec_ts_nanos: None,
+        fees_paid: 0,
+        credit: credit::CreditLine::new(&g.credit, now),
+        bankrupt_at_nanos: None,
+        quotes: quotes::QuoteUsage::default(),
+        listed: 0,
+        holdings: BTreeMap::new(),
+    };
+    g.users.insert(uname.to_owned(), account);
+    g.issued.cash += balance;
+    g.board_changed();
+    g.journal.record(|| journal::Event::UserAdded { uname: uname.to_owned(), balance, ts_nanos: now });
+}
+
+/// Puts a new name on the roster and opens its account, returning the
+/// balance it got.
+pub fn join(g: &mut AppState, uname: &str, key: Option<String>, now: i64) -> Result<i64, String> {
+    g.names.add(uname)?;
+    let elapsed = now - g.calendar.first_open();
+    let balance = match &g.late_registration {
+        Some(cfg) if elapsed > 0 => cfg.prorate(g.init_balance, elapsed),
+        _ => g.init_balance,
+    };
+    register(g, uname, balance, now);
+    if let Some(k) = key {
+        g.user_keys.insert(uname.to_owned(), k);
+    }
+    g.feeds.timeline.admin(uname, format!("registered with balance {}", balance));
+    tracing::warn!("added user {} with balance {}", uname, balance);
+    Ok(balance)
+}
+
+/// Adds a user at runtime. Before the first open everyone gets the usual
+/// balance; afterwards `[late_registration]`, if set, decides.
+pub async fn admin_add_user(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<AddUserRequest>,
+) -> (StatusCode, Json<AddUserResult>) {
+    let clock = ReqClock::start();
+    let now = now();
+    let mut g = state.locked();
+    if req.uname.is_empty() || req.uname.contains('/') {
+        return clock.reply(StatusCode::BAD_REQUEST, AddUserResult::default());
+    }
+    if g.names.canonical(&req.uname).is_some_and(|c| g.users.contains_key(c)) {
+        return clock.reply(StatusCode::CONFLICT, AddUserResult::default());
+    }
+    let balance = match join(&mut g, &req.uname, req.key, now) {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::warn!("refusing to add user: {}", e);
+            return clock.reply(StatusCode::CONFLICT, AddUserResult::default());
+        }
+    };
+    let res = AddUserResult { prorated: balance != g.init_balance, uname: req.uname, balance, ..Default::default() };
+    clock.reply(StatusCode::OK, res)
+}
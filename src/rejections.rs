@@ -0,0 +1,106 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{contention::StateLock, fees::Endpoint, penalty, AppState, ReqClock, RespMeta};
+
+/// Rejections kept per user; oldest go first.
+const MAX_REJECTIONS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    pub seq: u64,
+    pub endpoint: Endpoint,
+    pub reason: String,
+    /// What the refused call cost; 0 when it was turned away before the fee.
+    pub fee_charged: i64,
+    pub ts_nanos: i64,
+}
+
+/// Every paid call a known user made that was refused, held in memory only.
+#[derive(Debug, Default)]
+pub struct Rejections {
+    users: HashMap<String, VecDeque<Rejection>>,
+    seq: u64,
+}
+
+impl Rejections {
+    pub fn record(&mut self, uname: &str, endpoint: Endpoint, reason: &str, fee_charged: i64, now: i64) {
+        self.seq += 1;
+        let q = self.users.entry(uname.to_owned()).or_default();
+        if q.len() == MAX_REJECTIONS {
+            q.pop_front();
+        }
+        let seq = self.seq;
+        q.push_back(Rejection { seq, endpoint, reason: reason.to_owned(), fee_charged, ts_nanos: now });
+    }
+
+    pub fn of_user(&self, uname: &str) -> Vec<Rejection> {
+        self.users.get(uname).into_iter().flatten().cloned().collect()
+    }
+
+    pub fn forget(&mut self, uname: &str) {
+        self.users.remove(uname);
+    }
+
+    /// Rough size in memory, see `[memory]`.
+    pub fn approx_bytes(&self) -> usize {
+        self.users.values().flatten().map(rejection_bytes).sum()
+    }
+
+    /// Drops the oldest rejections, whoever they belong to, until the rest
+    /// fit in `max_bytes`. Returns how many went.
+    pub fn evict_to(&mut self, max_bytes: usize) -> usize {
+        let mut over = self.approx_bytes().saturating_sub(max_bytes);
+        let mut n = 0;
+        while over > 0 {
+            let oldest = self.users.values_mut().filter(|q| !q.is_empty()).min_by_key(|q| q[0].seq);
+            let Some(r) = oldest.and_then(|q| q.pop_front()) else {
+                break;
+            };
+            over = over.saturating_sub(rejection_bytes(&r));
+            n += 1;
+        }
+        self.users.retain(|_, q| !q.is_empty());
+        n
+    }
+}
+
+fn rejection_bytes(r: &Rejection) -> usize {
+    std::mem::size_of::<Rejection>() + r.reason.len()
+}
+
+#[derive(Serialize, Default)]
+pub struct RejectionsResult {
+    pub rejections: Vec<Rejection>,
+    /// Sum of `fee_charged` over the list.
+    pub fees_charged: i64,
+    /// What `[[penalties]]` handed out for violations.
+    pub penalties: Vec<penalty::Penalty>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Free, so checking whether refused calls were billed costs nothing.
+pub async fn user_rejections(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<RejectionsResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.reply(StatusCode::NOT_FOUND, RejectionsResult::default());
+    }
+    let rejections = g.rejections.of_user(&uname);
+    let fees_charged = rejections.iter().map(|r| r.fee_charged).sum();
+    let penalties = g.penalties.of_user(&uname);
+    clock.reply(StatusCode::OK, RejectionsResult { rejections, fees_charged, penalties, ..Default::default() })
+}
@@ -0,0 +1,149 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+
+use crate::{book, cli, config_check, contention::StateLock, errors::ApiError, now, registration, AppConfig, AppState, PriceVol, ReqClock, RespMeta};
+
+/// Top-level keys a reload applies; a change anywhere else waits for a
+/// restart. `user_keys` is applied for new users only.
+const APPLIED: [&str; 5] = ["users", "asks", "fee", "fee_schedule", "user_keys"];
+
+#[derive(Serialize, Default)]
+pub struct ReloadResult {
+    pub users_added: Vec<String>,
+    /// Lots offered at each price on top of what was there.
+    pub asks_added: Vec<PriceVol>,
+    /// The base fee, if it changed.
+    pub fee: Option<i64>,
+    pub fee_schedule_changed: bool,
+    /// Changes in the file this left alone, and why.
+    pub not_applied: Vec<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Applies what can change while the game runs: users and ask volume are
+/// only ever added, so balances and trades are never lost, and fees take
+/// effect from the next call.
+pub fn apply(g: &mut AppState, new: AppConfig, now: i64) -> ReloadResult {
+    let mut res = ReloadResult::default();
+    // Once settled the final record is written, so no one joins and
+    // nothing more is offered, as with `/admin/users` and `/admin/asks`.
+    let settled = g.settlement.is_some();
+
+    for u in &new.users {
+        if g.names.canonical(u).is_some_and(|c| g.users.contains_key(c)) {
+            continue;
+        }
+        if settled {
+            res.not_applied.push(format!("users: {} not added, the game is settled", u));
+            continue;
+        }
+        match registration::join(g, u, new.user_keys.get(u).cloned(), now) {
+            Ok(_) => {
+                g.config.users.push(u.clone());
+                if let Some(k) = new.user_keys.get(u) {
+                    g.config.user_keys.insert(u.clone(), k.clone());
+                }
+                res.users_added.push(u.clone());
+            }
+            Err(e) => res.not_applied.push(format!("users: {}", e)),
+        }
+    }
+    let gone = g.users.keys().filter(|u| !new.users.iter().any(|n| g.names.canonical(n) == Some(u.as_str())));
+    for u in gone {
+        res.not_applied.push(format!("users: {} is no longer listed but keeps their account", u));
+    }
+    let keys: BTreeMap<_, _> = new.user_keys.iter().filter(|(u, _)| !res.users_added.contains(u)).collect();
+    if keys != g.config.user_keys.iter().collect() {
+        res.not_applied.push("user_keys: keys of existing users need a restart".to_owned());
+    }
+
+    for pv in &new.asks {
+        let have = g.config.asks.iter().find(|a| a.price == pv.price).map_or(0, |a| a.vol);
+        match pv.vol.cmp(&have) {
+            Ordering::Greater if settled => {
+                res.not_applied.push(format!("asks: {} lots at {} not offered, the game is settled", pv.vol - have, pv.price));
+            }
+            Ordering::Greater => {
+                let vol = pv.vol - have;
+                book::offer(g, pv.price, vol, pv.expires_at_nanos, now);
+                g.feeds.timeline.admin_global(format!("{} lots offered at {}", vol, pv.price));
+                book::match_resting(g, pv.price, now);
+                res.asks_added.push(PriceVol { price: pv.price, vol, expires_at_nanos: pv.expires_at_nanos });
+            }
+            Ordering::Less => {
+                res.not_applied.push(format!("asks: {} lots at {} can't be withdrawn by a reload", have - pv.vol, pv.price));
+            }
+            Ordering::Equal => {}
+        }
+    }
+
+    if new.fee != g.fee {
+        g.fee = new.fee;
+        g.config.fee = new.fee;
+        res.fee = Some(new.fee);
+    }
+    let schedule = new.fee_schedule.clone().unwrap_or_default();
+    if serde_json::to_value(&schedule).ok() != serde_json::to_value(&g.fee_schedule).ok() {
+        g.fee_schedule = schedule;
+        g.config.fee_schedule = new.fee_schedule.clone();
+        res.fee_schedule_changed = true;
+    }
+
+    let (old, new) = (serde_json::to_value(&g.config).unwrap(), serde_json::to_value(&new).unwrap());
+    for (k, v) in new.as_object().into_iter().flatten() {
+        if !APPLIED.contains(&k.as_str()) && old.get(k) != Some(v) {
+            res.not_applied.push(format!("{}: needs a restart", k));
+        }
+    }
+
+    tracing::warn!(
+        "config reloaded: {} users and {} ask levels added, fee {:?}, schedule changed {}; not applied: {:?}",
+        res.users_added.len(),
+        res.asks_added.len(),
+        res.fee,
+        res.fee_schedule_changed,
+        res.not_applied
+    );
+    g.feeds.timeline.admin_global("config reloaded");
+    res
+}
+
+/// Re-reads the config the server started from.
+fn reload(state: &Arc<Mutex<AppState>>) -> Result<ReloadResult, String> {
+    let path = state.locked().config_path.clone();
+    let new = cli::load_config(&path)?;
+    let problems = config_check::problems(&new);
+    if !problems.is_empty() {
+        return Err(format!("{}: {}", path, problems.join("; ")));
+    }
+    Ok(apply(&mut state.locked(), new, now()))
+}
+
+/// Reloads on every SIGHUP.
+pub fn listen_for_hup(state: Arc<Mutex<AppState>>) {
+    tokio::spawn(async move {
+        let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        while hup.recv().await.is_some() {
+            if let Err(e) = reload(&state) {
+                tracing::error!("SIGHUP: reload failed, nothing changed: {}", e);
+            }
+        }
+    });
+}
+
+/// `POST /admin/reload`, as SIGHUP. A file that doesn't load changes
+/// nothing and gets 400 BAD_CONFIG.
+pub async fn admin_reload(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<ReloadResult>) {
+    let clock = ReqClock::start();
+    match reload(&state) {
+        Ok(res) => clock.reply(StatusCode::OK, res),
+        Err(e) => clock.refuse(ApiError::with(StatusCode::BAD_REQUEST, "BAD_CONFIG", e), ReloadResult::default()),
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allocation::Outcome, book, client_deadline, contention::StateLock, deadline_passed, errors::ApiError, expiry,
+    fees::Endpoint, matching, modes, now, rules, storage::Store, AppState, BidResult, ReqClock, RespMeta,
+};
+
+/// Two-step bids: `POST /users/:uname/reservations` takes lots at a price
+/// off the book for `ttl_secs` and pays the `place_bid` fee, then
+/// `.../:id/confirm` buys them or `DELETE` puts them back. Unconfirmed lots
+/// go back on their own once the time is up.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReservationsConfig {
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    5
+}
+
+/// Lots held for one user. Nothing here is journaled or stored: after a
+/// restart the lots are simply back on the book.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub uname: String,
+    pub price: i64,
+    pub vol: i64,
+    pub expires_at_nanos: i64,
+    /// Lots users had listed among `vol`, see `OrderBook::take_sold`.
+    sold: Vec<(String, i64)>,
+    /// When the level's house volume was due to expire, so lots put back
+    /// after that are withdrawn as they would have been.
+    level_expiry: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Reservations {
+    pub open: BTreeMap<u64, Reservation>,
+    next_id: u64,
+}
+
+/// The book with every reservation back on it, as backups, the store and
+/// the invariant checks must see it.
+pub fn whole_book(g: &AppState) -> Cow<'_, matching::OrderBook> {
+    if g.reservations.open.is_empty() {
+        return Cow::Borrowed(&g.book);
+    }
+    let mut book = g.book.clone();
+    for r in g.reservations.open.values() {
+        *book.asks.entry(r.price).or_default() += r.vol;
+        book.unsell(r.price, &r.sold);
+    }
+    Cow::Owned(book)
+}
+
+fn put_back(g: &mut AppState, r: &Reservation, now: i64) {
+    *g.book.asks.entry(r.price).or_default() += r.vol;
+    g.book.unsell(r.price, &r.sold);
+    g.book_changed(now);
+    let house = r.vol - r.sold.iter().map(|(_, v)| v).sum::<i64>();
+    let expired = r.level_expiry.is_some_and(|at| at <= now) && !g.ask_expiry.contains_key(&r.price);
+    if expired && house > 0 {
+        expiry::withdraw(g, r.price, house, now);
+    }
+}
+
+/// Takes what the level at `price` has, up to `qty`, off the book for
+/// `uname` until `expires_at_nanos`. Returns the reservation's id and the
+/// lots held, or `None` if nothing is offered at that price.
+pub fn hold(g: &mut AppState, uname: &str, price: i64, qty: i64, expires_at_nanos: i64, now: i64) -> Option<(u64, matching::Fill)> {
+    let fill = matching::match_bid(&mut g.book.asks, price, qty)?;
+    let sold = g.book.take_sold(price);
+    g.book_changed(now);
+    let level_expiry = g.ask_expiry.get(&price).copied();
+    g.reservations.next_id += 1;
+    let id = g.reservations.next_id;
+    let r = Reservation { uname: uname.to_owned(), price, vol: fill.vol, expires_at_nanos, sold, level_expiry };
+    g.reservations.open.insert(id, r);
+    Some((id, fill))
+}
+
+/// Puts reservation `id` back and, unless trading is paused or halted,
+/// lets resting bids at its price have it.
+pub fn release(g: &mut AppState, id: u64, now: i64) -> Option<Reservation> {
+    let r = g.reservations.open.remove(&id)?;
+    put_back(g, &r, now);
+    if !g.paused && !g.breaker.halted(now) {
+        book::match_resting(g, r.price, now);
+    }
+    Some(r)
+}
+
+/// Puts every reservation back before settlement.
+pub fn release_all(g: &mut AppState, now: i64) {
+    for r in std::mem::take(&mut g.reservations.open).into_values() {
+        put_back(g, &r, now);
+    }
+}
+
+/// Puts back what `uname` holds and what anyone holds of their listed
+/// lots, before they leave. Other reservations are kept.
+pub fn release_user(g: &mut AppState, uname: &str, now: i64) {
+    let theirs = |r: &Reservation| r.uname == uname || r.sold.iter().any(|(seller, _)| seller == uname);
+    let ids: Vec<u64> = g.reservations.open.iter().filter(|(_, r)| theirs(r)).map(|(id, _)| *id).collect();
+    for id in ids {
+        let r = g.reservations.open.remove(&id).unwrap();
+        put_back(g, &r, now);
+    }
+}
+
+/// Releases whatever is past its time.
+pub fn sweep(g: &mut AppState, now: i64) {
+    let due: Vec<u64> = g.reservations.open.iter().filter(|(_, r)| r.expires_at_nanos <= now).map(|(id, _)| *id).collect();
+    for id in due {
+        release(g, id, now);
+    }
+}
+
+pub fn spawn_sweeper(state: Arc<Mutex<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            sweep(&mut state.locked(), now());
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReserveRequest {
+    pub price: i64,
+    #[serde(default = "default_qty")]
+    pub qty: i64,
+}
+
+fn default_qty() -> i64 {
+    1
+}
+
+#[derive(Serialize, Default)]
+pub struct ReserveResult {
+    pub id: Option<u64>,
+    pub price: i64,
+    /// Lots held, at most the `qty` asked for.
+    pub vol: i64,
+    /// What confirming will cost.
+    pub cost: i64,
+    pub expires_at_nanos: i64,
+    pub total_fees: i64,
+    pub reject_reason: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Passes the same checks as `place_bid` and pays its fee, then holds what
+/// the level has, up to `qty`. One reservation per user at a time.
+pub async fn user_reserve(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<ReserveResult>) {
+    let clock = ReqClock::start();
+    let ep = Endpoint::PlaceBid;
+    let Ok(deadline) = client_deadline(&headers) else {
+        state.locked().reject(&uname, ep, "BAD_DEADLINE", 0, now());
+        return clock.refuse(ApiError::new(StatusCode::BAD_REQUEST, "BAD_DEADLINE"), ReserveResult::default());
+    };
+    let mut g = state.locked();
+    let now = now();
+    let Some(cfg) = g.reservations_cfg.clone() else {
+        let err = ApiError::with(StatusCode::NOT_FOUND, "NOT_OFFERED", "reservations are not offered");
+        return clock.refuse(err, ReserveResult::default());
+    };
+    let Ok(req) = serde_json::from_slice::<ReserveRequest>(&body) else {
+        g.reject(&uname, ep, "INVALID_ORDER", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "INVALID_ORDER"), ReserveResult::default());
+    };
+    if deadline_passed(deadline) {
+        g.reject(&uname, ep, "DEADLINE_PASSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::REQUEST_TIMEOUT, "DEADLINE_PASSED"), ReserveResult::default());
+    }
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), ReserveResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(&uname, ep, "HALTED", 0, now);
+        let res = ReserveResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    let Some(ua) = g.users.get(&uname) else {
+        return clock.refuse(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"), ReserveResult::default());
+    };
+
+    let elapsed_secs = now.saturating_sub(g.calendar.first_open()) / 1_000_000_000;
+    let fee = g.fee_schedule.fee(g.fee, ep, now);
+    let bid = rules::Bid { price: req.price, qty: req.qty, fee, balance: ua.balance, position: ua.position, elapsed_secs };
+    let (bid, vetoed) = match g.rules.apply(bid) {
+        Ok(b) => (b, None),
+        Err(code) => (bid, Some(code)),
+    };
+    let (price, qty, fee) = (bid.price, bid.qty, bid.fee.max(0));
+    if let Err(reason) = g.charge_request(&uname, fee, ep, Some(clock.id()), now) {
+        g.reject(&uname, ep, reason, 0, now);
+        let res = ReserveResult { reject_reason: Some(reason.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, reason), res);
+    }
+    let mode = g.mode.clone();
+    let entry = match vetoed {
+        Some(code) => Err(code),
+        None if qty < 1 => Err("INVALID_ORDER"),
+        None if g.reservations.open.values().any(|r| r.uname == uname) => Err("ALREADY_RESERVED"),
+        None => mode.on_bid(&g, &uname, price, qty, now),
+    };
+    let refused = match entry {
+        Ok(modes::Entry::Match) => None,
+        // Sealed bids are only matched at the close.
+        Ok(modes::Entry::Seal) => Some("SEALED_BIDS"),
+        Err(code) => Some(code),
+    };
+    if let Some(code) = refused {
+        g.reject(&uname, ep, code, fee, now);
+        let res = ReserveResult { reject_reason: Some(code.to_owned()), total_fees: fee, ..Default::default() };
+        let code = if code == "MARKET_CLOSED" { g.closed_reason(&uname, now) } else { code };
+        let err = match vetoed {
+            Some(_) => ApiError::with(StatusCode::FORBIDDEN, code, "refused by an operator rule"),
+            None => ApiError::new(StatusCode::FORBIDDEN, code),
+        };
+        return clock.refuse(err, res);
+    }
+
+    let expires_at_nanos = now + Duration::from_secs(cfg.ttl_secs).as_nanos() as i64;
+    let Some((id, fill)) = hold(&mut g, &uname, price, qty, expires_at_nanos, now) else {
+        g.reject(&uname, ep, "NO_VOLUME", fee, now);
+        let res = ReserveResult { price, reject_reason: Some("NO_VOLUME".to_owned()), total_fees: fee, ..Default::default() };
+        return clock.refuse(ApiError::with(StatusCode::CONFLICT, "NO_VOLUME", "nothing is offered at that price"), res);
+    };
+    let res = ReserveResult {
+        id: Some(id),
+        price,
+        vol: fill.vol,
+        cost: matching::fill_cost(fill),
+        expires_at_nanos,
+        total_fees: fee,
+        ..Default::default()
+    };
+    clock.reply(StatusCode::OK, res)
+}
+
+/// The user's reservation `id`, or why it can't be used.
+fn own(g: &AppState, uname: &str, id: u64) -> Result<(), ApiError> {
+    if g.reservations_cfg.is_none() {
+        return Err(ApiError::with(StatusCode::NOT_FOUND, "NOT_OFFERED", "reservations are not offered"));
+    }
+    if !g.users.contains_key(uname) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_USER"));
+    }
+    match g.reservations.open.get(&id) {
+        Some(r) if r.uname == uname => Ok(()),
+        // Released, confirmed, or past its time and swept.
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "UNKNOWN_RESERVATION")),
+    }
+}
+
+/// Buys the reserved lots, free. While trading is paused, halted or closed
+/// the lots stay held. The entry checks run again, so a user who traded or
+/// ran short in the meantime gets the lots released instead.
+pub async fn user_confirm(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<BidResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    let now = now();
+    if let Err(err) = own(&g, &uname, id) {
+        return clock.refuse(err, BidResult::default());
+    }
+    let ep = Endpoint::PlaceBid;
+    if g.paused {
+        g.reject(&uname, ep, "PAUSED", 0, now);
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "PAUSED"), BidResult::default());
+    }
+    if g.breaker.halted(now) {
+        g.reject(&uname, ep, "HALTED", 0, now);
+        let res = BidResult { reject_reason: Some("HALTED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, "HALTED"), res);
+    }
+    if !g.trading_open(&uname, now) {
+        g.reject(&uname, ep, "MARKET_CLOSED", 0, now);
+        let res = BidResult { reject_reason: Some("MARKET_CLOSED".to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, g.closed_reason(&uname, now)), res);
+    }
+    if g.reservations.open[&id].expires_at_nanos <= now {
+        release(&mut g, id, now);
+        return clock.refuse(ApiError::new(StatusCode::CONFLICT, "EXPIRED"), BidResult::default());
+    }
+    let r = g.reservations.open.remove(&id).unwrap();
+    // Back on the book and straight off again under the same lock, so the
+    // sale goes through the usual fill path.
+    put_back(&mut g, &r, now);
+    let mode = g.mode.clone();
+    if let Err(code) = mode.on_bid(&g, &uname, r.price, r.vol, now) {
+        g.reject(&uname, ep, code, 0, now);
+        book::match_resting(&mut g, r.price, now);
+        let res = BidResult { reject_reason: Some(code.to_owned()), ..Default::default() };
+        return clock.refuse(ApiError::new(StatusCode::FORBIDDEN, code), res);
+    }
+    let fill = g.take_ask(r.price, r.vol);
+    if let Some(fill) = fill {
+        g.fill(&uname, fill, now);
+        g.check_breaker(now);
+    }
+    let remaining = r.vol - fill.map_or(0, |f| f.vol);
+    let mut res = BidResult { qty: r.vol, ..Default::default() };
+    res.report(Outcome { fill, remaining, resting: false, position: g.users[&uname].position });
+    clock.reply(StatusCode::OK, res)
+}
+
+#[derive(Serialize, Default)]
+pub struct ReleaseResult {
+    pub price: i64,
+    pub vol: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Gives the lots back early. Free; the reservation fee is not refunded.
+pub async fn user_release(
+    Path((uname, id)): Path<(String, u64)>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<ReleaseResult>) {
+    let clock = ReqClock::start();
+    let mut g = state.locked();
+    if let Err(err) = own(&g, &uname, id) {
+        return clock.refuse(err, ReleaseResult::default());
+    }
+    let r = release(&mut g, id, now()).unwrap();
+    clock.reply(StatusCode::OK, ReleaseResult { price: r.price, vol: r.vol, ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{invariants, orders::{OrderStatus, TimeInForce}, testing};
+
+    const CONFIG: &str = "asks = [{ price = 10, vol = 4 }, { price = 20, vol = 2, expires_at_nanos = 50 }]
+[reservations]";
+
+    fn game() -> AppState {
+        testing::game(CONFIG)
+    }
+
+    #[test]
+    fn held_lots_leave_the_book_but_not_the_game() {
+        let mut g = game();
+        let (id, fill) = hold(&mut g, "bob", 10, 3, 100, 1).unwrap();
+        assert_eq!((fill.price, fill.vol), (10, 3));
+        assert_eq!(g.book.asks[&10], 1);
+        assert_eq!(whole_book(&g).asks[&10], 4);
+        assert!(invariants::verify(&g).ok);
+        assert!(hold(&mut g, "carol", 15, 1, 100, 1).is_none());
+
+        release(&mut g, id, 2).unwrap();
+        assert_eq!(g.book.asks[&10], 4);
+        assert!(g.reservations.open.is_empty());
+        assert!(release(&mut g, id, 2).is_none());
+    }
+
+    #[test]
+    fn listed_lots_go_back_to_the_head_of_the_queue() {
+        let mut g = game();
+        let a = g.orders.accept("alice", 10, 2, TimeInForce::Ioc, 1);
+        book::enter(&mut g, "alice", a, 1);
+        book::list(&mut g, "alice", 10, 2, 2);
+        // House volume goes first, so holding 3 of 4 takes the house's 2
+        // and one of alice's.
+        let (id, _) = hold(&mut g, "bob", 10, 3, 100, 3).unwrap();
+        assert_eq!(g.book.listed_at(10), 1);
+        assert_eq!(whole_book(&g).listed_at(10), 2);
+        assert!(invariants::verify(&g).ok);
+        release(&mut g, id, 4);
+        let lots: Vec<(i64, &str, i64)> = g.book.sell_lots().collect();
+        assert_eq!(lots, vec![(10, "alice", 1), (10, "alice", 1)]);
+        assert_eq!(g.users["alice"].listed, 2);
+    }
+
+    #[test]
+    fn sweep_releases_only_what_is_due_and_resting_bids_get_it() {
+        let mut g = game();
+        let (due, _) = hold(&mut g, "bob", 10, 4, 10, 1).unwrap();
+        let (later, _) = hold(&mut g, "carol", 20, 1, 30, 1).unwrap();
+        let o = g.orders.accept("alice", 10, 1, TimeInForce::Gtc, 2);
+        book::enter(&mut g, "alice", o, 2);
+        assert_eq!(g.orders.orders[&o].status, OrderStatus::Resting);
+
+        sweep(&mut g, 10);
+        assert!(!g.reservations.open.contains_key(&due));
+        assert!(g.reservations.open.contains_key(&later));
+        assert_eq!(g.orders.orders[&o].status, OrderStatus::Filled);
+        assert_eq!(g.book.asks[&10], 3);
+        assert!(invariants::verify(&g).ok, "{:?}", invariants::verify(&g).violations);
+    }
+
+    #[test]
+    fn house_lots_held_past_their_expiry_are_withdrawn_on_return() {
+        let mut g = game();
+        let (id, _) = hold(&mut g, "bob", 20, 1, 100, 1).unwrap();
+        expiry::sweep(&mut g, 50);
+        assert!(!g.book.asks.contains_key(&20));
+        release(&mut g, id, 60);
+        assert!(!g.book.asks.contains_key(&20));
+        assert_eq!(g.issued.units, 4);
+        assert!(invariants::verify(&g).ok, "{:?}", invariants::verify(&g).violations);
+    }
+
+    #[test]
+    fn a_leaving_user_takes_back_only_their_own_holds() {
+        let mut g = game();
+        let a = g.orders.accept("alice", 10, 4, TimeInForce::Ioc, 1);
+        book::enter(&mut g, "alice", a, 1);
+        book::list(&mut g, "alice", 15, 2, 2);
+        let (on_alice, _) = hold(&mut g, "bob", 15, 1, 100, 3).unwrap();
+        let (by_alice, _) = hold(&mut g, "alice", 20, 1, 100, 3).unwrap();
+        let (other, _) = hold(&mut g, "carol", 20, 1, 100, 3).unwrap();
+        release_user(&mut g, "alice", 4);
+        assert!(!g.reservations.open.contains_key(&on_alice));
+        assert!(!g.reservations.open.contains_key(&by_alice));
+        assert!(g.reservations.open.contains_key(&other));
+        assert_eq!(g.book.listed_at(15), 2);
+        assert!(invariants::verify(&g).ok, "{:?}", invariants::verify(&g).violations);
+    }
+
+    #[tokio::test]
+    async fn confirming_while_paused_is_refused_and_the_lots_stay_held() {
+        let state = testing::shared(CONFIG);
+        let (id, _) = hold(&mut state.locked(), "bob", 10, 3, i64::MAX, 1).unwrap();
+        let confirm = || user_confirm(Path(("bob".to_owned(), id)), State(state.clone()));
+
+        state.locked().paused = true;
+        let (code, Json(res)) = confirm().await;
+        assert_eq!((code, res.meta.error.as_ref().map(|e| e.code)), (StatusCode::FORBIDDEN, Some("PAUSED")));
+        {
+            let g = state.locked();
+            assert!(g.reservations.open.contains_key(&id));
+            assert_eq!((g.users["bob"].position, g.book.asks[&10]), (0, 1));
+        }
+
+        state.locked().paused = false;
+        let (code, _) = confirm().await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(state.locked().users["bob"].position, 3);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, now, tape::Trade, AppState};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    pub tape_max_entries: Option<usize>,
+    pub tape_max_age_secs: Option<u64>,
+    /// Pruned trades are appended here as JSON lines; without it they are dropped.
+    pub prune_dir: Option<String>,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    30
+}
+
+/// Serializes appends from the pruner with rewrites from anonymization.
+static ARCHIVE_LOCK: Mutex<()> = Mutex::new(());
+
+fn archive_path(dir: &str) -> PathBuf {
+    Path::new(dir).join("tape.jsonl")
+}
+
+pub fn archive(dir: &str, trades: &[Trade]) -> std::io::Result<()> {
+    let _g = ARCHIVE_LOCK.lock().unwrap();
+    std::fs::create_dir_all(dir)?;
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_path(dir))?;
+    for t in trades {
+        serde_json::to_writer(&mut f, t)?;
+        f.write_all(b"\n")?;
+    }
+    f.flush()
+}
+
+/// Reads back every archived trade. A missing archive is an empty one.
+pub fn read_archive(dir: &str) -> std::io::Result<Vec<Trade>> {
+    let body = match std::fs::read_to_string(archive_path(dir)) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    body.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| serde_json::from_str(l).map_err(std::io::Error::other))
+        .collect()
+}
+
+/// Rewrites the archive with `uname` replaced by `alias`, returning how many
+/// trades were touched.
+pub fn anonymize_archive(dir: &str, uname: &str, alias: &str) -> std::io::Result<usize> {
+    let _g = ARCHIVE_LOCK.lock().unwrap();
+    let mut trades = read_archive(dir)?;
+    let mut n = 0;
+    for t in trades.iter_mut().filter(|t| t.uname == uname) {
+        t.uname = alias.to_owned();
+        n += 1;
+    }
+    if n > 0 {
+        let tmp = archive_path(dir).with_extension("jsonl.tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        for t in &trades {
+            serde_json::to_writer(&mut f, t)?;
+            f.write_all(b"\n")?;
+        }
+        f.sync_all()?;
+        std::fs::rename(tmp, archive_path(dir))?;
+    }
+    Ok(n)
+}
+
+/// Periodically trims in-memory histories according to `cfg` so a
+/// long-running server doesn't grow without bound. Stops once the game is
+/// settled, so the whole tape stays up for review.
+pub fn spawn_pruner(cfg: RetentionConfig, state: Arc<Mutex<AppState>>) {
+    if cfg.tape_max_entries.is_none() && cfg.tape_max_age_secs.is_none() {
+        return;
+    }
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(cfg.interval_secs.max(1)));
+        let min_ts = cfg
+            .tape_max_age_secs
+            .map(|s| now().saturating_sub((s as i64).saturating_mul(1_000_000_000)));
+        let pruned = {
+            let mut g = state.locked();
+            if g.settlement.is_some() {
+                return;
+            }
+            g.tape.prune(cfg.tape_max_entries, min_ts)
+        };
+        if pruned.is_empty() {
+            continue;
+        }
+        match &cfg.prune_dir {
+            Some(dir) => match archive(dir, &pruned) {
+                Ok(()) => tracing::info!("archived {} trades to {}", pruned.len(), dir),
+                Err(e) => tracing::error!("archiving {} pruned trades failed: {}", pruned.len(), e),
+            },
+            None => tracing::info!("dropped {} trades past retention", pruned.len()),
+        }
+    });
+}
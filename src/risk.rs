@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+use crate::UserAccount;
+
+/// Per-user caps checked at order entry, before anything is matched.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RiskConfig {
+    /// Most a user may spend on fills over the whole game, fees excluded.
+    pub max_notional: Option<i64>,
+    /// Most lots a user may hold.
+    pub max_position: Option<i64>,
+}
+
+impl RiskConfig {
+    /// Error code for the first limit the order would breach.
+    pub fn check(&self, ua: &UserAccount, price: i64, qty: i64) -> Result<(), &'static str> {
+        let notional = price.checked_mul(qty).ok_or("RISK_LIMIT_NOTIONAL")?;
+        if self
+            .max_notional
+            .is_some_and(|max| ua.notional_spent.checked_add(notional).map_or(true, |n| n > max))
+        {
+            return Err("RISK_LIMIT_NOTIONAL");
+        }
+        if self
+            .max_position
+            .is_some_and(|max| ua.position.checked_add(qty).map_or(true, |p| p > max))
+        {
+            return Err("RISK_LIMIT_POSITION");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! Operator rules for bids, written as small expressions in config, so new
+//! game rules can be tried without a rebuild. The language is integers and
+//! booleans only: `+ - * / %`, comparisons, `&& || !`, parentheses, and
+//! `min`, `max` and `abs`. It can't loop or reach anything outside the
+//! bid, and each expression is capped in size, so a rule always finishes
+//! in a few microseconds.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest expression accepted, in tokens.
+const MAX_TOKENS: usize = 256;
+
+/// One rule under `[[rules]]`. When `when` holds for a bid, `veto` refuses
+/// it with that code; otherwise `price`, `qty` and `fee` are replaced by
+/// their expressions' values. Rules apply in order, each seeing what the
+/// ones before it changed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RuleConfig {
+    #[serde(default = "default_when")]
+    pub when: String,
+    /// Upper-case code returned with a 403, e.g. `"TOO_LATE"`.
+    pub veto: Option<String>,
+    pub price: Option<String>,
+    pub qty: Option<String>,
+    pub fee: Option<String>,
+}
+
+fn default_when() -> String {
+    "true".to_owned()
+}
+
+/// What a rule can see: the bid and the bidder.
+#[derive(Debug, Clone, Copy)]
+pub struct Bid {
+    pub price: i64,
+    pub qty: i64,
+    pub fee: i64,
+    pub balance: i64,
+    pub position: i64,
+    /// Seconds since the market first opened.
+    pub elapsed_secs: i64,
+}
+
+impl Bid {
+    fn var(&self, name: &str) -> Option<i64> {
+        Some(match name {
+            "price" => self.price,
+            "qty" => self.qty,
+            "fee" => self.fee,
+            "balance" => self.balance,
+            "position" => self.position,
+            "elapsed_secs" => self.elapsed_secs,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Longest first, so `<=` isn't read as `<`.
+const OPS: [&str; 17] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", ","];
+
+fn lex(src: &str) -> Result<Vec<Tok>, String> {
+    let mut toks = Vec::new();
+    let mut rest = src.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            toks.push(Tok::Num(rest[..end].parse().map_err(|_| format!("number too large: {}", &rest[..end]))?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            toks.push(Tok::Ident(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else {
+            let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) else {
+                return Err(format!("unexpected {:?}", c));
+            };
+            toks.push(match *op {
+                "(" => Tok::LParen,
+                ")" => Tok::RParen,
+                "," => Tok::Comma,
+                op => Tok::Op(op),
+            });
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+        if toks.len() > MAX_TOKENS {
+            return Err(format!("longer than {} tokens", MAX_TOKENS));
+        }
+    }
+    Ok(toks)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Int(i64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+fn precedence(op: &str) -> u8 {
+    match op {
+        "||" => 1,
+        "&&" => 2,
+        "==" | "!=" => 3,
+        "<" | "<=" | ">" | ">=" => 4,
+        "+" | "-" => 5,
+        _ => 6,
+    }
+}
+
+struct Parser {
+    toks: Vec<Tok>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Tok> {
+        let t = self.toks.get(self.at).cloned();
+        self.at += 1;
+        t
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.at)
+    }
+
+    fn expect(&mut self, want: Tok) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == want => Ok(()),
+            t => Err(format!("expected {:?}, found {:?}", want, t)),
+        }
+    }
+
+    fn expr(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut lhs = self.atom()?;
+        while let Some(Tok::Op(op)) = self.peek() {
+            let op = *op;
+            if op == "!" || precedence(op) < min_prec {
+                break;
+            }
+            self.at += 1;
+            let rhs = self.expr(precedence(op) + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Tok::Num(n)) => Ok(Expr::Lit(Value::Int(n))),
+            Some(Tok::Op(op @ ("-" | "!"))) => Ok(Expr::Unary(op, Box::new(self.expr(7)?))),
+            Some(Tok::LParen) => {
+                let e = self.expr(0)?;
+                self.expect(Tok::RParen)?;
+                Ok(e)
+            }
+            Some(Tok::Ident(name)) if name == "true" || name == "false" => Ok(Expr::Lit(Value::Bool(name == "true"))),
+            Some(Tok::Ident(name)) if self.peek() == Some(&Tok::LParen) => {
+                self.at += 1;
+                let mut args = Vec::new();
+                if self.peek() != Some(&Tok::RParen) {
+                    loop {
+                        args.push(self.expr(0)?);
+                        if self.peek() != Some(&Tok::Comma) {
+                            break;
+                        }
+                        self.at += 1;
+                    }
+                }
+                self.expect(Tok::RParen)?;
+                Ok(Expr::Call(name, args))
+            }
+            Some(Tok::Ident(name)) => Ok(Expr::Var(name)),
+            t => Err(format!("unexpected {:?}", t)),
+        }
+    }
+}
+
+/// Parses an expression that must come out a boolean, or a number.
+fn parse(src: &str, want_bool: bool) -> Result<Expr, String> {
+    let mut p = Parser { toks: lex(src)?, at: 0 };
+    let e = p.expr(0)?;
+    if let Some(t) = p.peek() {
+        return Err(format!("unexpected {:?}", t));
+    }
+    // Catches unknown names and type errors now rather than on a live bid.
+    let probe = Bid { price: 1, qty: 1, fee: 1, balance: 1, position: 1, elapsed_secs: 1 };
+    match eval(&e, &probe) {
+        Ok(Value::Bool(_)) if !want_bool => Err("expected a number, found a boolean".to_owned()),
+        Ok(Value::Int(_)) if want_bool => Err("expected a boolean, found a number".to_owned()),
+        Err(e) if !e.contains("by zero") && !e.contains("overflow") => Err(e),
+        _ => Ok(e),
+    }
+}
+
+fn int(v: Value) -> Result<i64, String> {
+    match v {
+        Value::Int(n) => Ok(n),
+        Value::Bool(_) => Err("expected a number, found a boolean".to_owned()),
+    }
+}
+
+fn boolean(v: Value) -> Result<bool, String> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        Value::Int(_) => Err("expected a boolean, found a number".to_owned()),
+    }
+}
+
+fn eval(e: &Expr, bid: &Bid) -> Result<Value, String> {
+    let overflow = || "overflow".to_owned();
+    Ok(match e {
+        Expr::Lit(v) => *v,
+        Expr::Var(name) => Value::Int(bid.var(name).ok_or_else(|| format!("unknown name {}", name))?),
+        Expr::Unary("-", a) => Value::Int(int(eval(a, bid)?)?.checked_neg().ok_or_else(overflow)?),
+        Expr::Unary(_, a) => Value::Bool(!boolean(eval(a, bid)?)?),
+        Expr::Binary(op @ ("&&" | "||"), a, b) => {
+            let a = boolean(eval(a, bid)?)?;
+            // No short-circuit, so a type error shows whichever way `a` goes.
+            let b = boolean(eval(b, bid)?)?;
+            Value::Bool(if *op == "&&" { a && b } else { a || b })
+        }
+        Expr::Binary(op @ ("==" | "!="), a, b) => {
+            let eq = eval(a, bid)? == eval(b, bid)?;
+            Value::Bool(if *op == "==" { eq } else { !eq })
+        }
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (int(eval(a, bid)?)?, int(eval(b, bid)?)?);
+            match *op {
+                "<" => Value::Bool(a < b),
+                "<=" => Value::Bool(a <= b),
+                ">" => Value::Bool(a > b),
+                ">=" => Value::Bool(a >= b),
+                "+" => Value::Int(a.checked_add(b).ok_or_else(overflow)?),
+                "-" => Value::Int(a.checked_sub(b).ok_or_else(overflow)?),
+                "*" => Value::Int(a.checked_mul(b).ok_or_else(overflow)?),
+                "/" => Value::Int(a.checked_div(b).ok_or("division by zero")?),
+                _ => Value::Int(a.checked_rem(b).ok_or("remainder by zero")?),
+            }
+        }
+        Expr::Call(f, args) => {
+            let args: Vec<i64> = args.iter().map(|a| int(eval(a, bid)?)).collect::<Result<_, _>>()?;
+            match (f.as_str(), args.as_slice()) {
+                ("min", [a, rest @ ..]) => Value::Int(rest.iter().fold(*a, |m, x| m.min(*x))),
+                ("max", [a, rest @ ..]) => Value::Int(rest.iter().fold(*a, |m, x| m.max(*x))),
+                ("abs", [a]) => Value::Int(a.checked_abs().ok_or_else(overflow)?),
+                _ => return Err(format!("unknown function {}/{}", f, args.len())),
+            }
+        }
+    })
+}
+
+#[derive(Debug)]
+struct Rule {
+    source: String,
+    when: Expr,
+    veto: Option<&'static str>,
+    price: Option<Expr>,
+    qty: Option<Expr>,
+    fee: Option<Expr>,
+}
+
+/// The parsed `[[rules]]`.
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(cfg: &[RuleConfig]) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for (i, r) in cfg.iter().enumerate() {
+            let at = |field: &str, e: String| format!("[[rules]] #{} {}: {}", i + 1, field, e);
+            let opt = |field: &str, src: &Option<String>| {
+                src.as_deref().map(|s| parse(s, false)).transpose().map_err(|e| at(field, e))
+            };
+            let veto = match r.veto.as_deref() {
+                Some(code) if code.is_empty() || !code.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') => {
+                    return Err(at("veto", "codes are upper-case letters and underscores".to_owned()));
+                }
+                // Codes live as long as the server, like the built-in ones.
+                Some(code) => Some(&*Box::leak(code.to_owned().into_boxed_str())),
+                None => None,
+            };
+            rules.push(Rule {
+                source: r.when.clone(),
+                when: parse(&r.when, true).map_err(|e| at("when", e))?,
+                veto,
+                price: opt("price", &r.price)?,
+                qty: opt("qty", &r.qty)?,
+                fee: opt("fee", &r.fee)?,
+            });
+        }
+        Ok(Rules(rules))
+    }
+
+    /// Runs every rule over the bid, returning it as changed or the code of
+    /// the first veto. A rule that fails to evaluate, say on a division by
+    /// zero, is logged and skipped.
+    pub fn apply(&self, mut bid: Bid) -> Result<Bid, &'static str> {
+        for r in self.0.iter() {
+            match r.run(&bid) {
+                Ok(Some(next)) => bid = next?,
+                Ok(None) => {}
+                Err(e) => tracing::warn!("rule `{}` skipped: {}", r.source, e),
+            }
+        }
+        Ok(bid)
+    }
+}
+
+impl Rule {
+    /// `None` if `when` doesn't hold.
+    fn run(&self, bid: &Bid) -> Result<Option<Result<Bid, &'static str>>, String> {
+        if !boolean(eval(&self.when, bid)?)? {
+            return Ok(None);
+        }
+        if let Some(code) = self.veto {
+            return Ok(Some(Err(code)));
+        }
+        let mut next = *bid;
+        for (e, field) in [(&self.price, &mut next.price), (&self.qty, &mut next.qty), (&self.fee, &mut next.fee)] {
+            if let Some(e) = e {
+                *field = int(eval(e, bid)?)?;
+            }
+        }
+        Ok(Some(Ok(next)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bid() -> Bid {
+        Bid { price: 100, qty: 3, fee: 10, balance: 1000, position: 0, elapsed_secs: 30 }
+    }
+
+    fn value(src: &str) -> Result<Value, String> {
+        eval(&parse(src, false).or_else(|_| parse(src, true))?, &bid())
+    }
+
+    fn rules(toml: &str) -> Result<Rules, String> {
+        #[derive(Deserialize)]
+        struct Cfg {
+            rules: Vec<RuleConfig>,
+        }
+        Rules::new(&toml::from_str::<Cfg>(toml).unwrap().rules)
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(value("1 + 2 * 3"), Ok(Value::Int(7)));
+        assert_eq!(value("(1 + 2) * 3"), Ok(Value::Int(9)));
+        assert_eq!(value("10 - 4 - 3"), Ok(Value::Int(3)));
+        assert_eq!(value("-price + 1"), Ok(Value::Int(-99)));
+        assert_eq!(value("1 < 2 && 2 < 1 || true"), Ok(Value::Bool(true)));
+        assert_eq!(value("!(price >= 100) == false"), Ok(Value::Bool(true)));
+        assert_eq!(value("min(price, 7, qty) + max(1) + abs(-5) + price % 7"), Ok(Value::Int(3 + 1 + 5 + 2)));
+    }
+
+    #[test]
+    fn mistakes_are_caught_at_parse_time() {
+        assert!(parse("price +", false).unwrap_err().contains("unexpected"));
+        assert!(parse("(price", false).unwrap_err().contains("expected"));
+        assert!(parse("price 1", false).unwrap_err().contains("unexpected"));
+        assert!(parse("price # 1", false).unwrap_err().contains("unexpected"));
+        assert_eq!(parse("volume > 1", true).unwrap_err(), "unknown name volume");
+        assert_eq!(parse("sqrt(price)", false).unwrap_err(), "unknown function sqrt/1");
+        assert_eq!(parse("price > 1", false).unwrap_err(), "expected a number, found a boolean");
+        assert_eq!(parse("price + 1", true).unwrap_err(), "expected a boolean, found a number");
+        assert_eq!(parse("true && 1", true).unwrap_err(), "expected a boolean, found a number");
+        assert!(parse("99999999999999999999", false).unwrap_err().contains("too large"));
+        assert!(parse(&vec!["1"; MAX_TOKENS].join("+"), false).unwrap_err().contains("tokens"));
+    }
+
+    #[test]
+    fn runtime_faults_wait_for_a_live_bid() {
+        // Only the probe bid divides by zero here, so the rule is accepted.
+        assert!(parse("100 / (price - 1)", false).is_ok());
+        let e = parse("price / (qty - 3)", false).unwrap();
+        assert_eq!(eval(&e, &bid()), Err("division by zero".to_owned()));
+        let e = parse("price * 9223372036854775807", false).unwrap();
+        assert_eq!(eval(&e, &bid()), Err("overflow".to_owned()));
+    }
+
+    #[test]
+    fn rules_apply_in_order_and_veto() {
+        let r = rules(
+            r#"
+            [[rules]]
+            when = "elapsed_secs < 60"
+            fee = "fee * 2"
+
+            [[rules]]
+            price = "price - 1"
+            qty = "min(qty, 2)"
+
+            [[rules]]
+            when = "fee > 15 && price < 100"
+            veto = "TOO_EARLY"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(r.apply(bid()).unwrap_err(), "TOO_EARLY");
+        let late = r.apply(Bid { elapsed_secs: 90, ..bid() }).unwrap();
+        assert_eq!((late.price, late.qty, late.fee), (99, 2, 10));
+    }
+
+    #[test]
+    fn a_rule_that_fails_is_skipped() {
+        let r = rules(
+            r#"
+            [[rules]]
+            price = "price / (qty - 3)"
+
+            [[rules]]
+            qty = "qty + 1"
+            "#,
+        )
+        .unwrap();
+        let b = r.apply(bid()).unwrap();
+        assert_eq!((b.price, b.qty), (100, 4));
+    }
+
+    #[test]
+    fn bad_config_names_the_rule_and_field() {
+        let err = rules("[[rules]]\nwhen = \"true\"\nveto = \"too_early\"").unwrap_err();
+        assert!(err.starts_with("[[rules]] #1 veto:"));
+        let err = rules("[[rules]]\nqty = \"1\"\n\n[[rules]]\nwhen = \"qty\"").unwrap_err();
+        assert_eq!(err, "[[rules]] #2 when: expected a boolean, found a number");
+    }
+}
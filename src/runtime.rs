@@ -0,0 +1,101 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpSocket, UnixListener},
+};
+
+/// Tokio and listener knobs. Unset fields keep tokio's defaults, which suit
+/// a large box; a single-core VPS usually wants `worker_threads = 1`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RuntimeConfig {
+    /// Defaults to one per core.
+    pub worker_threads: Option<usize>,
+    /// Pool for blocking work such as SQLite and archive files.
+    pub max_blocking_threads: Option<usize>,
+    /// Accepted-but-unserved connections the kernel may queue; TCP only.
+    pub listen_backlog: Option<u32>,
+}
+
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+pub fn build(cfg: &RuntimeConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let workers = cfg
+        .worker_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let blocking = cfg.max_blocking_threads.unwrap_or(DEFAULT_MAX_BLOCKING_THREADS);
+    tracing::info!(
+        "runtime: {} worker threads, {} max blocking threads, listen backlog {}",
+        workers,
+        blocking,
+        cfg.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG)
+    );
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .max_blocking_threads(blocking)
+        .enable_all()
+        .build()
+}
+
+/// A bound `--listen` or `[listeners]` address: `host:port`, or
+/// `unix:/path.sock` to sit behind a local proxy without a TCP port.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+/// An accepted connection of either kind.
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Conn for T {}
+
+impl Listener {
+    /// Unix socket peers have no address; they count as 127.0.0.1, for
+    /// per-IP limits and `trusted_proxies` alike.
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn Conn>, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => l.accept().await.map(|(s, peer)| (Box::new(s) as Box<dyn Conn>, peer)),
+            Listener::Unix(l, _) => {
+                let (s, _) = l.accept().await?;
+                Ok((Box::new(s), SocketAddr::from((Ipv4Addr::LOCALHOST, 0))))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub async fn bind(addr: &str, cfg: &RuntimeConfig) -> std::io::Result<Listener> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // A socket left behind by an earlier run would make bind fail.
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(Listener::Unix(UnixListener::bind(path)?, PathBuf::from(path)));
+    }
+    bind_tcp(addr, cfg).await.map(Listener::Tcp)
+}
+
+async fn bind_tcp(addr: &str, cfg: &RuntimeConfig) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "address resolved to nothing"))?;
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(cfg.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))
+}
@@ -0,0 +1,234 @@
+//! Versioning for state written to disk. Every persisted `StateImage` carries
+//! `schema_version`; loading walks it forward one step at a time so files
+//! from older releases keep loading after the in-memory types grow.
+
+use serde_json::Value;
+
+/// Bump together with a new arm in `upgrade_step` whenever `StateImage` or
+/// anything it contains changes shape.
+pub const STATE_SCHEMA_VERSION: u64 = 23;
+
+/// Images written before versioning have no `schema_version` field.
+const UNVERSIONED: u64 = 1;
+
+fn upgrade_step(from: u64, mut image: Value) -> Result<Value, String> {
+    match from {
+        // v1 -> v2: the version stamp itself was introduced.
+        1 => {}
+        // v2 -> v3: accounts track `position` and `notional_spent`, derived
+        // here from the tape. A pruned tape can miss fills, but `done_trade`
+        // still proves the single lot every v2 account could buy.
+        2 => {
+            let mut spent = std::collections::HashMap::<String, (i64, i64)>::new();
+            for t in image["tape"]["trades"].as_array().into_iter().flatten() {
+                if let (Some(u), Some(p), Some(v)) = (t["uname"].as_str(), t["price"].as_i64(), t["vol"].as_i64()) {
+                    let e = spent.entry(u.to_owned()).or_default();
+                    e.0 += v;
+                    e.1 += p * v;
+                }
+            }
+            for (u, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                let (mut position, notional) = spent.get(u).copied().unwrap_or_default();
+                if position == 0 && ua["done_trade"].as_bool() == Some(true) {
+                    position = 1;
+                }
+                ua["position"] = Value::from(position);
+                ua["notional_spent"] = Value::from(notional);
+            }
+        }
+        // v3 -> v4: accounts carry a credit line; older ones had none.
+        3 => {
+            for (_, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                ua["credit"] = serde_json::json!({
+                    "limit": 0,
+                    "interest_paid": 0,
+                    "accrued_at_nanos": 0,
+                    "interest_frac": 0,
+                });
+            }
+        }
+        // v4 -> v5: accounts record their latest fill and fees paid. The fill
+        // comes from the tape when it is still there; earlier fees were
+        // never recorded and start from zero.
+        4 => {
+            let mut last_fill = std::collections::HashMap::<String, (Value, Value)>::new();
+            for t in image["tape"]["trades"].as_array().into_iter().flatten() {
+                if let Some(u) = t["uname"].as_str() {
+                    last_fill.insert(u.to_owned(), (t["price"].clone(), t["ts_nanos"].clone()));
+                }
+            }
+            for (u, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                let (price, ts) = last_fill.remove(u).unwrap_or((Value::Null, Value::Null));
+                ua["exec_price"] = price;
+                ua["exec_ts_nanos"] = ts;
+                ua["fees_paid"] = Value::from(0);
+            }
+        }
+        // v5 -> v6: the house account is explicit. Everything users were
+        // charged is exactly what it would have collected.
+        5 => {
+            let (mut fees, mut proceeds, mut interest) = (0i64, 0i64, 0i64);
+            for ua in image["users"].as_object().into_iter().flat_map(|m| m.values()) {
+                fees += ua["fees_paid"].as_i64().unwrap_or(0);
+                proceeds += ua["notional_spent"].as_i64().unwrap_or(0);
+                interest += ua["credit"]["interest_paid"].as_i64().unwrap_or(0);
+            }
+            image["house"] = serde_json::json!({ "fees": fees, "proceeds": proceeds, "interest": interest });
+        }
+        // v6 -> v7: images record what was issued. Older ones are assumed
+        // to have been conserved, so issuance is whatever they hold.
+        6 => {
+            let mut cash = 0i64;
+            let mut units = 0i64;
+            for ua in image["users"].as_object().into_iter().flat_map(|m| m.values()) {
+                cash += ua["balance"].as_i64().unwrap_or(0);
+                units += ua["position"].as_i64().unwrap_or(0);
+            }
+            for k in ["fees", "proceeds", "interest"] {
+                cash += image["house"][k].as_i64().unwrap_or(0);
+            }
+            units += image["asks"].as_object().into_iter().flat_map(|m| m.values()).filter_map(Value::as_i64).sum::<i64>();
+            image["issued"] = serde_json::json!({ "cash": cash, "units": units });
+        }
+        // v7 -> v8: orders are kept; nothing earlier recorded them.
+        7 => {
+            image["orders"] = serde_json::json!({ "orders": {}, "next_id": 0 });
+        }
+        // v8 -> v9: orders carry queue priority and replace links. Priority
+        // is the time the order was accepted.
+        8 => {
+            for (_, o) in image["orders"]["orders"].as_object_mut().into_iter().flatten() {
+                o["priority_nanos"] = o["history"][0]["ts_nanos"].clone();
+                o["replaces"] = Value::Null;
+                o["replaced_by"] = Value::Null;
+            }
+        }
+        // v9 -> v10: accounts can be bankrupt; no earlier one was.
+        9 => {
+            for (_, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                ua["bankrupt_at_nanos"] = Value::Null;
+            }
+        }
+        // v10 -> v11: accounts record quote subscription usage.
+        10 => {
+            for (_, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                ua["quotes"] = serde_json::json!({ "updates": 0, "fees_paid": 0 });
+            }
+        }
+        // v11 -> v12: orders carry time in force and a client id. Every
+        // earlier order was immediate-or-cancel.
+        11 => {
+            for (_, o) in image["orders"]["orders"].as_object_mut().into_iter().flatten() {
+                o["tif"] = Value::from("ioc");
+                o["client_id"] = Value::Null;
+            }
+        }
+        // v12 -> v13: users can list lots; nobody could before.
+        12 => {
+            for (_, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                ua["listed"] = Value::from(0);
+            }
+            image["sells"] = serde_json::json!([]);
+        }
+        // v13 -> v14: games can be settled, paying out of the house.
+        13 => {
+            image["house"]["payouts"] = Value::from(0);
+            image["settlement"] = Value::Null;
+        }
+        // v14 -> v15: further instruments, which earlier games didn't have.
+        14 => {
+            for (_, ua) in image["users"].as_object_mut().into_iter().flatten() {
+                ua["holdings"] = serde_json::json!({});
+            }
+            image["instruments"] = serde_json::json!({});
+        }
+        // v15 -> v16: the house collects penalty fines; none were taken before.
+        15 => {
+            image["house"]["fines"] = Value::from(0);
+        }
+        // v16 -> v17: ask levels may expire; none did before.
+        16 => {
+            image["ask_expiry"] = serde_json::json!({});
+        }
+        // v17 -> v18: fees are itemized; earlier ones were only summed in
+        // `fees_paid`.
+        17 => {
+            image["fee_ledger"] = serde_json::json!({ "users": {}, "seq": 0 });
+        }
+        // v18 -> v19: the fee ledger became a ledger of every balance change.
+        // Fees keep their lines, without the balance they left.
+        18 => {
+            let mut ledger = image["fee_ledger"].take();
+            for (_, items) in ledger["users"].as_object_mut().into_iter().flatten() {
+                for f in items.as_array_mut().into_iter().flatten() {
+                    let reason = match f["endpoint"].as_str() {
+                        Some(ep) => format!("fee:{}", ep),
+                        None => "fee".to_owned(),
+                    };
+                    let delta = -f["amount"].as_i64().unwrap_or(0);
+                    *f = serde_json::json!({
+                        "seq": f["seq"],
+                        "reason": reason,
+                        "delta": delta,
+                        "balance": null,
+                        "ts_nanos": f["ts_nanos"],
+                        "request_id": f["request_id"],
+                    });
+                }
+            }
+            image.as_object_mut().ok_or("state image is not an object")?.remove("fee_ledger");
+            image["ledger"] = ledger;
+        }
+        // v19 -> v20: users may lend to each other; no one had.
+        19 => {
+            image["loans"] = serde_json::json!({ "loans": {}, "next_id": 0 });
+        }
+        // v20 -> v21: the tape records who sold listed lots to whom. Older
+        // sales can't be told from house volume.
+        20 => {
+            image["tape"]["sales"] = serde_json::json!([]);
+        }
+        // v21 -> v22: user keys and names are kept with the game. Older
+        // images leave them to config, and every user is known by their own
+        // name.
+        21 => {
+            let names: serde_json::Map<String, Value> = image["users"]
+                .as_object()
+                .into_iter()
+                .flat_map(|m| m.keys())
+                .map(|u| (u.clone(), Value::from(u.as_str())))
+                .collect();
+            image["user_keys"] = serde_json::json!({});
+            image["names"] = Value::Object(names);
+        }
+        // v22 -> v23: the schedule is kept with the game. Older images leave
+        // it to config, so one armed in the lobby has to be armed again.
+        22 => {
+            image["trade_start_nanos"] = Value::Null;
+            image["calendar"] = Value::Null;
+            image["trade_end_nanos"] = Value::Null;
+        }
+        other => return Err(format!("no migration from state schema v{}", other)),
+    }
+    image["schema_version"] = Value::from(from + 1);
+    Ok(image)
+}
+
+/// Brings a raw state image up to `STATE_SCHEMA_VERSION`.
+pub fn upgrade_state(mut image: Value) -> Result<Value, String> {
+    let mut version = match image.get("schema_version") {
+        None => UNVERSIONED,
+        Some(v) => v.as_u64().ok_or("schema_version is not a number")?,
+    };
+    if version > STATE_SCHEMA_VERSION {
+        return Err(format!(
+            "state schema v{} is newer than this server (v{})",
+            version, STATE_SCHEMA_VERSION
+        ));
+    }
+    while version < STATE_SCHEMA_VERSION {
+        image = upgrade_step(version, image)?;
+        version += 1;
+    }
+    Ok(image)
+}
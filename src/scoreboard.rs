@@ -0,0 +1,59 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, public_board, AppState};
+
+const TOKEN_ENV: &str = "SCOREBOARD_TOKEN";
+
+/// Pushes the `/board` body, masked as `[public_board]` says, to an outside
+/// scoreboard every `interval_secs`, for displays that can't poll. Needs
+/// `[public_board]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScoreboardConfig {
+    /// Where the board is POSTed as JSON.
+    pub url: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Sent as `Authorization: Bearer`. `SCOREBOARD_TOKEN` in the
+    /// environment takes precedence.
+    pub token: Option<String>,
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+impl ScoreboardConfig {
+    pub fn token(&self) -> Option<String> {
+        std::env::var(TOKEN_ENV).ok().filter(|t| !t.is_empty()).or_else(|| self.token.clone())
+    }
+}
+
+/// Pushes until shutdown. A failed push is logged and the next one tried on
+/// schedule; nothing is queued.
+pub fn spawn_pusher(cfg: ScoreboardConfig, state: Arc<Mutex<AppState>>) {
+    let every = Duration::from_secs(cfg.interval_secs);
+    let client = reqwest::Client::builder().timeout(every).build().unwrap();
+    let token = cfg.token();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let body = public_board::snapshot(&mut state.locked()).0;
+            let mut req = client.post(&cfg.url).header(reqwest::header::CONTENT_TYPE, "application/json").body(body);
+            if let Some(t) = &token {
+                req = req.bearer_auth(t);
+            }
+            match req.send().await {
+                Ok(r) if r.status().is_success() => {}
+                Ok(r) => tracing::warn!("scoreboard push to {} refused: {}", cfg.url, r.status()),
+                Err(e) => tracing::warn!("scoreboard push to {} failed: {}", cfg.url, e),
+            }
+        }
+    });
+}
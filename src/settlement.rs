@@ -0,0 +1,469 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Query, Request, State,
+    },
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{allocation, contention::StateLock, credit, errors::ApiError, execution, handoff::hmac_sha256, journal, ledger, loans, now, reservations, AppState, ReqClock, RespMeta};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// How `POST /admin/settle` behaves. Settlement works without this section;
+/// records are then neither archived nor keyed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettlementConfig {
+    /// Where each settlement record is written as JSON.
+    pub archive_dir: Option<String>,
+    /// Key for the record's HMAC-SHA256 signature; without it the
+    /// signature is a plain SHA-256 digest.
+    pub signing_key: Option<String>,
+    /// How long a preview's token may be used to execute it.
+    #[serde(default = "default_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    /// Price held lots are paid out at when the game settles itself at
+    /// `trade_end_nanos`; the last trade's price if unset.
+    pub mark_price: Option<i64>,
+}
+
+fn default_token_ttl_secs() -> u64 {
+    300
+}
+
+impl Default for SettlementConfig {
+    fn default() -> Self {
+        SettlementConfig {
+            archive_dir: None,
+            signing_key: None,
+            token_ttl_secs: default_token_ttl_secs(),
+            mark_price: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Round {
+    #[default]
+    Live,
+    Settled,
+}
+
+/// One user's standing once every lot held is paid out at the settlement
+/// price.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettlementEntry {
+    pub rank: usize,
+    pub uname: String,
+    /// Balance with interest to date, before the payout.
+    pub balance: i64,
+    pub position: i64,
+    /// Lots of further instruments, paid at their own marks.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub holdings: BTreeMap<String, i64>,
+    pub payout: i64,
+    pub final_balance: i64,
+    pub bankrupt: bool,
+}
+
+/// The final board at `price` for the main book, best first. Works on copies of the
+/// accounts, so interest that would accrue in the meantime is included
+/// without being charged.
+pub fn board(g: &AppState, price: i64, now: i64) -> Vec<SettlementEntry> {
+    let mut entries: Vec<SettlementEntry> = g
+        .users
+        .iter()
+        .map(|(u, ua)| {
+            let mut ua = ua.clone();
+            credit::accrue(&mut ua, &g.credit, now);
+            let payout = ua
+                .holdings
+                .iter()
+                .map(|(s, n)| n.saturating_mul(g.instruments.mark(s, price)))
+                .fold(ua.position.saturating_mul(price), i64::saturating_add);
+            SettlementEntry {
+                rank: 0,
+                uname: u.clone(),
+                balance: ua.balance,
+                position: ua.position,
+                holdings: ua.holdings.clone(),
+                payout,
+                final_balance: ua.balance.saturating_add(payout),
+                bankrupt: ua.bankrupt_at_nanos.is_some(),
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| b.final_balance.cmp(&a.final_balance).then_with(|| a.uname.cmp(&b.uname)));
+    for (i, e) in entries.iter_mut().enumerate() {
+        e.rank = i + 1;
+    }
+    entries
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    pub price: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct SettlementPreviewResult {
+    pub price: i64,
+    pub entries: Vec<SettlementEntry>,
+    /// Paid out across all users.
+    pub total_payout: i64,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// What the board would be if the game settled now at `?price=P`. Nothing
+/// is changed.
+pub async fn admin_settlement_preview(
+    State(state): State<Arc<Mutex<AppState>>>,
+    query: Result<Query<PreviewQuery>, QueryRejection>,
+) -> (StatusCode, Json<SettlementPreviewResult>) {
+    let clock = ReqClock::start();
+    let Ok(Query(q)) = query else {
+        return clock.reply(StatusCode::BAD_REQUEST, SettlementPreviewResult::default());
+    };
+    let g = state.locked();
+    let entries = board(&g, q.price, now());
+    let total_payout = entries.iter().map(|e| e.payout).fold(0i64, i64::saturating_add);
+    clock.reply(StatusCode::OK, SettlementPreviewResult { price: q.price, entries, total_payout, ..Default::default() })
+}
+
+/// The outcome of a settlement, kept in state and archived.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettlementRecord {
+    pub price: i64,
+    pub settled_at_nanos: i64,
+    pub entries: Vec<SettlementEntry>,
+    pub total_payout: i64,
+    /// Open orders cancelled, and listed lots handed back, at the close.
+    pub orders_cancelled: usize,
+    pub lots_withdrawn: i64,
+    /// Each user's buys against the best price they could have had.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub execution: BTreeMap<String, execution::ExecutionReport>,
+    /// Hex HMAC-SHA256 (or SHA-256) of this record serialized with an
+    /// empty `signature`.
+    pub signature: String,
+}
+
+/// A previewed settlement awaiting confirmation.
+#[derive(Debug, Clone)]
+pub struct PendingSettlement {
+    pub token: String,
+    pub price: i64,
+    pub expires_at_nanos: i64,
+}
+
+fn sign(record: &SettlementRecord, key: Option<&str>) -> String {
+    let body = serde_json::to_vec(&SettlementRecord { signature: String::new(), ..record.clone() }).unwrap();
+    match key {
+        Some(k) => hex::encode(hmac_sha256(k.as_bytes(), &body)),
+        None => hex::encode(Sha256::digest(&body)),
+    }
+}
+
+/// Closes trading and pays every held lot out at `price`, from the house.
+/// The game mode has its last say, then open orders are cancelled and listed lots go back to their sellers
+/// first; redeemed lots leave the game, and everyone is done trading.
+fn execute(g: &mut AppState, price: i64, now: i64) -> SettlementRecord {
+    g.accrue_all(now);
+    reservations::release_all(g, now);
+    let mode = g.mode.clone();
+    mode.on_settle(g, now);
+    let execution = execution::report(g);
+    let orders_cancelled = allocation::cancel_all(g, "SETTLED", now);
+    let open: Vec<u64> = g.orders.orders.values().filter(|o| o.status.is_open()).map(|o| o.id).collect();
+    for id in open.iter() {
+        let price = g.orders.orders[id].price;
+        g.book.remove_bid(price, *id);
+        g.orders.cancel(*id, "SETTLED", now);
+        g.notify_order(*id, now);
+    }
+    let mut lots_withdrawn = 0;
+    for (u, ua) in g.users.iter_mut() {
+        lots_withdrawn += g.book.withdraw_asks(u);
+        ua.listed = 0;
+    }
+
+    loans::close_all(g, now);
+
+    let entries = board(g, price, now);
+    let mut total_payout = 0i64;
+    for e in entries.iter() {
+        let ua = g.users.get_mut(&e.uname).unwrap();
+        ua.balance += e.payout;
+        ua.position = 0;
+        ua.done_trade = true;
+        for (s, n) in std::mem::take(&mut ua.holdings) {
+            g.instruments.books.get_mut(&s).unwrap().issued -= n;
+        }
+        g.house.payouts += e.payout;
+        g.issued.units -= e.position;
+        total_payout += e.payout;
+    }
+    for e in entries.iter() {
+        g.balance_moved(&e.uname, ledger::Reason::Payout, e.payout, None, now);
+    }
+    g.book_changed(now);
+    g.board_changed();
+    g.feeds.timeline.admin_global(format!("settled at {}", price));
+    tracing::warn!("settled at {}: {} paid out", price, total_payout);
+
+    let mut record = SettlementRecord {
+        price,
+        settled_at_nanos: now,
+        entries,
+        total_payout,
+        orders_cancelled: orders_cancelled + open.len(),
+        lots_withdrawn,
+        execution,
+        signature: String::new(),
+    };
+    record.signature = sign(&record, g.settlement_cfg.signing_key.as_deref());
+    record
+}
+
+/// Settles and keeps the record, handing back what `archive` needs.
+pub fn settle(g: &mut AppState, price: i64, now: i64) -> (SettlementRecord, Option<String>) {
+    let record = execute(g, price, now);
+    g.journal.record(|| journal::Event::Settled { price, ts_nanos: now });
+    g.settlement = Some(record.clone());
+    g.pending_settlement = None;
+    (record, g.settlement_cfg.archive_dir.clone())
+}
+
+/// Writes the record to `dir`, if set, off the async runtime. Returns
+/// where it went, or why it couldn't.
+async fn archive_record(record: &SettlementRecord, dir: Option<String>) -> Result<Option<String>, String> {
+    let Some(dir) = dir else {
+        return Ok(None);
+    };
+    let r = record.clone();
+    match tokio::task::spawn_blocking(move || archive(&dir, &r)).await.unwrap() {
+        Ok(path) => Ok(Some(path)),
+        Err(e) => {
+            tracing::error!("settlement archive failed: {}", e);
+            Err(format!("archive: {}", e))
+        }
+    }
+}
+
+fn archive(dir: &str, record: &SettlementRecord) -> std::io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(format!("settlement-{:020}.json", record.settled_at_nanos));
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(record)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(path.display().to_string())
+}
+
+/// Either a price to preview, or the token of a preview to execute.
+#[derive(Debug, Deserialize)]
+pub struct SettleRequest {
+    pub price: Option<i64>,
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct SettleResult {
+    pub price: i64,
+    /// Set on a preview: pass it back within `expires_at_nanos` to execute.
+    pub token: Option<String>,
+    pub expires_at_nanos: Option<i64>,
+    pub preview: Vec<SettlementEntry>,
+    pub settlement: Option<SettlementRecord>,
+    pub archived_to: Option<String>,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+/// Settles the game in two steps: `{"price": P}` previews the settlement
+/// and returns a token, `{"token": T}` executes exactly that preview. A new
+/// preview replaces the last one.
+pub async fn admin_settle(
+    State(state): State<Arc<Mutex<AppState>>>,
+    req: Result<Json<SettleRequest>, JsonRejection>,
+) -> (StatusCode, Json<SettleResult>) {
+    let clock = ReqClock::start();
+    let Ok(Json(req)) = req else {
+        return clock.reply(StatusCode::BAD_REQUEST, SettleResult::default());
+    };
+    let now = now();
+    let (record, dir) = {
+        let mut g = state.locked();
+        if let Some(s) = &g.settlement {
+            let res = SettleResult { price: s.price, settlement: Some(s.clone()), ..Default::default() };
+            return clock.reply(StatusCode::CONFLICT, res);
+        }
+        match (req.price, req.token) {
+            (Some(price), None) => {
+                let token = format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..));
+                let expires_at_nanos = now + g.settlement_cfg.token_ttl_secs as i64 * NANOS_PER_SEC;
+                g.pending_settlement = Some(PendingSettlement { token: token.clone(), price, expires_at_nanos });
+                let res = SettleResult {
+                    price,
+                    token: Some(token),
+                    expires_at_nanos: Some(expires_at_nanos),
+                    preview: board(&g, price, now),
+                    ..Default::default()
+                };
+                return clock.reply(StatusCode::OK, res);
+            }
+            (None, Some(token)) => {
+                let pending = g.pending_settlement.take_if(|p| p.token == token && now < p.expires_at_nanos);
+                let Some(pending) = pending else {
+                    let res = SettleResult { error: Some("unknown or expired token".to_owned()), ..Default::default() };
+                    return clock.reply(StatusCode::FORBIDDEN, res);
+                };
+                settle(&mut g, pending.price, now)
+            }
+            _ => return clock.reply(StatusCode::BAD_REQUEST, SettleResult::default()),
+        }
+    };
+
+    let mut res = SettleResult { price: record.price, ..Default::default() };
+    match archive_record(&record, dir).await {
+        Ok(path) => res.archived_to = path,
+        Err(e) => res.error = Some(e),
+    }
+    res.settlement = Some(record);
+    clock.reply(StatusCode::OK, res)
+}
+
+/// Settles the game by itself at `trade_end_nanos`, unless an admin got
+/// there first. Held lots are marked at `[settlement] mark_price`, or the
+/// last trade, or nothing if there never was one.
+pub fn spawn_close(state: Arc<Mutex<AppState>>, trade_end_nanos: i64) {
+    tokio::spawn(async move {
+        let wait = trade_end_nanos.saturating_sub(now()).max(0) as u64;
+        tokio::time::sleep(std::time::Duration::from_nanos(wait)).await;
+        let settled = {
+            let mut g = state.locked();
+            if g.settlement.is_some() {
+                return;
+            }
+            let last_trade = g.users.values().filter_map(|ua| ua.exec_ts_nanos.zip(ua.exec_price)).max();
+            let price = g.settlement_cfg.mark_price.or(last_trade.map(|(_, p)| p)).unwrap_or(0);
+            settle(&mut g, price, now())
+        };
+        let (record, dir) = settled;
+        let _ = archive_record(&record, dir).await;
+    });
+}
+
+#[derive(Serialize, Default)]
+pub struct SettlementResult {
+    pub settlement: Option<SettlementRecord>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+pub async fn admin_settlement(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, Json<SettlementResult>) {
+    let clock = ReqClock::start();
+    let settlement = state.locked().settlement.clone();
+    let code = if settlement.is_some() { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    clock.reply(code, SettlementResult { settlement, ..Default::default() })
+}
+
+/// Once settled the game is read-only: users can still look, free of
+/// charge, but nothing that would trade, list or add to the game goes through.
+pub async fn read_only(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let mutates = req.method() != Method::GET
+        && (path.starts_with("/users/") && !is_query(path) || path == "/admin/asks" || path == "/admin/users");
+    if mutates && state.locked().settlement.is_some() {
+        return ApiError::with(StatusCode::FORBIDDEN, "GAME_OVER", "the game has been settled").into_response();
+    }
+    next.run(req).await
+}
+
+/// The paid user calls that only look: `ping` and `check_asks`, for any
+/// instrument.
+fn is_query(path: &str) -> bool {
+    let mut rest = path.split('/').skip(3);
+    matches!(rest.next(), Some("ping" | "check_asks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{book, invariants, orders::{OrderStatus, TimeInForce}, testing};
+
+    fn game() -> AppState {
+        testing::game("asks = [{ price = 10, vol = 4 }]
+[settlement]
+signing_key = \"s\"")
+    }
+
+    /// alice buys 2 at 10 and lists one of them at 15; bob's bid at 5 rests.
+    fn traded() -> (AppState, u64) {
+        let mut g = game();
+        let a = g.orders.accept("alice", 10, 2, TimeInForce::Ioc, 1);
+        book::enter(&mut g, "alice", a, 1);
+        book::list(&mut g, "alice", 15, 1, 2);
+        let b = g.orders.accept("bob", 5, 1, TimeInForce::Gtc, 3);
+        book::enter(&mut g, "bob", b, 3);
+        assert!(invariants::verify(&g).ok);
+        (g, b)
+    }
+
+    #[test]
+    fn preview_changes_nothing() {
+        let (g, _) = traded();
+        let before = serde_json::to_string(&g.users).unwrap();
+        let entries = board(&g, 20, 10);
+        assert_eq!(entries[0].final_balance, 1020);
+        assert_eq!(serde_json::to_string(&g.users).unwrap(), before);
+        assert!(g.settlement.is_none());
+    }
+
+    #[test]
+    fn settling_pays_out_held_lots_and_closes_the_book() {
+        let (mut g, bob_bid) = traded();
+        let (record, archive_dir) = settle(&mut g, 20, 10);
+        assert_eq!(archive_dir, None);
+        assert_eq!((record.price, record.total_payout, record.orders_cancelled, record.lots_withdrawn), (20, 40, 1, 1));
+        let ranked: Vec<(usize, &str, i64, i64)> =
+            record.entries.iter().map(|e| (e.rank, e.uname.as_str(), e.payout, e.final_balance)).collect();
+        assert_eq!(ranked, vec![(1, "alice", 40, 1020), (2, "bob", 0, 1000), (3, "carol", 0, 1000)]);
+
+        let alice = &g.users["alice"];
+        assert_eq!((alice.balance, alice.position, alice.listed, alice.done_trade), (1020, 0, 0, true));
+        let o = &g.orders.orders[&bob_bid];
+        assert_eq!((o.status, o.history.last().unwrap().reason.as_deref()), (OrderStatus::Cancelled, Some("SETTLED")));
+        assert_eq!(g.book.bid_levels().count(), 0);
+        assert_eq!(g.house.payouts, 40);
+        assert!(g.settlement.is_some());
+        assert!(invariants::verify(&g).ok, "{:?}", invariants::verify(&g).violations);
+    }
+
+    #[test]
+    fn record_signature_covers_every_field() {
+        let (mut g, _) = traded();
+        let (record, _) = settle(&mut g, 20, 10);
+        assert_eq!(record.signature, sign(&record, Some("s")));
+        assert_ne!(record.signature, sign(&record, Some("t")));
+        assert_ne!(record.signature, sign(&record, None));
+        let tampered = SettlementRecord { total_payout: 41, ..record.clone() };
+        assert_ne!(sign(&tampered, Some("s")), record.signature);
+        // The stored signature itself is left out of what is signed.
+        let resigned = SettlementRecord { signature: "x".to_owned(), ..record.clone() };
+        assert_eq!(sign(&resigned, Some("s")), record.signature);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{backup, contention::StateLock, errors::ApiError, persist::Persister, AppState};
+
+/// What happens on SIGTERM or SIGINT: the listeners stop accepting, open
+/// connections get `drain_secs` to finish what they are serving, and the
+/// game is written out before the process exits.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShutdownConfig {
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+    /// How long `/readyz` answers 503 before the listeners stop accepting,
+    /// so a load balancer can move traffic away first.
+    #[serde(default)]
+    pub unready_secs: u64,
+    /// Where the final dump goes; `[backup] dir` if unset. The game is
+    /// written as a backup, so `--restore` can start from it, with the
+    /// board beside it.
+    pub dump_dir: Option<String>,
+}
+
+fn default_drain_secs() -> u64 {
+    10
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_secs: default_drain_secs(), unready_secs: 0, dump_dir: None }
+    }
+}
+
+/// Flips to true once a shutdown signal arrives.
+pub type Stopping = watch::Receiver<bool>;
+
+pub fn listen_for_signals() -> Stopping {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = term.recv() => tracing::warn!("SIGTERM: shutting down"),
+            _ = tokio::signal::ctrl_c() => tracing::warn!("SIGINT: shutting down"),
+        }
+        tx.send_replace(true);
+        // Keep the sender, so receivers never see the channel close.
+        std::future::pending::<()>().await;
+    });
+    rx
+}
+
+/// Flips `delay` after `stop` does.
+pub fn after(mut stop: Stopping, delay: Duration) -> Stopping {
+    if delay.is_zero() {
+        return stop;
+    }
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        stopped(&mut stop).await;
+        tokio::time::sleep(delay).await;
+        tx.send_replace(true);
+        std::future::pending::<()>().await;
+    });
+    rx
+}
+
+/// Resolves once shutdown starts.
+pub async fn stopped(stop: &mut Stopping) {
+    let _ = stop.wait_for(|s| *s).await;
+}
+
+/// Refuses new user calls that would change the game once shutdown starts,
+/// so nothing lands after the final dump. Looking is still allowed while
+/// connections drain.
+pub async fn refuse_when_stopping(State(stop): State<Stopping>, req: Request, next: Next) -> Response {
+    if *stop.borrow() && req.method() != Method::GET && req.uri().path().starts_with("/users/") {
+        let err = ApiError::with(StatusCode::SERVICE_UNAVAILABLE, "SHUTTING_DOWN", "the server is shutting down");
+        return err.into_response();
+    }
+    next.run(req).await
+}
+
+/// Flushes the game store and writes the final dump. Called once every
+/// listener has drained.
+pub fn finish(cfg: &ShutdownConfig, backup_dir: Option<&str>, state: &Arc<Mutex<AppState>>, persister: Option<&Persister>) {
+    let (image, board) = {
+        let mut g = state.locked();
+        let board = crate::board(&mut g);
+        (backup::StateImage::capture(&g), board)
+    };
+    if let Some(p) = persister {
+        p.flush();
+    }
+    let Some(dir) = cfg.dump_dir.as_deref().or(backup_dir) else {
+        tracing::warn!("no [shutdown] dump_dir or [backup] dir: no final dump written");
+        return;
+    };
+    let board_path = Path::new(dir).join(format!("board-{:020}.json", image.taken_nanos));
+    let res = backup::write_backup(dir, &image)
+        .and_then(|p| Ok((p, serde_json::to_vec_pretty(&board)?)))
+        .and_then(|(p, board)| std::fs::write(&board_path, board).map(|_| p));
+    match res {
+        Ok(p) => tracing::warn!("final dump written to {} and {}", p.display(), board_path.display()),
+        Err(e) => tracing::error!("final dump to {} failed: {}", dir, e),
+    }
+}
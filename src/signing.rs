@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, errors::ApiError, handoff::{hmac_sha256, token_matches}, killswitch, now, AppState};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+const TIMESTAMP_HEADER: &str = "x-timestamp-nanos";
+const NONCE_HEADER: &str = "x-nonce";
+const SIGNATURE_HEADER: &str = "x-signature";
+const MAX_NONCE_BYTES: usize = 128;
+
+/// Every `/users/:uname/*` call must be signed with the user's key from
+/// `[user_keys]`: `x-signature` is the hex HMAC-SHA256 of
+/// `"{path and query}\n{x-timestamp-nanos}\n{x-nonce}"`. Timestamps more
+/// than `max_skew_secs` off the server clock are refused, and so is a
+/// nonce the user already sent within that window.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SignedRequestsConfig {
+    #[serde(default = "default_max_skew_secs")]
+    pub max_skew_secs: u64,
+}
+
+fn default_max_skew_secs() -> u64 {
+    30
+}
+
+/// Nonces seen per user with their timestamps. Ones older than the skew
+/// window are dropped, as their timestamps would be refused anyway.
+#[derive(Debug, Default)]
+pub struct NonceCache {
+    by_user: HashMap<String, HashMap<String, i64>>,
+}
+
+impl NonceCache {
+    /// Records the nonce, returning false if it was already used.
+    fn admit(&mut self, uname: &str, nonce: &str, ts: i64, oldest: i64) -> bool {
+        let seen = self.by_user.entry(uname.to_owned()).or_default();
+        seen.retain(|_, t| *t >= oldest);
+        if seen.contains_key(nonce) {
+            return false;
+        }
+        seen.insert(nonce.to_owned(), ts);
+        true
+    }
+
+    pub fn forget(&mut self, uname: &str) {
+        self.by_user.remove(uname);
+    }
+}
+
+/// The code and message a call is refused with.
+type Refusal = (&'static str, &'static str);
+
+fn refuse((code, message): Refusal) -> Response {
+    ApiError::with(StatusCode::UNAUTHORIZED, code, message).into_response()
+}
+
+/// What can be checked without the state: the timestamp, returned parsed,
+/// and the nonce's length.
+fn fresh(ts: &str, nonce: &str, now: i64, skew: i64) -> Result<i64, Refusal> {
+    let Ok(ts_nanos) = ts.parse::<i64>() else {
+        return Err(("BAD_SIGNATURE", "x-timestamp-nanos is not a number"));
+    };
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_BYTES {
+        return Err(("BAD_SIGNATURE", "x-nonce must be 1 to 128 bytes"));
+    }
+    if now.abs_diff(ts_nanos) > skew as u64 {
+        return Err(("STALE_TIMESTAMP", "x-timestamp-nanos is too far from the server clock"));
+    }
+    Ok(ts_nanos)
+}
+
+/// Checks `sig` over `msg` against the user's key, then takes the nonce.
+fn authentic(g: &mut AppState, uname: &str, msg: &str, sig: &str, nonce: &str, ts_nanos: i64, oldest: i64) -> Result<(), Refusal> {
+    let Some(key) = g.user_keys.get(uname) else {
+        return Err(("BAD_SIGNATURE", "the user has no key to sign with"));
+    };
+    let expected = hex::encode(hmac_sha256(key.as_bytes(), msg.as_bytes()));
+    if !token_matches(&expected, sig.to_ascii_lowercase().as_bytes()) {
+        return Err(("BAD_SIGNATURE", "the signature doesn't match"));
+    }
+    if !g.nonces.admit(uname, nonce, ts_nanos, oldest) {
+        return Err(("REPLAYED_NONCE", "this nonce was already used"));
+    }
+    Ok(())
+}
+
+/// Checks the signature before any handler runs, so a refused call is never
+/// charged. Sits inside `usernames::canonicalize`, but checks the path as
+/// the client sent it.
+pub async fn verify(
+    State((cfg, state)): State<(SignedRequestsConfig, Arc<Mutex<AppState>>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(uname) = killswitch::user_of(req.uri().path()).map(str::to_owned) else {
+        return next.run(req).await;
+    };
+    let h = req.headers();
+    let header = |name: &str| h.get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+    let (Some(ts), Some(nonce), Some(sig)) = (header(TIMESTAMP_HEADER), header(NONCE_HEADER), header(SIGNATURE_HEADER))
+    else {
+        return refuse(("UNSIGNED", "x-timestamp-nanos, x-nonce and x-signature are required"));
+    };
+    let sent = req.extensions().get::<OriginalUri>().map_or(req.uri(), |o| &o.0);
+    let msg = format!("{}\n{}\n{}", sent.path_and_query().map_or("/", |p| p.as_str()), ts, nonce);
+    let now = now();
+    let skew = cfg.max_skew_secs as i64 * NANOS_PER_SEC;
+    let checked = fresh(&ts, &nonce, now, skew)
+        .and_then(|ts_nanos| authentic(&mut state.locked(), &uname, &msg, &sig, &nonce, ts_nanos, now - skew));
+    if let Err(r) = checked {
+        return refuse(r);
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const SKEW: i64 = 30 * NANOS_PER_SEC;
+    const NOW: i64 = 1_000 * NANOS_PER_SEC;
+
+    fn game() -> AppState {
+        testing::game("[user_keys]\nalice = \"k1\"")
+    }
+
+    fn sign(key: &str, msg: &str) -> String {
+        hex::encode(hmac_sha256(key.as_bytes(), msg.as_bytes()))
+    }
+
+    #[test]
+    fn fresh_checks_the_stamp_and_nonce() {
+        assert_eq!(fresh(&NOW.to_string(), "n", NOW, SKEW), Ok(NOW));
+        assert_eq!(fresh(&(NOW - SKEW).to_string(), "n", NOW, SKEW), Ok(NOW - SKEW));
+        assert_eq!(fresh(&(NOW + SKEW + 1).to_string(), "n", NOW, SKEW).unwrap_err().0, "STALE_TIMESTAMP");
+        assert_eq!(fresh("soon", "n", NOW, SKEW).unwrap_err().0, "BAD_SIGNATURE");
+        assert_eq!(fresh(&NOW.to_string(), "", NOW, SKEW).unwrap_err().0, "BAD_SIGNATURE");
+        let long = "n".repeat(MAX_NONCE_BYTES + 1);
+        assert_eq!(fresh(&NOW.to_string(), &long, NOW, SKEW).unwrap_err().0, "BAD_SIGNATURE");
+    }
+
+    #[test]
+    fn signature_must_match_the_users_key() {
+        let mut g = game();
+        let msg = "/users/alice/ping\n1\nn1";
+        let oldest = NOW - SKEW;
+        let wrong = sign("k2", msg);
+        assert_eq!(authentic(&mut g, "alice", msg, &wrong, "n1", NOW, oldest), Err(("BAD_SIGNATURE", "the signature doesn't match")));
+        // A refused call doesn't use up its nonce, and hex case doesn't matter.
+        let sig = sign("k1", msg).to_ascii_uppercase();
+        assert_eq!(authentic(&mut g, "alice", msg, &sig, "n1", NOW, oldest), Ok(()));
+        let e = authentic(&mut g, "bob", msg, &sig, "n1", NOW, oldest);
+        assert_eq!(e, Err(("BAD_SIGNATURE", "the user has no key to sign with")));
+    }
+
+    #[test]
+    fn a_nonce_is_good_once_per_window() {
+        let mut g = game();
+        let msg = "/users/alice/ping\n1\nn1";
+        let sig = sign("k1", msg);
+        assert_eq!(authentic(&mut g, "alice", msg, &sig, "n1", NOW, NOW - SKEW), Ok(()));
+        assert_eq!(authentic(&mut g, "alice", msg, &sig, "n1", NOW, NOW - SKEW).unwrap_err().0, "REPLAYED_NONCE");
+
+        let mut cache = NonceCache::default();
+        assert!(cache.admit("alice", "n", NOW, NOW - SKEW));
+        assert!(cache.admit("bob", "n", NOW, NOW - SKEW));
+        assert!(!cache.admit("alice", "n", NOW + 1, NOW - SKEW));
+        // Once the first use has left the window, its stamp would be refused
+        // anyway, so the nonce is forgotten.
+        assert!(cache.admit("alice", "n", NOW + SKEW + 1, NOW + 1));
+    }
+}
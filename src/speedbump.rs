@@ -0,0 +1,49 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, AppState};
+
+/// Holds every order for a random time before it reaches the book, so a
+/// few microseconds of network advantage stop deciding who fills.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SpeedBumpConfig {
+    /// Delays are drawn uniformly from `0..=max_micros`.
+    pub max_micros: u64,
+    /// Fixes the sequence of delays, e.g. to replay a game.
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct SpeedBump {
+    cfg: SpeedBumpConfig,
+    rng: fastrand::Rng,
+}
+
+impl SpeedBump {
+    pub fn new(cfg: SpeedBumpConfig) -> Self {
+        let rng = cfg.seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed);
+        SpeedBump { cfg, rng }
+    }
+
+    fn draw(&mut self) -> Duration {
+        Duration::from_micros(self.rng.u64(0..=self.cfg.max_micros))
+    }
+}
+
+/// Route layer for order entry.
+pub async fn delay(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let wait = state.locked().speed_bump.as_mut().map(SpeedBump::draw);
+    if let Some(wait) = wait {
+        tokio::time::sleep(wait).await;
+    }
+    next.run(req).await
+}
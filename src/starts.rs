@@ -0,0 +1,72 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Staggered starts: a user may not check or bid before their own start,
+/// on top of the calendar. Only that user's ping reveals it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StaggeredStartConfig {
+    /// Fixed starts, keyed by user or cohort name.
+    #[serde(default)]
+    pub starts: HashMap<String, i64>,
+    /// Users sharing a start.
+    #[serde(default)]
+    pub cohorts: HashMap<String, Vec<String>>,
+    /// Users (or cohorts) without a fixed start get one drawn uniformly
+    /// between trading's first open and this many seconds after it.
+    #[serde(default)]
+    pub draw_max_secs: u64,
+    /// Fixes the draw, so a restart hands out the same starts.
+    pub seed: Option<u64>,
+}
+
+/// Per-user start times; users missing here start with the calendar.
+#[derive(Debug, Default)]
+pub struct Starts(HashMap<String, i64>);
+
+impl Starts {
+    pub fn new(cfg: &StaggeredStartConfig, roster: &[String], first_open: i64) -> Result<Self, String> {
+        let mut rng = cfg.seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed);
+        let mut draw = || first_open + rng.i64(0..=cfg.draw_max_secs as i64 * NANOS_PER_SEC);
+
+        let mut groups: Vec<(&String, Vec<&String>)> = Vec::new();
+        let mut grouped = BTreeSet::new();
+        let mut cohorts: Vec<_> = cfg.cohorts.iter().collect();
+        cohorts.sort();
+        for (name, members) in cohorts {
+            for m in members {
+                if !roster.contains(m) {
+                    return Err(format!("cohort {} lists {}, who is not in users", name, m));
+                }
+                if !grouped.insert(m) {
+                    return Err(format!("{} is in more than one cohort", m));
+                }
+            }
+            groups.push((name, members.iter().collect()));
+        }
+        let mut singles: Vec<_> = roster.iter().filter(|u| !grouped.contains(u)).collect();
+        singles.sort();
+        groups.extend(singles.into_iter().map(|u| (u, vec![u])));
+        if let Some(k) = cfg.starts.keys().find(|k| !groups.iter().any(|(g, _)| g == k)) {
+            return Err(format!("start given for {}, which is not a cohort or a user outside one", k));
+        }
+
+        // Drawn in name order, so a seed always maps to the same starts.
+        let mut starts = HashMap::new();
+        for (name, members) in groups {
+            let start = cfg.starts.get(name).copied().unwrap_or_else(&mut draw);
+            starts.extend(members.into_iter().map(|m| (m.clone(), start)));
+        }
+        Ok(Starts(starts))
+    }
+
+    pub fn of(&self, uname: &str) -> Option<i64> {
+        self.0.get(uname).copied()
+    }
+
+    pub fn started(&self, uname: &str, now: i64) -> bool {
+        self.of(uname).map_or(true, |s| now >= s)
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    journal,
+    matching::{self, Fill},
+    AppState, UserAccount,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Memory,
+}
+
+/// Where accounts, asks and trades live. Only `memory` exists; for a game
+/// that survives restarts, see `[persistence]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: Backend,
+}
+
+/// A few of the records order entry reads and writes, for helpers that
+/// don't need the rest of `AppState`. Most request paths still use the maps
+/// on `AppState` directly, so this is not a seam for another backend.
+pub trait Store {
+    fn get_user(&self, uname: &str) -> Option<&UserAccount>;
+    /// Takes `amount` off `uname`'s balance and returns what is left; a
+    /// negative amount credits.
+    fn debit(&mut self, uname: &str, amount: i64) -> i64;
+    /// Takes up to `qty` lots offered at exactly `price`.
+    fn take_ask(&mut self, price: i64, qty: i64) -> Option<Fill>;
+    /// Appends a fill to the tape, returning its sequence number.
+    fn record_trade(&mut self, uname: &str, price: i64, vol: i64, ts_nanos: i64) -> u64;
+    /// Every account, highest balance first.
+    fn board(&self) -> Vec<(String, UserAccount)>;
+}
+
+/// The in-memory store: the maps `AppState` already holds.
+impl Store for AppState {
+    fn get_user(&self, uname: &str) -> Option<&UserAccount> {
+        self.users.get(uname)
+    }
+
+    fn debit(&mut self, uname: &str, amount: i64) -> i64 {
+        let ua = self.users.get_mut(uname).unwrap();
+        ua.balance -= amount;
+        ua.balance
+    }
+
+    fn take_ask(&mut self, price: i64, qty: i64) -> Option<Fill> {
+        let fill = matching::match_bid(&mut self.book.asks, price, qty)?;
+        self.journal.record(|| journal::Event::AskTaken { price, vol: fill.vol });
+        Some(fill)
+    }
+
+    fn record_trade(&mut self, uname: &str, price: i64, vol: i64, ts_nanos: i64) -> u64 {
+        self.tape.record(uname, price, vol, ts_nanos).seq
+    }
+
+    fn board(&self) -> Vec<(String, UserAccount)> {
+        let mut all: Vec<(String, UserAccount)> = self.users.iter().map(|(u, ua)| (u.clone(), ua.clone())).collect();
+        all.sort_by_key(|(_, ua)| -ua.balance);
+        all
+    }
+}
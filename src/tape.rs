@@ -0,0 +1,203 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, AppState, ReqClock, RespMeta};
+
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Trade {
+    pub seq: u64,
+    pub uname: String,
+    pub price: i64,
+    pub vol: i64,
+    pub ts_nanos: i64,
+}
+
+/// Lots a user listed with `place_ask` that went in trade `seq`. House
+/// volume leaves no sale.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sale {
+    pub seq: u64,
+    pub seller: String,
+    pub buyer: String,
+    pub price: i64,
+    pub vol: i64,
+    pub ts_nanos: i64,
+}
+
+/// Append-only record of fills. `seq` is strictly increasing so it doubles
+/// as the pagination cursor.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Tape {
+    pub trades: Vec<Trade>,
+    /// Who sold to whom, for the trades still kept.
+    pub sales: Vec<Sale>,
+    next_seq: u64,
+}
+
+impl Tape {
+    pub fn record(&mut self, uname: &str, price: i64, vol: i64, ts_nanos: i64) -> &Trade {
+        self.next_seq += 1;
+        self.trades.push(Trade {
+            seq: self.next_seq,
+            uname: uname.to_owned(),
+            price,
+            vol,
+            ts_nanos,
+        });
+        self.trades.last().unwrap()
+    }
+
+    /// Records that `vol` of trade `seq`'s lots were `seller`'s.
+    pub fn record_sale(&mut self, seq: u64, seller: &str, buyer: &str, price: i64, vol: i64, ts_nanos: i64) {
+        let (seller, buyer) = (seller.to_owned(), buyer.to_owned());
+        self.sales.push(Sale { seq, seller, buyer, price, vol, ts_nanos });
+    }
+
+    /// Rewrites `uname` to `alias` on every trade and sale, returning how
+    /// many trades changed.
+    pub fn anonymize(&mut self, uname: &str, alias: &str) -> usize {
+        let mut n = 0;
+        for t in self.trades.iter_mut().filter(|t| t.uname == uname) {
+            t.uname = alias.to_owned();
+            n += 1;
+        }
+        for s in self.sales.iter_mut() {
+            for name in [&mut s.seller, &mut s.buyer] {
+                if name == uname {
+                    *name = alias.to_owned();
+                }
+            }
+        }
+        n
+    }
+
+    /// Drops the sales of trades that are gone.
+    fn drop_sales_before(&mut self, removed: &[Trade]) {
+        if let Some(last) = removed.last() {
+            let cut = self.sales.partition_point(|s| s.seq <= last.seq);
+            self.sales.drain(..cut);
+        }
+    }
+
+    /// Drops the oldest trades so at most `max_entries` remain and none is
+    /// older than `min_ts_nanos`, returning what was removed.
+    pub fn prune(&mut self, max_entries: Option<usize>, min_ts_nanos: Option<i64>) -> Vec<Trade> {
+        let mut cut = 0;
+        if let Some(max) = max_entries {
+            cut = self.trades.len().saturating_sub(max);
+        }
+        if let Some(min_ts) = min_ts_nanos {
+            cut = cut.max(self.trades.partition_point(|t| t.ts_nanos < min_ts));
+        }
+        let removed: Vec<Trade> = self.trades.drain(..cut).collect();
+        self.drop_sales_before(&removed);
+        removed
+    }
+
+    /// The sequence counter and sales alone, for stores that keep trades
+    /// elsewhere.
+    pub fn without_trades(&self) -> Tape {
+        Tape { trades: Vec::new(), sales: self.sales.clone(), next_seq: self.next_seq }
+    }
+
+    /// Rough size in memory, see `[memory]`.
+    pub fn approx_bytes(&self) -> usize {
+        self.trades.iter().map(trade_bytes).sum::<usize>() + self.sales.iter().map(sale_bytes).sum::<usize>()
+    }
+
+    /// Drops the oldest trades until the rest fit in `max_bytes`, returning
+    /// what was removed.
+    pub fn evict_to(&mut self, max_bytes: usize) -> Vec<Trade> {
+        let mut over = self.approx_bytes().saturating_sub(max_bytes);
+        let (mut cut, mut sale) = (0, 0);
+        while over > 0 && cut < self.trades.len() {
+            over = over.saturating_sub(trade_bytes(&self.trades[cut]));
+            while sale < self.sales.len() && self.sales[sale].seq <= self.trades[cut].seq {
+                over = over.saturating_sub(sale_bytes(&self.sales[sale]));
+                sale += 1;
+            }
+            cut += 1;
+        }
+        let removed: Vec<Trade> = self.trades.drain(..cut).collect();
+        self.drop_sales_before(&removed);
+        removed
+    }
+}
+
+fn trade_bytes(t: &Trade) -> usize {
+    std::mem::size_of::<Trade>() + t.uname.len()
+}
+
+fn sale_bytes(s: &Sale) -> usize {
+    std::mem::size_of::<Sale>() + s.seller.len() + s.buyer.len()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TapeQuery {
+    /// Only return trades with `seq` greater than this (the previous page's `next_cursor`).
+    pub after: Option<u64>,
+    pub limit: Option<usize>,
+    pub user: Option<String>,
+    pub min_price: Option<i64>,
+    pub max_price: Option<i64>,
+    pub from_nanos: Option<i64>,
+    pub to_nanos: Option<i64>,
+}
+
+impl TapeQuery {
+    fn matches(&self, t: &Trade) -> bool {
+        self.user.as_ref().map_or(true, |u| *u == t.uname)
+            && self.min_price.map_or(true, |p| t.price >= p)
+            && self.max_price.map_or(true, |p| t.price <= p)
+            && self.from_nanos.map_or(true, |ts| t.ts_nanos >= ts)
+            && self.to_nanos.map_or(true, |ts| t.ts_nanos < ts)
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct TapeResult {
+    pub trades: Vec<Trade>,
+    /// Pass back as `after` to continue, also once `has_more` is false to poll for new trades.
+    pub next_cursor: u64,
+    pub has_more: bool,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+pub async fn admin_tape(
+    Query(q): Query<TapeQuery>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<TapeResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    let limit = q.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let after = q.after.unwrap_or(0);
+
+    let trades = &g.tape.trades;
+    let start = trades.partition_point(|t| t.seq <= after);
+    let mut res = TapeResult {
+        next_cursor: after,
+        ..Default::default()
+    };
+    for t in trades[start..].iter() {
+        res.next_cursor = t.seq;
+        if q.matches(t) {
+            res.trades.push(t.clone());
+            if res.trades.len() == limit {
+                break;
+            }
+        }
+    }
+    res.has_more = trades.last().is_some_and(|t| t.seq > res.next_cursor);
+
+    clock.reply(StatusCode::OK, res)
+}
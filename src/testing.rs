@@ -0,0 +1,24 @@
+//! Games for unit tests, built from config the way `serve` builds them.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{AppConfig, AppState};
+
+/// A game from a config snippet. Unless it says otherwise, trading is open
+/// from the start, alice, bob and carol have 1000 each and calls are free.
+pub fn game(config: &str) -> AppState {
+    let mut v: toml::Value = toml::from_str(config).unwrap();
+    let t = v.as_table_mut().unwrap();
+    t.entry("trade_start_nanos").or_insert(toml::Value::Integer(0));
+    t.entry("init_balance").or_insert(toml::Value::Integer(1000));
+    t.entry("fee").or_insert(toml::Value::Integer(0));
+    let users = ["alice", "bob", "carol"].map(|u| toml::Value::String(u.to_owned()));
+    t.entry("users").or_insert(toml::Value::Array(users.to_vec()));
+    let cfg: AppConfig = v.try_into().unwrap();
+    AppState::new(&cfg, String::new())
+}
+
+/// `game` behind the lock, as handlers and spawned tasks take it.
+pub fn shared(config: &str) -> Arc<Mutex<AppState>> {
+    Arc::new(Mutex::new(game(config)))
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{contention::StateLock, feed::UserEvent, killswitch::user_of, now, AppState, ReqClock, RespMeta};
+
+/// Entries kept per user, and for game-wide actions; oldest go first.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Item {
+    Request { method: String, path: String, status: u16 },
+    Event { event: UserEvent },
+    /// Something done to the user, or to the whole game, by an admin or
+    /// the server itself.
+    Admin { action: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub ts_nanos: i64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+/// Recent history per user, held in memory only.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    users: HashMap<String, VecDeque<Entry>>,
+    global: VecDeque<Entry>,
+}
+
+fn push(q: &mut VecDeque<Entry>, item: Item) {
+    if q.len() == MAX_ENTRIES {
+        q.pop_front();
+    }
+    q.push_back(Entry { ts_nanos: now(), item });
+}
+
+fn entry_bytes(e: &Entry) -> usize {
+    let heap = match &e.item {
+        Item::Request { method, path, .. } => method.len() + path.len(),
+        Item::Admin { action } => action.len(),
+        Item::Event { .. } => 0,
+    };
+    std::mem::size_of::<Entry>() + heap
+}
+
+impl Timeline {
+    pub fn record(&mut self, uname: &str, item: Item) {
+        push(self.users.entry(uname.to_owned()).or_default(), item);
+    }
+
+    /// For actions that touch every user, like a pause or a restore.
+    pub fn record_global(&mut self, item: Item) {
+        push(&mut self.global, item);
+    }
+
+    pub fn admin(&mut self, uname: &str, action: impl Into<String>) {
+        self.record(uname, Item::Admin { action: action.into() });
+    }
+
+    pub fn admin_global(&mut self, action: impl Into<String>) {
+        self.record_global(Item::Admin { action: action.into() });
+    }
+
+    pub fn forget(&mut self, uname: &str) {
+        self.users.remove(uname);
+    }
+
+    /// Rough size in memory, see `[memory]`.
+    pub fn approx_bytes(&self) -> usize {
+        self.users.values().chain([&self.global]).flatten().map(entry_bytes).sum()
+    }
+
+    /// Drops the oldest entries, whoever they belong to, until the rest fit
+    /// in `max_bytes`. Returns how many went.
+    pub fn evict_to(&mut self, max_bytes: usize) -> usize {
+        let mut over = self.approx_bytes().saturating_sub(max_bytes);
+        let mut n = 0;
+        while over > 0 {
+            let oldest = self
+                .users
+                .values_mut()
+                .chain([&mut self.global])
+                .filter(|q| !q.is_empty())
+                .min_by_key(|q| q[0].ts_nanos);
+            let Some(e) = oldest.and_then(|q| q.pop_front()) else {
+                break;
+            };
+            over = over.saturating_sub(entry_bytes(&e));
+            n += 1;
+        }
+        self.users.retain(|_, q| !q.is_empty());
+        n
+    }
+
+    /// The user's entries merged with game-wide ones, oldest first.
+    pub fn of_user(&self, uname: &str) -> Vec<Entry> {
+        let mut all: Vec<Entry> = self.users.get(uname).into_iter().flatten().chain(&self.global).cloned().collect();
+        all.sort_by_key(|e| e.ts_nanos);
+        all
+    }
+}
+
+/// Records every request a known user makes, with the status it got.
+pub async fn record_requests(State(state): State<Arc<Mutex<AppState>>>, req: Request, next: Next) -> Response {
+    let Some(uname) = user_of(req.uri().path()).map(str::to_owned) else {
+        return next.run(req).await;
+    };
+    let (method, path) = (req.method().to_string(), req.uri().path().to_owned());
+    let resp = next.run(req).await;
+    let mut g = state.locked();
+    if g.users.contains_key(&uname) {
+        let status = resp.status().as_u16();
+        g.feeds.timeline.record(&uname, Item::Request { method, path, status });
+    }
+    resp
+}
+
+#[derive(Serialize, Default)]
+pub struct TimelineResult {
+    pub uname: String,
+    pub entries: Vec<Entry>,
+    #[serde(flatten)]
+    pub meta: RespMeta,
+}
+
+pub async fn admin_user_timeline(
+    Path(uname): Path<String>,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> (StatusCode, Json<TimelineResult>) {
+    let clock = ReqClock::start();
+    let g = state.locked();
+    if !g.users.contains_key(&uname) {
+        return clock.reply(StatusCode::NOT_FOUND, TimelineResult::default());
+    }
+    let entries = g.feeds.timeline.of_user(&uname);
+    clock.reply(StatusCode::OK, TimelineResult { uname, entries, ..Default::default() })
+}
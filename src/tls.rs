@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// HTTPS for every listener, from PEM files.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TlsConfig {
+    /// This build has no TLS stack, so `[tls]` stops the start rather than
+    /// serving keys and signatures in cleartext. Terminate TLS in a reverse
+    /// proxy in front of the `--listen` address instead.
+    pub fn check(&self) -> Result<(), String> {
+        Err(format!(
+            "[tls] ({}, {}) needs rustls, which this build doesn't include; terminate TLS in a reverse proxy",
+            self.cert_path, self.key_path
+        ))
+    }
+}
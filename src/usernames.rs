@@ -0,0 +1,119 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::Uri,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{contention::StateLock, AppState};
+
+/// How names in request paths are matched against the roster. Whatever a
+/// user types, state, the tape and exports only ever see the roster name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UsernamesConfig {
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Other names a user may go by, e.g. student ID -> roster name.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+/// Lookup from anything a user may send to their roster name.
+#[derive(Debug, Default)]
+pub struct Names {
+    case_insensitive: bool,
+    lookup: HashMap<String, String>,
+}
+
+impl Names {
+    pub fn new(cfg: &UsernamesConfig, roster: &[String]) -> Result<Self, String> {
+        let mut names = Names { case_insensitive: cfg.case_insensitive, lookup: HashMap::new() };
+        for u in roster {
+            names.insert(u, u)?;
+        }
+        for (alias, u) in &cfg.aliases {
+            if !roster.contains(u) {
+                return Err(format!("alias {} points to {}, who is not in users", alias, u));
+            }
+            names.insert(alias, u)?;
+        }
+        Ok(names)
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name.to_owned()
+        }
+    }
+
+    fn insert(&mut self, name: &str, canonical: &str) -> Result<(), String> {
+        match self.lookup.insert(self.key(name), canonical.to_owned()) {
+            Some(prev) if prev != canonical => Err(format!("{} could mean either {} or {}", name, prev, canonical)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes a user added at runtime known under their own name.
+    pub fn add(&mut self, uname: &str) -> Result<(), String> {
+        self.insert(uname, uname)
+    }
+
+    /// Drops `uname` and every alias that points to them.
+    pub fn forget(&mut self, uname: &str) {
+        self.lookup.retain(|_, c| c != uname);
+    }
+
+    /// Lookup keys and the roster names they stand for, to be kept with
+    /// the game.
+    pub fn entries(&self) -> BTreeMap<String, String> {
+        self.lookup.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Adds names kept with a game, such as users registered at runtime.
+    /// Names config already gives are left as they are.
+    pub fn restore(&mut self, entries: BTreeMap<String, String>) {
+        for (name, canonical) in entries {
+            let key = self.key(&name);
+            self.lookup.entry(key).or_insert(canonical);
+        }
+    }
+
+    /// The roster name for `name`, if it is known at all.
+    pub fn canonical(&self, name: &str) -> Option<&str> {
+        self.lookup.get(&self.key(name)).map(String::as_str)
+    }
+}
+
+/// Position of the username segment in `path`, for routes that take one.
+fn name_span(path: &str) -> Option<(usize, usize)> {
+    let prefix = ["/users/", "/admin/users/"].into_iter().find(|p| path.starts_with(p))?;
+    let start = prefix.len();
+    let end = path[start..].find('/').map_or(path.len(), |i| start + i);
+    Some((start, end))
+}
+
+/// Rewrites the username in the path to its roster name, ahead of routing.
+/// Unknown names pass through and get the usual 404. The URI as sent stays
+/// available as `OriginalUri`.
+pub async fn canonicalize(State(state): State<Arc<Mutex<AppState>>>, mut req: Request, next: Next) -> Response {
+    let rewritten = name_span(req.uri().path()).and_then(|(start, end)| {
+        let path = req.uri().path();
+        let g = state.locked();
+        let c = g.names.canonical(&path[start..end]).filter(|c| *c != &path[start..end])?;
+        let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+        format!("{}{}{}{}", &path[..start], c, &path[end..], query).parse::<Uri>().ok()
+    });
+    if let Some(uri) = rewritten {
+        let sent = std::mem::replace(req.uri_mut(), uri);
+        req.extensions_mut().insert(OriginalUri(sent));
+    }
+    next.run(req).await
+}